@@ -13,12 +13,18 @@ let descriptionBlock = null;
 let downloadLink = null;
 
 function galleryInit() {
-    document.querySelectorAll(".dir-listing .image").forEach((entry, index) => {
+    let index = 0;
+    document.querySelectorAll(".dir-listing .image, .dir-listing .video").forEach((entry) => {
         let mainLink = entry.querySelector(".main-link");
         let download = entry.querySelector("a.download")
         let thumbnail = entry.querySelector("img.thumbnail")
-        images.push([mainLink.innerText, download.href, thumbnail.src]);
+        if (entry.classList.contains("video") && !thumbnail.dataset.preview)
+            return; // Video thumbnails are disabled
+        // Videos are shown as their largest thumbnail
+        let src = thumbnail.dataset.preview || download.href;
+        images.push([mainLink.innerText, src, thumbnail.src, thumbnail.dataset.dimensions, download.href]);
         mainLink.href = hashForIndex(index);
+        index++;
     });
 
     galleryBg = document.getElementById("gallery");
@@ -76,12 +82,21 @@ function setCurrentNoHistory(index) {
         index = images.length - 1;
 
     currentIndex = index;
+    if (images[index][3]) {
+        // Reserve correctly shaped space for the image while it loads
+        let [width, height] = images[index][3].split("x");
+        galleryImg.width = width;
+        galleryImg.height = height;
+    } else {
+        galleryImg.removeAttribute("width");
+        galleryImg.removeAttribute("height");
+    }
     galleryImg.src = images[index][1];
     progressBar.removeAttribute('value');
     progressBar.innerText = "";
     galleryImgWrap.classList.add("loading");
     descriptionBlock.innerText = (index + 1) + "/" + images.length + " " + images[index][0];
-    downloadLink.href = images[index][1];
+    downloadLink.href = images[index][4];
 
     if (index > 0) {
         prevButton.href = hashForIndex(index - 1);
@@ -0,0 +1,49 @@
+function inboxInit() {
+    let form = document.querySelector("form.inbox");
+    if (!form)
+        return;
+    form.addEventListener("submit", inboxSubmit);
+}
+
+async function inboxSubmit(event) {
+    event.preventDefault();
+    let form = event.target;
+    let input = form.querySelector("input[type=file]");
+    let status = form.querySelector(".inbox-status");
+    let button = form.querySelector("button");
+
+    button.disabled = true;
+    for (let file of input.files) {
+        let item = document.createElement("li");
+        item.innerText = file.name + ": uploading";
+        status.appendChild(item);
+
+        // The key query parameter of the page has to be passed along
+        let url = form.dataset.uploadUrl + encodeURIComponent(file.name) + location.search;
+        try {
+            let response = await fetch(url, { method: "PUT", body: file });
+            if (response.ok) {
+                item.innerText = file.name + ": done";
+            } else {
+                item.innerText = file.name + ": failed (" + await errorMessage(response) + ")";
+            }
+        } catch (e) {
+            item.innerText = file.name + ": failed";
+        }
+    }
+    input.value = "";
+    button.disabled = false;
+}
+
+// Error description from the JSON body of a failed response, or the status if there is none.
+async function errorMessage(response) {
+    try {
+        let body = await response.json();
+        let fields = (body.fields || []).map(field => field.message);
+        return [body.error].concat(fields).join(": ");
+    } catch (e) {
+        return response.status + " " + response.statusText;
+    }
+}
+
+inboxInit();
@@ -6,6 +6,8 @@ use std::{
 };
 
 use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256, Sha384};
 use walkdir::WalkDir;
 
 fn main() {
@@ -22,6 +24,9 @@ fn process_assets(source_dir: &Path, dest_dir: &Path) -> anyhow::Result<()> {
     println!("cargo::rerun-if-changed={}", source_dir.display());
 
     let mut assets_rs = File::create(dest_dir.join("assets.rs"))?;
+    let mut data_uris = Vec::new();
+    let mut hashes = Vec::new();
+    let mut integrities = Vec::new();
 
     write!(
         assets_rs,
@@ -54,10 +59,34 @@ fn assets(name: &str) -> Option<(&'static [u8], mime::Mime)> {{
                 reference_path.set_extension("css");
                 (true, "TEXT_CSS")
             }
-            Some("svg") => (false, "IMAGE_SVG"),
+            Some("svg") => {
+                let content = std::fs::read_to_string(path)?;
+                if content.len() <= MAX_INLINE_SIZE {
+                    data_uris.push((
+                        path.strip_prefix(source_dir)?.display().to_string(),
+                        svg_data_uri(&content),
+                    ));
+                }
+                (false, "IMAGE_SVG")
+            }
             _ => (false, "APPLICATION_OCTET_STREAM"),
         };
 
+        let name = reference_path
+            .strip_prefix(source_dir)?
+            .display()
+            .to_string();
+        let content_path = if generated {
+            dest_dir.join(reference_path.strip_prefix(source_dir)?)
+        } else {
+            path.to_owned()
+        };
+        let content = std::fs::read(content_path)?;
+        if matches!(ext, Some("js") | Some("scss")) {
+            integrities.push((name.clone(), integrity(&content)));
+        }
+        hashes.push((name, content_hash(&content)));
+
         write!(
             assets_rs,
             "        \"{}\" => Some((include_bytes!(concat!(env!(\"{}\"), \"/{}\")).as_slice(), mime::{})),\n",
@@ -70,9 +99,90 @@ fn assets(name: &str) -> Option<(&'static [u8], mime::Mime)> {{
 
     write!(assets_rs, "        _ => None\n    }}\n}}\n")?;
 
+    write!(
+        assets_rs,
+        r#"
+fn asset_data_uris(name: &str) -> Option<&'static str> {{
+    match name {{
+"#
+    )?;
+    for (name, data_uri) in data_uris {
+        write!(assets_rs, "        {:?} => Some({:?}),\n", name, data_uri)?;
+    }
+    write!(assets_rs, "        _ => None\n    }}\n}}\n")?;
+
+    write!(
+        assets_rs,
+        r#"
+fn asset_hashes(name: &str) -> Option<&'static str> {{
+    match name {{
+"#
+    )?;
+    for (name, hash) in hashes {
+        write!(assets_rs, "        {:?} => Some({:?}),\n", name, hash)?;
+    }
+    write!(assets_rs, "        _ => None\n    }}\n}}\n")?;
+
+    write!(
+        assets_rs,
+        r#"
+fn asset_integrities(name: &str) -> Option<&'static str> {{
+    match name {{
+"#
+    )?;
+    for (name, integrity) in integrities {
+        write!(assets_rs, "        {:?} => Some({:?}),\n", name, integrity)?;
+    }
+    write!(assets_rs, "        _ => None\n    }}\n}}\n")?;
+
     Ok(())
 }
 
+/// Fingerprint of the asset content used for cache busting.
+/// Truncated SHA-256, collisions only cause a stale cached asset.
+fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content)[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Subresource integrity metadata of the asset content.
+fn integrity(content: &[u8]) -> String {
+    format!("sha384-{}", BASE64.encode(Sha384::digest(content)))
+}
+
+/// Largest SVG in bytes that is made available as a data URI
+const MAX_INLINE_SIZE: usize = 2048;
+
+/// Percent encode SVG into a data URI.
+/// Only characters that are unsafe in URIs or in HTML attributes are encoded,
+/// which keeps the URI shorter than base64.
+fn svg_data_uri(content: &str) -> String {
+    let mut uri = String::from("data:image/svg+xml,");
+    for c in content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+    {
+        match c {
+            '"' => uri.push('\''),
+            '%' | '#' | '<' | '>' | '&' | '{' | '}' | '\\' | '^' | '`' | '|' => {
+                uri.push_str(&format!("%{:02X}", c as u32))
+            }
+            c if c.is_ascii() => uri.push(c),
+            c => {
+                let mut buf = [0; 4];
+                for byte in c.encode_utf8(&mut buf).bytes() {
+                    uri.push_str(&format!("%{:02X}", byte));
+                }
+            }
+        }
+    }
+    uri
+}
+
 fn minify_js(source: &Path, dest: &Path) -> anyhow::Result<()> {
     use minify_js::{minify, Session};
 
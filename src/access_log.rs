@@ -0,0 +1,192 @@
+//! Access log with one line per request, written to standard output so that it stays
+//! separate from the application log on standard error.
+
+use crate::{app_data::EMBEDDED_KEY_SEPARATOR, config::AccessLogFormat};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{io::Write, net::IpAddr, time::Duration};
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub time: DateTime<Utc>,
+    pub client: Option<IpAddr>,
+    pub method: String,
+    /// Path including the query string, with the secrets redacted
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    /// Size of the response body actually sent
+    pub bytes: u64,
+    /// Time from receiving the request until the response body was finished
+    #[serde(serialize_with = "serialize_millis", rename = "duration_ms")]
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_micros() as f64 / 1000.0)
+}
+
+impl AccessLogEntry {
+    /// Apache combined log format, with the duration in microseconds appended (like `%D`).
+    fn to_combined(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {}",
+            self.client
+                .map_or_else(|| "-".to_owned(), |client| client.to_string()),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            self.bytes,
+            self.referer.as_deref().map_or_else(|| "-".into(), escape),
+            self.user_agent
+                .as_deref()
+                .map_or_else(|| "-".into(), escape),
+            self.duration.as_micros()
+        )
+    }
+
+    pub fn format(&self, format: AccessLogFormat) -> Option<String> {
+        match format {
+            AccessLogFormat::Off => None,
+            AccessLogFormat::Combined => Some(self.to_combined()),
+            AccessLogFormat::Json => {
+                Some(serde_json::to_string(self).expect("Serializing the entry can't fail"))
+            }
+        }
+    }
+
+    pub fn write(&self, format: AccessLogFormat) {
+        if let Some(line) = self.format(format) {
+            // Nothing sensible to do if stdout is gone
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+        }
+    }
+}
+
+/// Replace the secrets in a request path or URL, so that the log doesn't hand out access
+/// to unlisted objects: keys and share link tokens embedded in path segments after `~`,
+/// and the `key` query parameter.
+pub fn redact_path(path: &str) -> String {
+    const REDACTED: &str = "REDACTED";
+
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let mut output = path
+        .split('/')
+        .map(|segment| {
+            let separator = segment
+                .find(EMBEDDED_KEY_SEPARATOR)
+                .map(|i| (i, EMBEDDED_KEY_SEPARATOR.len_utf8()))
+                .or_else(|| segment.to_ascii_lowercase().find("%7e").map(|i| (i, 3)));
+            match separator {
+                Some((i, len)) => format!("{}{}", &segment[..i + len], REDACTED),
+                None => segment.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    if let Some(query) = query {
+        output.push('?');
+        output.push_str(
+            &query
+                .split('&')
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, _)) if name.eq_ignore_ascii_case("key") => {
+                        format!("{}={}", name, REDACTED)
+                    }
+                    _ => pair.to_owned(),
+                })
+                .collect::<Vec<_>>()
+                .join("&"),
+        );
+    }
+    output
+}
+
+/// Escape quotes, backslashes and control characters, so that a request can't forge
+/// log lines or break the quoted fields.
+fn escape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                output.push('\\');
+                output.push(c);
+            }
+            c if c.is_control() => output.extend(c.escape_default()),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: Utc.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap(),
+            client: Some("192.0.2.7".parse().unwrap()),
+            method: "GET".to_owned(),
+            path: "/download/a b?mode=preview".to_owned(),
+            protocol: "HTTP/1.1".to_owned(),
+            status: 200,
+            bytes: 1234,
+            duration: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.0 \"quoted\"\n".to_owned()),
+        }
+    }
+
+    #[test]
+    fn combined_format() {
+        assert!(
+            entry().format(AccessLogFormat::Combined).unwrap()
+                == "192.0.2.7 - - [05/Mar/2024:14:07:09 +0000] \"GET /download/a b?mode=preview HTTP/1.1\" 200 1234 \"-\" \"curl/8.0 \\\"quoted\\\"\\n\" 1500"
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line = entry().format(AccessLogFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(value["status"] == 200);
+        assert!(value["client"] == "192.0.2.7");
+        assert!(value["duration_ms"] == 1.5);
+        assert!(value["referer"].is_null());
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        assert!(redact_path("/download/a/b.txt?mode=preview") == "/download/a/b.txt?mode=preview");
+        assert!(redact_path("/download/a~secret/b.txt") == "/download/a~REDACTED/b.txt");
+        assert!(redact_path("/download/a%7Esecret/b.txt") == "/download/a%7EREDACTED/b.txt");
+        assert!(redact_path("/download/a~1700000000.5.c2lnbmF0dXJl") == "/download/a~REDACTED");
+        assert!(
+            redact_path("/download/a/?mode=preview&key=secret")
+                == "/download/a/?mode=preview&key=REDACTED"
+        );
+        assert!(
+            redact_path("https://files.example.com/download/a~secret/?key=secret")
+                == "https://files.example.com/download/a~REDACTED/?key=REDACTED"
+        );
+    }
+
+    #[test]
+    fn off() {
+        assert!(entry().format(AccessLogFormat::Off).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{
+    app_data::AppData,
+    error::{FiledlError, Result},
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap, HeaderValue},
+    web::Data,
+    Error, HttpResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use std::{future::Future, pin::Pin, sync::Arc};
+
+const ADMIN_REALM: &str = "Basic realm=\"filedl admin\"";
+
+/// Is the (already decoded and normalized) request path part of the admin interface?
+pub fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+/// Check the request headers against the configured admin token.
+/// The token is accepted either as a bearer token, or as the password of basic auth
+/// (with any user name), so that the admin interface can be used from a browser.
+/// Fails closed if no token is configured.
+pub fn check_admin_auth(admin_token: Option<&str>, headers: &HeaderMap) -> Result<()> {
+    let Some(admin_token) = admin_token else {
+        return Err(FiledlError::AdminDisabled);
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(token_from_authorization);
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        _ => Err(FiledlError::AdminAuthRequired),
+    }
+}
+
+/// Extract the token from a value of the Authorization header
+fn token_from_authorization(value: &str) -> Option<String> {
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(credentials.to_owned())
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+        let (_user, password) = decoded.split_once(':')?;
+        Some(password.to_owned())
+    } else {
+        None
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware function rejecting all requests to the admin interface that don't carry
+/// the admin token.
+/// Must be registered inside of NormalizePath, so that it sees the normalized path.
+pub fn require_admin<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> Pin<Box<dyn Future<Output = std::result::Result<ServiceResponse<BoxBody>, Error>>>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    if is_admin_path(req.match_info().as_str()) {
+        let admin_token = req
+            .app_data::<Data<Arc<AppData>>>()
+            .and_then(|app| app.get_admin_token().map(str::to_owned));
+
+        if let Err(e) = check_admin_auth(admin_token.as_deref(), req.headers()) {
+            let auth_required = matches!(e, FiledlError::AdminAuthRequired);
+            let mut response = HttpResponse::from_error(e);
+            if auth_required {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(ADMIN_REALM),
+                );
+            }
+            // Unlike `into_response`, keeps the error for `html_errors`
+            let response = ServiceResponse::new(req.into_parts().0, response);
+            return Box::pin(async move { Ok(response) });
+        }
+    }
+
+    let response_future = srv.call(req);
+    Box::pin(async move { Ok(response_future.await?.map_into_boxed_body()) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(authorization).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn admin_paths() {
+        assert!(is_admin_path("/admin"));
+        assert!(is_admin_path("/admin/objects"));
+        assert!(is_admin_path("/admin/object/abc"));
+        assert!(!is_admin_path("/administrator"));
+        assert!(!is_admin_path("/download/admin"));
+        assert!(!is_admin_path("/"));
+    }
+
+    #[test]
+    fn fails_closed_without_token() {
+        let result = check_admin_auth(None, &headers(Some("Bearer anything")));
+        assert!(let Err(FiledlError::AdminDisabled) = result);
+        let result = check_admin_auth(None, &headers(None));
+        assert!(let Err(FiledlError::AdminDisabled) = result);
+    }
+
+    #[test]
+    fn accepts_bearer_token() {
+        assert!(let Ok(()) = check_admin_auth(Some("secret"), &headers(Some("Bearer secret"))));
+        assert!(let Ok(()) = check_admin_auth(Some("secret"), &headers(Some("bearer secret"))));
+    }
+
+    #[test]
+    fn accepts_basic_auth_password() {
+        let credentials = format!("Basic {}", STANDARD.encode("admin:secret"));
+        assert!(let Ok(()) = check_admin_auth(Some("secret"), &headers(Some(&credentials))));
+        let credentials = format!("Basic {}", STANDARD.encode(":secret"));
+        assert!(let Ok(()) = check_admin_auth(Some("secret"), &headers(Some(&credentials))));
+    }
+
+    #[test]
+    fn rejects_bad_credentials() {
+        let token = Some("secret");
+        let wrong_basic = format!("Basic {}", STANDARD.encode("secret:wrong"));
+        for authorization in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer secre"),
+            Some("Bearer"),
+            Some("Token secret"),
+            Some("Basic !!!"),
+            Some(wrong_basic.as_str()),
+        ] {
+            let result = check_admin_auth(token, &headers(authorization));
+            assert!(let Err(FiledlError::AdminAuthRequired) = result);
+        }
+    }
+
+    #[actix_web::test]
+    async fn middleware_guards_admin_scope() {
+        use actix_web::{http::StatusCode, test, web, App, HttpResponse};
+
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(require_admin)
+                .route("/admin/objects", web::get().to(HttpResponse::Ok))
+                .route("/download", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for uri in ["/admin/objects", "/%61dmin/objects"] {
+            let response =
+                test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert!(response.status() == StatusCode::FORBIDDEN);
+        }
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/download").to_request()).await;
+        assert!(response.status() == StatusCode::OK);
+    }
+}
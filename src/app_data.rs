@@ -1,32 +1,68 @@
 use crate::{
-    config::Config,
+    assets::AssetSource,
+    backup::Backups,
+    bandwidth::{Bandwidth, BandwidthReport, TransferCaps},
+    checksums::Checksums,
+    chunks::ChunkStore,
+    config::{AccessLogFormat, Config, HotlinkResponse, MetadataBackend},
     error::{FiledlError, Result},
+    glob::glob_match,
+    ical::CalendarEvent,
+    password::{constant_time_eq, hex, PasswordHash},
+    probe,
+    rate_limit::RateLimiter,
+    search::SearchIndex,
+    share_links::{ShareLink, ShareLinks},
+    site_auth::SiteAuth,
+    stats::{ObjectStats, Stats},
     storage::Storage,
-    templates::util::url_encode,
-    thumbnails::{is_thumbnailable, CacheStats, CachedThumbnails},
+    templates::{util::url_encode, RootGrouping},
+    thumbnails::{
+        clamp_thumbnail_size, is_document, is_thumbnailable, is_video, CacheStats,
+        CachedThumbnails, DocumentThumbnailer, ImageThumbnailer, ThumbnailFit, Thumbnailer,
+        VideoThumbnailer, Watermark,
+    },
+    uploads::UploadSessions,
+    validation::FieldError,
 };
-use actix_web::web::Bytes;
-use chrono::{DateTime, Utc};
+#[cfg(feature = "sqlite")]
+use crate::{sqlite_storage::SqliteBackend, storage::read_map};
+use actix_web::{cookie::Key, web::Bytes};
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc, Weekday};
 use chrono_tz::Tz;
+use futures_util::{Stream, StreamExt};
 use rand::{thread_rng, RngCore};
 use relative_path::RelativePathBuf;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    fs::Metadata,
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, Metadata},
     hash::{Hash, Hasher},
+    io::BufReader,
+    net::IpAddr,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
     time::SystemTime,
 };
 use tokio::{
     fs,
+    io::AsyncWriteExt,
     sync::{RwLock, RwLockReadGuard},
+    task::spawn_blocking,
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ObjectOwnership {
     Owned,
     Linked(RelativePathBuf),
+    /// Directory inside another object, shared with its own key and expiry.
+    /// Only available while the parent object exists and hasn't ended.
+    Nested {
+        parent: Arc<str>,
+        path: RelativePathBuf,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,6 +72,176 @@ pub struct Object {
     pub expires: Option<DateTime<Utc>>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub unlisted_key: Option<Arc<str>>,
+    /// The object was removed, only its tombstone remains.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub removed: bool,
+    /// If set, a "share has ended" page is displayed after the object is removed or expires,
+    /// instead of a plain 404.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tombstone: Option<Tombstone>,
+    /// Terms that visitors have to accept before downloading files of this object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terms: Option<Arc<str>>,
+    /// Maximum number of requests per minute to this object (including listings and thumbnails).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
+    /// Image overlaid over thumbnails of this object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<Watermark>,
+    /// File inside the object (e.g. `index.html`) served instead of paths that don't exist,
+    /// for hosting static sites and single page applications.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<RelativePathBuf>,
+    /// Name of the access group whose tokens are required to access this object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_group: Option<Arc<str>>,
+    /// Time windows when the object can be accessed, in the display timezone.
+    /// The object is always available if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<AvailabilityWindow>,
+    /// Password required for downloading anything from the object.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<PasswordHash>,
+    /// Serve `index.html` of directories instead of the listing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub static_site: bool,
+    /// Pinned objects never expire and can't be removed until they are unpinned.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// Visitors can upload files into the object, but not see or download them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbox: Option<Inbox>,
+    /// Free-form labels for organizing objects, the root listing can be filtered by them.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<Arc<str>>,
+    /// Accessible without the site authentication, when it is configured.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub public: bool,
+    /// Created anonymously through the drop box, removed with its data once it expires.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub drop_box: bool,
+}
+
+/// Settings of an object that accepts uploads from visitors.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Inbox {
+    /// Maximum total size of the uploaded files, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Uploads are refused after this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closes: Option<DateTime<Utc>>,
+}
+
+impl Inbox {
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.closes.map_or(true, |closes| now < closes)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Custom message displayed on the tombstone page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Arc<str>>,
+}
+
+/// Recurring time window when an object is available.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AvailabilityWindow {
+    /// Days when the window starts, every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// End of the window, if it is before the start, the window continues over midnight.
+    pub end: TimeOfDay,
+}
+
+impl AvailabilityWindow {
+    fn contains<Z: TimeZone>(&self, time: &DateTime<Z>) -> bool {
+        let starts_on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let weekday = time.weekday();
+        let time = time.time();
+
+        if self.start.0 < self.end.0 {
+            starts_on(weekday) && self.start.0 <= time && time < self.end.0
+        } else {
+            (starts_on(weekday) && self.start.0 <= time)
+                || (starts_on(weekday.pred()) && time < self.end.0)
+        }
+    }
+}
+
+/// Time of day, written as `HH:MM` or `HH:MM:SS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0.format("%H:%M:%S"))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&s, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(&s, "%H:%M"))
+            .map(TimeOfDay)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Object {
+    pub fn new(ownership: ObjectOwnership) -> Self {
+        Object {
+            ownership,
+            expires: None,
+            unlisted_key: None,
+            removed: false,
+            tombstone: None,
+            terms: None,
+            rate_limit: None,
+            watermark: None,
+            fallback: None,
+            access_group: None,
+            availability: Vec::new(),
+            password: None,
+            static_site: false,
+            pinned: false,
+            inbox: None,
+            tags: BTreeSet::new(),
+            public: false,
+            drop_box: false,
+        }
+    }
+
+    /// Returns true if anyone can browse the object without a key, password, token
+    /// or accepting terms, so its content can appear in search results.
+    pub fn is_searchable(&self, now: DateTime<Utc>) -> bool {
+        self.unlisted_key.is_none()
+            && self.password.is_none()
+            && self.access_group.is_none()
+            && self.terms.is_none()
+            && self.inbox.is_none()
+            && !self.has_ended(now)
+    }
+
+    /// Returns true if the object is inside one of its availability windows.
+    pub fn is_available<Z: TimeZone>(&self, now: &DateTime<Z>) -> bool {
+        self.availability.is_empty() || self.availability.iter().any(|window| window.contains(now))
+    }
+
+    /// Returns true if the object was removed or has expired.
+    /// Expiry doesn't apply to pinned objects.
+    pub fn has_ended(&self, now: DateTime<Utc>) -> bool {
+        self.removed || (!self.pinned && self.expires.is_some_and(|expires| expires <= now))
+    }
 }
 
 #[derive(Debug)]
@@ -44,6 +250,8 @@ pub struct ResolvedObject<'a> {
     object: RwLockReadGuard<'a, Object>,
     metadata: Metadata,
     thumbnails: &'a CachedThumbnails,
+    /// Share link through which the object was accessed
+    share_link: Option<ShareLink>,
 }
 
 impl<'a> ResolvedObject<'a> {
@@ -51,6 +259,7 @@ impl<'a> ResolvedObject<'a> {
         path: PathBuf,
         object: RwLockReadGuard<'a, Object>,
         thumbnails: &'a CachedThumbnails,
+        share_link: Option<ShareLink>,
     ) -> Result<Self> {
         let metadata = fs::metadata(&path).await?;
 
@@ -59,6 +268,7 @@ impl<'a> ResolvedObject<'a> {
             object,
             metadata,
             thumbnails,
+            share_link,
         })
     }
 
@@ -74,32 +284,125 @@ impl<'a> ResolvedObject<'a> {
         ItemType::new(&self.path, &self.metadata)
     }
 
-    pub async fn into_thumbnail(self, size: (u32, u32)) -> Result<(Bytes, String)> {
-        self.thumbnails.get(self.path, &self.metadata, size).await
+    pub fn source_hash(&self) -> Option<u64> {
+        get_source_hash(&self.path, &self.metadata)
     }
 
-    pub async fn list(&self) -> Result<Vec<DirListingItem>> {
-        let mut result = Vec::new();
+    /// Hash of the thumbnail that `into_thumbnail` would return.
+    pub fn thumbnail_hash(&self, size: (u32, u32), quality: u8, fit: ThumbnailFit) -> String {
+        CachedThumbnails::hash(
+            self.path.clone(),
+            &self.metadata,
+            size,
+            quality,
+            fit,
+            self.object.watermark.clone(),
+        )
+    }
+
+    pub async fn into_thumbnail(
+        self,
+        size: (u32, u32),
+        quality: u8,
+        fit: ThumbnailFit,
+    ) -> Result<(Bytes, String)> {
+        let watermark = self.object.watermark.clone();
+        self.thumbnails
+            .get(self.path, &self.metadata, size, quality, fit, watermark)
+            .await
+    }
 
+    /// Items of the directory without image dimensions, which are slow to probe.
+    /// Used for paginated listings, where only the displayed items are probed.
+    pub async fn list_unprobed(&self) -> Result<Vec<DirListingItem>> {
         let mut dir = fs::read_dir(&self.path).await?;
+        let mut items = Vec::new();
         while let Some(entry) = dir.next_entry().await? {
-            if let Some(item) = DirListingItem::with_dir_entry(entry).await? {
-                result.push(item);
+            if let Some(item) = DirListingItem::with_dir_entry(entry, false).await? {
+                items.push(item);
             }
         }
+        Ok(items)
+    }
 
-        Ok(result)
+    /// Items of the directory, produced as they are read.
+    /// The stream doesn't borrow the object and ends after the first error.
+    pub fn list_stream(&self) -> impl Stream<Item = std::io::Result<DirListingItem>> + 'static {
+        enum ListState {
+            Start(PathBuf),
+            Reading(fs::ReadDir),
+            Done,
+        }
+
+        futures_util::stream::unfold(
+            ListState::Start(self.path.clone()),
+            |mut state| async move {
+                loop {
+                    state = match state {
+                        ListState::Start(path) => match fs::read_dir(path).await {
+                            Ok(dir) => ListState::Reading(dir),
+                            Err(e) => return Some((Err(e), ListState::Done)),
+                        },
+                        ListState::Reading(mut dir) => match dir.next_entry().await {
+                            Ok(Some(entry)) => match DirListingItem::with_dir_entry(entry, true)
+                                .await
+                            {
+                                Ok(Some(item)) => return Some((Ok(item), ListState::Reading(dir))),
+                                Ok(None) => ListState::Reading(dir),
+                                Err(e) => return Some((Err(e), ListState::Done)),
+                            },
+                            Ok(None) => return None,
+                            Err(e) => return Some((Err(e), ListState::Done)),
+                        },
+                        ListState::Done => return None,
+                    }
+                }
+            },
+        )
     }
 
     pub fn is_unlisted(&self) -> bool {
         self.object.unlisted_key.is_some()
     }
+
+    pub fn share_link(&self) -> Option<&ShareLink> {
+        self.share_link.as_ref()
+    }
+
+    /// Expiry time of the object, pinned objects don't expire.
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.object.expires.filter(|_| !self.object.pinned)
+    }
+
+    pub fn terms(&self) -> Option<&str> {
+        self.object.terms.as_deref()
+    }
+
+    pub fn is_static_site(&self) -> bool {
+        self.object.static_site
+    }
+
+    pub fn password(&self) -> Option<&PasswordHash> {
+        self.object.password.as_ref()
+    }
+
+    pub fn inbox(&self) -> Option<&Inbox> {
+        self.object.inbox.as_ref()
+    }
+
+    pub fn watermark(&self) -> Option<&Watermark> {
+        self.object.watermark.as_ref()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum ItemType {
     Directory,
     Image,
+    Video,
+    Audio,
+    /// Office document, previewed by the converter command
+    Document,
     /// File of other/unknown type
     File,
 }
@@ -108,6 +411,12 @@ impl ItemType {
     pub fn new(path: &Path, metadata: &Metadata) -> Self {
         if is_thumbnailable(path) {
             ItemType::Image
+        } else if is_video(path) && !metadata.is_dir() {
+            ItemType::Video
+        } else if is_audio(path) && !metadata.is_dir() {
+            ItemType::Audio
+        } else if is_document(path) && !metadata.is_dir() {
+            ItemType::Document
         } else if metadata.is_dir() {
             ItemType::Directory
         } else {
@@ -122,6 +431,31 @@ impl ItemType {
     pub fn is_thumbnailable(&self) -> bool {
         matches!(self, ItemType::Image)
     }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, ItemType::Video)
+    }
+
+    pub fn is_document(&self) -> bool {
+        matches!(self, ItemType::Document)
+    }
+
+    /// Video or audio, playable in the browser.
+    pub fn is_media(&self) -> bool {
+        matches!(self, ItemType::Video | ItemType::Audio)
+    }
+}
+
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "m4a", "aac", "ogg", "oga", "opus", "flac", "wav"];
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .any(|audio_extension| audio_extension.eq_ignore_ascii_case(extension))
+        })
 }
 
 impl std::fmt::Display for ItemType {
@@ -169,20 +503,27 @@ pub struct DirListingItem {
     pub file_size: u64,
     pub modified: Option<DateTime<Utc>>,
     pub source_hash: Option<u64>,
+    /// Displayed width and height of images
+    pub dimensions: Option<(u32, u32)>,
+    /// Tags of objects in the root listing
+    pub tags: Vec<Arc<str>>,
 }
 
 impl DirListingItem {
     /// Create the dir listing item from directory entry.
     /// If the filename contains non-unicode characters, returns Ok(None).
-    async fn with_dir_entry(entry: fs::DirEntry) -> std::io::Result<Option<Self>> {
+    /// Dimensions are only probed if `probe` is set.
+    async fn with_dir_entry(entry: fs::DirEntry, probe: bool) -> std::io::Result<Option<Self>> {
         let Ok(name) = entry.file_name().into_string() else {
             return Ok(None);
         };
-        Ok(Some(Self::with_metadata(
-            &entry.path(),
-            name.into(),
-            &entry.metadata().await?,
-        )))
+        let path = entry.path();
+        let item = Self::with_metadata(&path, name.into(), &entry.metadata().await?);
+        if probe {
+            Ok(Some(item.with_probed_dimensions(path).await))
+        } else {
+            Ok(Some(item))
+        }
     }
 
     fn with_metadata(path: &Path, name: Arc<str>, metadata: &Metadata) -> Self {
@@ -193,8 +534,516 @@ impl DirListingItem {
             file_size: metadata.len(),
             modified: metadata.modified().ok().map(Into::into),
             source_hash: get_source_hash(path, metadata),
+            dimensions: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Fill in dimensions of images.
+    pub async fn with_probed_dimensions(mut self, path: PathBuf) -> Self {
+        if self.item_type.is_thumbnailable() {
+            self.dimensions = spawn_blocking(move || probe::image_dimensions(&path))
+                .await
+                .expect("Probing image dimensions should not panic");
+        }
+        self
+    }
+}
+
+/// Maintenance mode state, while enabled all download routes return 503.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Maintenance {
+    pub enabled: bool,
+    /// Value of the Retry-After header sent to clients, in seconds
+    #[serde(default = "default_maintenance_retry_after")]
+    pub retry_after: u32,
+}
+
+fn default_maintenance_retry_after() -> u32 {
+    600
+}
+
+impl Maintenance {
+    /// Returns the retry after delay if the maintenance mode is enabled.
+    pub fn retry_after(&self) -> Option<u32> {
+        self.enabled.then_some(self.retry_after)
+    }
+
+    /// Load the persisted state, maintenance mode is disabled if the file doesn't exist.
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e),
+        };
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+/// Selection of objects for bulk operations, all given conditions must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct ObjectFilter {
+    /// Exact object IDs
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
+    /// Glob pattern matching the object IDs
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub unlisted: Option<bool>,
+    /// Object was removed or has expired
+    #[serde(default)]
+    pub ended: Option<bool>,
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    /// The object has this tag
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Confirms that a filter without any condition is meant to select every object
+    #[serde(default)]
+    pub all: bool,
+}
+
+impl ObjectFilter {
+    /// True if no condition is given, so that every object matches.
+    fn is_unconditional(&self) -> bool {
+        self.ids.is_none()
+            && self.pattern.is_none()
+            && self.unlisted.is_none()
+            && self.ended.is_none()
+            && self.pinned.is_none()
+            && self.tag.is_none()
+    }
+
+    fn matches(&self, id: &str, object: &Object, now: DateTime<Utc>) -> bool {
+        self.ids
+            .as_ref()
+            .map_or(true, |ids| ids.iter().any(|filter_id| filter_id == id))
+            && self
+                .pattern
+                .as_deref()
+                .map_or(true, |pattern| glob_match(pattern, id))
+            && self
+                .unlisted
+                .map_or(true, |unlisted| object.unlisted_key.is_some() == unlisted)
+            && self
+                .ended
+                .map_or(true, |ended| object.has_ended(now) == ended)
+            && self.pinned.map_or(true, |pinned| object.pinned == pinned)
+            && self
+                .tag
+                .as_deref()
+                .map_or(true, |tag| object.tags.contains(tag))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BulkOperation {
+    /// Set or clear (null) the expiry time
+    SetExpiry { expires: Option<DateTime<Utc>> },
+    /// Remove the objects, the same way as removing them one by one
+    Remove,
+    /// Replace unlisted keys with new random ones, listed objects are skipped
+    RotateUnlistedKeys,
+}
+
+/// Outcome of a bulk operation, per changed object.
+#[derive(Debug, Serialize)]
+pub struct BulkResult {
+    pub dry_run: bool,
+    pub objects: Vec<BulkChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkChange {
+    pub id: Arc<str>,
+    /// The new key, when rotating unlisted keys
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unlisted_key: Option<Arc<str>>,
+}
+
+/// Placeholder replacing unlisted keys in redacted metadata exports.
+pub const REDACTED_KEY: &str = "redacted";
+
+/// What to do when an imported object ID already exists.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportConflict {
+    /// Refuse the whole import
+    #[default]
+    Fail,
+    /// Keep the existing object
+    Skip,
+    /// Replace the existing object with the imported one
+    Overwrite,
+}
+
+/// Outcome of a metadata import, IDs of the objects by what happened to them.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportResult {
+    pub dry_run: bool,
+    pub added: Vec<Arc<str>>,
+    pub replaced: Vec<Arc<str>>,
+    pub skipped: Vec<Arc<str>>,
+}
+
+/// Decide how the imported objects change the existing ones.
+/// Redacted unlisted keys keep the key of the replaced object, or get a new random one,
+/// so that an unlisted object never becomes listed or guessable by the import.
+fn plan_import<'a>(
+    existing: impl Fn(&str) -> Option<&'a Object>,
+    imported: BTreeMap<Arc<str>, Object>,
+    on_conflict: ImportConflict,
+) -> Result<(Vec<(Arc<str>, Option<Object>)>, ImportResult)> {
+    let mut changes = Vec::new();
+    let mut result = ImportResult::default();
+    for (id, mut object) in imported {
+        if !is_valid_object_id(&id) {
+            return Err(FiledlError::InvalidRequest {
+                errors: vec![FieldError::new("objects", "Invalid object ID")],
+            });
+        }
+        if let ObjectOwnership::Nested { path, .. } = &object.ownership {
+            if sanitize_subobject_path(path.as_str()).is_none() {
+                return Err(FiledlError::InvalidRequest {
+                    errors: vec![FieldError::new("objects", "Invalid nested object path")],
+                });
+            }
+        }
+        let current = existing(&id);
+        match (current, on_conflict) {
+            (Some(_), ImportConflict::Fail) => return Err(FiledlError::ImportConflict { id }),
+            (Some(_), ImportConflict::Skip) => {
+                result.skipped.push(id);
+                continue;
+            }
+            (Some(_), ImportConflict::Overwrite) => result.replaced.push(Arc::clone(&id)),
+            (None, _) => result.added.push(Arc::clone(&id)),
+        }
+        if object.unlisted_key.as_deref() == Some(REDACTED_KEY) {
+            object.unlisted_key = current
+                .and_then(|current| current.unlisted_key.clone())
+                .or_else(|| Some(generate_unlisted_key()));
+        }
+        changes.push((id, Some(object)));
+    }
+    Ok((changes, result))
+}
+
+/// Random key for unlisted objects.
+fn generate_unlisted_key() -> Arc<str> {
+    let mut key = [0u8; 16];
+    thread_rng().fill_bytes(&mut key);
+    key.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+        .into()
+}
+
+/// Object description for the admin interface
+#[derive(Debug, Serialize)]
+pub struct AdminObjectInfo {
+    pub id: Arc<str>,
+    #[serde(flatten)]
+    pub object: Object,
+    pub stats: ObjectStats,
+}
+
+/// Check that path inside an object only consists of normal components, so that it can't
+/// point outside of the object.
+/// Returns None if the path contains `..`, `.`, empty components, backslashes or NUL characters.
+fn sanitize_subobject_path(path: &str) -> Option<RelativePathBuf> {
+    let valid = path.split('/').all(|component| {
+        !component.is_empty()
+            && component != "."
+            && component != ".."
+            && !component.contains(['\\', '\0'])
+    });
+    valid.then(|| RelativePathBuf::from(path))
+}
+
+/// Check that the object ID can be used as a file name in the owned data directory
+/// and in download URLs.
+fn is_valid_object_id(object_id: &str) -> bool {
+    !object_id.is_empty()
+        && !object_id.starts_with('.')
+        && !object_id.contains(['/', '\\', '\0', EMBEDDED_KEY_SEPARATOR])
+}
+
+/// Refuse metadata with object IDs that would be split at the embedded key separator
+/// when downloading, making the objects unreachable.
+fn check_stored_object_ids(objects: &Storage<Object>) -> Result<()> {
+    let mut ids: Vec<Arc<str>> = objects
+        .iter()
+        .map(|(id, _)| id)
+        .filter(|id| id.contains(EMBEDDED_KEY_SEPARATOR))
+        .cloned()
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    ids.sort();
+    Err(FiledlError::ObjectIdWithKeySeparator { ids })
+}
+
+/// Client for all outbound HTTP requests, going through the configured proxy.
+fn outbound_http_client(config: &Config) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().user_agent(concat!(
+        env!("CARGO_PKG_NAME"),
+        "/",
+        env!("CARGO_PKG_VERSION")
+    ));
+    if let Some(proxy_url) = &config.outbound_proxy {
+        let proxy = reqwest::Proxy::all(proxy_url)?.no_proxy(
+            config
+                .outbound_no_proxy
+                .as_deref()
+                .and_then(reqwest::NoProxy::from_string),
+        );
+        builder = builder.proxy(proxy);
+    }
+    builder.build()
+}
+
+/// Find files and directories in the data directories that could become objects,
+/// for migrating from plain static hosting.
+fn find_legacy_objects(config: &Config) -> std::io::Result<Vec<(Arc<str>, Object)>> {
+    let mut result: Vec<(Arc<str>, Object)> = Vec::new();
+    let sources = [
+        (config.data_path.join("owned_data"), true),
+        (config.linked_objects_root.clone(), false),
+    ];
+    for (dir, owned) in sources {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let Ok(name) = entry?.file_name().into_string() else {
+                continue;
+            };
+            if !is_valid_object_id(&name) || result.iter().any(|(id, _)| **id == *name) {
+                continue;
+            }
+            let ownership = if owned {
+                ObjectOwnership::Owned
+            } else {
+                ObjectOwnership::Linked(RelativePathBuf::from(name.as_str()))
+            };
+            result.push((name.into(), Object::new(ownership)));
+        }
+    }
+    Ok(result)
+}
+
+/// Open the SQLite metadata database. When it is empty, objects from `metadata.json`
+/// are migrated into it and the file is renamed to `metadata.json.migrated`.
+#[cfg(feature = "sqlite")]
+fn open_sqlite_metadata(config: &Config) -> Result<(Storage<Object>, PathBuf)> {
+    let path = config.data_path.join("metadata.sqlite");
+    let backend = SqliteBackend::open(path.clone())?;
+    let mut objects = Storage::with_backend(Box::new(backend))?;
+    if objects.len() > 0 {
+        return Ok((objects, path));
+    }
+
+    let json_path = config.data_path.join("metadata.json");
+    match read_map::<Object>(&json_path)? {
+        Some(json_objects) => {
+            log::info!(
+                "Migrating {} objects from metadata.json to {}",
+                json_objects.len(),
+                path.display()
+            );
+            // A single transaction, an interrupted migration is repeated on the next start
+            objects.update_all(
+                json_objects
+                    .into_iter()
+                    .map(|(id, object)| (id, Some(object))),
+            )?;
+            std::fs::rename(&json_path, config.data_path.join("metadata.json.migrated"))?;
+        }
+        None => import_legacy_objects(config, &mut objects)?,
+    }
+    Ok((objects, path))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_metadata(_config: &Config) -> Result<(Storage<Object>, PathBuf)> {
+    Err(FiledlError::SqliteUnavailable)
+}
+
+/// Create objects for the legacy layout if enabled, otherwise only suggest doing it.
+fn import_legacy_objects(config: &Config, objects: &mut Storage<Object>) -> std::io::Result<()> {
+    let legacy_objects = find_legacy_objects(config)?;
+    if legacy_objects.is_empty() {
+        return Ok(());
+    }
+
+    if config.import_legacy {
+        log::info!("Importing {} legacy objects", legacy_objects.len());
+        for (id, object) in legacy_objects {
+            objects.set(id, object);
+        }
+        objects.dump()?;
+    } else {
+        log::warn!(
+            "metadata.json doesn't exist, but {} files or directories were found in data \
+             directories. Run with --import-legacy to create objects for them.",
+            legacy_objects.len()
+        );
+    }
+    Ok(())
+}
+
+/// Stream the upload body to a file, failing if it is larger than `max_size`.
+async fn write_upload<S, E>(path: &Path, body: &mut S, max_size: u64) -> Result<u64>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut file = fs::File::create(path).await?;
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        // Client aborts and malformed payloads, not a problem on the server side
+        let chunk = chunk.map_err(|e| FiledlError::UploadBodyError {
+            message: e.to_string(),
+        })?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(FiledlError::UploadTooLarge);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    Ok(size)
+}
+
+/// Total size of files in the directory, zero if it doesn't exist.
+fn directory_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = match entry {
+            Err(e)
+                if e.io_error()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+            {
+                return Ok(0)
+            }
+            entry => entry?,
+        };
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Longest accepted tag, in bytes.
+const MAX_TAG_LENGTH: usize = 64;
+/// Most tags a single object can have.
+const MAX_TAGS: usize = 32;
+
+fn normalize_tags(tags: &[String]) -> Result<BTreeSet<Arc<str>>> {
+    let mut errors = Vec::new();
+    let mut normalized = BTreeSet::new();
+    for tag in tags {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            errors.push(FieldError::new("tags", "Tags must not be empty"));
+        } else if tag.len() > MAX_TAG_LENGTH {
+            errors.push(FieldError::new(
+                "tags",
+                format!("Tag {:?} is longer than {} bytes", tag, MAX_TAG_LENGTH),
+            ));
+        } else {
+            normalized.insert(tag.into());
         }
     }
+    if normalized.len() > MAX_TAGS {
+        errors.push(FieldError::new(
+            "tags",
+            format!("Objects can have at most {} tags", MAX_TAGS),
+        ));
+    }
+    if errors.is_empty() {
+        Ok(normalized)
+    } else {
+        Err(FiledlError::InvalidRequest { errors })
+    }
+}
+
+/// Verify that path, after resolving all symlinks, is still inside the object root.
+async fn verify_inside_root(object_root: &Path, path: &Path) -> Result<()> {
+    let object_root = fs::canonicalize(object_root).await?;
+    let path = fs::canonicalize(path).await?;
+    if path.starts_with(&object_root) {
+        Ok(())
+    } else {
+        log::warn!("Symlink {} points outside of its object", path.display());
+        Err(FiledlError::ObjectNotFound)
+    }
+}
+
+/// Approximate memory used by in-memory caches and tables, in bytes
+#[derive(Debug, Serialize)]
+pub struct MemoryUsage {
+    pub thumbnails: usize,
+    pub checksums: usize,
+    pub stats: usize,
+    pub rate_limiter: usize,
+    pub total: usize,
+    pub ceiling: Option<usize>,
+}
+
+/// Number of file checksums kept in memory
+const CHECKSUM_CACHE_CAPACITY: usize = 4096;
+
+/// Number of file chunk maps kept in memory
+const CHUNK_MAP_CACHE_CAPACITY: usize = 64;
+
+/// Separates object ID from unlisted key embedded in the path (`/download/{object}~{key}/...`).
+pub const EMBEDDED_KEY_SEPARATOR: char = '~';
+
+/// Object password attempts allowed per client and minute.
+const PASSWORD_ATTEMPTS_PER_MINUTE: u32 = 10;
+
+/// How often expired drop box objects are looked for.
+const DROP_BOX_PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Split a download path into object segment (object ID, possibly with embedded unlisted key)
+/// and the path inside the object.
+pub fn split_object_segment(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('/') {
+        Some((object_segment, subobject_path)) => (object_segment, Some(subobject_path)),
+        None => (path, None),
+    }
+}
+
+/// Split a download path into object ID and the path inside the object.
+/// Unlisted key embedded in the path is dropped, see `embedded_key`.
+pub fn split_object_path(path: &str) -> (&str, Option<&str>) {
+    let (object_segment, subobject_path) = split_object_segment(path);
+    let object_id = object_segment
+        .split_once(EMBEDDED_KEY_SEPARATOR)
+        .map_or(object_segment, |(object_id, _)| object_id);
+    (object_id, subobject_path)
+}
+
+/// Return unlisted key embedded in the download path, if any.
+pub fn embedded_key(path: &str) -> Option<&str> {
+    let (object_segment, _) = split_object_segment(path);
+    object_segment
+        .split_once(EMBEDDED_KEY_SEPARATOR)
+        .map(|(_, key)| key)
 }
 
 pub struct AppData {
@@ -202,25 +1051,128 @@ pub struct AppData {
     objects: RwLock<Storage<Object>>,
     // The RwLock not only protects the Storage object, but also the data stored on the filesystem
     thumbnails: CachedThumbnails,
-    static_content_hash: String,
+    checksums: Arc<Checksums>,
+    stats: Stats,
+    rate_limiter: RateLimiter,
+    /// Drop box uploads by client address
+    drop_box_limiter: RateLimiter,
+    /// Object password attempts by client address
+    password_limiter: RateLimiter,
+    uploads: UploadSessions,
+    chunks: ChunkStore,
+    backups: Option<Backups>,
+    search_index: Option<SearchIndex>,
+    share_links: ShareLinks,
+    bandwidth: Arc<Bandwidth>,
+    maintenance: std::sync::Mutex<Maintenance>,
     download_base_url: String,
+    cookie_key: Key,
+    site_auth: Option<SiteAuth>,
 }
 
 impl AppData {
     pub fn with_config(config: Config) -> Result<Self> {
-        let path = config.data_path.join("metadata.json");
-        let objects = RwLock::new(Storage::new(path)?);
+        let (objects, metadata_path) = match config.metadata_backend {
+            MetadataBackend::Json => {
+                let path = config.data_path.join("metadata.json");
+                let metadata_exists = path.exists();
+                let mut objects = Storage::new(&path)?;
+                if !metadata_exists {
+                    import_legacy_objects(&config, &mut objects)?;
+                }
+                (objects, path)
+            }
+            MetadataBackend::Sqlite => open_sqlite_metadata(&config)?,
+        };
+        check_stored_object_ids(&objects)?;
+        let objects = RwLock::new(objects);
+        let maintenance = Maintenance::load(&config.data_path.join("maintenance.json"))?;
+        if maintenance.enabled {
+            log::warn!("Starting in maintenance mode");
+        }
+        let stats = Stats::new(config.data_path.join("stats.json"))?;
+        let bandwidth = Arc::new(Bandwidth::new(
+            config.data_path.join("bandwidth.json"),
+            config.display_timezone,
+        )?);
         let thumbnail_cache_size = config.thumbnail_cache_size;
-        let static_content_hash = format!("{:X}", thread_rng().next_u32());
+        let mut thumbnailers: Vec<Arc<dyn Thumbnailer>> = vec![Arc::new(ImageThumbnailer)];
+        if let Some(ffmpeg_path) = &config.ffmpeg_path {
+            thumbnailers.push(Arc::new(VideoThumbnailer::new(ffmpeg_path.clone())));
+        }
+        if let Some(command) = &config.document_preview_command {
+            thumbnailers.push(Arc::new(DocumentThumbnailer::new(command.clone())));
+        }
         let download_base_url = format!("{}", url_encode(&config.download_url))
             .trim_end_matches('/')
             .to_owned();
+        let http_client = outbound_http_client(&config)
+            .map_err(|source| FiledlError::InvalidOutboundProxy { source })?;
+        let site_auth = config
+            .site_auth
+            .as_ref()
+            .map(|site_auth| SiteAuth::new(site_auth, http_client.clone()));
+        let cookie_key = match &config.secret_key {
+            Some(secret) if secret.len() < 32 => return Err(FiledlError::SecretKeyTooShort),
+            Some(secret) => Key::derive_from(secret.as_bytes()),
+            None => {
+                log::warn!("No secret key configured, cookies will be invalidated on restart");
+                Key::generate()
+            }
+        };
+        let share_links = ShareLinks::new(
+            config.data_path.join("share_links.json"),
+            cookie_key.signing(),
+        )?;
+        let chunks = ChunkStore::new(config.data_path.join("chunks"));
+        let backups = match (&config.backup_path, &config.backup_passphrase) {
+            (None, _) => None,
+            (Some(_), None) => return Err(FiledlError::BackupPassphraseMissing),
+            (Some(path), Some(passphrase)) => {
+                let mut sources = vec![metadata_path];
+                if config.backup_owned_data {
+                    sources.push(config.data_path.join("owned_data"));
+                }
+                Some(Backups {
+                    path: path.clone(),
+                    passphrase: passphrase.clone(),
+                    interval: std::time::Duration::from_secs(
+                        u64::from(config.backup_interval_hours.max(1)) * 3600,
+                    ),
+                    retention: config.backup_retention.max(1),
+                    sources,
+                })
+            }
+        };
+        let search_index = config.search_index_size.map(|max_size| {
+            SearchIndex::new(
+                max_size,
+                config.search_max_file_size,
+                std::time::Duration::from_secs(config.search_reindex_interval.max(1)),
+            )
+        });
         Ok(AppData {
             config,
             objects,
-            thumbnails: CachedThumbnails::new(thumbnail_cache_size),
-            static_content_hash,
+            thumbnails: CachedThumbnails::new(thumbnail_cache_size, thumbnailers),
+            checksums: Arc::new(Checksums::new(
+                NonZeroUsize::new(CHECKSUM_CACHE_CAPACITY).unwrap(),
+                NonZeroUsize::new(CHUNK_MAP_CACHE_CAPACITY).unwrap(),
+            )),
+            stats,
+            rate_limiter: RateLimiter::default(),
+            drop_box_limiter: RateLimiter::default(),
+            password_limiter: RateLimiter::default(),
+            uploads: UploadSessions::default(),
+            chunks,
+            backups,
+            search_index,
+            share_links,
+            bandwidth,
+            maintenance: std::sync::Mutex::new(maintenance),
             download_base_url,
+            cookie_key,
+            site_auth,
         })
     }
 
@@ -228,6 +1180,18 @@ impl AppData {
         &self.download_base_url
     }
 
+    pub fn get_access_log_format(&self) -> AccessLogFormat {
+        self.config.access_log
+    }
+
+    pub fn get_site_auth(&self) -> Option<&SiteAuth> {
+        self.site_auth.as_ref()
+    }
+
+    pub fn get_trusted_proxies(&self) -> &[IpAddr] {
+        &self.config.trusted_proxies
+    }
+
     pub fn get_app_name(&self) -> &str {
         &self.config.app_name
     }
@@ -236,79 +1200,1657 @@ impl AppData {
         &self.config.display_timezone
     }
 
-    pub fn get_static_content_hash(&self) -> &str {
-        &self.static_content_hash
+    pub fn get_admin_token(&self) -> Option<&str> {
+        self.config.admin_token.as_deref()
     }
 
-    pub async fn get_thumbnail_cache_stats(&self) -> CacheStats {
-        self.thumbnails.cache_stats().await
+    pub fn get_relative_time_cutoff(&self) -> Duration {
+        Duration::hours(self.config.relative_time_cutoff_hours.into())
     }
 
-    fn get_object_path(&self, object_id: &str, obj: &Object) -> PathBuf {
-        match &obj.ownership {
-            ObjectOwnership::Owned => {
-                let mut path = self.config.data_path.join("owned_data");
-                path.push(object_id);
-                path
-            }
-            ObjectOwnership::Linked(link_path) => {
-                link_path.to_path(&self.config.linked_objects_root)
-            }
-        }
+    pub fn get_root_grouping(&self) -> RootGrouping {
+        self.config.root_grouping
     }
 
-    pub async fn resolve_object<'a>(
-        &'a self,
-        path: &str,
-        key: Option<&str>,
-    ) -> Result<ResolvedObject<'a>> {
-        let (object_id, subobject_path) = match path.split_once('/') {
-            Some((object_id, subobject_path)) => (object_id, Some(subobject_path)),
-            None => (path, None),
-        };
+    pub fn get_root_group_separator(&self) -> char {
+        self.config.root_group_separator
+    }
 
-        let obj = self.object_from_id(object_id).await?;
-        if obj
-            .unlisted_key
-            .as_ref()
-            .is_some_and(|expected_key| key != Some(expected_key))
-        {
-            // Someone is snooping around for unlisted objects
-            return Err(FiledlError::Unlisted);
+    pub fn get_asset_source(&self) -> AssetSource {
+        if self.config.live_assets {
+            AssetSource::Live
+        } else {
+            AssetSource::Embedded
         }
+    }
 
-        // TODO: Verify that subobject path is not weird
-        // TODO: Handle expiry?
+    /// Content hashes are computed from the embedded assets, live assets are served
+    /// without them and not cached.
+    pub fn asset_fingerprints_enabled(&self) -> bool {
+        self.get_asset_source() == AssetSource::Embedded
+    }
 
-        let mut object_fs_path = self.get_object_path(object_id, &obj);
-        if let Some(subobject_path) = subobject_path {
-            object_fs_path.push(subobject_path);
-        }
+    /// Live assets are loaded from disk, the embedded data URIs would be stale.
+    pub fn inline_icons_enabled(&self) -> bool {
+        self.config.inline_icons && !self.config.live_assets
+    }
 
-        let result = ResolvedObject::new(object_fs_path, obj, &self.thumbnails).await?;
-        Ok(result)
+    pub fn immutable_caching_enabled(&self) -> bool {
+        self.config.immutable_caching
     }
 
-    async fn object_from_id<'a>(&'a self, id: &str) -> Result<RwLockReadGuard<'a, Object>> {
-        RwLockReadGuard::try_map(self.objects.read().await, |objects| objects.get(id))
-            .map_err(|_| FiledlError::ObjectNotFound)
+    pub fn video_thumbnails_enabled(&self) -> bool {
+        self.config.ffmpeg_path.is_some()
     }
 
-    pub async fn list_objects(&self) -> Result<Vec<DirListingItem>> {
-        let mut result = Vec::new();
+    pub fn document_thumbnails_enabled(&self) -> bool {
+        self.config.document_preview_command.is_some()
+    }
 
-        for (key, obj) in self.objects.read().await.iter() {
-            let path = self.get_object_path(key, obj);
-            let metadata = fs::metadata(&path).await?;
-            if obj.unlisted_key.is_none() {
-                result.push(DirListingItem::with_metadata(
-                    &path,
-                    Arc::clone(key),
-                    &metadata,
-                ));
-            }
-        }
+    pub fn get_thumbnail_fit(&self) -> ThumbnailFit {
+        self.config.thumbnail_fit
+    }
 
-        Ok(result)
+    /// Map a requested thumbnail size to one of the configured sizes.
+    pub fn clamp_thumbnail_size(&self, size: u32) -> u32 {
+        clamp_thumbnail_size(size, &self.config.thumbnail_sizes)
+    }
+
+    pub fn get_max_preview_size(&self) -> u64 {
+        self.config.max_preview_size
+    }
+
+    /// Default number of listing items per page, None disables pagination.
+    pub fn get_listing_page_size(&self) -> Option<usize> {
+        Some(self.config.listing_page_size).filter(|&size| size > 0)
+    }
+
+    pub fn get_flush_interval(&self) -> Option<std::time::Duration> {
+        (self.config.flush_interval > 0)
+            .then(|| std::time::Duration::from_secs(self.config.flush_interval))
+    }
+
+    pub fn get_metadata_reload_interval(&self) -> Option<std::time::Duration> {
+        (self.config.metadata_reload_interval > 0)
+            .then(|| std::time::Duration::from_secs(self.config.metadata_reload_interval))
+    }
+
+    pub fn drop_box_enabled(&self) -> bool {
+        self.config.drop_box_rate_limit.is_some()
+    }
+
+    pub fn get_stream_stall_timeout(&self) -> Option<std::time::Duration> {
+        (self.config.stream_stall_timeout > 0)
+            .then(|| std::time::Duration::from_secs(self.config.stream_stall_timeout))
+    }
+
+    pub fn stream_listings_enabled(&self) -> bool {
+        self.config.stream_listings
+    }
+
+    pub fn show_expiry(&self) -> bool {
+        self.config.show_expiry
+    }
+
+    /// Return true if a request with Referer from the given host is allowed to access files.
+    pub fn referrer_allowed(&self, referrer_host: &str) -> bool {
+        self.config.allowed_referrers.is_empty()
+            || self
+                .config
+                .allowed_referrers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(referrer_host))
+    }
+
+    pub fn get_hotlink_response(&self) -> HotlinkResponse {
+        self.config.hotlink_response
+    }
+
+    pub fn content_checksums_enabled(&self) -> bool {
+        self.config.content_checksums
+    }
+
+    pub fn get_checksums(&self) -> &Arc<Checksums> {
+        &self.checksums
+    }
+
+    pub fn get_stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn get_uploads(&self) -> &UploadSessions {
+        &self.uploads
+    }
+
+    /// Chunk store for deduplicated uploads, if they are enabled.
+    pub fn get_chunk_store(&self) -> Result<&ChunkStore> {
+        if self.config.chunked_uploads {
+            Ok(&self.chunks)
+        } else {
+            Err(FiledlError::ChunkedUploadsDisabled)
+        }
+    }
+
+    /// How long unused chunks are kept, None if they are kept forever
+    /// or chunked uploads are disabled.
+    pub fn get_chunk_retention(&self) -> Option<std::time::Duration> {
+        (self.config.chunked_uploads && self.config.chunk_retention_hours > 0).then(|| {
+            std::time::Duration::from_secs(u64::from(self.config.chunk_retention_hours) * 3600)
+        })
+    }
+
+    pub fn get_share_links(&self) -> &ShareLinks {
+        &self.share_links
+    }
+
+    /// Backup settings, if backups are enabled.
+    pub fn get_backups(&self) -> Option<&Backups> {
+        self.backups.as_ref()
+    }
+
+    pub fn get_search_index(&self) -> Option<&SearchIndex> {
+        self.search_index.as_ref()
+    }
+
+    pub fn search_enabled(&self) -> bool {
+        self.search_index.is_some()
+    }
+
+    /// IDs and root directories of objects whose content can be searched.
+    pub async fn searchable_objects(&self) -> Vec<(Arc<str>, PathBuf)> {
+        let now = Utc::now();
+        let objects = self.objects.read().await;
+        objects
+            .iter()
+            .filter(|(_, object)| object.is_searchable(now))
+            .filter_map(|(id, object)| {
+                Some((Arc::clone(id), self.get_object_path(&objects, id, object)?))
+            })
+            .collect()
+    }
+
+    pub fn get_bandwidth(&self) -> &Arc<Bandwidth> {
+        &self.bandwidth
+    }
+
+    fn get_transfer_caps(&self) -> TransferCaps {
+        TransferCaps {
+            daily: self.config.daily_transfer_cap,
+            monthly: self.config.monthly_transfer_cap,
+        }
+    }
+
+    /// Usage report of the last `days` days.
+    pub fn get_bandwidth_report(&self, days: u32) -> BandwidthReport {
+        self.bandwidth
+            .report(Utc::now(), days, self.get_transfer_caps())
+    }
+
+    /// If the transfer quota is exhausted, returns number of seconds until it resets.
+    pub fn transfer_quota_exceeded(&self) -> Option<u32> {
+        let now = Utc::now();
+        let reset = self
+            .bandwidth
+            .quota_exceeded(now, self.get_transfer_caps())?;
+        Some((reset - now).num_seconds().clamp(1, u32::MAX.into()) as u32)
+    }
+
+    pub fn get_maintenance(&self) -> Maintenance {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    /// Change the maintenance mode state.
+    /// The state is persisted, so that the server stays in maintenance mode across restarts.
+    pub fn set_maintenance(&self, maintenance: Maintenance) -> Result<()> {
+        if maintenance.enabled {
+            log::warn!("Entering maintenance mode");
+        } else {
+            log::info!("Leaving maintenance mode");
+        }
+        let mut current = self.maintenance.lock().unwrap();
+        maintenance.save(&self.config.data_path.join("maintenance.json"))?;
+        *current = maintenance;
+        Ok(())
+    }
+
+    pub fn get_cookie_key(&self) -> &Key {
+        &self.cookie_key
+    }
+
+    pub async fn get_thumbnail_cache_stats(&self) -> CacheStats {
+        self.thumbnails.cache_stats().await
+    }
+
+    pub async fn get_memory_usage(&self) -> MemoryUsage {
+        let thumbnails = self.thumbnails.cache_stats().await.used_size;
+        let checksums = self.checksums.memory_usage();
+        let stats = self.stats.memory_usage();
+        let rate_limiter = self.rate_limiter.memory_usage()
+            + self.drop_box_limiter.memory_usage()
+            + self.password_limiter.memory_usage();
+        MemoryUsage {
+            thumbnails,
+            checksums,
+            stats,
+            rate_limiter,
+            total: thumbnails + checksums + stats + rate_limiter,
+            ceiling: self.config.memory_ceiling,
+        }
+    }
+
+    /// Evict cached thumbnails and checksums if the memory usage is over the configured ceiling.
+    pub async fn relieve_memory_pressure(&self) {
+        let Some(ceiling) = self.config.memory_ceiling else {
+            return;
+        };
+        let usage = self.get_memory_usage().await;
+        if usage.total <= ceiling {
+            return;
+        }
+
+        // Free some more, so that we don't have to evict again with every request
+        let target = ceiling - ceiling / 4;
+        let excess = usage.total - target.min(usage.total);
+        log::info!(
+            "Memory usage {} is over the ceiling {}, evicting {} bytes from caches",
+            usage.total,
+            ceiling,
+            excess
+        );
+
+        let thumbnails_target = usage.thumbnails.saturating_sub(excess);
+        self.thumbnails.shrink(thumbnails_target).await;
+        let excess = excess - (usage.thumbnails - thumbnails_target);
+        self.checksums
+            .shrink(usage.checksums.saturating_sub(excess));
+    }
+
+    /// Check that the token belongs to the access group.
+    fn has_access(&self, access_group: &str, token: Option<&str>) -> bool {
+        let Some(tokens) = self.config.access_groups.get(access_group) else {
+            log::warn!("Access group {} is not configured", access_group);
+            return false;
+        };
+        token.is_some_and(|token| {
+            tokens
+                .iter()
+                .any(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+        })
+    }
+
+    fn owned_object_path(&self, object_id: &str) -> PathBuf {
+        let mut path = self.config.data_path.join("owned_data");
+        path.push(object_id);
+        path
+    }
+
+    /// Root directory of the object.
+    /// Returns None for nested objects whose parent is missing, has ended or is nested itself.
+    fn get_object_path(
+        &self,
+        objects: &Storage<Object>,
+        object_id: &str,
+        obj: &Object,
+    ) -> Option<PathBuf> {
+        match &obj.ownership {
+            ObjectOwnership::Owned => Some(self.owned_object_path(object_id)),
+            ObjectOwnership::Linked(link_path) => {
+                Some(link_path.to_path(&self.config.linked_objects_root))
+            }
+            ObjectOwnership::Nested { parent, path } => {
+                let parent_obj = objects.get(parent)?;
+                if parent_obj.has_ended(Utc::now())
+                    || matches!(parent_obj.ownership, ObjectOwnership::Nested { .. })
+                {
+                    return None;
+                }
+                Some(path.to_path(self.get_object_path(objects, parent, parent_obj)?))
+            }
+        }
+    }
+
+    /// Find the object and file inside it that corresponds to the download path.
+    /// `key` is the unlisted key from the query, `token` the access token sent by the visitor.
+    /// A share link token in place of the key replaces both the unlisted key and the access
+    /// token.
+    pub async fn resolve_object<'a>(
+        &'a self,
+        path: &str,
+        key: Option<&str>,
+        token: Option<&str>,
+    ) -> Result<ResolvedObject<'a>> {
+        let (object_id, subobject_path) = split_object_path(path);
+        let key = key.or(embedded_key(path));
+
+        let objects = self.objects.read().await;
+        let object_root = objects
+            .get(object_id)
+            .and_then(|obj| self.get_object_path(&objects, object_id, obj))
+            .ok_or(FiledlError::ObjectNotFound)?;
+        let obj = RwLockReadGuard::try_map(objects, |objects| objects.get(object_id))
+            .map_err(|_| FiledlError::ObjectNotFound)?;
+        let key_matches = obj
+            .unlisted_key
+            .as_ref()
+            .map_or(true, |expected_key| key == Some(expected_key));
+        let has_group_access = obj
+            .access_group
+            .as_ref()
+            .map_or(true, |access_group| self.has_access(access_group, token));
+        let share_link = match key {
+            _ if key_matches && has_group_access => None,
+            Some(key) => match self.share_links.verify(object_id, key, Utc::now()) {
+                Err(FiledlError::Unlisted) if key_matches => return Err(FiledlError::AccessDenied),
+                share_link => Some(share_link?),
+            },
+            // Someone is snooping around for unlisted objects
+            None if !key_matches => return Err(FiledlError::Unlisted),
+            None => return Err(FiledlError::AccessDenied),
+        };
+
+        if let Some(rate_limit) = obj.rate_limit {
+            if !self.rate_limiter.check(object_id, rate_limit) {
+                return Err(FiledlError::RateLimited);
+            }
+        }
+
+        if obj.has_ended(Utc::now()) {
+            return Err(match &obj.tombstone {
+                Some(tombstone) => FiledlError::ShareEnded {
+                    message: tombstone.message.clone(),
+                },
+                None => FiledlError::ObjectNotFound,
+            });
+        }
+
+        if !obj.is_available(&Utc::now().with_timezone(self.get_display_timezone())) {
+            return Err(FiledlError::OutsideAvailability);
+        }
+
+        let object_fs_path = match subobject_path {
+            Some(subobject_path) => {
+                let subobject_path =
+                    sanitize_subobject_path(subobject_path).ok_or(FiledlError::ObjectNotFound)?;
+                let mut object_fs_path = subobject_path.to_path(&object_root);
+                if let Some(fallback) = &obj.fallback {
+                    if !fs::try_exists(&object_fs_path).await? {
+                        object_fs_path = fallback.to_path(&object_root);
+                    }
+                }
+                if !self.config.allow_symlinks {
+                    verify_inside_root(&object_root, &object_fs_path).await?;
+                }
+                object_fs_path
+            }
+            None => object_root,
+        };
+
+        let result = ResolvedObject::new(object_fs_path, obj, &self.thumbnails, share_link).await?;
+        Ok(result)
+    }
+
+    async fn object_from_id<'a>(&'a self, id: &str) -> Result<RwLockReadGuard<'a, Object>> {
+        RwLockReadGuard::try_map(self.objects.read().await, |objects| objects.get(id))
+            .map_err(|_| FiledlError::ObjectNotFound)
+    }
+
+    /// Remove an object.
+    /// Objects with a tombstone are only marked as removed, others are forgotten completely.
+    /// Pinned objects are refused.
+    pub async fn remove_object(&self, id: &str) -> Result<()> {
+        let mut objects = self.objects.write().await;
+        let Some(obj) = objects.get(id) else {
+            return Err(FiledlError::ObjectNotFound);
+        };
+        if obj.pinned {
+            return Err(FiledlError::ObjectPinned);
+        }
+
+        if obj.tombstone.is_some() {
+            let mut obj = obj.clone();
+            obj.removed = true;
+            objects.set(id.into(), obj);
+        } else {
+            objects.remove(id);
+        }
+        Ok(())
+    }
+
+    /// Apply the operation to all objects matching the filter.
+    /// Either all objects are changed, or none of them is (for example when one of them is
+    /// pinned and can't be removed). With `dry_run` nothing is changed, the result only
+    /// shows which objects would be affected.
+    /// Removing objects or changing their expiry with an empty filter needs `all` set,
+    /// so that a forgotten filter doesn't hit every object.
+    pub async fn bulk_update(
+        &self,
+        filter: &ObjectFilter,
+        operation: &BulkOperation,
+        dry_run: bool,
+    ) -> Result<BulkResult> {
+        let destructive = matches!(
+            operation,
+            BulkOperation::Remove | BulkOperation::SetExpiry { .. }
+        );
+        if destructive && filter.is_unconditional() && !filter.all {
+            return Err(FiledlError::InvalidRequest {
+                errors: vec![FieldError::new(
+                    "filter",
+                    "Give at least one condition, or set \"all\" to select every object",
+                )],
+            });
+        }
+        let mut objects = self.objects.write().await;
+        let now = Utc::now();
+
+        let mut selected: Vec<_> = objects
+            .iter()
+            .filter(|(id, object)| filter.matches(id, object, now))
+            .map(|(id, object)| (Arc::clone(id), object.clone()))
+            .collect();
+        selected.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let mut changes = Vec::new();
+        let mut result = Vec::new();
+        for (id, mut object) in selected {
+            let mut unlisted_key = None;
+            let new_object = match operation {
+                BulkOperation::SetExpiry { expires } => {
+                    object.expires = *expires;
+                    Some(object)
+                }
+                BulkOperation::Remove if object.pinned => {
+                    return Err(FiledlError::ObjectPinned);
+                }
+                BulkOperation::Remove if object.tombstone.is_some() => {
+                    object.removed = true;
+                    Some(object)
+                }
+                BulkOperation::Remove => None,
+                BulkOperation::RotateUnlistedKeys if object.unlisted_key.is_none() => continue,
+                BulkOperation::RotateUnlistedKeys => {
+                    if !dry_run {
+                        let key = generate_unlisted_key();
+                        object.unlisted_key = Some(Arc::clone(&key));
+                        unlisted_key = Some(key);
+                    }
+                    Some(object)
+                }
+            };
+            result.push(BulkChange {
+                id: Arc::clone(&id),
+                unlisted_key,
+            });
+            changes.push((id, new_object));
+        }
+
+        if !dry_run {
+            objects.update_all(changes)?;
+        }
+        Ok(BulkResult {
+            dry_run,
+            objects: result,
+        })
+    }
+
+    /// Write all changed persistent state (objects, statistics, bandwidth usage) to disk.
+    /// Storage is otherwise only written when dropped, which doesn't happen if the process
+    /// is killed.
+    pub async fn flush(&self) -> Result<()> {
+        self.objects.write().await.flush()?;
+        self.stats.flush()?;
+        self.bandwidth.flush()?;
+        self.share_links.flush()?;
+        Ok(())
+    }
+
+    /// Flush the persistent state with the configured interval, forever.
+    pub async fn flush_periodically(&self) {
+        let Some(interval) = self.get_flush_interval() else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.flush().await {
+                log::error!("Flushing state to disk failed: {}", e);
+            }
+        }
+    }
+
+    /// Load external edits of `metadata.json` with the configured interval, forever.
+    pub async fn reload_metadata_periodically(&self) {
+        let Some(interval) = self.get_metadata_reload_interval() else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            let mut objects = self.objects.write().await;
+            match objects.reload_if_changed() {
+                Ok(true) => {
+                    log::info!("Reloaded metadata.json after an external change");
+                    if let Err(e) = check_stored_object_ids(&objects) {
+                        log::error!("{}", e);
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => log::error!(
+                    "Reloading metadata.json failed, keeping the current objects: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Remove expired drop box objects and their data every minute, while the drop box
+    /// is enabled.
+    pub async fn purge_drop_box_periodically(&self) {
+        if !self.drop_box_enabled() {
+            return;
+        }
+        loop {
+            tokio::time::sleep(DROP_BOX_PURGE_INTERVAL).await;
+            if let Err(e) = self.purge_drop_box().await {
+                log::error!("Removing expired drop box objects failed: {}", e);
+            }
+        }
+    }
+
+    async fn purge_drop_box(&self) -> Result<()> {
+        let now = Utc::now();
+        let expired: Vec<Arc<str>> = self
+            .objects
+            .read()
+            .await
+            .iter()
+            .filter(|(_, obj)| obj.drop_box && !obj.pinned && obj.has_ended(now))
+            .map(|(id, _)| Arc::clone(id))
+            .collect();
+        for id in expired {
+            self.objects.write().await.remove(&id);
+            match fs::remove_dir_all(self.owned_object_path(&id)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => log::info!("Removed expired drop box object {}", id),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the objects to `metadata.json` immediately.
+    pub async fn save_metadata(&self) -> Result<()> {
+        self.objects.write().await.dump()?;
+        Ok(())
+    }
+
+    /// All object metadata in the format of `metadata.json`, for backups and migration.
+    /// With `redact_keys` the unlisted keys are replaced by a placeholder.
+    pub async fn export_metadata(&self, redact_keys: bool) -> BTreeMap<Arc<str>, Object> {
+        self.objects
+            .read()
+            .await
+            .iter()
+            .map(|(id, object)| {
+                let mut object = object.clone();
+                if redact_keys && object.unlisted_key.is_some() {
+                    object.unlisted_key = Some(REDACTED_KEY.into());
+                }
+                (Arc::clone(id), object)
+            })
+            .collect()
+    }
+
+    /// Merge exported metadata into the current objects, all or nothing.
+    /// Only the metadata is imported, data of owned objects has to be copied separately.
+    pub async fn import_metadata(
+        &self,
+        imported: BTreeMap<Arc<str>, Object>,
+        on_conflict: ImportConflict,
+        dry_run: bool,
+    ) -> Result<ImportResult> {
+        let mut objects = self.objects.write().await;
+        let (changes, mut result) = plan_import(|id| objects.get(id), imported, on_conflict)?;
+        result.dry_run = dry_run;
+        if !dry_run {
+            objects.update_all(changes)?;
+        }
+        Ok(result)
+    }
+
+    /// Create a signed link to the object, valid until `expires` and optionally only
+    /// for a limited number of file downloads. Returns the download path of the link.
+    pub async fn create_share_link(
+        &self,
+        object_id: &str,
+        expires: DateTime<Utc>,
+        max_downloads: Option<u32>,
+    ) -> Result<String> {
+        if expires <= Utc::now() {
+            return Err(FiledlError::InvalidRequest {
+                errors: vec![FieldError::new("expires", "Must be in the future")],
+            });
+        }
+        self.object_from_id(object_id).await.map(drop)?;
+        let token = self.share_links.create(object_id, expires, max_downloads);
+        Ok(format!(
+            "{}/{}{}{}",
+            self.get_download_base_url(),
+            url_encode(object_id),
+            EMBEDDED_KEY_SEPARATOR,
+            token
+        ))
+    }
+
+    /// Create an object sharing a directory inside another object, with its own key
+    /// and expiry. Returns the download path of the new object.
+    pub async fn create_nested_object(
+        &self,
+        id: &str,
+        parent: &str,
+        path: &str,
+        expires: Option<DateTime<Utc>>,
+        unlisted: bool,
+    ) -> Result<String> {
+        let mut errors = Vec::new();
+        if !is_valid_object_id(id) {
+            errors.push(FieldError::new("object", "Invalid object ID"));
+        }
+        let path = sanitize_subobject_path(path.trim_matches('/'));
+        if path.is_none() {
+            errors.push(FieldError::new("path", "Invalid path"));
+        }
+        if expires.is_some_and(|expires| expires <= Utc::now()) {
+            errors.push(FieldError::new("expires", "Must be in the future"));
+        }
+
+        let mut objects = self.objects.write().await;
+        if objects.get(id).is_some() {
+            errors.push(FieldError::new("object", "Object already exists"));
+        }
+        let parent_root = match objects.get(parent) {
+            Some(Object {
+                ownership: ObjectOwnership::Nested { .. },
+                ..
+            }) => {
+                errors.push(FieldError::new("parent", "Nested objects can't be nested"));
+                None
+            }
+            Some(parent_obj) => self.get_object_path(&objects, parent, parent_obj),
+            None => {
+                errors.push(FieldError::new("parent", "Object not found"));
+                None
+            }
+        };
+        if let (Some(parent_root), Some(path)) = (parent_root, &path) {
+            let target = path.to_path(&parent_root);
+            if !fs::metadata(&target).await.is_ok_and(|m| m.is_dir()) {
+                errors.push(FieldError::new("path", "Directory not found"));
+            } else if !self.config.allow_symlinks {
+                verify_inside_root(&parent_root, &target).await?;
+            }
+        }
+        if !errors.is_empty() {
+            return Err(FiledlError::InvalidRequest { errors });
+        }
+
+        let mut obj = Object::new(ObjectOwnership::Nested {
+            parent: parent.into(),
+            path: path.expect("Checked above"),
+        });
+        obj.expires = expires;
+        obj.unlisted_key = unlisted.then(generate_unlisted_key);
+        let url = match &obj.unlisted_key {
+            Some(key) => format!(
+                "{}/{}{}{}",
+                self.get_download_base_url(),
+                url_encode(id),
+                EMBEDDED_KEY_SEPARATOR,
+                key
+            ),
+            None => format!("{}/{}", self.get_download_base_url(), url_encode(id)),
+        };
+        objects.set(id.into(), obj);
+        Ok(url)
+    }
+
+    /// Turn the object into an inbox (or back with None).
+    /// A missing object is created as an empty owned directory.
+    pub async fn set_object_inbox(&self, id: &str, inbox: Option<Inbox>) -> Result<()> {
+        let mut objects = self.objects.write().await;
+        let mut obj = match objects.get(id) {
+            Some(obj) => obj.clone(),
+            None if inbox.is_some() => {
+                if !is_valid_object_id(id) {
+                    return Err(FiledlError::InvalidRequest {
+                        errors: vec![FieldError::new("object", "Invalid object ID")],
+                    });
+                }
+                fs::create_dir_all(self.owned_object_path(id)).await?;
+                Object::new(ObjectOwnership::Owned)
+            }
+            None => return Err(FiledlError::ObjectNotFound),
+        };
+        if inbox.is_some() && !matches!(obj.ownership, ObjectOwnership::Owned) {
+            return Err(FiledlError::UploadToLinkedObject);
+        }
+
+        obj.inbox = inbox;
+        objects.set(id.into(), obj);
+        Ok(())
+    }
+
+    /// Store a file uploaded by a visitor into an inbox object.
+    /// Existing files are never overwritten. Returns size of the uploaded file.
+    pub async fn upload_to_inbox<S, E>(
+        &self,
+        object_id: &str,
+        file_name: &str,
+        mut body: S,
+    ) -> Result<u64>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let invalid_name = || FiledlError::InvalidRequest {
+            errors: vec![FieldError::new("file", "Invalid file name")],
+        };
+        let file_name = sanitize_subobject_path(file_name).ok_or_else(invalid_name)?;
+        if file_name.components().count() != 1 || file_name.as_str().starts_with('.') {
+            return Err(invalid_name());
+        }
+
+        let (object_root, inbox) = {
+            let obj = self.object_from_id(object_id).await?;
+            let inbox = obj.inbox.clone().ok_or(FiledlError::ObjectNotFound)?;
+            (self.owned_object_path(object_id), inbox)
+        };
+        if !inbox.is_open(Utc::now()) {
+            return Err(FiledlError::InboxClosed);
+        }
+        let dest = file_name.to_path(&object_root);
+        if fs::try_exists(&dest).await? {
+            return Err(FiledlError::InboxFileExists);
+        }
+
+        // Concurrent uploads can exceed the cap together, it is not meant to be exact
+        let mut max_size = self.config.max_upload_size;
+        if let Some(inbox_max_size) = inbox.max_size {
+            let root = object_root.clone();
+            let used = spawn_blocking(move || directory_size(&root))
+                .await
+                .expect("Walking the directory should not panic")?;
+            max_size = max_size.min(inbox_max_size.saturating_sub(used));
+        }
+
+        let owned_data = self.config.data_path.join("owned_data");
+        let temp_path = owned_data.join(format!(".upload-{:016x}", thread_rng().next_u64()));
+        let result = async {
+            let size = write_upload(&temp_path, &mut body, max_size).await?;
+            fs::create_dir_all(&object_root).await?;
+            // Unlike rename, linking fails if the destination exists, so a concurrent
+            // upload of the same name can't be overwritten
+            match fs::hard_link(&temp_path, &dest).await {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    return Err(FiledlError::InboxFileExists)
+                }
+                result => result?,
+            }
+            log::info!("Visitor uploaded {} bytes to {}", size, dest.display());
+            Ok(size)
+        }
+        .await;
+        let _ = fs::remove_file(&temp_path).await;
+        result
+    }
+
+    /// Check a password entered for a protected object. `client` is the address of the
+    /// visitor, used for rate limiting the guesses.
+    pub async fn verify_password(
+        &self,
+        client: &str,
+        expected: &PasswordHash,
+        password: &str,
+    ) -> Result<bool> {
+        if !self
+            .password_limiter
+            .try_take(client, PASSWORD_ATTEMPTS_PER_MINUTE)
+        {
+            log::warn!("Client {} is over the password attempt rate limit", client);
+            return Err(FiledlError::RateLimited);
+        }
+        let expected = expected.clone();
+        let password = password.to_owned();
+        Ok(spawn_blocking(move || expected.verify(&password))
+            .await
+            .unwrap())
+    }
+
+    /// Store a file uploaded anonymously through the drop box as a new unlisted object
+    /// that expires after the configured lifetime. `client` is the address of the uploader,
+    /// used for rate limiting. Returns the download path of the file.
+    pub async fn upload_to_drop_box<S, E>(
+        &self,
+        client: &str,
+        file_name: &str,
+        mut body: S,
+    ) -> Result<String>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let rate_limit = self
+            .config
+            .drop_box_rate_limit
+            .ok_or(FiledlError::DropBoxDisabled)?;
+        if !self.drop_box_limiter.try_take(client, rate_limit) {
+            log::warn!("Client {} is over the drop box rate limit", client);
+            return Err(FiledlError::RateLimited);
+        }
+        let invalid_name = || FiledlError::InvalidRequest {
+            errors: vec![FieldError::new("file", "Invalid file name")],
+        };
+        let file_name = sanitize_subobject_path(file_name).ok_or_else(invalid_name)?;
+        if file_name.components().count() != 1 || file_name.as_str().starts_with('.') {
+            return Err(invalid_name());
+        }
+
+        let drop_box_roots: Vec<PathBuf> = self
+            .objects
+            .read()
+            .await
+            .iter()
+            .filter(|(_, obj)| obj.drop_box)
+            .map(|(id, _)| self.owned_object_path(id))
+            .collect();
+        let used = spawn_blocking(move || {
+            drop_box_roots
+                .iter()
+                .map(|root| directory_size(root))
+                .sum::<std::io::Result<u64>>()
+        })
+        .await
+        .expect("Walking the directory should not panic")?;
+        let max_size = self
+            .config
+            .drop_box_max_size
+            .min(self.config.drop_box_max_total_size.saturating_sub(used));
+
+        let owned_data = self.config.data_path.join("owned_data");
+        fs::create_dir_all(&owned_data).await?;
+        let temp_path = owned_data.join(format!(".upload-{:016x}", thread_rng().next_u64()));
+        let size = match write_upload(&temp_path, &mut body, max_size).await {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+
+        let mut objects = self.objects.write().await;
+        let id: Arc<str> = loop {
+            let id = format!("{:08x}", thread_rng().next_u32());
+            if objects.get(&id).is_none() {
+                break id.into();
+            }
+        };
+        let object_root = self.owned_object_path(&id);
+        let moved = async {
+            fs::create_dir_all(&object_root).await?;
+            fs::rename(&temp_path, file_name.to_path(&object_root)).await
+        }
+        .await;
+        if let Err(e) = moved {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e.into());
+        }
+
+        let mut obj = Object::new(ObjectOwnership::Owned);
+        obj.expires = Some(Utc::now() + Duration::seconds(self.config.drop_box_lifetime as i64));
+        obj.drop_box = true;
+        let key = generate_unlisted_key();
+        obj.unlisted_key = Some(Arc::clone(&key));
+        objects.set(Arc::clone(&id), obj);
+        log::info!("Drop box upload of {} bytes created object {}", size, id);
+
+        Ok(format!(
+            "{}/{}{}{}/{}",
+            self.get_download_base_url(),
+            url_encode(&id),
+            EMBEDDED_KEY_SEPARATOR,
+            key,
+            url_encode(file_name.as_str())
+        ))
+    }
+
+    /// Pin or unpin the object.
+    /// Returns false if the object doesn't exist.
+    pub async fn set_object_pinned(&self, id: &str, pinned: bool) -> bool {
+        let mut objects = self.objects.write().await;
+        let Some(obj) = objects.get(id) else {
+            return false;
+        };
+
+        let mut obj = obj.clone();
+        obj.pinned = pinned;
+        objects.set(id.into(), obj);
+        true
+    }
+
+    /// Make the object accessible without the site authentication, or not.
+    /// Returns false if the object doesn't exist.
+    pub async fn set_object_public(&self, id: &str, public: bool) -> bool {
+        let mut objects = self.objects.write().await;
+        let Some(obj) = objects.get(id) else {
+            return false;
+        };
+
+        let mut obj = obj.clone();
+        obj.public = public;
+        objects.set(id.into(), obj);
+        true
+    }
+
+    /// Calendar events for objects that are going to expire, ordered by the expiry.
+    /// Event URLs are download paths, without the unlisted keys.
+    pub async fn expiration_events(&self) -> Vec<CalendarEvent> {
+        let now = Utc::now();
+        let mut events: Vec<CalendarEvent> = self
+            .objects
+            .read()
+            .await
+            .iter()
+            .filter(|(_, obj)| !obj.has_ended(now) && !obj.pinned)
+            .filter_map(|(id, obj)| {
+                Some(CalendarEvent {
+                    // Derived only from the ID, so that changing the expiry moves the event
+                    uid: format!(
+                        "expiry-{}@{}",
+                        hex(&Sha256::digest(id.as_bytes())),
+                        env!("CARGO_PKG_NAME")
+                    ),
+                    time: obj.expires?,
+                    summary: format!("{} expires", id),
+                    description: format!(
+                        "Share {} on {} stops being available.",
+                        id,
+                        self.get_app_name()
+                    ),
+                    url: Some(format!(
+                        "{}/{}",
+                        self.get_download_base_url(),
+                        url_encode(id)
+                    )),
+                })
+            })
+            .collect();
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    /// Returns true if the object exists and is accessible without the site authentication.
+    pub async fn is_public_object(&self, id: &str) -> bool {
+        self.objects
+            .read()
+            .await
+            .get(id)
+            .is_some_and(|obj| obj.public)
+    }
+
+    /// Replace tags of the object. Tags are trimmed, duplicates are merged.
+    pub async fn set_object_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        let tags = normalize_tags(tags)?;
+        let mut objects = self.objects.write().await;
+        let Some(obj) = objects.get(id) else {
+            return Err(FiledlError::ObjectNotFound);
+        };
+
+        let mut obj = obj.clone();
+        obj.tags = tags;
+        objects.set(id.into(), obj);
+        Ok(())
+    }
+
+    /// All tags with the number of objects that have them.
+    pub async fn list_tags(&self) -> BTreeMap<Arc<str>, usize> {
+        let mut tags = BTreeMap::new();
+        for (_, object) in self.objects.read().await.iter() {
+            for tag in &object.tags {
+                *tags.entry(Arc::clone(tag)).or_default() += 1;
+            }
+        }
+        tags
+    }
+
+    /// Set or remove password of the object.
+    /// Returns false if the object doesn't exist.
+    pub async fn set_object_password(&self, id: &str, password: Option<&str>) -> bool {
+        let mut objects = self.objects.write().await;
+        let Some(obj) = objects.get(id) else {
+            return false;
+        };
+
+        let mut obj = obj.clone();
+        obj.password = password.map(PasswordHash::new);
+        objects.set(id.into(), obj);
+        true
+    }
+
+    /// Store an uploaded file in an owned object, creating the object if it doesn't exist.
+    /// `path` is the object ID, optionally followed by path of the file inside a directory object.
+    /// The body is streamed to a temporary file first, so that partial uploads never become
+    /// visible. Returns size of the uploaded file.
+    pub async fn upload<S, E>(&self, path: &str, mut body: S) -> Result<u64>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let (object_id, subobject_path) = split_object_segment(path);
+        if !is_valid_object_id(object_id) {
+            return Err(FiledlError::InvalidRequest {
+                errors: vec![FieldError::new("object", "Invalid object ID")],
+            });
+        }
+        let subobject_path = subobject_path
+            .map(|subobject_path| {
+                sanitize_subobject_path(subobject_path).ok_or_else(|| FiledlError::InvalidRequest {
+                    errors: vec![FieldError::new("object", "Invalid path inside object")],
+                })
+            })
+            .transpose()?;
+
+        let is_linked = self
+            .objects
+            .read()
+            .await
+            .get(object_id)
+            .is_some_and(|obj| !matches!(obj.ownership, ObjectOwnership::Owned));
+        if is_linked {
+            return Err(FiledlError::UploadToLinkedObject);
+        }
+
+        let owned_data = self.config.data_path.join("owned_data");
+        fs::create_dir_all(&owned_data).await?;
+        let temp_path = owned_data.join(format!(".upload-{:016x}", thread_rng().next_u64()));
+        let result = self
+            .finish_upload(object_id, subobject_path, &temp_path, &mut body)
+            .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+
+    async fn finish_upload<S, E>(
+        &self,
+        object_id: &str,
+        subobject_path: Option<RelativePathBuf>,
+        temp_path: &Path,
+        body: &mut S,
+    ) -> Result<u64>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let size = write_upload(temp_path, body, self.config.max_upload_size).await?;
+
+        let mut objects = self.objects.write().await;
+        let object_root = self.owned_object_path(object_id);
+        let dest = match subobject_path {
+            Some(subobject_path) => {
+                let dest = subobject_path.to_path(&object_root);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+                dest
+            }
+            None => object_root,
+        };
+        fs::rename(temp_path, &dest).await?;
+
+        if objects.get(object_id).is_none() {
+            objects.set(object_id.into(), Object::new(ObjectOwnership::Owned));
+        }
+        log::info!("Uploaded {} bytes to {}", size, dest.display());
+
+        Ok(size)
+    }
+
+    /// List all objects, including the unlisted and ended ones, with their access statistics.
+    pub async fn list_objects_admin(&self) -> Vec<AdminObjectInfo> {
+        self.objects
+            .read()
+            .await
+            .iter()
+            .map(|(id, object)| AdminObjectInfo {
+                id: Arc::clone(id),
+                object: object.clone(),
+                stats: self.stats.get(id),
+            })
+            .collect()
+    }
+
+    pub async fn list_objects(&self) -> Result<Vec<DirListingItem>> {
+        let mut result = Vec::new();
+        let now = Utc::now();
+
+        let objects = self.objects.read().await;
+        for (key, obj) in objects.iter() {
+            if obj.has_ended(now) {
+                continue;
+            }
+            let Some(path) = self.get_object_path(&objects, key, obj) else {
+                continue;
+            };
+            let metadata = fs::metadata(&path).await?;
+            if obj.unlisted_key.is_none() {
+                let mut item = DirListingItem::with_metadata(&path, Arc::clone(key), &metadata);
+                item.tags = obj.tags.iter().cloned().collect();
+                result.push(item.with_probed_dimensions(path).await);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// AppData with its data in a fresh temporary directory, which is removed when dropped.
+#[cfg(test)]
+pub struct TestApp {
+    app: Option<Arc<AppData>>,
+    root: PathBuf,
+}
+
+#[cfg(test)]
+impl TestApp {
+    /// `config` contains additional configuration options in TOML.
+    pub fn new(config: &str) -> Self {
+        Self::with_objects(config, Vec::new()).unwrap()
+    }
+
+    /// Start the app with `objects` already stored in the metadata.
+    pub fn with_objects(config: &str, objects: Vec<(&str, Object)>) -> Result<Self> {
+        use figment::{
+            providers::{Format, Toml},
+            Figment,
+        };
+
+        let root = std::env::temp_dir().join(format!("filedl-test-{:016x}", rand::random::<u64>()));
+        let data_path = root.join("data");
+        let linked_objects_root = root.join("linked");
+        std::fs::create_dir_all(&data_path).unwrap();
+        std::fs::create_dir_all(&linked_objects_root).unwrap();
+        let mut metadata = Storage::new(data_path.join("metadata.json")).unwrap();
+        for (id, object) in objects {
+            metadata.set(id.into(), object);
+        }
+        metadata.dump().unwrap();
+        let config = Figment::new()
+            .merge(Toml::string(&format!(
+                "data_path = {:?}\nlinked_objects_root = {:?}\n{}",
+                data_path, linked_objects_root, config
+            )))
+            .extract()
+            .unwrap();
+        // Constructed first, so that the directory is removed when starting fails
+        let mut test_app = TestApp { app: None, root };
+        test_app.app = Some(Arc::new(AppData::with_config(config)?));
+        Ok(test_app)
+    }
+
+    /// Shared reference for passing the app to handlers.
+    pub fn data(&self) -> actix_web::web::Data<Arc<AppData>> {
+        actix_web::web::Data::new(Arc::clone(self.app.as_ref().unwrap()))
+    }
+
+    /// Root of linked objects.
+    pub fn linked_root(&self) -> PathBuf {
+        self.root.join("linked")
+    }
+
+    /// Directory with the owned data of an object.
+    pub fn owned_data(&self, object_id: &str) -> PathBuf {
+        self.root.join("data").join("owned_data").join(object_id)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestApp {
+    type Target = AppData;
+
+    fn deref(&self) -> &AppData {
+        self.app.as_ref().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        // The app saves its state when dropped, it must go before its directory
+        self.app.take();
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn window(days: &[Weekday], start: &str, end: &str) -> AvailabilityWindow {
+        AvailabilityWindow {
+            days: days.to_vec(),
+            start: TimeOfDay(start.parse().unwrap()),
+            end: TimeOfDay(end.parse().unwrap()),
+        }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn object_id_with_key_separator_is_refused() {
+        let objects = vec![
+            ("fine", Object::new(ObjectOwnership::Owned)),
+            ("photos~2023", Object::new(ObjectOwnership::Owned)),
+        ];
+        let result = TestApp::with_objects("", objects).map(drop);
+        assert!(let Err(FiledlError::ObjectIdWithKeySeparator { .. }) = result);
+    }
+
+    #[actix_web::test]
+    async fn bulk_remove_needs_a_condition() {
+        let objects = vec![
+            ("photos-2023", Object::new(ObjectOwnership::Owned)),
+            ("photos-2024", Object::new(ObjectOwnership::Owned)),
+            ("videos", Object::new(ObjectOwnership::Owned)),
+        ];
+        let app = TestApp::with_objects("", objects).unwrap();
+        let ids = |result: BulkResult| -> Vec<String> {
+            result
+                .objects
+                .iter()
+                .map(|change| change.id.to_string())
+                .collect()
+        };
+
+        let empty = ObjectFilter::default();
+        for operation in [
+            BulkOperation::Remove,
+            BulkOperation::SetExpiry { expires: None },
+        ] {
+            let result = app.bulk_update(&empty, &operation, false).await;
+            assert!(let Err(FiledlError::InvalidRequest { .. }) = result);
+        }
+        assert!(app.objects.read().await.len() == 3);
+
+        let all = ObjectFilter {
+            all: true,
+            ..Default::default()
+        };
+        let result = app.bulk_update(&all, &BulkOperation::Remove, true).await;
+        assert!(ids(result.unwrap()) == ["photos-2023", "photos-2024", "videos"]);
+        assert!(app.objects.read().await.len() == 3);
+
+        let photos = ObjectFilter {
+            pattern: Some("photos-*".into()),
+            ..Default::default()
+        };
+        let result = app
+            .bulk_update(&photos, &BulkOperation::Remove, false)
+            .await;
+        assert!(ids(result.unwrap()) == ["photos-2023", "photos-2024"]);
+        let objects = app.objects.read().await;
+        assert!(objects.len() == 1);
+        assert!(objects.get("videos").is_some());
+    }
+
+    #[actix_web::test]
+    async fn expiration_events() {
+        let now = Utc::now();
+        let expiring = |hours, pinned| {
+            let mut obj = Object::new(ObjectOwnership::Owned);
+            obj.expires = Some(now + Duration::hours(hours));
+            obj.pinned = pinned;
+            obj
+        };
+        let objects = vec![
+            ("later", expiring(48, false)),
+            ("soon", expiring(1, false)),
+            ("pinned", expiring(1, true)),
+            ("ended", expiring(-1, false)),
+            ("forever", Object::new(ObjectOwnership::Owned)),
+        ];
+        let app = TestApp::with_objects("", objects).unwrap();
+
+        let events = app.expiration_events().await;
+        let summaries: Vec<&str> = events.iter().map(|event| event.summary.as_str()).collect();
+        assert!(summaries == ["soon expires", "later expires"]);
+        let uid = events[0].uid.clone();
+
+        let soon = ObjectFilter {
+            ids: Some(vec!["soon".into()]),
+            ..Default::default()
+        };
+        let expires = now + Duration::hours(72);
+        let operation = BulkOperation::SetExpiry {
+            expires: Some(expires),
+        };
+        app.bulk_update(&soon, &operation, false).await.unwrap();
+
+        let events = app.expiration_events().await;
+        assert!(events.len() == 2);
+        assert!(events[1].summary == "soon expires");
+        assert!(events[1].time == expires);
+        assert!(events[1].uid == uid);
+        assert!(events[0].uid != uid);
+    }
+
+    #[test]
+    fn object_filter_conditions() {
+        let now = at("2024-01-02T00:00:00Z");
+        let mut obj = Object::new(ObjectOwnership::Owned);
+        obj.unlisted_key = Some("key".into());
+
+        assert!(ObjectFilter::default().matches("photos-2023", &obj, now));
+        let filter = ObjectFilter {
+            pattern: Some("photos-*".into()),
+            unlisted: Some(true),
+            ..Default::default()
+        };
+        assert!(filter.matches("photos-2023", &obj, now));
+        assert!(!filter.matches("videos-2023", &obj, now));
+
+        let filter = ObjectFilter {
+            ids: Some(vec!["a".into(), "b".into()]),
+            ended: Some(true),
+            ..Default::default()
+        };
+        assert!(!filter.matches("a", &obj, now));
+        obj.expires = Some(at("2024-01-01T00:00:00Z"));
+        assert!(filter.matches("a", &obj, now));
+        assert!(!filter.matches("c", &obj, now));
+    }
+
+    #[test]
+    fn import_conflicts() {
+        let mut existing = Object::new(ObjectOwnership::Owned);
+        existing.unlisted_key = Some("old-key".into());
+        let get_existing = |id: &str| (id == "a").then_some(&existing);
+        let mut imported = Object::new(ObjectOwnership::Owned);
+        imported.unlisted_key = Some(REDACTED_KEY.into());
+        let imported: BTreeMap<Arc<str>, Object> =
+            [("a".into(), imported.clone()), ("b".into(), imported)].into();
+
+        assert!(let Err(FiledlError::ImportConflict { .. }) = plan_import(get_existing, imported.clone(), ImportConflict::Fail));
+
+        let (changes, result) =
+            plan_import(get_existing, imported.clone(), ImportConflict::Skip).unwrap();
+        assert!(result.skipped == [Arc::from("a")]);
+        assert!(result.added == [Arc::from("b")]);
+        assert!(changes.len() == 1);
+
+        let (changes, result) =
+            plan_import(get_existing, imported, ImportConflict::Overwrite).unwrap();
+        assert!(result.replaced == [Arc::from("a")]);
+        let keys: Vec<_> = changes
+            .iter()
+            .map(|(_, object)| object.as_ref().unwrap().unlisted_key.clone().unwrap())
+            .collect();
+        assert!(&*keys[0] == "old-key");
+        assert!(&*keys[1] != REDACTED_KEY);
+    }
+
+    #[test]
+    fn import_rejects_nested_paths_outside_the_parent() {
+        for path in ["../secret", "2023/../../secret", "/etc", ""] {
+            let object = Object::new(ObjectOwnership::Nested {
+                parent: "photos".into(),
+                path: RelativePathBuf::from(path),
+            });
+            let imported: BTreeMap<Arc<str>, Object> = [("nested".into(), object)].into();
+            assert!(let Err(FiledlError::InvalidRequest { .. }) = plan_import(|_| None, imported, ImportConflict::Fail));
+        }
+    }
+
+    #[actix_web::test]
+    async fn nested_objects() {
+        let objects = vec![(
+            "photos",
+            Object::new(ObjectOwnership::Linked(RelativePathBuf::from("photos"))),
+        )];
+        let app = TestApp::with_objects("", objects).unwrap();
+        let linked_root = app.linked_root();
+        std::fs::create_dir_all(linked_root.join("photos/2023/summer")).unwrap();
+        std::fs::write(linked_root.join("photos/2023/summer/a.txt"), "a").unwrap();
+        std::fs::create_dir_all(linked_root.join("secret")).unwrap();
+        std::os::unix::fs::symlink(
+            linked_root.join("secret"),
+            linked_root.join("photos/escape"),
+        )
+        .unwrap();
+
+        app.create_nested_object("summer", "photos", "/2023/summer/", None, false)
+            .await
+            .unwrap();
+        let resolved = app
+            .resolve_object("summer/a.txt", None, None)
+            .await
+            .unwrap();
+        assert!(resolved.path() == linked_root.join("photos/2023/summer/a.txt"));
+        drop(resolved);
+
+        // Nested objects can't be parents
+        let result = app
+            .create_nested_object("deeper", "summer", "a.txt", None, false)
+            .await;
+        assert!(let Err(FiledlError::InvalidRequest { .. }) = result);
+
+        // Paths must stay inside the parent
+        for path in ["../secret", "2023/../../secret", "missing"] {
+            let result = app
+                .create_nested_object("escaped", "photos", path, None, false)
+                .await;
+            assert!(let Err(FiledlError::InvalidRequest { .. }) = result);
+        }
+        let result = app
+            .create_nested_object("escaped", "photos", "escape", None, false)
+            .await;
+        assert!(let Err(FiledlError::ObjectNotFound) = result);
+        assert!(app.objects.read().await.get("escaped").is_none());
+
+        // Ending the parent makes the nested object unavailable
+        let photos = ObjectFilter {
+            ids: Some(vec!["photos".into()]),
+            ..Default::default()
+        };
+        let operation = BulkOperation::SetExpiry {
+            expires: Some(Utc::now() - Duration::hours(1)),
+        };
+        app.bulk_update(&photos, &operation, false).await.unwrap();
+        let result = app.resolve_object("summer/a.txt", None, None).await;
+        assert!(let Err(FiledlError::ObjectNotFound) = result);
+    }
+
+    #[test]
+    fn inbox_closes() {
+        let inbox = Inbox {
+            max_size: None,
+            closes: Some(at("2024-01-08T00:00:00Z")),
+        };
+        assert!(inbox.is_open(at("2024-01-07T23:59:59Z")));
+        assert!(!inbox.is_open(at("2024-01-08T00:00:00Z")));
+        assert!(Inbox::default().is_open(at("2024-01-08T00:00:00Z")));
+    }
+
+    #[test]
+    fn tag_normalization() {
+        let tags =
+            normalize_tags(&[" docs ".to_owned(), "docs".to_owned(), "2024".to_owned()]).unwrap();
+        assert!(tags.iter().map(|tag| &**tag).collect::<Vec<_>>() == ["2024", "docs"]);
+        assert!(let Err(FiledlError::InvalidRequest { .. }) = normalize_tags(&[" ".to_owned()]));
+        assert!(let Err(FiledlError::InvalidRequest { .. }) = normalize_tags(&["x".repeat(65)]));
+    }
+
+    #[test]
+    fn filter_by_tag() {
+        let mut obj = Object::new(ObjectOwnership::Owned);
+        obj.tags.insert("docs".into());
+        let now = at("2024-01-01T00:00:00Z");
+        let filter = |tag: &str| ObjectFilter {
+            tag: Some(tag.to_owned()),
+            ..Default::default()
+        };
+        assert!(filter("docs").matches("a", &obj, now));
+        assert!(!filter("photos").matches("a", &obj, now));
+    }
+
+    #[test]
+    fn pinned_object_doesnt_expire() {
+        let mut obj = Object::new(ObjectOwnership::Owned);
+        obj.expires = Some(at("2024-01-01T00:00:00Z"));
+        assert!(obj.has_ended(at("2024-01-02T00:00:00Z")));
+
+        obj.pinned = true;
+        assert!(!obj.has_ended(at("2024-01-02T00:00:00Z")));
+
+        obj.removed = true;
+        assert!(obj.has_ended(at("2024-01-02T00:00:00Z")));
+    }
+
+    #[test]
+    fn availability_window_same_day() {
+        let window = window(&[Weekday::Mon, Weekday::Tue], "08:00:00", "18:00:00");
+        // 2024-01-01 is a Monday
+        assert!(window.contains(&at("2024-01-01T08:00:00Z")));
+        assert!(window.contains(&at("2024-01-02T17:59:59Z")));
+        assert!(!window.contains(&at("2024-01-01T18:00:00Z")));
+        assert!(!window.contains(&at("2024-01-01T07:59:59Z")));
+        assert!(!window.contains(&at("2024-01-03T12:00:00Z")));
+    }
+
+    #[test]
+    fn availability_window_over_midnight() {
+        let window = window(&[Weekday::Fri], "22:00:00", "02:00:00");
+        // 2024-01-05 is a Friday
+        assert!(window.contains(&at("2024-01-05T23:00:00Z")));
+        assert!(window.contains(&at("2024-01-06T01:00:00Z")));
+        assert!(!window.contains(&at("2024-01-06T23:00:00Z")));
+        assert!(!window.contains(&at("2024-01-05T01:00:00Z")));
+    }
+
+    #[test]
+    fn availability_in_timezone() {
+        let mut object = Object::new(ObjectOwnership::Owned);
+        assert!(object.is_available(&at("2024-01-01T03:00:00Z")));
+
+        object.availability = vec![window(&[], "08:00:00", "18:00:00")];
+        let now = at("2024-01-01T07:30:00Z");
+        assert!(!object.is_available(&now));
+        assert!(object.is_available(&now.with_timezone(&chrono_tz::Europe::Prague)));
+    }
+
+    #[test]
+    fn time_of_day_formats() {
+        let time: TimeOfDay = serde_json::from_str("\"8:30\"").unwrap();
+        assert!(time.0 == NaiveTime::from_hms_opt(8, 30, 0).unwrap());
+        let time: TimeOfDay = serde_json::from_str("\"18:00:15\"").unwrap();
+        assert!(time.0 == NaiveTime::from_hms_opt(18, 0, 15).unwrap());
+    }
+
+    #[test]
+    fn nested_ownership_format() {
+        let obj: Object = serde_json::from_str(
+            r#"{"ownership": {"Nested": {"parent": "photos", "path": "2023/summer"}}}"#,
+        )
+        .unwrap();
+        let ObjectOwnership::Nested { parent, path } = &obj.ownership else {
+            panic!("Expected nested ownership");
+        };
+        assert!(&**parent == "photos");
+        assert!(path.as_str() == "2023/summer");
+    }
+
+    #[test]
+    fn split_object_path_examples() {
+        assert!(split_object_path("obj") == ("obj", None));
+        assert!(split_object_path("obj/a/b") == ("obj", Some("a/b")));
+        assert!(split_object_path("obj~key") == ("obj", None));
+        assert!(split_object_path("obj~key/a/b") == ("obj", Some("a/b")));
+        assert!(split_object_path("obj/a~b") == ("obj", Some("a~b")));
+    }
+
+    #[test]
+    fn embedded_key_examples() {
+        assert!(embedded_key("obj") == None);
+        assert!(embedded_key("obj/a~b") == None);
+        assert!(embedded_key("obj~key") == Some("key"));
+        assert!(embedded_key("obj~key/a/b") == Some("key"));
+        assert!(embedded_key("obj~") == Some(""));
+    }
+
+    #[test]
+    fn valid_object_ids() {
+        assert!(is_valid_object_id("photos"));
+        assert!(is_valid_object_id("report.pdf"));
+        assert!(!is_valid_object_id(""));
+        assert!(!is_valid_object_id(".upload-1234"));
+        assert!(!is_valid_object_id(".."));
+        assert!(!is_valid_object_id("a/b"));
+        assert!(!is_valid_object_id("a~key"));
+    }
+
+    #[test]
+    fn sanitize_subobject_path_accepts_normal_paths() {
+        assert!(sanitize_subobject_path("a").unwrap() == RelativePathBuf::from("a"));
+        assert!(
+            sanitize_subobject_path("a/b c/d.jpg").unwrap() == RelativePathBuf::from("a/b c/d.jpg")
+        );
+        assert!(sanitize_subobject_path("..a/b..").is_some());
+        // Percent encoding is already decoded by the router, the rest is literal
+        assert!(sanitize_subobject_path("%2e%2e").is_some());
+    }
+
+    #[test]
+    fn sanitize_subobject_path_rejects_traversal() {
+        assert!(sanitize_subobject_path("..").is_none());
+        assert!(sanitize_subobject_path("../secret").is_none());
+        assert!(sanitize_subobject_path("a/../../secret").is_none());
+        assert!(sanitize_subobject_path("a/..").is_none());
+        assert!(sanitize_subobject_path("./a").is_none());
+        assert!(sanitize_subobject_path("/etc/passwd").is_none());
+        assert!(sanitize_subobject_path("a//b").is_none());
+        assert!(sanitize_subobject_path("").is_none());
+        assert!(sanitize_subobject_path("a\\..\\..\\secret").is_none());
+        assert!(sanitize_subobject_path("a\0b").is_none());
+    }
+
+    #[actix_web::test]
+    async fn broken_upload_body_is_client_error() {
+        let app = TestApp::new("");
+        let body =
+            futures_util::stream::iter([Ok(Bytes::from_static(b"abc")), Err("connection reset")]);
+        let result = app.upload("obj/file.txt", body).await;
+        assert!(let Err(FiledlError::UploadBodyError { .. }) = &result);
+        assert!(result.unwrap_err().category() == crate::error::ErrorCategory::BadRequest);
+    }
+
+    #[test]
+    fn maintenance_persists() {
+        let path = std::env::temp_dir().join(format!(
+            "filedl-maintenance-{:X}.json",
+            thread_rng().next_u64()
+        ));
+
+        let loaded = Maintenance::load(&path).unwrap();
+        assert!(loaded.retry_after() == None);
+
+        Maintenance {
+            enabled: true,
+            retry_after: 30,
+        }
+        .save(&path)
+        .unwrap();
+        let loaded = Maintenance::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.retry_after() == Some(30));
     }
 }
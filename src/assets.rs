@@ -0,0 +1,82 @@
+use crate::error::{FiledlError, Result};
+use actix_web::web::Bytes;
+use std::path::{Component, Path};
+use tokio::{fs, task::spawn_blocking};
+
+include! {concat!(env!("OUT_DIR"), "/assets/assets.rs")}
+
+/// Asset sources, used when serving live assets.
+const ASSETS_SOURCE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets");
+
+/// Where the served static assets come from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AssetSource {
+    /// Minified assets embedded in the binary
+    Embedded,
+    /// Assets loaded (and compiled) from the source directory, so that changes are visible
+    /// without rebuilding
+    Live,
+}
+
+/// Returns content and mime type of a static asset.
+pub async fn get_asset(name: &str, source: AssetSource) -> Result<Option<(Bytes, mime::Mime)>> {
+    match source {
+        AssetSource::Embedded => {
+            Ok(assets(name).map(|(content, mime)| (Bytes::from_static(content), mime)))
+        }
+        AssetSource::Live => get_live_asset(name).await,
+    }
+}
+
+/// Returns fingerprint of the embedded asset content, for cache busting URLs.
+pub fn asset_hash(name: &str) -> Option<&'static str> {
+    asset_hashes(name)
+}
+
+/// Returns subresource integrity metadata of an embedded script or stylesheet.
+/// Only valid for the minified embedded version.
+pub fn asset_integrity(name: &str) -> Option<&'static str> {
+    asset_integrities(name)
+}
+
+/// Returns a small SVG asset encoded as a data URI, to be inlined into pages instead of
+/// referencing the asset by URL.
+/// Larger and non-SVG assets return None.
+pub fn inline_asset(name: &str) -> Option<&'static str> {
+    asset_data_uris(name)
+}
+
+async fn get_live_asset(name: &str) -> Result<Option<(Bytes, mime::Mime)>> {
+    let name = Path::new(name);
+    if !name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Ok(None);
+    }
+
+    let source_path = Path::new(ASSETS_SOURCE_DIR).join(name);
+    let (content, mime) = match name.extension().and_then(|ext| ext.to_str()) {
+        Some("css") => {
+            let scss_path = source_path.with_extension("scss");
+            let compiled = spawn_blocking(move || {
+                grass::from_path(scss_path, &grass::Options::default()).map_err(|e| {
+                    FiledlError::AssetCompilation {
+                        message: e.to_string(),
+                    }
+                })
+            })
+            .await
+            .expect("Compiling SCSS should not panic")?;
+            (compiled.into_bytes(), mime::TEXT_CSS)
+        }
+        Some("js") => (
+            fs::read(source_path).await?,
+            mime::APPLICATION_JAVASCRIPT_UTF_8,
+        ),
+        Some("svg") => (fs::read(source_path).await?, mime::IMAGE_SVG),
+        _ => (fs::read(source_path).await?, mime::APPLICATION_OCTET_STREAM),
+    };
+
+    Ok(Some((content.into(), mime)))
+}
@@ -0,0 +1,444 @@
+//! Periodic encrypted backups of the object metadata and optionally of the owned data.
+//!
+//! A backup is a gzipped TAR archive encrypted with XChaCha20-Poly1305 in the STREAM
+//! construction, so that it can be written and read in segments and truncation is detected.
+//! The key is derived from the configured passphrase with Argon2id, using a random salt.
+//! The salt and the Argon2 parameters are stored in the header, so that the parameters can
+//! change without breaking older backups.
+//! File layout: magic, memory cost (KiB), iterations and parallelism as big endian `u32`,
+//! salt, nonce prefix, then the encrypted segments.
+
+use crate::{
+    app_data::AppData,
+    error::{FiledlError, Result},
+    tar::{gzip_stream, tar_stream_paths},
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit,
+    },
+    XChaCha20Poly1305,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures_util::StreamExt as _;
+use rand::{thread_rng, RngCore};
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{fs, io::AsyncWriteExt, task::spawn_blocking};
+
+const MAGIC: &[u8; 8] = b"FDLBAK02";
+/// Memory cost, iterations and parallelism
+const KDF_PARAMS_SIZE: usize = 3 * 4;
+const SALT_SIZE: usize = 16;
+/// XChaCha20 nonce without the 5 bytes used by the STREAM counter
+const NONCE_PREFIX_SIZE: usize = 19;
+const HEADER_SIZE: usize = MAGIC.len() + KDF_PARAMS_SIZE + SALT_SIZE + NONCE_PREFIX_SIZE;
+/// Argon2id parameters for new backups, the key is derived only once per backup,
+/// so they can be costlier than for passwords.
+const KDF_PARAMS: KdfParams = KdfParams {
+    memory_kib: 64 * 1024,
+    iterations: 3,
+    parallelism: 1,
+};
+/// Highest memory cost accepted from a backup header, so that a corrupted header can't
+/// exhaust the memory
+const MAX_KDF_MEMORY_KIB: u32 = 1024 * 1024;
+/// Size of plaintext segments, each is encrypted separately
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+const FILE_PREFIX: &str = "filedl-backup-";
+const FILE_SUFFIX: &str = ".tar.gz.enc";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Backup settings taken from the configuration.
+pub struct Backups {
+    /// Destination directory
+    pub path: PathBuf,
+    pub passphrase: String,
+    pub interval: Duration,
+    /// Number of backups to keep
+    pub retention: usize,
+    /// Files and directories to archive
+    pub sources: Vec<PathBuf>,
+}
+
+impl Backups {
+    /// Save the metadata and write a new backup, then remove backups over the retention limit.
+    /// Returns path of the new backup.
+    pub async fn create(&self, app: &AppData) -> Result<PathBuf> {
+        app.save_metadata().await?;
+        fs::create_dir_all(&self.path).await?;
+
+        let file_name = backup_file_name(Utc::now());
+        let path = self.path.join(&file_name);
+        let temp_path = self.path.join(format!(".{}", file_name));
+        let result = self.write_backup(&temp_path).await;
+        match result {
+            Ok(()) => fs::rename(&temp_path, &path).await?,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        }
+
+        for name in self.expired_backups().await? {
+            log::info!("Removing old backup {}", name);
+            fs::remove_file(self.path.join(name)).await?;
+        }
+        Ok(path)
+    }
+
+    async fn write_backup(&self, path: &Path) -> Result<()> {
+        let mut file = fs::File::create(path).await?;
+        let mut archive = Box::pin(gzip_stream(Box::pin(
+            tar_stream_paths(self.sources.clone()).await?,
+        )));
+        let passphrase = self.passphrase.clone();
+        // Deriving the key takes a while, it must not block the async workers
+        let mut encryptor = spawn_blocking(move || BackupEncryptor::new(&passphrase, KDF_PARAMS))
+            .await
+            .unwrap()?;
+        file.write_all(&encryptor.take_output()).await?;
+        while let Some(chunk) = archive.next().await {
+            encryptor.update(&chunk?)?;
+            file.write_all(&encryptor.take_output()).await?;
+        }
+        file.write_all(&encryptor.finish()?).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+
+    /// Names of backups in the destination directory, oldest first.
+    async fn existing_backups(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut entries = match fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                if parse_backup_time(name).is_some() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        // The timestamp format sorts chronologically
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    async fn expired_backups(&self) -> Result<Vec<String>> {
+        let mut names = self.existing_backups().await?;
+        let expired_count = names.len().saturating_sub(self.retention);
+        names.truncate(expired_count);
+        Ok(names)
+    }
+
+    /// Time until the next backup is due, based on the newest existing backup,
+    /// so that restarts don't postpone backups indefinitely.
+    async fn next_backup_delay(&self) -> Result<Duration> {
+        let newest = self
+            .existing_backups()
+            .await?
+            .last()
+            .and_then(|name| parse_backup_time(name));
+        Ok(match newest {
+            Some(newest) => {
+                let elapsed = (Utc::now() - newest).to_std().unwrap_or_default();
+                self.interval.saturating_sub(elapsed)
+            }
+            None => Duration::ZERO,
+        })
+    }
+}
+
+/// Create backups forever, with the configured interval.
+pub async fn run(app: Arc<AppData>) {
+    let Some(backups) = app.get_backups() else {
+        return;
+    };
+    let mut delay = backups.next_backup_delay().await.unwrap_or_else(|e| {
+        log::warn!("Listing existing backups failed: {}", e);
+        Duration::ZERO
+    });
+    loop {
+        tokio::time::sleep(delay).await;
+        match backups.create(&app).await {
+            Ok(path) => log::info!("Backup written to {}", path.display()),
+            Err(e) => log::error!("Backup failed: {}", e),
+        }
+        delay = backups.interval;
+    }
+}
+
+fn backup_file_name(time: DateTime<Utc>) -> String {
+    format!(
+        "{}{}{}",
+        FILE_PREFIX,
+        time.format(TIMESTAMP_FORMAT),
+        FILE_SUFFIX
+    )
+}
+
+fn parse_backup_time(file_name: &str) -> Option<DateTime<Utc>> {
+    let timestamp = file_name
+        .strip_prefix(FILE_PREFIX)?
+        .strip_suffix(FILE_SUFFIX)?;
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Argon2id parameters of the backup key derivation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KdfParams {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn to_bytes(self) -> [u8; KDF_PARAMS_SIZE] {
+        let mut bytes = [0u8; KDF_PARAMS_SIZE];
+        bytes[0..4].copy_from_slice(&self.memory_kib.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.iterations.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.parallelism.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let field = |i: usize| u32::from_be_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap());
+        KdfParams {
+            memory_kib: field(0),
+            iterations: field(1),
+            parallelism: field(2),
+        }
+    }
+}
+
+fn cipher(passphrase: &str, params: KdfParams, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    if params.memory_kib > MAX_KDF_MEMORY_KIB {
+        return Err(FiledlError::BackupDecryption);
+    }
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(32),
+    )
+    .map_err(|_| FiledlError::BackupDecryption)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| FiledlError::BackupDecryption)?;
+    Ok(XChaCha20Poly1305::new(GenericArray::from_slice(&key)))
+}
+
+/// Incremental encryption of the backup content.
+struct BackupEncryptor {
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    /// Plaintext that doesn't fill a whole segment yet
+    buffer: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl BackupEncryptor {
+    /// The header is the first output.
+    fn new(passphrase: &str, params: KdfParams) -> Result<Self> {
+        let mut salt = [0u8; SALT_SIZE];
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        thread_rng().fill_bytes(&mut salt);
+        thread_rng().fill_bytes(&mut nonce_prefix);
+
+        let mut output = Vec::with_capacity(HEADER_SIZE);
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&params.to_bytes());
+        output.extend_from_slice(&salt);
+        output.extend_from_slice(&nonce_prefix);
+        let cipher =
+            cipher(passphrase, params, &salt).map_err(|_| FiledlError::BackupEncryption)?;
+        Ok(BackupEncryptor {
+            encryptor: EncryptorBE32::from_aead(cipher, GenericArray::from_slice(&nonce_prefix)),
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+            output,
+        })
+    }
+
+    /// Encrypt full segments of the data, the rest is kept for later.
+    /// The last segment is only written by `finish`, so at least one byte more than
+    /// a full segment has to be buffered before a segment is encrypted.
+    fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.buffer.len() == SEGMENT_SIZE {
+                let segment = self
+                    .encryptor
+                    .encrypt_next(self.buffer.as_slice())
+                    .map_err(|_| FiledlError::BackupEncryption)?;
+                self.output.extend_from_slice(&segment);
+                self.buffer.clear();
+            }
+            let take = data.len().min(SEGMENT_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+        Ok(())
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Encrypt the last segment, returns the remaining output.
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let segment = self
+            .encryptor
+            .encrypt_last(self.buffer.as_slice())
+            .map_err(|_| FiledlError::BackupEncryption)?;
+        self.output.extend_from_slice(&segment);
+        Ok(self.output)
+    }
+}
+
+/// Decrypt a backup into the gzipped TAR archive.
+/// Fails if the passphrase is wrong or the backup was modified or truncated.
+pub fn decrypt(passphrase: &str, mut input: impl Read, mut output: impl Write) -> Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    input
+        .read_exact(&mut header)
+        .map_err(|_| FiledlError::BackupDecryption)?;
+    let (magic, rest) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(FiledlError::BackupDecryption);
+    }
+    let (params, rest) = rest.split_at(KDF_PARAMS_SIZE);
+    let (salt, nonce_prefix) = rest.split_at(SALT_SIZE);
+    let cipher = cipher(passphrase, KdfParams::from_bytes(params), salt)?;
+    let mut decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(nonce_prefix));
+
+    let mut segment = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+    let mut len = read_full(&mut input, &mut segment)?;
+    loop {
+        // A segment is the last one if nothing follows it
+        let mut next = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+        let next_len = if len == segment.len() {
+            read_full(&mut input, &mut next)?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let plaintext = decryptor
+                .decrypt_last(&segment[..len])
+                .map_err(|_| FiledlError::BackupDecryption)?;
+            output.write_all(&plaintext)?;
+            return Ok(());
+        }
+        let plaintext = decryptor
+            .decrypt_next(&segment[..len])
+            .map_err(|_| FiledlError::BackupDecryption)?;
+        output.write_all(&plaintext)?;
+        segment = next;
+        len = next_len;
+    }
+}
+
+/// Read until the buffer is full or the input ends, returns the number of bytes read.
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match input.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(read) => total += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    /// Cheap parameters, to keep the tests fast
+    const TEST_KDF_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn encrypt(passphrase: &str, data: &[u8]) -> Vec<u8> {
+        let mut encryptor = BackupEncryptor::new(passphrase, TEST_KDF_PARAMS).unwrap();
+        let mut output = encryptor.take_output();
+        // Uneven pieces, to exercise the buffering
+        for piece in data.chunks(1000) {
+            encryptor.update(piece).unwrap();
+            output.extend(encryptor.take_output());
+        }
+        output.extend(encryptor.finish().unwrap());
+        output
+    }
+
+    fn decrypt_to_vec(passphrase: &str, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decrypt(passphrase, encrypted, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn roundtrip() {
+        for size in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, 2 * SEGMENT_SIZE + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt("passphrase", &data);
+            assert!(decrypt_to_vec("passphrase", &encrypted).unwrap() == data);
+        }
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let encrypted = encrypt("passphrase", b"secret data");
+        assert!(let Err(FiledlError::BackupDecryption) = decrypt_to_vec("other", &encrypted));
+    }
+
+    #[test]
+    fn kdf_params_in_header() {
+        let encrypted = encrypt("passphrase", b"secret data");
+        let params = &encrypted[MAGIC.len()..MAGIC.len() + KDF_PARAMS_SIZE];
+        assert!(KdfParams::from_bytes(params) == TEST_KDF_PARAMS);
+
+        let mut tampered = encrypted.clone();
+        tampered[MAGIC.len() + 4..MAGIC.len() + 8].copy_from_slice(&2u32.to_be_bytes());
+        assert!(let Err(FiledlError::BackupDecryption) = decrypt_to_vec("passphrase", &tampered));
+
+        let mut huge = encrypted;
+        huge[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(let Err(FiledlError::BackupDecryption) = decrypt_to_vec("passphrase", &huge));
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let data = vec![0u8; 2 * SEGMENT_SIZE + 7];
+        let encrypted = encrypt("passphrase", &data);
+        let truncated = &encrypted[..HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE];
+        assert!(let Err(FiledlError::BackupDecryption) = decrypt_to_vec("passphrase", truncated));
+    }
+
+    #[test]
+    fn file_names() {
+        let time = "2024-03-01T12:30:05Z".parse().unwrap();
+        let name = backup_file_name(time);
+        assert!(name == "filedl-backup-20240301T123005Z.tar.gz.enc");
+        assert!(parse_backup_time(&name) == Some(time));
+        assert!(parse_backup_time("filedl-backup-latest.tar.gz.enc").is_none());
+        assert!(parse_backup_time(".filedl-backup-20240301T123005Z.tar.gz.enc").is_none());
+    }
+}
@@ -0,0 +1,279 @@
+//! Accounting of transferred bytes per day, for hosts on metered connections.
+
+use crate::storage::Storage;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    web::Bytes,
+};
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// Number of days of history that are kept.
+const RETENTION_DAYS: u64 = 400;
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// Limits on transferred bytes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TransferCaps {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+/// Bytes served during one day.
+#[derive(Clone, Debug, Serialize)]
+pub struct DayUsage {
+    pub date: NaiveDate,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BandwidthReport {
+    pub today: u64,
+    pub this_month: u64,
+    pub daily_cap: Option<u64>,
+    pub monthly_cap: Option<u64>,
+    /// Usage of the last days, oldest first, including days without any transfers
+    pub days: Vec<DayUsage>,
+}
+
+/// Bytes served per day, persisted in a JSON file.
+/// Days are counted in the display timezone.
+#[derive(Debug)]
+pub struct Bandwidth {
+    days: Mutex<Storage<u64>>,
+    timezone: Tz,
+}
+
+impl Bandwidth {
+    pub fn new<P: Into<PathBuf>>(path: P, timezone: Tz) -> std::io::Result<Self> {
+        Ok(Bandwidth {
+            days: Mutex::new(Storage::new(path)?),
+            timezone,
+        })
+    }
+
+    fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        now.with_timezone(&self.timezone).date_naive()
+    }
+
+    /// Add bytes served now.
+    pub fn record(&self, now: DateTime<Utc>, bytes: u64) {
+        let today = self.today(now);
+        let key = today.format(DAY_FORMAT).to_string();
+        let mut days = self.days.lock().unwrap();
+        match days.get(&key).copied() {
+            Some(previous) => {
+                days.set(key.into(), previous + bytes);
+            }
+            None => {
+                // First transfer of the day, a good time to forget old history
+                // and persist the previous days.
+                let expired: Vec<Arc<str>> = days
+                    .iter()
+                    .filter(|(day, _)| {
+                        NaiveDate::parse_from_str(day, DAY_FORMAT).map_or(true, |day| {
+                            day.checked_add_days(Days::new(RETENTION_DAYS))
+                                .is_some_and(|limit| limit < today)
+                        })
+                    })
+                    .map(|(day, _)| Arc::clone(day))
+                    .collect();
+                for day in expired {
+                    days.remove(&day);
+                }
+                days.set(key.into(), bytes);
+                if let Err(e) = days.dump() {
+                    log::warn!("Saving bandwidth usage failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Write changed usage to the file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.days.lock().unwrap().flush()
+    }
+
+    fn usage(days: &Storage<u64>, date: NaiveDate) -> u64 {
+        days.get(&date.format(DAY_FORMAT).to_string())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn usage_since(days: &Storage<u64>, since: NaiveDate, today: NaiveDate) -> u64 {
+        since
+            .iter_days()
+            .take_while(|date| *date <= today)
+            .map(|date| Self::usage(days, date))
+            .sum()
+    }
+
+    /// Usage report of the last `day_count` days.
+    pub fn report(
+        &self,
+        now: DateTime<Utc>,
+        day_count: u32,
+        caps: TransferCaps,
+    ) -> BandwidthReport {
+        let today = self.today(now);
+        let days = self.days.lock().unwrap();
+        let first_day = today
+            .checked_sub_days(Days::new(day_count.saturating_sub(1).into()))
+            .unwrap_or(today);
+
+        BandwidthReport {
+            today: Self::usage(&days, today),
+            this_month: Self::usage_since(&days, month_start(today), today),
+            daily_cap: caps.daily,
+            monthly_cap: caps.monthly,
+            days: first_day
+                .iter_days()
+                .take_while(|date| *date <= today)
+                .map(|date| DayUsage {
+                    date,
+                    bytes: Self::usage(&days, date),
+                })
+                .collect(),
+        }
+    }
+
+    /// If one of the caps is exhausted, returns the time when transfers are possible again.
+    pub fn quota_exceeded(&self, now: DateTime<Utc>, caps: TransferCaps) -> Option<DateTime<Utc>> {
+        let today = self.today(now);
+        let days = self.days.lock().unwrap();
+        let used_today = Self::usage(&days, today);
+        let used_this_month = Self::usage_since(&days, month_start(today), today);
+        drop(days);
+
+        quota_reset(today, used_today, used_this_month, caps)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .and_then(|midnight| {
+                self.timezone
+                    .from_local_datetime(&midnight)
+                    .earliest()
+                    .map(|reset| reset.with_timezone(&Utc))
+            })
+    }
+}
+
+fn month_start(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).expect("Every month has a first day")
+}
+
+/// Day when the transfers are allowed again, or None if no cap is exhausted.
+/// The monthly cap takes precedence, because it resets later.
+fn quota_reset(
+    today: NaiveDate,
+    used_today: u64,
+    used_this_month: u64,
+    caps: TransferCaps,
+) -> Option<NaiveDate> {
+    if caps.monthly.is_some_and(|cap| used_this_month >= cap) {
+        month_start(today).checked_add_months(Months::new(1))
+    } else if caps.daily.is_some_and(|cap| used_today >= cap) {
+        today.succ_opt()
+    } else {
+        None
+    }
+}
+
+/// Response body wrapper that reports the number of sent bytes once the body is dropped,
+/// so that interrupted transfers are counted too.
+pub struct CountingBody<B> {
+    inner: B,
+    on_finish: Option<Box<dyn FnOnce(u64)>>,
+    bytes: u64,
+    report_empty: bool,
+}
+
+impl<B> CountingBody<B> {
+    pub fn new(inner: B, on_finish: impl FnOnce(u64) + 'static) -> Self {
+        CountingBody {
+            inner,
+            on_finish: Some(Box::new(on_finish)),
+            bytes: 0,
+            report_empty: false,
+        }
+    }
+
+    /// Call `on_finish` even if no bytes were sent.
+    pub fn report_empty(mut self) -> Self {
+        self.report_empty = true;
+        self
+    }
+}
+
+impl<B: MessageBody + Unpin> MessageBody for CountingBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            this.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl<B> Drop for CountingBody<B> {
+    fn drop(&mut self) {
+        let report = self.bytes > 0 || self.report_empty;
+        if let Some(on_finish) = self.on_finish.take().filter(|_| report) {
+            on_finish(self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, DAY_FORMAT).unwrap()
+    }
+
+    #[test]
+    fn quota_not_exceeded() {
+        let caps = TransferCaps {
+            daily: Some(100),
+            monthly: Some(1000),
+        };
+        assert!(quota_reset(date("2024-01-15"), 99, 999, caps) == None);
+        assert!(quota_reset(date("2024-01-15"), 1000, 1000, TransferCaps::default()) == None);
+    }
+
+    #[test]
+    fn daily_quota_resets_tomorrow() {
+        let caps = TransferCaps {
+            daily: Some(100),
+            monthly: Some(1000),
+        };
+        assert!(quota_reset(date("2024-01-31"), 100, 500, caps) == Some(date("2024-02-01")));
+    }
+
+    #[test]
+    fn monthly_quota_resets_next_month() {
+        let caps = TransferCaps {
+            daily: Some(100),
+            monthly: Some(1000),
+        };
+        assert!(quota_reset(date("2024-12-15"), 100, 1000, caps) == Some(date("2025-01-01")));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::error::Result;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    web::Bytes,
+};
+use lru::LruCache;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{Display, Formatter},
+    io::Read,
+    num::NonZeroUsize,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::task::spawn_blocking;
+
+/// Size of chunks in chunk maps, except for the last one.
+pub const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// SHA-256 digest of a file content
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Sha256Digest(pub [u8; 32]);
+
+impl Display for Sha256Digest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Sha256Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Checksums of fixed size chunks of a file, for verifying parallel range downloads.
+#[derive(Debug, Serialize)]
+pub struct ChunkMap {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub sha256: Sha256Digest,
+    pub chunks: Vec<Sha256Digest>,
+}
+
+impl ChunkMap {
+    fn from_reader(mut reader: impl Read, chunk_size: u64) -> std::io::Result<Self> {
+        let mut whole = Sha256::new();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+
+        loop {
+            let mut chunk = Sha256::new();
+            let chunk_len = std::io::copy(
+                &mut (&mut reader).take(chunk_size),
+                &mut HashWriter(&mut chunk, &mut whole),
+            )?;
+            if chunk_len == 0 {
+                break;
+            }
+            size += chunk_len;
+            chunks.push(Sha256Digest(chunk.finalize().into()));
+            if chunk_len < chunk_size {
+                break;
+            }
+        }
+
+        Ok(ChunkMap {
+            size,
+            chunk_size,
+            sha256: Sha256Digest(whole.finalize().into()),
+            chunks,
+        })
+    }
+
+    /// Approximate memory used by the chunk map, in bytes.
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.chunks.len() * std::mem::size_of::<Sha256Digest>()
+    }
+}
+
+/// Writer feeding the data to two hashers at once.
+struct HashWriter<'a>(&'a mut Sha256, &'a mut Sha256);
+
+impl std::io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        self.1.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Cache of checksums of served files, keyed by source hash of the file.
+#[derive(Debug)]
+pub struct Checksums {
+    cache: Mutex<LruCache<u64, Sha256Digest>>,
+    chunk_maps: Mutex<LruCache<u64, Arc<ChunkMap>>>,
+}
+
+impl Checksums {
+    pub fn new(capacity: NonZeroUsize, chunk_map_capacity: NonZeroUsize) -> Self {
+        Checksums {
+            cache: Mutex::new(LruCache::new(capacity)),
+            chunk_maps: Mutex::new(LruCache::new(chunk_map_capacity)),
+        }
+    }
+
+    pub fn get(&self, source_hash: u64) -> Option<Sha256Digest> {
+        self.cache.lock().unwrap().get(&source_hash).copied()
+    }
+
+    pub fn insert(&self, source_hash: u64, digest: Sha256Digest) {
+        self.cache.lock().unwrap().put(source_hash, digest);
+    }
+
+    /// Return chunk map of the file, calculating it if it is not cached.
+    /// The whole file checksum is stored in the checksum cache along the way.
+    pub async fn chunk_map(&self, source_hash: u64, path: PathBuf) -> Result<Arc<ChunkMap>> {
+        if let Some(chunk_map) = self.chunk_maps.lock().unwrap().get(&source_hash) {
+            return Ok(Arc::clone(chunk_map));
+        }
+
+        let chunk_map = spawn_blocking(move || {
+            ChunkMap::from_reader(
+                std::io::BufReader::new(std::fs::File::open(path)?),
+                CHUNK_SIZE,
+            )
+        })
+        .await
+        .expect("Calculating chunk map should not panic")?;
+        let chunk_map = Arc::new(chunk_map);
+
+        self.insert(source_hash, chunk_map.sha256);
+        self.chunk_maps
+            .lock()
+            .unwrap()
+            .put(source_hash, Arc::clone(&chunk_map));
+        Ok(chunk_map)
+    }
+
+    /// Approximate memory used by the cached checksums and chunk maps, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.cache.lock().unwrap().len() * Self::ENTRY_SIZE
+            + self
+                .chunk_maps
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, chunk_map)| chunk_map.memory_usage())
+                .sum::<usize>()
+    }
+
+    /// Evict chunk maps and least recently used checksums until the cache uses at most
+    /// `target_size` bytes.
+    pub fn shrink(&self, target_size: usize) {
+        self.chunk_maps.lock().unwrap().clear();
+        let mut cache = self.cache.lock().unwrap();
+        while cache.len() * Self::ENTRY_SIZE > target_size && cache.pop_lru().is_some() {}
+    }
+
+    /// Approximate size of a cache entry, including the LRU list pointers.
+    const ENTRY_SIZE: usize =
+        std::mem::size_of::<u64>() + std::mem::size_of::<Sha256Digest>() + 4 * 8;
+}
+
+/// Response body wrapper that calculates SHA-256 of the data passing through
+/// and stores it in the checksum cache once the whole body was sent.
+pub struct HashingBody<B> {
+    inner: B,
+    hasher: Option<Sha256>,
+    checksums: Arc<Checksums>,
+    source_hash: u64,
+}
+
+impl<B> HashingBody<B> {
+    pub fn new(inner: B, checksums: Arc<Checksums>, source_hash: u64) -> Self {
+        HashingBody {
+            inner,
+            hasher: Some(Sha256::new()),
+            checksums,
+            source_hash,
+        }
+    }
+}
+
+impl<B: MessageBody + Unpin> MessageBody for HashingBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_next(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                if let Some(hasher) = this.hasher.as_mut() {
+                    hasher.update(chunk);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => {
+                // The body is incomplete, the checksum would be wrong
+                this.hasher = None;
+            }
+            Poll::Ready(None) => {
+                if let Some(hasher) = this.hasher.take() {
+                    this.checksums
+                        .insert(this.source_hash, Sha256Digest(hasher.finalize().into()));
+                }
+            }
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use test_strategy::proptest;
+
+    #[test]
+    fn chunk_map_example() {
+        let chunk_map = ChunkMap::from_reader(b"abcdefg".as_slice(), 3).unwrap();
+        assert!(chunk_map.size == 7);
+        assert!(chunk_map.chunks.len() == 3);
+        assert!(chunk_map.chunks[0] == Sha256Digest(Sha256::digest(b"abc").into()));
+        assert!(chunk_map.chunks[2] == Sha256Digest(Sha256::digest(b"g").into()));
+        assert!(chunk_map.sha256 == Sha256Digest(Sha256::digest(b"abcdefg").into()));
+    }
+
+    #[test]
+    fn chunk_map_empty() {
+        let chunk_map = ChunkMap::from_reader(b"".as_slice(), 3).unwrap();
+        assert!(chunk_map.size == 0);
+        assert!(chunk_map.chunks.is_empty());
+        assert!(chunk_map.sha256 == Sha256Digest(Sha256::digest(b"").into()));
+    }
+
+    #[proptest]
+    fn chunk_map_matches_chunks(data: Vec<u8>, #[strategy(1u64..100)] chunk_size: u64) {
+        let chunk_map = ChunkMap::from_reader(data.as_slice(), chunk_size).unwrap();
+        assert!(chunk_map.size == data.len() as u64);
+        let expected: Vec<_> = data
+            .chunks(chunk_size as usize)
+            .map(|chunk| Sha256Digest(Sha256::digest(chunk).into()))
+            .collect();
+        assert!(chunk_map.chunks == expected);
+    }
+}
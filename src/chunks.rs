@@ -0,0 +1,268 @@
+//! Content addressed store of upload chunks, for deduplicated uploads.
+//!
+//! The client splits the file into chunks (typically with content-defined chunking, so that
+//! a local modification changes only a few chunks), asks which chunks the server is missing,
+//! uploads only those and then assembles the file from the list of chunk hashes.
+
+use crate::error::{FiledlError, Result};
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt as _, TryStreamExt as _};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, io::AsyncWriteExt, task::spawn_blocking};
+
+/// Largest accepted chunk, in bytes.
+pub const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Prefix of files with chunks that are still being received.
+const TEMP_PREFIX: &str = ".chunk-";
+
+/// Chunks stored in files named by hex encoded SHA-256 of their content.
+#[derive(Debug)]
+pub struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        ChunkStore { root: root.into() }
+    }
+
+    /// Chunks are spread into subdirectories by the first byte of the hash,
+    /// to keep the directories reasonably small.
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// Returns the hashes of chunks that are not stored yet, in the original order.
+    /// Chunks that are present get their modification time refreshed, so that they
+    /// are not purged before the upload that asked about them is assembled.
+    pub async fn missing(&self, hashes: &[String]) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for hash in hashes {
+            check_hash(hash)?;
+            let path = self.chunk_path(hash);
+            match spawn_blocking(move || touch(&path)).await.unwrap() {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => missing.push(hash.clone()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Remove chunks and leftover temporary files that were not modified for longer
+    /// than max_age. Returns the number of removed files.
+    pub async fn purge(&self, max_age: Duration) -> Result<usize> {
+        let root = self.root.clone();
+        Ok(spawn_blocking(move || purge_dir(&root, max_age))
+            .await
+            .unwrap()?)
+    }
+
+    /// Store a chunk, verifying that its content matches the hash.
+    pub async fn store<S, E>(&self, hash: &str, mut body: S) -> Result<()>
+    where
+        S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        check_hash(hash)?;
+        let path = self.chunk_path(hash);
+        let dir = path.parent().expect("Chunk path has a parent");
+        fs::create_dir_all(dir).await?;
+
+        let temp_path = dir.join(format!("{}{:016x}", TEMP_PREFIX, thread_rng().next_u64()));
+        let result = write_chunk(&temp_path, &mut body)
+            .await
+            .and_then(|content_hash| {
+                if content_hash == hash {
+                    Ok(())
+                } else {
+                    Err(FiledlError::ChunkHashMismatch)
+                }
+            });
+        match result {
+            Ok(()) => Ok(fs::rename(&temp_path, &path).await?),
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Content of the file assembled from the chunks.
+    /// Fails upfront if any of the chunks is missing.
+    pub async fn assemble(
+        &self,
+        hashes: &[String],
+    ) -> Result<Pin<Box<dyn Stream<Item = std::io::Result<Bytes>>>>> {
+        if let Some(hash) = self.missing(hashes).await?.into_iter().next() {
+            return Err(FiledlError::ChunkMissing { hash });
+        }
+        let paths: Vec<_> = hashes.iter().map(|hash| self.chunk_path(hash)).collect();
+        Ok(Box::pin(
+            stream::iter(paths).then(fs::read).map_ok(Bytes::from),
+        ))
+    }
+}
+
+/// Write the chunk to path, returns the hex encoded hash of its content.
+async fn write_chunk<S, E>(path: &Path, body: &mut S) -> Result<String>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut file = fs::File::create(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        // Client aborts and malformed payloads, not a problem on the server side
+        let chunk = chunk.map_err(|e| FiledlError::UploadBodyError {
+            message: e.to_string(),
+        })?;
+        size += chunk.len() as u64;
+        if size > MAX_CHUNK_SIZE {
+            return Err(FiledlError::UploadTooLarge);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+
+    let mut hash = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        write!(hash, "{:02x}", byte).expect("Writing to string can't fail");
+    }
+    Ok(hash)
+}
+
+/// Set the modification time of the file to now.
+fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+fn purge_dir(root: &Path, max_age: Duration) -> std::io::Result<usize> {
+    let shards = match std::fs::read_dir(root) {
+        Ok(shards) => shards,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for shard in shards {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(shard.path())? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if !is_valid_hash(name) && !name.starts_with(TEMP_PREFIX) {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() > max_age {
+                match std::fs::remove_file(entry.path()) {
+                    Ok(()) => removed += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Chunk hashes are lowercase hex encoded SHA-256.
+/// Checking the format also keeps the hashes safe to use as file names.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn check_hash(hash: &str) -> Result<()> {
+    if is_valid_hash(hash) {
+        Ok(())
+    } else {
+        Err(FiledlError::InvalidChunkHash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn valid_hashes() {
+        assert!(is_valid_hash(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        ));
+        assert!(!is_valid_hash(
+            "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855"
+        ));
+        assert!(!is_valid_hash("e3b0c442"));
+        assert!(!is_valid_hash(
+            "../0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        ));
+    }
+
+    #[test]
+    fn chunk_path_is_sharded() {
+        let store = ChunkStore::new("/data/chunks");
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(store.chunk_path(hash) == Path::new("/data/chunks/e3").join(hash));
+    }
+
+    #[actix_web::test]
+    async fn purge_removes_only_old_chunks() {
+        let root =
+            std::env::temp_dir().join(format!("filedl-chunks-{:X}", thread_rng().next_u64()));
+        let store = ChunkStore::new(&root);
+        let old = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned();
+        let used = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned();
+        store
+            .store(&old, stream::iter([Ok::<_, std::io::Error>(Bytes::new())]))
+            .await
+            .unwrap();
+        store
+            .store(
+                &used,
+                stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(b"abc"))]),
+            )
+            .await
+            .unwrap();
+
+        let day_ago = SystemTime::now() - Duration::from_secs(24 * 3600);
+        for hash in [&old, &used] {
+            std::fs::File::options()
+                .write(true)
+                .open(store.chunk_path(hash))
+                .unwrap()
+                .set_modified(day_ago)
+                .unwrap();
+        }
+        // Asking about a chunk marks it as used
+        assert!(store
+            .missing(std::slice::from_ref(&used))
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(store.purge(Duration::from_secs(3600)).await.unwrap() == 1);
+        assert!(store.missing(&[old.clone(), used.clone()]).await.unwrap() == vec![old]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
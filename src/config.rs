@@ -1,14 +1,26 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, net::IpAddr, path::PathBuf};
 
 use chrono_tz::{Tz, UTC};
 use clap::Parser;
 use figment::{
-    providers::{Env, Format, Toml},
+    providers::{Env, Format, Serialized, Toml},
     Figment,
 };
 use serde::Deserialize;
 
-use crate::error::Result;
+use crate::{error::Result, templates::RootGrouping, thumbnails::ThumbnailFit};
+
+fn default_profile() -> String {
+    "default".into()
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_true() -> bool {
+    true
+}
 
 fn default_bind_address() -> String {
     "localhost".into()
@@ -30,12 +42,183 @@ fn default_timezone() -> Tz {
     UTC
 }
 
+fn default_relative_time_cutoff_hours() -> u32 {
+    48
+}
+
+fn default_listing_page_size() -> usize {
+    1000
+}
+
+fn default_max_preview_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_max_upload_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_chunk_retention_hours() -> u32 {
+    7 * 24
+}
+
+fn default_stream_stall_timeout() -> u64 {
+    60
+}
+
+fn default_backup_interval_hours() -> u32 {
+    24
+}
+
+fn default_backup_retention() -> usize {
+    7
+}
+
+fn default_flush_interval() -> u64 {
+    60
+}
+
+fn default_root_group_separator() -> char {
+    '-'
+}
+
+fn default_metadata_reload_interval() -> u64 {
+    5
+}
+
+fn default_drop_box_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_drop_box_max_total_size() -> u64 {
+    10 * 1024 * 1024 * 1024
+}
+
+fn default_drop_box_lifetime() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_search_max_file_size() -> u64 {
+    256 * 1024
+}
+
+fn default_search_reindex_interval() -> u64 {
+    600
+}
+
+fn default_thumbnail_sizes() -> Vec<u32> {
+    vec![64, 128, 256, 512]
+}
+
 fn default_thumbnail_cache_size() -> usize {
     1024 * 1024 * 20
 }
 
+/// What to send to requests for files that come from a foreign referrer.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HotlinkResponse {
+    /// Redirect to the listing page containing the file.
+    #[default]
+    Redirect,
+    /// Serve a placeholder image instead of the file.
+    Placeholder,
+}
+
+/// Where object metadata is stored.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataBackend {
+    /// `metadata.json`, rewritten on every change.
+    #[default]
+    Json,
+    /// `metadata.sqlite`, only changed objects are written. Requires the `sqlite` feature.
+    /// Existing `metadata.json` is migrated on the first start.
+    Sqlite,
+}
+
+/// Format of the access log written to standard output.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Off,
+    /// Apache combined log format, with the request duration in microseconds appended.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Authentication required for the whole site, except objects marked as public.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SiteAuthConfig {
+    /// HTTP basic authentication with static credentials, user name -> password.
+    /// Access tokens of access groups then have to be sent in the `X-Access-Token` header.
+    /// The admin token is accepted in place of the credentials.
+    Basic { users: HashMap<String, String> },
+    /// Login through an OpenID Connect provider.
+    Oidc(OidcConfig),
+}
+
+fn default_oidc_scopes() -> String {
+    "openid email".into()
+}
+
+fn default_session_lifetime() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer URL, the provider configuration is discovered from
+    /// `{issuer}/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Full URL of `/auth/callback` on this server, as registered at the provider.
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: String,
+    /// E-mail addresses (or subject IDs for users without one) allowed to log in.
+    /// Anyone who can log in at the provider is allowed if empty.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// Seconds until the login has to be repeated.
+    #[serde(default = "default_session_lifetime")]
+    pub session_lifetime: u64,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    /// Name of the selected configuration profile.
+    #[serde(default = "default_profile")]
+    pub profile: String,
+
+    /// Default log filter, in env_logger syntax. Overridden by the RUST_LOG variable.
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+
+    /// Log every request (method, path, status, bytes sent, duration, client address)
+    /// to standard output, for auditing downloads. The application log goes to standard
+    /// error.
+    #[serde(default)]
+    pub access_log: AccessLogFormat,
+
+    /// Allow browsers to cache static assets and thumbnails indefinitely.
+    #[serde(default = "default_true")]
+    pub immutable_caching: bool,
+
+    /// Serve static assets from the `assets/` source directory instead of the embedded ones,
+    /// compiling SCSS on every request. Intended for development of the front-end.
+    #[serde(default)]
+    pub live_assets: bool,
+
+    /// Embed small icons into the pages as data URIs instead of loading each one
+    /// with a separate request. Ignored with live assets.
+    #[serde(default)]
+    pub inline_icons: bool,
+
     #[serde(default = "default_bind_address")]
     pub bind_address: String,
     #[serde(default = "default_bind_port")]
@@ -47,20 +230,268 @@ pub struct Config {
     /// Root path for all linked objects
     pub linked_objects_root: PathBuf,
 
+    /// Maximum size in bytes of a single uploaded file.
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+
+    /// Largest text file in bytes that is shown with `mode=preview`,
+    /// larger files are downloaded instead.
+    #[serde(default = "default_max_preview_size")]
+    pub max_preview_size: u64,
+
+    /// Allow deduplicated uploads, where the client uploads only chunks the server doesn't
+    /// have yet and the file is assembled from them.
+    /// Chunks are kept in `data_path/chunks`.
+    #[serde(default)]
+    pub chunked_uploads: bool,
+
+    /// Chunks that were not uploaded or used by any upload for this many hours are removed.
+    /// Zero keeps the chunks forever.
+    #[serde(default = "default_chunk_retention_hours")]
+    pub chunk_retention_hours: u32,
+
+    /// Create objects for all entries of the owned data directory and linked objects root
+    /// if metadata.json doesn't exist yet. Usually set with the `--import-legacy` flag.
+    #[serde(default)]
+    pub import_legacy: bool,
+
+    /// Directory where encrypted backups are periodically written. Backups are disabled
+    /// if not set. A backup can be restored with the `--decrypt-backup` flag.
+    #[serde(default)]
+    pub backup_path: Option<PathBuf>,
+
+    /// Passphrase from which the backup encryption key is derived, required with `backup_path`.
+    #[serde(default)]
+    pub backup_passphrase: Option<String>,
+
+    /// Hours between backups.
+    #[serde(default = "default_backup_interval_hours")]
+    pub backup_interval_hours: u32,
+
+    /// Number of most recent backups that are kept, older ones are removed.
+    #[serde(default = "default_backup_retention")]
+    pub backup_retention: usize,
+
+    /// Include the owned data in backups, not just metadata.json.
+    /// Linked objects are never backed up.
+    #[serde(default)]
+    pub backup_owned_data: bool,
+
+    /// Decrypt this backup to the standard output and exit, instead of running the server.
+    /// Usually set with the `--decrypt-backup` flag.
+    #[serde(default)]
+    pub decrypt_backup: Option<PathBuf>,
+
+    /// Proxy for all outbound HTTP requests (currently the OpenID Connect login),
+    /// e.g. `http://proxy:3128` or `socks5://proxy:1080`. If not set, the standard
+    /// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables are used.
+    #[serde(default)]
+    pub outbound_proxy: Option<String>,
+
+    /// Comma separated hosts and networks reached without `outbound_proxy`,
+    /// in the format of `NO_PROXY`.
+    #[serde(default)]
+    pub outbound_no_proxy: Option<String>,
+
+    /// Require authentication for the download pages and the admin interface.
+    /// Disabled if not set.
+    #[serde(default)]
+    pub site_auth: Option<SiteAuthConfig>,
+
+    /// Named access groups, each with a list of tokens that grant access to objects
+    /// of the group. Visitors send the token as the password of HTTP basic authentication,
+    /// or in the `X-Access-Token` header.
+    #[serde(default)]
+    pub access_groups: HashMap<String, Vec<String>>,
+
+    /// Maximum number of bytes served per day, in the display timezone.
+    /// Downloads get an over-quota page once it is reached.
+    #[serde(default)]
+    pub daily_transfer_cap: Option<u64>,
+
+    /// Maximum number of bytes served per calendar month.
+    #[serde(default)]
+    pub monthly_transfer_cap: Option<u64>,
+
+    /// Allow symlinks inside objects to point outside of the object directory.
+    #[serde(default)]
+    pub allow_symlinks: bool,
+
     /// URL where the download side of the app is located.
     /// The default `download` means that links lead to `http://server/download/`.
+    /// Behind a reverse proxy at a subpath, include the subpath (e.g. `/files/download`).
     #[serde(default = "default_download_url")]
     pub download_url: String,
 
+    /// Addresses of reverse proxies whose `X-Forwarded-For`, `X-Forwarded-Proto`,
+    /// `X-Forwarded-Host` and `X-Forwarded-Prefix` headers are trusted. The headers are
+    /// used for client addresses, generated absolute URLs and redirects.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+
     #[serde(default = "default_app_name")]
     pub app_name: String,
 
     #[serde(default = "default_timezone")]
     pub display_timezone: Tz,
 
+    /// Modification times more recent than this many hours are displayed as relative
+    /// ("5 minutes ago"), older ones as absolute timestamps. Zero disables relative display.
+    #[serde(default = "default_relative_time_cutoff_hours")]
+    pub relative_time_cutoff_hours: u32,
+
+    /// Abort file and archive downloads when reading the data makes no progress for this
+    /// many seconds, e.g. because of a hung network mount. Zero disables the timeout.
+    #[serde(default = "default_stream_stall_timeout")]
+    pub stream_stall_timeout: u64,
+
+    /// Seconds between writing changed metadata, statistics and bandwidth usage to disk.
+    /// Limits what is lost if the process is killed. Zero disables the periodic writes,
+    /// the state is then only written on shutdown and after admin changes.
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+
+    /// Storage of object metadata, `json` or `sqlite`.
+    #[serde(default)]
+    pub metadata_backend: MetadataBackend,
+
+    /// Seconds between checks whether metadata.json was edited by hand. Edits are loaded
+    /// without a restart, unless there are unsaved changes made through the admin API,
+    /// which take precedence. Zero disables the checks.
+    #[serde(default = "default_metadata_reload_interval")]
+    pub metadata_reload_interval: u64,
+
+    /// Number of anonymous uploads per minute allowed from a single client address
+    /// through the public drop box (`PUT /drop/{file_name}`). Each upload creates
+    /// an unlisted object with one file and the response contains its link.
+    /// The drop box is disabled if not set.
+    #[serde(default)]
+    pub drop_box_rate_limit: Option<u32>,
+
+    /// Maximum size in bytes of a file uploaded through the drop box.
+    #[serde(default = "default_drop_box_max_size")]
+    pub drop_box_max_size: u64,
+
+    /// Maximum total size in bytes of all files kept in the drop box.
+    /// Uploads are refused once it is full, until the old ones expire.
+    #[serde(default = "default_drop_box_max_total_size")]
+    pub drop_box_max_total_size: u64,
+
+    /// Seconds after which objects created through the drop box expire.
+    /// Expired drop box objects are removed together with their data.
+    #[serde(default = "default_drop_box_lifetime")]
+    pub drop_box_lifetime: u64,
+
+    /// Maximum total size in bytes of text kept in the full-text search index.
+    /// Only small text files of objects that are listed and not protected by a password,
+    /// access group or terms are indexed. Search is disabled if not set.
+    #[serde(default)]
+    pub search_index_size: Option<u64>,
+
+    /// Text files larger than this many bytes are not indexed for search.
+    #[serde(default = "default_search_max_file_size")]
+    pub search_max_file_size: u64,
+
+    /// Seconds between rebuilds of the search index.
+    #[serde(default = "default_search_reindex_interval")]
+    pub search_reindex_interval: u64,
+
+    /// Send directory listings progressively while the directory is being read,
+    /// instead of waiting for all entries. Useful for slow network file systems.
+    /// Streamed listings are not sorted, items appear in the order they are read.
+    #[serde(default)]
+    pub stream_listings: bool,
+
+    /// Group objects of the root listing into collapsible sections, `tag` by their tags,
+    /// `prefix` by the part of the object ID before `root_group_separator`.
+    #[serde(default)]
+    pub root_grouping: RootGrouping,
+
+    /// Separator ending the ID prefix used for grouping the root listing.
+    #[serde(default = "default_root_group_separator")]
+    pub root_group_separator: char,
+
+    /// Number of items per page of directory listings, 0 shows all items on a single page.
+    /// Image dimensions are only probed for the displayed page, which keeps huge directories
+    /// usable. Streamed listings are not paginated.
+    #[serde(default = "default_listing_page_size")]
+    pub listing_page_size: usize,
+
+    /// Show expiry time of objects to visitors.
+    #[serde(default)]
+    pub show_expiry: bool,
+
+    /// Thumbnail sizes in pixels that can be requested.
+    /// Other sizes are rounded up to the nearest allowed one, or down to the largest.
+    /// If empty, any size up to 2048 pixels can be requested.
+    #[serde(default = "default_thumbnail_sizes")]
+    pub thumbnail_sizes: Vec<u32>,
+
+    /// How thumbnails of images with a different aspect ratio are rendered by default,
+    /// `cover` crops them, `contain` shows the whole image. Can be overridden by the `fit`
+    /// query parameter.
+    #[serde(default)]
+    pub thumbnail_fit: ThumbnailFit,
+
     /// Maximum size in bytes for cached thumbnails.
     #[serde(default = "default_thumbnail_cache_size")]
     pub thumbnail_cache_size: usize,
+
+    /// Token required for accessing the admin interface under `/admin`,
+    /// sent either as a bearer token or as the basic auth password.
+    /// If not set, the admin interface is disabled.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+
+    /// Approximate limit in bytes for memory used by in-memory caches.
+    /// When it is exceeded, least recently used thumbnails and checksums are evicted.
+    #[serde(default)]
+    pub memory_ceiling: Option<usize>,
+
+    /// Path to the ffmpeg binary used for thumbnails of videos.
+    /// Video thumbnails are disabled if not set.
+    #[serde(default)]
+    pub ffmpeg_path: Option<PathBuf>,
+
+    /// Command rendering first page previews of office documents (docx, odt, xlsx, ...),
+    /// given as a program followed by its arguments.
+    /// `{input}` in the arguments is replaced by the path of the document, `{output}` by
+    /// the path of the image file the command should write.
+    /// Document previews are disabled if not set.
+    #[serde(default)]
+    pub document_preview_command: Option<Vec<String>>,
+
+    /// Send SHA-256 of downloaded files in the `X-Content-SHA256` header.
+    /// Checksums are calculated while the file is first downloaded and the header is sent
+    /// only for subsequent downloads.
+    #[serde(default)]
+    pub content_checksums: bool,
+
+    /// Secret used for signing cookies, at least 32 bytes long.
+    /// If not set, a random one is generated at startup, invalidating all cookies on restart.
+    #[serde(default)]
+    pub secret_key: Option<String>,
+
+    /// Host names of sites that are allowed to link to files and thumbnails directly.
+    /// Requests with a Referer from other hosts get the `hotlink_response`.
+    /// Requests without Referer and from the own host are always allowed.
+    /// Empty list disables the hotlink protection.
+    #[serde(default)]
+    pub allowed_referrers: Vec<String>,
+
+    /// Response to requests from hosts not in `allowed_referrers`.
+    #[serde(default)]
+    pub hotlink_response: HotlinkResponse,
+
+    /// Number of HTTP worker threads. Defaults to the number of physical CPU cores.
+    #[serde(default)]
+    pub http_workers: Option<usize>,
+
+    /// Maximum number of threads per HTTP worker for blocking work (thumbnails, hashing, ...).
+    /// Keeping this low prevents thumbnail generation from starving request handling
+    /// on small machines. Defaults to 512 divided by the number of workers.
+    #[serde(default)]
+    pub blocking_threads: Option<usize>,
 }
 
 #[derive(Debug, Parser)]
@@ -68,15 +499,49 @@ struct Cli {
     /// Location of the config file. If not specified, no config file is loaded.
     #[arg(short, long = "config")]
     config_path: Option<PathBuf>,
+
+    /// Configuration profile to use (e.g. `dev`), can also be set with FILEDL_PROFILE.
+    #[arg(short, long, env = "FILEDL_PROFILE", default_value = "default")]
+    profile: String,
+
+    /// Create objects for existing files and directories when starting without metadata.json.
+    #[arg(long)]
+    import_legacy: bool,
+
+    /// Decrypt a backup made with the configured passphrase to standard output and exit.
+    #[arg(long, value_name = "BACKUP")]
+    decrypt_backup: Option<PathBuf>,
+}
+
+/// Built-in defaults of the configuration profiles.
+fn profile_defaults(profile: &str) -> Figment {
+    let figment = Figment::new();
+    match profile {
+        "dev" => figment
+            .merge(Serialized::default("log_level", "debug"))
+            .merge(Serialized::default("immutable_caching", false))
+            .merge(Serialized::default("live_assets", true)),
+        _ => figment,
+    }
 }
 
 impl Config {
+    /// Load the configuration.
+    /// Sources in increasing priority are built-in profile defaults, the config file,
+    /// the `[profiles.<profile>]` table of the config file and FILEDL_* environment variables.
     pub fn get() -> Result<Config> {
-        let mut figment = Figment::new();
-
         let cli = Cli::parse();
+        let mut figment = profile_defaults(&cli.profile)
+            .merge(Serialized::default("profile", &cli.profile))
+            .merge(Serialized::default("import_legacy", cli.import_legacy));
+        if let Some(decrypt_backup) = &cli.decrypt_backup {
+            figment = figment.merge(Serialized::default("decrypt_backup", decrypt_backup));
+        }
+
         if let Some(config_path) = cli.config_path {
-            figment = figment.merge(Toml::file(config_path));
+            let file = Figment::from(Toml::file(config_path));
+            let profile_overrides = file.focus(&format!("profiles.{}", cli.profile));
+            figment = figment.merge(file).merge(profile_overrides);
         }
         Ok(figment.merge(Env::prefixed("FILEDL_")).extract()?)
     }
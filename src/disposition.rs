@@ -0,0 +1,103 @@
+//! Content-Disposition headers with file names that work across browsers.
+//!
+//! Non-ASCII names are sent in the RFC 5987 `filename*` parameter, with a transliterated
+//! ASCII `filename` for clients that don't understand it.
+
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use deunicode::deunicode_with_tofu;
+
+/// Used when nothing of the name survives the transliteration.
+const FALLBACK_NAME: &str = "download";
+
+pub fn content_disposition(disposition: DispositionType, file_name: &str) -> ContentDisposition {
+    let mut parameters = vec![DispositionParam::Filename(ascii_file_name(file_name))];
+    if !file_name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: file_name.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
+
+/// Transliterate the name to printable ASCII, replacing characters that browsers
+/// handle inconsistently in the quoted `filename` parameter.
+fn ascii_file_name(file_name: &str) -> String {
+    let transliterated = deunicode_with_tofu(file_name, "_");
+    let sanitized: String = transliterated
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '/' | '%' => '_',
+            c if c.is_ascii_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Keep the extension, so that the file still opens with the right application
+    let (stem, extension) = match sanitized.rsplit_once('.') {
+        Some((stem, extension)) => (stem, Some(extension)),
+        None => (sanitized.as_str(), None),
+    };
+    if !stem.chars().all(|c| c == '_' || c.is_whitespace()) {
+        return sanitized;
+    }
+    match extension {
+        Some(extension) if !extension.is_empty() => format!("{}.{}", FALLBACK_NAME, extension),
+        _ => FALLBACK_NAME.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::{assert, let_assert};
+    use test_strategy::proptest;
+
+    #[test]
+    fn ascii_names_are_kept() {
+        assert!(ascii_file_name("report 2024.pdf") == "report 2024.pdf");
+        let cd = content_disposition(DispositionType::Attachment, "report.pdf");
+        assert!(cd.parameters.len() == 1);
+    }
+
+    #[test]
+    fn transliteration() {
+        assert!(ascii_file_name("Příliš žluťoučký kůň.txt") == "Prilis zlutoucky kun.txt");
+        assert!(ascii_file_name("Привет.zip") == "Privet.zip");
+    }
+
+    #[test]
+    fn unsafe_characters_are_replaced() {
+        assert!(ascii_file_name("a\"b\\c%d\ne.txt") == "a_b_c_d_e.txt");
+    }
+
+    #[test]
+    fn fallback_name() {
+        assert!(ascii_file_name("\u{e000}\u{e001}.txt") == "download.txt");
+        assert!(ascii_file_name("\u{e000}") == "download");
+        assert!(ascii_file_name("") == "download");
+    }
+
+    #[test]
+    fn non_ascii_name_has_extended_parameter() {
+        let cd = content_disposition(DispositionType::Attachment, "Příliš.txt");
+        let_assert!(Some(DispositionParam::Filename(name)) = cd.parameters.first());
+        assert!(name == "Prilis.txt");
+        let_assert!(Some(DispositionParam::FilenameExt(ext)) = cd.parameters.get(1));
+        assert!(ext.value == "Příliš.txt".as_bytes());
+    }
+
+    #[proptest]
+    fn always_printable_ascii(file_name: String) {
+        let name = ascii_file_name(&file_name);
+        assert!(!name.is_empty());
+        assert!(name.chars().all(|c| c.is_ascii() && !c.is_ascii_control()));
+        assert!(!name.contains(['"', '\\', '/']));
+    }
+}
@@ -1,15 +1,107 @@
+use crate::validation::FieldError;
+use actix_web::http::StatusCode;
+use std::sync::Arc;
+
 pub type Result<T> = std::result::Result<T, FiledlError>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum FiledlError {
     #[error("Object not found")]
     ObjectNotFound,
+    #[error("Object was removed or has expired")]
+    ShareEnded { message: Option<Arc<str>> },
+    #[error("Too many requests to the object")]
+    RateLimited,
+    #[error("Share link has expired")]
+    ShareLinkExpired,
+    #[error("Share link has no downloads left")]
+    ShareLinkExhausted,
+    #[error("Object is outside of its availability windows")]
+    OutsideAvailability,
+    #[error("Access token is missing or wrong")]
+    AccessDenied,
     #[error("Object exists, but is unlisted")]
     Unlisted,
     #[error("Attempting to use unsupported download mode")]
     BadDownloadMode,
+    #[error("Invalid request parameters: {errors:?}")]
+    InvalidRequest { errors: Vec<FieldError> },
+    #[error("Uploaded file is larger than the allowed maximum")]
+    UploadTooLarge,
+    #[error("Only owned objects can be uploaded to")]
+    UploadToLinkedObject,
+    #[error("Inbox no longer accepts uploads")]
+    InboxClosed,
+    #[error("File already exists in the inbox")]
+    InboxFileExists,
+    #[error("Object is pinned")]
+    ObjectPinned,
+    #[error("Imported object {id} already exists")]
+    ImportConflict { id: Arc<str> },
+    #[error("Upload session not found")]
+    UploadSessionNotFound,
+    #[error("Upload with the same session ID is still in progress")]
+    UploadSessionActive,
+    #[error("Upload was cancelled")]
+    UploadCancelled,
+    #[error("Upload was interrupted before it finished")]
+    UploadInterrupted,
+    #[error("Reading the upload body failed: {message}")]
+    UploadBodyError { message: String },
+    #[error("Chunked uploads are disabled")]
+    ChunkedUploadsDisabled,
+    #[error("Chunk hash is not a lowercase hex encoded SHA-256")]
+    InvalidChunkHash,
+    #[error("Chunk content doesn't match its hash")]
+    ChunkHashMismatch,
+    #[error("Chunk {hash} is not stored")]
+    ChunkMissing { hash: String },
+    #[error("Search is disabled")]
+    SearchDisabled,
+    #[error("Drop box is disabled")]
+    DropBoxDisabled,
+    #[error("Login required")]
+    LoginRequired,
+    #[error("Login failed: {reason}")]
+    LoginFailed { reason: String },
+    #[error("Invalid outbound proxy configuration: {source}")]
+    InvalidOutboundProxy {
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Login provider request failed: {source}")]
+    LoginProvider {
+        #[from]
+        #[source]
+        source: reqwest::Error,
+    },
     #[error("Zip downloads are unimplemented")]
     UnimplementedZipDownload,
+    #[error("Admin interface is disabled, no admin token is configured")]
+    AdminDisabled,
+    #[error("Admin authorization required")]
+    AdminAuthRequired,
+    #[cfg(not(feature = "sqlite"))]
+    #[error(
+        "SQLite metadata backend is configured, but filedl was built without the sqlite feature"
+    )]
+    SqliteUnavailable,
+    #[error("Configured secret key is too short")]
+    SecretKeyTooShort,
+    #[error("Watermark font {path:?} can't be loaded")]
+    WatermarkFont { path: std::path::PathBuf },
+    #[error(
+        "Object IDs {ids:?} contain '~', which separates the unlisted key in download paths; rename them in the metadata"
+    )]
+    ObjectIdWithKeySeparator { ids: Vec<Arc<str>> },
+    #[error("Backups are enabled, but no backup passphrase is configured")]
+    BackupPassphraseMissing,
+    #[error("Encrypting the backup failed")]
+    BackupEncryption,
+    #[error("Backup is damaged or the passphrase is wrong")]
+    BackupDecryption,
+    #[error("Compiling asset failed: {message}")]
+    AssetCompilation { message: String },
     #[error("Template error: {source}")]
     TemplateError {
         #[from]
@@ -35,3 +127,168 @@ pub enum FiledlError {
         source: std::io::Error,
     },
 }
+
+/// Broad classification of errors, determines the HTTP status and whether retrying makes sense.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// The object doesn't exist, or the visitor is not supposed to know about it
+    NotFound,
+    /// The object existed, but is no longer available
+    Gone,
+    /// The visitor has to authenticate to access the object
+    Unauthorized,
+    /// The visitor is not allowed to access the object at all
+    Forbidden,
+    /// The request itself is invalid
+    BadRequest,
+    /// The request body is too large
+    PayloadTooLarge,
+    /// Temporary failure, the request may succeed when retried
+    Unavailable,
+    /// The client is sending too many requests, it may retry later
+    RateLimited,
+    /// The requested functionality is not implemented
+    Unimplemented,
+    /// Problem on the server side
+    Internal,
+}
+
+impl ErrorCategory {
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            ErrorCategory::NotFound => StatusCode::NOT_FOUND,
+            ErrorCategory::Gone => StatusCode::GONE,
+            ErrorCategory::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCategory::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCategory::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCategory::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCategory::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCategory::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCategory::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            ErrorCategory::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl FiledlError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            FiledlError::ObjectNotFound
+            | FiledlError::Unlisted
+            | FiledlError::BadDownloadMode
+            | FiledlError::UploadSessionNotFound => ErrorCategory::NotFound,
+            FiledlError::ShareEnded { .. }
+            | FiledlError::ShareLinkExpired
+            | FiledlError::ShareLinkExhausted
+            | FiledlError::InboxClosed => ErrorCategory::Gone,
+            FiledlError::AdminAuthRequired => ErrorCategory::Unauthorized,
+            FiledlError::AdminDisabled => ErrorCategory::Forbidden,
+            FiledlError::RateLimited => ErrorCategory::RateLimited,
+            FiledlError::OutsideAvailability => ErrorCategory::Unavailable,
+            FiledlError::AccessDenied | FiledlError::LoginRequired => ErrorCategory::Unauthorized,
+            FiledlError::LoginFailed { .. } => ErrorCategory::BadRequest,
+            FiledlError::LoginProvider { .. } => ErrorCategory::Unavailable,
+            FiledlError::InvalidRequest { .. }
+            | FiledlError::UploadToLinkedObject
+            | FiledlError::ObjectPinned
+            | FiledlError::InboxFileExists
+            | FiledlError::ImportConflict { .. }
+            | FiledlError::UploadSessionActive
+            | FiledlError::UploadCancelled
+            | FiledlError::UploadInterrupted
+            | FiledlError::UploadBodyError { .. }
+            | FiledlError::InvalidChunkHash
+            | FiledlError::ChunkHashMismatch
+            | FiledlError::ChunkMissing { .. } => ErrorCategory::BadRequest,
+            FiledlError::ChunkedUploadsDisabled
+            | FiledlError::SearchDisabled
+            | FiledlError::DropBoxDisabled => ErrorCategory::Unimplemented,
+            FiledlError::UploadTooLarge => ErrorCategory::PayloadTooLarge,
+            FiledlError::UnimplementedZipDownload => ErrorCategory::Unimplemented,
+            FiledlError::IOError { source } => match source.kind() {
+                std::io::ErrorKind::NotFound => ErrorCategory::NotFound,
+                std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::Interrupted
+                | std::io::ErrorKind::WouldBlock => ErrorCategory::Unavailable,
+                _ => ErrorCategory::Internal,
+            },
+            #[cfg(not(feature = "sqlite"))]
+            FiledlError::SqliteUnavailable => ErrorCategory::Internal,
+            FiledlError::SecretKeyTooShort
+            | FiledlError::WatermarkFont { .. }
+            | FiledlError::ObjectIdWithKeySeparator { .. }
+            | FiledlError::InvalidOutboundProxy { .. }
+            | FiledlError::BackupPassphraseMissing
+            | FiledlError::BackupEncryption
+            | FiledlError::BackupDecryption
+            | FiledlError::AssetCompilation { .. }
+            | FiledlError::TemplateError { .. }
+            | FiledlError::ImageError { .. }
+            | FiledlError::ConfigError { .. } => ErrorCategory::Internal,
+        }
+    }
+
+    /// Message that is safe to show to the visitor.
+    /// Unlike the Display implementation it never contains internal details.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            FiledlError::BadDownloadMode => "Unsupported download mode",
+            FiledlError::OutsideAvailability => "This share is not available at this time",
+            FiledlError::ShareLinkExpired => "This link has expired",
+            FiledlError::ShareLinkExhausted => "This link has reached its download limit",
+            FiledlError::InvalidRequest { .. } => "Invalid request parameters",
+            FiledlError::UploadToLinkedObject => "Only owned objects can be uploaded to",
+            FiledlError::InboxClosed => "This inbox no longer accepts uploads",
+            FiledlError::InboxFileExists => "A file with this name was already uploaded",
+            FiledlError::ObjectPinned => "Pinned objects can't be removed, unpin them first",
+            FiledlError::ImportConflict { .. } => "Some of the imported objects already exist",
+            FiledlError::UploadSessionNotFound => "Upload session not found",
+            FiledlError::UploadSessionActive => {
+                "Upload with the same session ID is still in progress"
+            }
+            FiledlError::UploadCancelled => "The upload was cancelled",
+            FiledlError::UploadInterrupted => "The upload was interrupted",
+            FiledlError::UploadBodyError { .. } => "The upload could not be received",
+            FiledlError::ChunkedUploadsDisabled => "Chunked uploads are disabled",
+            FiledlError::SearchDisabled => "Search is not available",
+            FiledlError::DropBoxDisabled => "Drop box is not available",
+            FiledlError::LoginRequired => "Login required",
+            FiledlError::LoginFailed { .. } => "Login failed",
+            FiledlError::LoginProvider { .. } => "Login provider is not available",
+            FiledlError::InvalidChunkHash => "Invalid chunk hash",
+            FiledlError::ChunkHashMismatch => "Chunk content doesn't match its hash",
+            FiledlError::ChunkMissing { .. } => "Some of the chunks are not uploaded",
+            FiledlError::UnimplementedZipDownload => "Directory downloads are not available yet",
+            FiledlError::ImageError { .. } => "The image could not be processed",
+            FiledlError::AdminDisabled => "Admin interface is disabled",
+            FiledlError::AccessDenied => "Access token required",
+            _ => match self.category() {
+                ErrorCategory::NotFound => "Object not found",
+                ErrorCategory::Gone => "This share has ended",
+                ErrorCategory::Unauthorized => "Authorization required",
+                ErrorCategory::Forbidden => "Access denied",
+                ErrorCategory::BadRequest => "Bad request",
+                ErrorCategory::PayloadTooLarge => "The uploaded file is too large",
+                ErrorCategory::Unavailable => "Temporarily unavailable, please try again later",
+                ErrorCategory::RateLimited => "Too many requests, please try again later",
+                ErrorCategory::Unimplemented => "Not implemented",
+                ErrorCategory::Internal => "Internal server error",
+            },
+        }
+    }
+
+    /// Problems with individual request parameters, if any.
+    pub fn field_errors(&self) -> &[FieldError] {
+        match self {
+            FiledlError::InvalidRequest { errors } => errors,
+            _ => &[],
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.category(),
+            ErrorCategory::Unavailable | ErrorCategory::RateLimited
+        )
+    }
+}
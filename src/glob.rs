@@ -0,0 +1,69 @@
+//! Shell style wildcard patterns for filtering directory listings.
+
+/// Match the name against a pattern where `*` matches any sequence of characters
+/// and `?` matches a single character. Matching ignores case.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+
+    let mut p = 0;
+    let mut n = 0;
+    // Position after the last star in the pattern and the name position it was tried at,
+    // to backtrack to when the rest doesn't match
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use test_strategy::proptest;
+
+    #[test]
+    fn examples() {
+        assert!(glob_match("*.jpg", "photo.jpg"));
+        assert!(glob_match("*.jpg", "Photo.JPG"));
+        assert!(!glob_match("*.jpg", "photo.jpeg"));
+        assert!(glob_match("img_????.*", "img_0042.png"));
+        assert!(!glob_match("img_????.*", "img_042.png"));
+        assert!(glob_match("*report*2023*", "annual report for 2023.pdf"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(glob_match("**", ""));
+        assert!(glob_match("a*b*c", "abbbc"));
+        assert!(!glob_match("a*b*c", "abbbcd"));
+    }
+
+    #[proptest]
+    fn star_matches_everything(name: String) {
+        assert!(glob_match("*", &name));
+    }
+
+    #[proptest]
+    fn literal_matches_itself(#[strategy("[a-zA-Z0-9 ._-]*")] name: String) {
+        assert!(glob_match(&name, &name));
+    }
+}
@@ -0,0 +1,136 @@
+//! Minimal iCalendar (RFC 5545) output for the feed of object expirations.
+
+use chrono::{DateTime, Utc};
+
+/// Lines longer than this many bytes are folded.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Reminder sent this long before the event.
+const REMINDER: &str = "-P1D";
+
+#[derive(Debug)]
+pub struct CalendarEvent {
+    /// Has to stay the same for the same event across feed updates, so that calendar
+    /// clients update the event instead of adding a duplicate.
+    pub uid: String,
+    pub time: DateTime<Utc>,
+    pub summary: String,
+    pub description: String,
+    pub url: Option<String>,
+}
+
+/// Render the events as an iCalendar document.
+pub fn calendar(name: &str, events: &[CalendarEvent], now: DateTime<Utc>) -> String {
+    let mut output = String::new();
+    let mut line = |content: String| {
+        fold_line(&mut output, &content);
+    };
+    line("BEGIN:VCALENDAR".to_owned());
+    line("VERSION:2.0".to_owned());
+    line(format!(
+        "PRODID:-//{}//{}//EN",
+        escape(env!("CARGO_PKG_NAME")),
+        escape(name)
+    ));
+    line(format!("X-WR-CALNAME:{}", escape(name)));
+    for event in events {
+        line("BEGIN:VEVENT".to_owned());
+        line(format!("UID:{}", escape(&event.uid)));
+        line(format!("DTSTAMP:{}", timestamp(now)));
+        line(format!("DTSTART:{}", timestamp(event.time)));
+        line(format!("SUMMARY:{}", escape(&event.summary)));
+        line(format!("DESCRIPTION:{}", escape(&event.description)));
+        if let Some(url) = &event.url {
+            line(format!("URL:{}", url));
+        }
+        line("BEGIN:VALARM".to_owned());
+        line("ACTION:DISPLAY".to_owned());
+        line(format!("DESCRIPTION:{}", escape(&event.summary)));
+        line(format!("TRIGGER:{}", REMINDER));
+        line("END:VALARM".to_owned());
+        line("END:VEVENT".to_owned());
+    }
+    line("END:VCALENDAR".to_owned());
+    output
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape a TEXT value.
+fn escape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                output.push('\\');
+                output.push(c);
+            }
+            '\n' => output.push_str("\\n"),
+            '\r' => {}
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// Write the content line, folded to lines of at most 75 bytes, with CRLF line endings.
+/// Continuation lines start with a space.
+fn fold_line(output: &mut String, content: &str) {
+    let mut line_length = 0;
+    for c in content.chars() {
+        if line_length + c.len_utf8() > MAX_LINE_LENGTH {
+            output.push_str("\r\n ");
+            line_length = 1;
+        }
+        output.push(c);
+        line_length += c.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use chrono::TimeZone;
+
+    #[test]
+    fn text_is_escaped() {
+        assert!(escape("a,b;c\\d\r\ne") == "a\\,b\\;c\\\\d\\ne");
+    }
+
+    #[test]
+    fn long_lines_are_folded() {
+        let mut output = String::new();
+        let content = format!("SUMMARY:{}", "ž".repeat(50));
+        fold_line(&mut output, &content);
+        let lines: Vec<&str> = output.split_terminator("\r\n").collect();
+        assert!(lines.len() == 2);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+        assert!(lines[1].starts_with(' '));
+        assert!(lines.concat().replacen(' ', "", 1) == content);
+    }
+
+    #[test]
+    fn event_output() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 8, 0, 0).unwrap();
+        let events = [CalendarEvent {
+            uid: "expiry-1@filedl".to_owned(),
+            time: Utc.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap(),
+            summary: "photos expires".to_owned(),
+            description: "Share photos, stays available until then".to_owned(),
+            url: Some("https://example.com/download/photos".to_owned()),
+        }];
+        let output = calendar("Expirations", &events, now);
+        assert!(output.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(output.contains("\r\nUID:expiry-1@filedl\r\n"));
+        assert!(output.contains("\r\nDTSTAMP:20240301T080000Z\r\n"));
+        assert!(output.contains("\r\nDTSTART:20240305T140709Z\r\n"));
+        // Without DTEND the event takes no time, the same value would make it invalid
+        assert!(!output.contains("DTEND"));
+        assert!(output.contains("\r\nDESCRIPTION:Share photos\\, stays available until then\r\n"));
+        assert!(output.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+    }
+}
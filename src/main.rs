@@ -1,10 +1,36 @@
+mod access_log;
+mod admin_auth;
 mod app_data;
+mod assets;
+mod backup;
+mod bandwidth;
+mod checksums;
+mod chunks;
 mod config;
+mod disposition;
 mod error;
+mod glob;
+mod ical;
+mod middlewares;
 mod pages;
+mod password;
+mod preview;
+mod probe;
+mod proxy;
+mod rate_limit;
+mod search;
+mod share_links;
+mod site_auth;
+#[cfg(feature = "sqlite")]
+mod sqlite_storage;
+mod stats;
 mod storage;
+mod tar;
 mod templates;
 mod thumbnails;
+mod uploads;
+mod validation;
+mod watchdog;
 
 use crate::pages::configure_pages;
 
@@ -16,26 +42,92 @@ use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
     let config = Config::get()?;
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.log_level.as_str()),
+    )
+    .init();
+    log::info!("Using configuration profile {}", config.profile);
+
+    if let Some(backup_path) = &config.decrypt_backup {
+        let passphrase = config
+            .backup_passphrase
+            .as_deref()
+            .ok_or(error::FiledlError::BackupPassphraseMissing)?;
+        let input = std::io::BufReader::new(std::fs::File::open(backup_path)?);
+        return backup::decrypt(passphrase, input, std::io::stdout().lock());
+    }
+
     let host = config.bind_address.clone();
     let port = config.bind_port;
+    let http_workers = config.http_workers;
+    let blocking_threads = config.blocking_threads;
     let app_data = Arc::new(AppData::with_config(config)?);
 
+    if let Some(retention) = app_data.get_chunk_retention() {
+        actix_web::rt::spawn(purge_chunks(Arc::clone(&app_data), retention));
+    }
+
+    actix_web::rt::spawn(backup::run(Arc::clone(&app_data)));
+    actix_web::rt::spawn(search::run(Arc::clone(&app_data)));
+    actix_web::rt::spawn({
+        let app_data = Arc::clone(&app_data);
+        async move { app_data.flush_periodically().await }
+    });
+    actix_web::rt::spawn({
+        let app_data = Arc::clone(&app_data);
+        async move { app_data.reload_metadata_periodically().await }
+    });
+    actix_web::rt::spawn({
+        let app_data = Arc::clone(&app_data);
+        async move { app_data.purge_drop_box_periodically().await }
+    });
+    let shutdown_app_data = Arc::clone(&app_data);
+
     log::info!("Will bind to {}:{}", host, port);
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         let app_data = Arc::clone(&app_data);
         App::new()
             .app_data(Data::new(app_data))
+            .wrap_fn(admin_auth::require_admin)
+            .wrap(middleware::from_fn(middlewares::require_login))
+            .wrap_fn(middlewares::catch_panic)
+            .wrap_fn(|req, srv| middlewares::html_errors(req, srv))
+            .wrap_fn(|req, srv| middlewares::count_bandwidth(req, srv))
+            .wrap_fn(|req, srv| middlewares::access_log(req, srv))
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::DefaultHeaders::new().add(header::ContentType::html()))
             .configure(configure_pages)
-    })
-    .bind((host, port))?
-    .run()
-    .await?;
+    });
+    if let Some(http_workers) = http_workers {
+        server = server.workers(http_workers);
+    }
+    if let Some(blocking_threads) = blocking_threads {
+        server = server.worker_max_blocking_threads(blocking_threads);
+    }
+
+    // Actix stops gracefully on SIGINT and SIGTERM, in-flight requests are finished first
+    server.bind((host, port))?.run().await?;
+
+    log::info!("Shutting down, saving state");
+    shutdown_app_data.flush().await?;
 
     Ok(())
 }
+
+/// Periodically remove chunks of deduplicated uploads that were not used for a while.
+async fn purge_chunks(app_data: Arc<AppData>, retention: std::time::Duration) {
+    let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+        let Ok(store) = app_data.get_chunk_store() else {
+            return;
+        };
+        match store.purge(retention).await {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} unused upload chunks", removed),
+            Err(e) => log::warn!("Removing unused upload chunks failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use crate::{
+    access_log::{redact_path, AccessLogEntry},
+    admin_auth::check_admin_auth,
+    app_data::{split_object_path, AppData},
+    bandwidth::CountingBody,
+    config::AccessLogFormat,
+    error::FiledlError,
+    proxy,
+    site_auth::SiteAuth,
+    templates,
+};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, Header as _},
+        Method, StatusCode,
+    },
+    middleware::Next,
+    web::Data,
+    Error, HttpResponse, ResponseError,
+};
+use chrono::Utc;
+use futures_util::FutureExt;
+use horrorshow::Template as _;
+use percent_encoding::percent_decode_str;
+use rand::{thread_rng, RngCore};
+use serde::Serialize;
+use std::{
+    any::Any,
+    fmt::{Display, Formatter},
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+    time::Instant,
+};
+
+/// Output sent instead of the response of a panicked handler.
+/// It is returned as an error, because the middleware can't keep a copy of the request
+/// for building its own response (routing needs exclusive access to the request).
+#[derive(Debug, Serialize)]
+struct PanicOutput {
+    error: &'static str,
+    request_id: String,
+}
+
+impl Display for PanicOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (request {})", self.error, self.request_id)
+    }
+}
+
+impl ResponseError for PanicOutput {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError()
+            .insert_header((
+                header::HeaderName::from_static("x-request-id"),
+                self.request_id.as_str(),
+            ))
+            .json(self)
+    }
+}
+
+/// Middleware function converting panics during request handling into a 500 response,
+/// instead of dropping the connection.
+/// The panic is logged together with a random request ID, which is also sent to the client.
+pub fn catch_panic<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let method = req.method().clone();
+    let path = req.path().to_owned();
+    let response_future = catch_unwind(AssertUnwindSafe(|| srv.call(req)));
+
+    async move {
+        let result = match response_future {
+            Ok(response_future) => AssertUnwindSafe(response_future).catch_unwind().await,
+            Err(payload) => Err(payload),
+        };
+
+        match result {
+            Ok(response) => response.map(ServiceResponse::map_into_boxed_body),
+            Err(payload) => {
+                let request_id = format!("{:016X}", thread_rng().next_u64());
+                log::error!(
+                    "Request {} ({} {}) panicked: {}",
+                    request_id,
+                    method,
+                    path,
+                    panic_message(payload.as_ref())
+                );
+                Err(PanicOutput {
+                    error: "Internal server error",
+                    request_id,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// Middleware function recording the size of response bodies in the bandwidth accounting.
+pub fn count_bandwidth<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let bandwidth = req
+        .app_data::<Data<Arc<AppData>>>()
+        .map(|app| Arc::clone(app.get_bandwidth()));
+    let response_future = srv.call(req);
+
+    async move {
+        let response = response_future.await?;
+        Ok(match bandwidth {
+            Some(bandwidth) => response.map_body(|_, body| {
+                BoxBody::new(CountingBody::new(body.boxed(), move |bytes| {
+                    bandwidth.record(Utc::now(), bytes)
+                }))
+            }),
+            None => response.map_into_boxed_body(),
+        })
+    }
+}
+
+/// Middleware function replacing JSON error responses with HTML pages for browsers,
+/// based on the `Accept` header of the request.
+/// Only responses carrying a `FiledlError` are replaced, other responses already are
+/// in the format their handler chose.
+pub fn html_errors<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let wants_html = prefers_html(&req);
+    let response_future = srv.call(req);
+
+    async move {
+        let response = response_future.await?;
+        let page = wants_html
+            .then(|| {
+                let error = response.response().error()?.as_error::<FiledlError>()?;
+                let app = response.request().app_data::<Data<Arc<AppData>>>()?;
+                let title = response.status().canonical_reason().unwrap_or("Error");
+                match templates::Message::new_wrapped(app, title, error.user_message())
+                    .into_string()
+                {
+                    Ok(page) => Some(page),
+                    Err(e) => {
+                        log::error!("Rendering the error page failed: {}", e);
+                        None
+                    }
+                }
+            })
+            .flatten();
+        Ok(match page {
+            Some(page) => response.map_body(|head, _| {
+                head.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                BoxBody::new(page)
+            }),
+            None => response.map_into_boxed_body(),
+        })
+    }
+}
+
+/// Check whether HTML is the most preferred type in the `Accept` header, like it is
+/// for browsers navigating to a page.
+fn prefers_html(req: &ServiceRequest) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        accept
+            .ranked()
+            .first()
+            .is_some_and(|mime| mime.type_() == mime::TEXT && mime.subtype() == mime::HTML)
+    })
+}
+
+/// Middleware function writing an access log entry when the response body is finished.
+pub fn access_log<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let start = Instant::now();
+    let entry = req.app_data::<Data<Arc<AppData>>>().and_then(|app| {
+        let format = app.get_access_log_format();
+        if format == AccessLogFormat::Off {
+            return None;
+        }
+        let header_value = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let entry = AccessLogEntry {
+            time: Utc::now(),
+            client: proxy::client_address(app.get_trusted_proxies(), req.request()),
+            method: req.method().to_string(),
+            path: redact_path(&req.uri().to_string()),
+            protocol: format!("{:?}", req.version()),
+            status: 0,
+            bytes: 0,
+            duration: Default::default(),
+            referer: header_value(header::REFERER).map(|referer| redact_path(&referer)),
+            user_agent: header_value(header::USER_AGENT),
+        };
+        Some((entry, format))
+    });
+    let response_future = srv.call(req);
+
+    async move {
+        let response = response_future.await?;
+        Ok(match entry {
+            Some((mut entry, format)) => {
+                entry.status = response.status().as_u16();
+                response.map_body(move |_, body| {
+                    BoxBody::new(
+                        CountingBody::new(body.boxed(), move |bytes| {
+                            entry.bytes = bytes;
+                            entry.duration = start.elapsed();
+                            entry.write(format);
+                        })
+                        .report_empty(),
+                    )
+                })
+            }
+            None => response.map_into_boxed_body(),
+        })
+    }
+}
+
+/// Middleware function requiring the site authentication, if it is configured.
+/// Bundled assets, the login pages and public objects are accessible without it.
+/// A valid admin token also counts as logged in, because with basic site authentication
+/// both are sent in the Authorization header.
+pub async fn require_login(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(app) = req.app_data::<Data<Arc<AppData>>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Some(site_auth) = app.get_site_auth() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let allowed = is_exempt_from_login(&req)
+        || site_auth
+            .authenticated_user(req.request(), app.get_cookie_key())
+            .is_some()
+        || check_admin_auth(app.get_admin_token(), req.headers()).is_ok()
+        || is_public_download(&app, req.path()).await;
+    if allowed {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let response = match site_auth {
+        SiteAuth::Oidc(_) if matches!(*req.method(), Method::GET | Method::HEAD) => {
+            let trusted_proxies = app.get_trusted_proxies();
+            let query = serde_urlencoded::to_string([(
+                "return_to",
+                proxy::original_uri(trusted_proxies, req.request()),
+            )])
+            .expect("Serializing strings can't fail");
+            HttpResponse::SeeOther()
+                .insert_header((
+                    header::LOCATION,
+                    format!(
+                        "{}/auth/login?{}",
+                        proxy::path_prefix(trusted_proxies, req.request()),
+                        query
+                    ),
+                ))
+                .finish()
+        }
+        _ => HttpResponse::from_error(FiledlError::LoginRequired),
+    };
+    // Unlike `into_response`, keeps the error for `html_errors`
+    Ok(ServiceResponse::new(req.into_parts().0, response))
+}
+
+/// Login pages and bundled assets (`mode=internal`) are always accessible.
+/// Assets are only ever read, other methods on the download handler need the login.
+fn is_exempt_from_login(req: &ServiceRequest) -> bool {
+    req.path().starts_with("/auth/")
+        || (matches!(*req.method(), Method::GET | Method::HEAD)
+            && req.path().starts_with("/download/")
+            && req
+                .query_string()
+                .split('&')
+                .any(|parameter| parameter == "mode=internal"))
+}
+
+async fn is_public_download(app: &AppData, path: &str) -> bool {
+    let Some(object_path) = path.strip_prefix("/download/") else {
+        return false;
+    };
+    let Ok(object_path) = percent_decode_str(object_path).decode_utf8() else {
+        return false;
+    };
+    let (object_id, _) = split_object_path(&object_path);
+    app.is_public_object(object_id).await
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App};
+    use assert2::assert;
+
+    #[actix_web::test]
+    async fn panics_become_500_with_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(catch_panic)
+                .route("/ok", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/panic",
+                    web::get().to(|| async {
+                        panic!("handler failed");
+                        #[allow(unreachable_code)]
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/ok").to_request()).await;
+        assert!(response.status() == StatusCode::OK);
+
+        let request = test::TestRequest::get().uri("/panic").to_request();
+        let response = match app.call(request).await {
+            Ok(response) => response.into_parts().1,
+            Err(e) => e.error_response(),
+        };
+        assert!(response.status() == StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.headers().contains_key("x-request-id"));
+    }
+
+    #[actix_web::test]
+    async fn login_exemptions() {
+        let exempt = |request: test::TestRequest| is_exempt_from_login(&request.to_srv_request());
+
+        assert!(exempt(test::TestRequest::get().uri("/auth/login")));
+        assert!(exempt(test::TestRequest::get().uri("/auth/logout")));
+        assert!(exempt(
+            test::TestRequest::get().uri("/download/style.css?mode=internal")
+        ));
+        assert!(exempt(
+            test::TestRequest::default()
+                .method(Method::HEAD)
+                .uri("/download/style.css?mode=internal")
+        ));
+
+        assert!(!exempt(test::TestRequest::get().uri("/download/obj")));
+        assert!(!exempt(
+            test::TestRequest::post().uri("/download/obj?mode=internal")
+        ));
+        assert!(!exempt(
+            test::TestRequest::put().uri("/download/obj?mode=internal")
+        ));
+    }
+
+    #[actix_web::test]
+    async fn basic_site_auth_with_admin_token() {
+        use crate::{admin_auth::require_admin, app_data::TestApp};
+        use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+        let test_app = TestApp::new(
+            "admin_token = \"admin-secret\"\n\
+             [site_auth]\n\
+             type = \"basic\"\n\
+             users = { alice = \"alice-secret\" }",
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(test_app.data())
+                .wrap_fn(require_admin)
+                .wrap(actix_web::middleware::from_fn(require_login))
+                .route("/admin/objects", web::get().to(HttpResponse::Ok))
+                .route("/download/obj", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |uri: &'static str, credentials: Option<&str>| {
+            let mut request = test::TestRequest::get().uri(uri);
+            if let Some(credentials) = credentials {
+                request = request.insert_header((
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                ));
+            }
+            let app = &app;
+            async move { test::call_service(app, request.to_request()).await.status() }
+        };
+
+        assert!(status("/download/obj", None).await == StatusCode::UNAUTHORIZED);
+        assert!(status("/download/obj", Some("alice:wrong")).await == StatusCode::UNAUTHORIZED);
+        assert!(status("/download/obj", Some("alice:alice-secret")).await == StatusCode::OK);
+        assert!(
+            status("/admin/objects", Some("alice:alice-secret")).await == StatusCode::UNAUTHORIZED
+        );
+        assert!(status("/admin/objects", Some("admin:admin-secret")).await == StatusCode::OK);
+        assert!(status("/download/obj", Some(":admin-secret")).await == StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn browsers_get_html_errors() {
+        use crate::{app_data::TestApp, pages::configure_pages};
+
+        let test_app = TestApp::new("admin_token = \"admin-secret\"");
+        let app = test::init_service(
+            App::new()
+                .app_data(test_app.data())
+                .wrap_fn(crate::admin_auth::require_admin)
+                .wrap_fn(html_errors)
+                .configure(configure_pages),
+        )
+        .await;
+        let get = |uri: &'static str, accept: &'static str| {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ACCEPT, accept));
+            let app = &app;
+            async move { test::call_service(app, request.to_request()).await }
+        };
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+        let response = get("/download/missing", browser).await;
+        assert!(response.status() == StatusCode::NOT_FOUND);
+        assert!(
+            response.headers().get(header::CONTENT_TYPE).unwrap() == "text/html; charset=utf-8"
+        );
+        let body = test::read_body(response).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("<html"));
+        assert!(body.contains(FiledlError::ObjectNotFound.user_message()));
+
+        // Errors from the middlewares are covered too, with their headers
+        let response = get("/admin/objects", browser).await;
+        assert!(response.status() == StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+        assert!(
+            response.headers().get(header::CONTENT_TYPE).unwrap() == "text/html; charset=utf-8"
+        );
+
+        for accept in [
+            "*/*",
+            "application/json",
+            "application/json, text/html;q=0.5",
+        ] {
+            let response = get("/download/missing", accept).await;
+            assert!(response.status() == StatusCode::NOT_FOUND);
+            assert!(response.headers().get(header::CONTENT_TYPE).unwrap() == "application/json");
+        }
+    }
+}
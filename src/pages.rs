@@ -1,34 +1,121 @@
 use crate::{
-    app_data::{AppData, DirListingItem, ItemType, ResolvedObject},
-    error::{FiledlError, Result},
-    templates,
+    app_data::{
+        embedded_key, split_object_path, split_object_segment, AppData, BulkOperation,
+        DirListingItem, ImportConflict, Inbox, ItemType, Maintenance, Object, ObjectFilter,
+        ResolvedObject, EMBEDDED_KEY_SEPARATOR,
+    },
+    assets::{asset_hash, get_asset},
+    bandwidth::CountingBody,
+    checksums::HashingBody,
+    config::HotlinkResponse,
+    disposition::content_disposition,
+    error::{ErrorCategory, FiledlError, Result},
+    ical,
+    password::constant_time_eq,
+    password::PasswordHash,
+    preview, proxy, rate_limit,
+    site_auth::{self, LoginState, SiteAuth},
+    tar,
+    templates::{self, util::url_encode, ListingOptions, Pagination, SortKey, SortOrder, ViewMode},
+    thumbnails::{
+        create_watermarked_image, is_thumbnailable, ClientHints, ThumbnailFit, ThumbnailParams,
+    },
+    uploads::MAX_SESSION_ID_LENGTH,
+    validation::{extractor_error_handler, FieldError, ValidQuery, Validate},
+    watchdog::WatchdogBody,
 };
 use actix_files::NamedFile;
+use actix_multipart::{Multipart, MultipartError};
 use actix_web::{
+    body::BoxBody,
+    cookie::{Cookie, CookieJar, SameSite},
+    delete,
+    error::PayloadError,
     get,
-    http::{header, StatusCode},
-    routes, web,
+    guard::GuardContext,
+    http::{header, uri::Authority, Method, StatusCode, Uri},
+    post, put, routes, web,
+    web::Bytes,
     web::Redirect,
-    Either, HttpResponse, Responder, ResponseError,
+    HttpMessage as _, HttpRequest, HttpResponse, HttpResponseBuilder, Responder, ResponseError,
 };
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures_util::{stream, Stream, StreamExt as _, TryStreamExt};
 use horrorshow::Template as _;
-use serde::Deserialize;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::ready,
+    hash::{Hash as _, Hasher as _},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{fs, task::spawn_blocking};
 
 pub const PROJECT_NAME: &str = env!("CARGO_PKG_NAME");
 pub const PROJECT_REPO: &str = env!("CARGO_PKG_REPOSITORY");
 pub const PROJECT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, Default, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
+#[serde(try_from = "String")]
 enum DownloadMode {
     #[default]
     Default,
     Internal,
     Download,
-    Thumb64,
-    Thumb128,
-    Thumb256,
+    /// Thumbnail, with size either taken from the `size` parameter (`thumb`),
+    /// or from the mode itself (`thumb256`)
+    Thumb(Option<u32>),
+    /// Checksums of fixed size chunks of a file, as JSON
+    Chunks,
+    /// Syntax highlighted text files, other files are downloaded
+    Preview,
+    /// Full-text search in text files of a directory
+    Search,
+}
+
+impl TryFrom<String> for DownloadMode {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.as_str() {
+            "default" => Ok(DownloadMode::Default),
+            "internal" => Ok(DownloadMode::Internal),
+            "download" => Ok(DownloadMode::Download),
+            "thumb" => Ok(DownloadMode::Thumb(None)),
+            "chunks" => Ok(DownloadMode::Chunks),
+            "preview" => Ok(DownloadMode::Preview),
+            "search" => Ok(DownloadMode::Search),
+            _ => value
+                .strip_prefix("thumb")
+                .and_then(|size| size.parse().ok())
+                .map(|size| DownloadMode::Thumb(Some(size)))
+                .ok_or_else(|| format!("unknown download mode {:?}", value)),
+        }
+    }
+}
+
+/// Archive format of directory downloads
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,14 +123,152 @@ struct DownloadQuery {
     key: Option<String>,
     #[serde(default)]
     mode: DownloadMode,
+    /// Thumbnail size for `mode=thumb`
+    #[serde(default)]
+    size: Option<u32>,
+    /// Fitting of thumbnails, overrides the configured one
+    #[serde(default)]
+    fit: Option<ThumbnailFit>,
     #[serde(default)]
     cache_hash: Option<String>,
+    #[serde(default)]
+    format: ArchiveFormat,
+    #[serde(default)]
+    view: Option<ViewMode>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    /// Glob pattern filtering the listing, empty matches everything
+    #[serde(default)]
+    filter: Option<String>,
+    /// Only objects with this tag are listed in the root listing
+    #[serde(default)]
+    tag: Option<String>,
+    /// Words to search for with `mode=search`
+    #[serde(default)]
+    q: Option<String>,
+    /// One based page of the listing
+    #[serde(default)]
+    page: Option<usize>,
+    /// Listing items per page, overrides the configured one
+    #[serde(default)]
+    per_page: Option<usize>,
+    /// Timezone for displaying times, overrides the configured one
+    #[serde(default)]
+    tz: Option<String>,
+}
+
+impl Validate for DownloadQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(tz) = &self.tz {
+            if Tz::from_str(tz).is_err() {
+                errors.push(FieldError::new("tz", format!("Unknown timezone {:?}", tz)));
+            }
+        }
+        if self.key.as_deref().is_some_and(str::is_empty) {
+            errors.push(FieldError::new("key", "Key must not be empty"));
+        }
+        match (&self.mode, self.size) {
+            (DownloadMode::Thumb(None), None) => {
+                errors.push(FieldError::new("size", "Thumbnail size is required"))
+            }
+            (DownloadMode::Thumb(Some(_)), Some(_)) => errors.push(FieldError::new(
+                "size",
+                "Thumbnail size is already given by the mode",
+            )),
+            (DownloadMode::Thumb(_), _) => {}
+            (_, Some(_)) => errors.push(FieldError::new(
+                "size",
+                "Size can only be used with thumbnails",
+            )),
+            (_, None) => {}
+        }
+        if self
+            .filter
+            .as_ref()
+            .is_some_and(|filter| filter.len() > MAX_FILTER_LENGTH)
+        {
+            errors.push(FieldError::new("filter", "Filter is too long"));
+        }
+        if self
+            .tag
+            .as_ref()
+            .is_some_and(|tag| tag.len() > MAX_FILTER_LENGTH)
+        {
+            errors.push(FieldError::new("tag", "Tag is too long"));
+        }
+        match (&self.mode, &self.q) {
+            (DownloadMode::Search, None) => {
+                errors.push(FieldError::new("q", "Search query is required"))
+            }
+            (DownloadMode::Search, Some(q)) if q.len() > MAX_FILTER_LENGTH => {
+                errors.push(FieldError::new("q", "Search query is too long"))
+            }
+            (DownloadMode::Search, Some(_)) | (_, None) => {}
+            (_, Some(_)) => errors.push(FieldError::new("q", "Query can only be used with search")),
+        }
+        if self.page == Some(0) {
+            errors.push(FieldError::new("page", "Pages are numbered from 1"));
+        }
+        if self
+            .per_page
+            .is_some_and(|per_page| per_page == 0 || per_page > MAX_PER_PAGE)
+        {
+            errors.push(FieldError::new(
+                "per_page",
+                format!("Items per page must be between 1 and {}", MAX_PER_PAGE),
+            ));
+        }
+        if let DownloadMode::Thumb(Some(0)) = self.mode {
+            errors.push(FieldError::new("mode", "Thumbnail size must not be zero"));
+        } else if self.size == Some(0) {
+            errors.push(FieldError::new("size", "Thumbnail size must not be zero"));
+        }
+        errors
+    }
 }
 
-const CACHE_CONTROL_IMMUTABLE: (&'static str, &'static str) = (
-    "Cache-Control",
-    "max-age=31536000, immutable", // 1 year
-);
+/// Largest accepted page size of listings.
+const MAX_PER_PAGE: usize = 10_000;
+
+/// Most files shown in search results.
+const MAX_SEARCH_HITS: usize = 100;
+
+/// Longest accepted listing filter pattern, in bytes.
+const MAX_FILTER_LENGTH: usize = 256;
+
+/// Response header carrying hex encoded SHA-256 of the file content.
+const CONTENT_SHA256_HEADER: &str = "x-content-sha256";
+
+/// Name of the signed cookie storing acceptance of object terms.
+const TERMS_COOKIE: &str = "terms_accepted";
+
+/// Name of the signed cookie storing that the visitor entered password of an object.
+const PASSWORD_COOKIE: &str = "object_password";
+
+/// Name of the cookie remembering the selected listing view mode.
+const VIEW_MODE_COOKIE: &str = "view_mode";
+
+/// Client hints that affect thumbnails, in both the legacy and the current naming.
+const CLIENT_HINTS: &str = "DPR, Width, Sec-CH-DPR, Sec-CH-Width, Save-Data";
+
+/// Content security policy for user provided HTML files viewed inline.
+const SANDBOX_POLICY: &str = "sandbox";
+
+/// Content security policy for HTML files of objects in static site mode,
+/// scripts can run, but still in an opaque origin.
+const SANDBOX_POLICY_STATIC_SITE: &str = "sandbox allow-scripts allow-forms allow-popups";
+
+/// File served instead of directory listings of objects in static site mode.
+const STATIC_SITE_INDEX: &str = "index.html";
+
+/// Asset served instead of files to requests from foreign referrers.
+const HOTLINK_PLACEHOLDER_ASSET: &str = "hotlink.svg";
+
+/// Name of the cookie remembering the selected display timezone.
+const TIMEZONE_COOKIE: &str = "tz";
 
 /// generate a cache control header based on the cache_hash received
 fn cache_control(cache_hash: Option<&str>) -> (&'static str, &'static str) {
@@ -58,37 +283,64 @@ fn cache_control(cache_hash: Option<&str>) -> (&'static str, &'static str) {
     )
 }
 
+/// Error description sent to the client
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: &'static str,
+    retryable: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
 impl ResponseError for FiledlError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match self {
-            FiledlError::ObjectNotFound => StatusCode::NOT_FOUND,
-            FiledlError::Unlisted => StatusCode::NOT_FOUND,
-            FiledlError::BadDownloadMode => StatusCode::NOT_FOUND,
-            FiledlError::IOError { source } => match source.kind() {
-                std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
-                _ => {
-                    log::error!("Converting to user error: {}", source);
-                    StatusCode::INTERNAL_SERVER_ERROR
-                }
-            },
-            source => {
-                log::error!("Converting to user error: {}", source);
-                StatusCode::INTERNAL_SERVER_ERROR
-            }
+    fn status_code(&self) -> StatusCode {
+        self.category().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.category() == ErrorCategory::Internal {
+            log::error!("Converting to user error: {}", self);
         }
+
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header(cache_control(None));
+        if self.category() == ErrorCategory::Unauthorized {
+            response.insert_header((
+                header::WWW_AUTHENTICATE,
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", PROJECT_NAME),
+            ));
+        }
+        response.json(ErrorOutput {
+            error: self.user_message(),
+            retryable: self.is_retryable(),
+            fields: self.field_errors().to_vec(),
+        })
     }
 }
 
 #[routes]
 #[get("/index.html")]
 #[get("/")]
-async fn index_redirect() -> impl Responder {
-    Redirect::to("/download").permanent()
+async fn index_redirect(app: web::Data<Arc<AppData>>) -> impl Responder {
+    Redirect::to(app.get_download_base_url().to_owned()).permanent()
 }
 
 #[get("/admin")]
-async fn admin(app: web::Data<Arc<AppData>>) -> impl Responder {
-    "TODO"
+async fn admin(app: web::Data<Arc<AppData>>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(templates::Admin::new_wrapped(&app, app.get_stats().all()).into_string()?))
+}
+
+#[get("/admin/stats")]
+async fn admin_stats(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok().json(app.get_stats().all())
+}
+
+#[get("/admin/objects")]
+async fn admin_objects(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok().json(app.list_objects_admin().await)
 }
 
 #[get("/admin/thumbnail_cache_stats")]
@@ -96,141 +348,2281 @@ async fn thumbnail_cache_stats(app: web::Data<Arc<AppData>>) -> HttpResponse {
     HttpResponse::Ok().json(app.get_thumbnail_cache_stats().await)
 }
 
+#[get("/admin/memory_stats")]
+async fn memory_stats(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok().json(app.get_memory_usage().await)
+}
+
+#[derive(Debug, Deserialize)]
+struct BandwidthQuery {
+    /// Number of days to report
+    #[serde(default = "default_bandwidth_report_days")]
+    days: u32,
+}
+
+fn default_bandwidth_report_days() -> u32 {
+    30
+}
+
+impl Validate for BandwidthQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        if (1..=366).contains(&self.days) {
+            Vec::new()
+        } else {
+            vec![FieldError::new("days", "Days must be between 1 and 366")]
+        }
+    }
+}
+
+/// Bytes served per day, also used as the data for the usage chart.
+#[get("/admin/bandwidth")]
+async fn bandwidth_report(
+    app: web::Data<Arc<AppData>>,
+    query: ValidQuery<BandwidthQuery>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(app.get_bandwidth_report(query.days))
+}
+
 #[get("/download")]
-async fn download_root(app: web::Data<Arc<AppData>>) -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok().content_type(mime::TEXT_HTML_UTF_8).body(
-        templates::DirListing::new_wrapped(&app, "", false, app.list_objects().await?)
-            .into_string()?,
-    ))
+async fn download_root(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    query: ValidQuery<DownloadQuery>,
+) -> Result<HttpResponse> {
+    if let Some(retry_after) = app.get_maintenance().retry_after() {
+        return maintenance_page(&app, retry_after);
+    }
+
+    dir_listing(
+        &app,
+        &req,
+        &query,
+        "",
+        false,
+        None,
+        app.list_objects().await?,
+        None,
+    )
+    .await
+}
+
+/// Whether the request fetches the whole file for keeping, as opposed to checking headers,
+/// resuming an earlier download or previewing it in the page.
+fn is_full_download(req: &HttpRequest, mode: &DownloadMode) -> bool {
+    let range = req.headers().get(header::RANGE);
+    req.method() == Method::GET
+        && *mode != DownloadMode::Preview
+        && range.map_or(true, |range| range == "bytes=0-")
 }
 
+#[routes]
 #[get("/download/{object:.*}")]
+#[head("/download/{object:.*}")]
 async fn download_object(
     app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
     path: web::Path<String>,
-    query: web::Query<DownloadQuery>,
-) -> Result<Either<NamedFile, HttpResponse>> {
+    query: ValidQuery<DownloadQuery>,
+) -> Result<HttpResponse> {
     let object_path = path.into_inner();
     if query.mode == DownloadMode::Internal {
-        let (content, ct) = assets(&object_path).ok_or(FiledlError::ObjectNotFound)?;
-        Ok(Either::Right(
-            HttpResponse::Ok()
-                .insert_header(header::ContentType(ct))
-                .insert_header(CACHE_CONTROL_IMMUTABLE)
-                .body(content),
-        ))
+        let (content, ct) = get_asset(&object_path, app.get_asset_source())
+            .await?
+            .ok_or(FiledlError::ObjectNotFound)?;
+        Ok(HttpResponse::Ok()
+            .insert_header(header::ContentType(ct))
+            .insert_header(cache_control(
+                query
+                    .cache_hash
+                    .as_deref()
+                    .filter(|_| app.immutable_caching_enabled() && app.asset_fingerprints_enabled())
+                    .filter(|cache_hash| asset_hash(&object_path) == Some(*cache_hash)),
+            ))
+            .body(content))
     } else {
-        let resolved_object = app
-            .resolve_object(object_path.as_str(), query.key.as_deref())
-            .await?;
+        if let Some(retry_after) = app.get_maintenance().retry_after() {
+            return maintenance_page(&app, retry_after);
+        }
+        if let Some(retry_after) = app.transfer_quota_exceeded() {
+            return over_quota_page(&app, retry_after);
+        }
+
+        let resolved_object = match app
+            .resolve_object(
+                object_path.as_str(),
+                query.key.as_deref(),
+                access_token(&req).as_deref(),
+            )
+            .await
+        {
+            Err(FiledlError::ShareEnded { message }) => return tombstone(&app, message.as_deref()),
+            resolved_object => resolved_object?,
+        };
+
+        let is_file_download = !resolved_object.item_type().is_directory()
+            && matches!(
+                query.mode,
+                DownloadMode::Default | DownloadMode::Download | DownloadMode::Preview
+            );
+
+        if let Some(password) = resolved_object.password() {
+            if !has_object_cookie(
+                &app,
+                &req,
+                PASSWORD_COOKIE,
+                &password_cookie_value(&object_path, password),
+            ) {
+                return password_page(&app, &object_path, resolved_object.is_unlisted(), false);
+            }
+        }
+
+        if let Some(inbox) = resolved_object.inbox() {
+            return inbox_page(&app, &object_path, resolved_object.is_unlisted(), inbox);
+        }
+
+        if !resolved_object.item_type().is_directory() && is_hotlinked(&app, &req) {
+            return hotlink_response(&app, &object_path, query.key.as_deref()).await;
+        }
+
+        if let Some(terms) = resolved_object.terms() {
+            if is_file_download
+                && !has_object_cookie(&app, &req, TERMS_COOKIE, &terms_cookie_value(&object_path))
+            {
+                return terms_page(&app, &object_path, resolved_object.is_unlisted(), terms);
+            }
+        }
+
+        let (object_id, _) = split_object_path(&object_path);
+        app.get_stats().record_access(object_id, is_file_download);
+        if let Some(share_link) = resolved_object
+            .share_link()
+            .filter(|_| is_file_download && is_full_download(&req, &query.mode))
+        {
+            app.get_share_links()
+                .record_download(share_link, Utc::now())?;
+        }
+
+        let cache_hash = query
+            .cache_hash
+            .as_deref()
+            .filter(|_| app.immutable_caching_enabled());
+
+        let has_static_site_index = resolved_object.is_static_site()
+            && resolved_object.item_type().is_directory()
+            && fs::try_exists(resolved_object.path().join(STATIC_SITE_INDEX)).await?;
 
-        match resolved_object.item_type() {
+        let response = match resolved_object.item_type() {
             ItemType::Directory => match query.mode {
+                DownloadMode::Default if has_static_site_index => Ok(static_site_redirect(
+                    &app,
+                    &object_path,
+                    query.key.as_deref(),
+                )),
+                DownloadMode::Default if app.stream_listings_enabled() => {
+                    streamed_dir_listing(
+                        &app,
+                        &req,
+                        &query,
+                        &object_path,
+                        resolved_object.is_unlisted(),
+                        resolved_object.expires().filter(|_| app.show_expiry()),
+                        resolved_object.list_stream(),
+                    )
+                    .await
+                }
                 DownloadMode::Default => {
-                    let items = resolved_object.list().await?;
+                    let items = resolved_object.list_unprobed().await?;
                     dir_listing(
                         &app,
+                        &req,
+                        &query,
                         &object_path,
-                        query.key.as_deref(),
                         resolved_object.is_unlisted(),
+                        resolved_object.expires().filter(|_| app.show_expiry()),
                         items,
+                        Some(resolved_object.path()),
                     )
                     .await
-                    .map(Either::Right)
                 }
-                DownloadMode::Download => Err(FiledlError::UnimplementedZipDownload),
+                // Archives would contain the images without the watermark
+                DownloadMode::Download if resolved_object.watermark().is_some() => {
+                    Err(FiledlError::BadDownloadMode)
+                }
+                DownloadMode::Download => match query.format {
+                    ArchiveFormat::Zip => Err(FiledlError::UnimplementedZipDownload),
+                    format => archive_download(&app, resolved_object, format).await,
+                },
+                DownloadMode::Search => search_results(&app, &query, &object_path, resolved_object),
                 DownloadMode::Internal => unreachable!("Was handled before"),
                 _ => Err(FiledlError::BadDownloadMode),
             },
             _ => match query.mode {
-                DownloadMode::Default => file_download(resolved_object, false)
-                    .await
-                    .map(Either::Left),
-                DownloadMode::Download => {
-                    file_download(resolved_object, true).await.map(Either::Left)
+                DownloadMode::Default => file_download(&app, &req, resolved_object, false).await,
+                DownloadMode::Download => file_download(&app, &req, resolved_object, true).await,
+                DownloadMode::Thumb(size) => {
+                    let size = size.or(query.size).expect("Checked in validation");
+                    let fit = query.fit.unwrap_or(app.get_thumbnail_fit());
+                    thumb_download(&app, &req, resolved_object, size, fit, cache_hash).await
                 }
-                DownloadMode::Thumb64 => {
-                    thumb_download(resolved_object, 64, query.cache_hash.as_deref())
-                        .await
-                        .map(Either::Right)
+                DownloadMode::Chunks => chunk_map(&app, resolved_object).await,
+                DownloadMode::Preview if resolved_object.item_type().is_media() => {
+                    media_player(&app, &query, &object_path, resolved_object)
                 }
-                DownloadMode::Thumb128 => {
-                    thumb_download(resolved_object, 128, query.cache_hash.as_deref())
-                        .await
-                        .map(Either::Right)
-                }
-                DownloadMode::Thumb256 => {
-                    thumb_download(resolved_object, 256, query.cache_hash.as_deref())
-                        .await
-                        .map(Either::Right)
+                DownloadMode::Preview => {
+                    text_preview(&app, &req, &query, &object_path, resolved_object).await
                 }
+                DownloadMode::Search => Err(FiledlError::BadDownloadMode),
                 DownloadMode::Internal => unreachable!("Was handled before"),
             },
+        }?;
+
+        let app = Arc::clone(app.get_ref());
+        let object_id = object_id.to_owned();
+        Ok(response.map_body(|_, body| {
+            BoxBody::new(CountingBody::new(body, move |bytes| {
+                app.get_stats().record_bytes(&object_id, bytes)
+            }))
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectForm {
+    /// Password of a password protected object, terms acceptance form doesn't send any
+    password: Option<String>,
+}
+
+/// Record acceptance of object terms, or a correct object password, in a signed cookie
+/// and redirect back to the download.
+#[post("/download/{object:.*}")]
+async fn submit_object_form(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: ValidQuery<DownloadQuery>,
+    form: web::Form<ObjectForm>,
+) -> Result<HttpResponse> {
+    if let Some(retry_after) = app.get_maintenance().retry_after() {
+        return maintenance_page(&app, retry_after);
+    }
+
+    let object_path = path.into_inner();
+    // Make sure that the object exists and that the key matches.
+    let resolved_object = app
+        .resolve_object(
+            object_path.as_str(),
+            query.key.as_deref(),
+            access_token(&req).as_deref(),
+        )
+        .await?;
+    let is_unlisted = resolved_object.is_unlisted();
+    // Hashing takes a while, the metadata must not stay locked for it
+    let expected_password = resolved_object.password().cloned();
+    drop(resolved_object);
+
+    let cookie = match (&form.password, &expected_password) {
+        (Some(password), Some(expected)) => {
+            let client =
+                rate_limit::client_key(proxy::client_address(app.get_trusted_proxies(), &req));
+            if !app.verify_password(&client, expected, password).await? {
+                return password_page(&app, &object_path, is_unlisted, true);
+            }
+            object_cookie(
+                &app,
+                PASSWORD_COOKIE,
+                password_cookie_value(&object_path, expected),
+                &object_path,
+            )
         }
+        (Some(_), None) => {
+            return Err(FiledlError::InvalidRequest {
+                errors: vec![FieldError::new(
+                    "password",
+                    "Object is not password protected",
+                )],
+            })
+        }
+        (None, _) => object_cookie(
+            &app,
+            TERMS_COOKIE,
+            terms_cookie_value(&object_path),
+            &object_path,
+        ),
+    };
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((
+            header::LOCATION,
+            proxy::original_uri(app.get_trusted_proxies(), &req),
+        ))
+        .cookie(cookie)
+        .finish())
+}
+
+/// File uploaded by a visitor into an inbox object.
+#[put("/download/{object:.*}")]
+async fn upload_to_inbox(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: ValidQuery<DownloadQuery>,
+    body: web::Payload,
+) -> Result<HttpResponse> {
+    if let Some(retry_after) = app.get_maintenance().retry_after() {
+        return maintenance_page(&app, retry_after);
     }
+
+    let (object_segment, file_name) = split_object_segment(&path);
+    let file_name = file_name.ok_or_else(|| FiledlError::InvalidRequest {
+        errors: vec![FieldError::new("file", "Missing file name")],
+    })?;
+    let object_id = inbox_upload_target(&app, &req, object_segment, query.key.as_deref()).await?;
+
+    app.upload_to_inbox(&object_id, file_name, body).await?;
+    Ok(HttpResponse::Created().finish())
 }
 
-async fn file_download<'a>(
-    resolved_object: ResolvedObject<'a>,
-    force_download: bool,
-) -> Result<NamedFile> {
-    let mut nf = NamedFile::open_async(resolved_object.path()).await?;
+/// Files uploaded into an inbox object through the upload form as multipart form data,
+/// when JavaScript is not available. Redirects back to the inbox page.
+#[post("/download/{object:.*}", guard = "is_multipart_form")]
+async fn upload_to_inbox_form(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: ValidQuery<DownloadQuery>,
+    body: web::Payload,
+) -> Result<HttpResponse> {
+    if let Some(retry_after) = app.get_maintenance().retry_after() {
+        return maintenance_page(&app, retry_after);
+    }
 
-    if force_download {
-        let mut cd = nf.content_disposition().clone();
-        cd.disposition = header::DispositionType::Attachment;
-        nf = nf.set_content_disposition(cd);
+    let (object_segment, _) = split_object_segment(&path);
+    let object_id = inbox_upload_target(&app, &req, object_segment, query.key.as_deref()).await?;
+
+    let mut multipart = Multipart::new(req.headers(), body);
+    let mut file_count = 0;
+    while let Some(field) = multipart
+        .try_next()
+        .await
+        .map_err(|e| FiledlError::InvalidRequest {
+            errors: vec![FieldError::new("body", e.to_string())],
+        })?
+    {
+        let Some(file_name) = field
+            .content_disposition()
+            .get_filename()
+            .and_then(multipart_file_name)
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+        app.upload_to_inbox(&object_id, &file_name, field).await?;
+        file_count += 1;
+    }
+    if file_count == 0 {
+        return Err(FiledlError::InvalidRequest {
+            errors: vec![FieldError::new("files", "No file selected")],
+        });
     }
 
-    Ok(nf)
+    Ok(HttpResponse::SeeOther()
+        .insert_header((
+            header::LOCATION,
+            proxy::original_uri(app.get_trusted_proxies(), &req),
+        ))
+        .finish())
 }
 
-async fn thumb_download<'a>(
-    resolved_object: ResolvedObject<'a>,
-    size: u32,
-    cache_hash: Option<&str>,
+fn is_multipart_form(ctx: &GuardContext) -> bool {
+    ctx.header::<header::ContentType>()
+        .is_some_and(|content_type| {
+            content_type.type_() == mime::MULTIPART && content_type.subtype() == mime::FORM_DATA
+        })
+}
+
+/// Object ID of the inbox that an upload goes to, after the same access checks
+/// as for viewing the inbox.
+async fn inbox_upload_target(
+    app: &AppData,
+    req: &HttpRequest,
+    object_segment: &str,
+    key: Option<&str>,
+) -> Result<String> {
+    let resolved_object = app
+        .resolve_object(object_segment, key, access_token(req).as_deref())
+        .await?;
+    if let Some(password) = resolved_object.password() {
+        if !has_object_cookie(
+            app,
+            req,
+            PASSWORD_COOKIE,
+            &password_cookie_value(object_segment, password),
+        ) {
+            return Err(FiledlError::AccessDenied);
+        }
+    }
+    let (object_id, _) = split_object_path(object_segment);
+    Ok(object_id.to_owned())
+}
+
+/// Anonymous upload creating a new object with a single file, when the drop box is enabled.
+/// Responds with the download URL of the file as plain text.
+#[put("/drop/{file_name}")]
+async fn upload_to_drop_box(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    file_name: web::Path<String>,
+    body: web::Payload,
 ) -> Result<HttpResponse> {
-    let (thumb, hash) = resolved_object.into_thumbnail((size, size)).await?;
-    Ok(HttpResponse::Ok()
-        .insert_header(header::ContentType(mime::IMAGE_JPEG))
-        .insert_header(header::ETag(header::EntityTag::new_strong(hash)))
-        .insert_header(cache_control(cache_hash))
-        .body(thumb))
+    if let Some(retry_after) = app.get_maintenance().retry_after() {
+        return maintenance_page(&app, retry_after);
+    }
+    let client = rate_limit::client_key(proxy::client_address(app.get_trusted_proxies(), &req));
+    let url = app.upload_to_drop_box(&client, &file_name, body).await?;
+    app.flush().await?;
+    Ok(HttpResponse::Created()
+        .content_type(mime::TEXT_PLAIN_UTF_8)
+        .body(format!(
+            "{}\n",
+            proxy::absolute_url(app.get_trusted_proxies(), &req, &url)
+        )))
+}
 
-    // TODO: Support HEAD request, that only verifies the cache hash, and doesn't
-    // recompute the thumbnail unless necessary (if client has the image cached, but
-    // is unsure about the validity, and we don't have it cached any more)
-    // TODO: Proper browser caching control
+#[derive(Debug, Deserialize)]
+struct InboxChange {
+    /// New inbox settings, or null to turn the inbox back into a normal object
+    inbox: Option<Inbox>,
 }
 
-async fn dir_listing(
+#[put("/admin/object/{object_id}/inbox")]
+async fn set_object_inbox(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+    change: web::Json<InboxChange>,
+) -> Result<HttpResponse> {
+    app.set_object_inbox(&object_id, change.into_inner().inbox)
+        .await?;
+    app.flush().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Signed cookie scoped to the object.
+fn object_cookie(
+    app: &AppData,
+    name: &'static str,
+    value: String,
+    object_path: &str,
+) -> Cookie<'static> {
+    // The path must match the URL form that was used, including a possible embedded key
+    let (object_segment, _) = split_object_segment(object_path);
+    let cookie = Cookie::build(name, value)
+        .path(format!(
+            "{}/{}",
+            app.get_download_base_url(),
+            url_encode(object_segment)
+        ))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish();
+    let mut jar = CookieJar::new();
+    jar.signed_mut(app.get_cookie_key()).add(cookie);
+    jar.get(name).expect("The cookie was just added").clone()
+}
+
+fn terms_cookie_value(object_path: &str) -> String {
+    let (object_id, _) = split_object_path(object_path);
+    url_encode(object_id).to_string()
+}
+
+/// The salt is included, so that changing the password invalidates the cookies.
+fn password_cookie_value(object_path: &str, password: &PasswordHash) -> String {
+    format!("{}:{}", terms_cookie_value(object_path), password.salt())
+}
+
+/// Redirect to index file of a directory in static site mode.
+/// Redirecting instead of serving the index directly makes relative links in it resolve
+/// inside the directory.
+/// The key is also embedded in the path, so that the relative links of an unlisted site
+/// carry it too.
+fn static_site_redirect(app: &AppData, object_path: &str, key: Option<&str>) -> HttpResponse {
+    let (object_segment, subobject_path) = split_object_segment(object_path);
+    let mut location = format!(
+        "{}/{}",
+        app.get_download_base_url(),
+        url_encode(object_segment)
+    );
+    if let Some(key) = key.filter(|_| embedded_key(object_path).is_none()) {
+        location.push(EMBEDDED_KEY_SEPARATOR);
+        location.extend(url_encode(key));
+    }
+    for segment in subobject_path
+        .into_iter()
+        .flat_map(|path| path.split('/'))
+        .filter(|segment| !segment.is_empty())
+    {
+        location.push('/');
+        location.extend(url_encode(segment));
+    }
+    location.push('/');
+    location.push_str(STATIC_SITE_INDEX);
+    if let Some(key) = key {
+        location.push_str("?key=");
+        location.extend(url_encode(key));
+    }
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+/// Header carrying the access token when the Authorization header is taken by the site
+/// authentication.
+const ACCESS_TOKEN_HEADER: &str = "x-access-token";
+
+/// Access token for access groups, sent in its own header or as password of HTTP basic
+/// authentication.
+fn access_token(req: &HttpRequest) -> Option<String> {
+    if let Some(token) = req.headers().get(ACCESS_TOKEN_HEADER) {
+        return token.to_str().ok().map(str::to_owned);
+    }
+    let (_user, password) = site_auth::basic_credentials(req)?;
+    Some(password)
+}
+
+/// Check whether the request carries a signed cookie with the expected value.
+fn has_object_cookie(app: &AppData, req: &HttpRequest, name: &str, expected_value: &str) -> bool {
+    let Ok(cookies) = req.cookies() else {
+        return false;
+    };
+
+    let jar = CookieJar::new();
+    let signed = jar.signed(app.get_cookie_key());
+    cookies
+        .iter()
+        .filter(|cookie| cookie.name() == name)
+        .filter_map(|cookie| signed.verify(cookie.clone()))
+        .any(|cookie| cookie.value() == expected_value)
+}
+
+fn password_page(
     app: &AppData,
     object_path: &str,
-    query_key: Option<&str>,
     is_unlisted: bool,
-    items: Vec<DirListingItem>,
+    wrong_password: bool,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Unauthorized()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(
+            templates::PasswordPrompt::new_wrapped(app, object_path, is_unlisted, wrong_password)
+                .into_string()?,
+        ))
+}
+
+/// Upload form shown instead of the content of inbox objects.
+fn inbox_page(
+    app: &AppData,
+    object_path: &str,
+    is_unlisted: bool,
+    inbox: &Inbox,
 ) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok()
         .content_type(mime::TEXT_HTML_UTF_8)
         .insert_header(cache_control(None))
         .body(
-            templates::DirListing::new_wrapped(app, object_path, is_unlisted, items)
+            templates::InboxForm::new_wrapped(app, object_path, is_unlisted, inbox)
                 .into_string()?,
         ))
 }
 
-/// Not found handler used for default route
-async fn default_service() -> Result<HttpResponse> {
-    Err(FiledlError::ObjectNotFound)
+fn terms_page(
+    app: &AppData,
+    object_path: &str,
+    is_unlisted: bool,
+    terms: &str,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(templates::Terms::new_wrapped(app, object_path, is_unlisted, terms).into_string()?))
 }
 
-pub fn configure_pages(cfg: &mut web::ServiceConfig) {
-    cfg.default_service(web::to(default_service))
-        .service(index_redirect)
-        .service(admin)
-        .service(thumbnail_cache_stats)
-        .service(download_root)
-        .service(download_object);
+/// Show a text file with syntax highlighting.
+/// Binary files and files over the size limit are downloaded instead.
+async fn text_preview<'a>(
+    app: &AppData,
+    req: &HttpRequest,
+    query: &DownloadQuery,
+    object_path: &str,
+    resolved_object: ResolvedObject<'a>,
+) -> Result<HttpResponse> {
+    if resolved_object.metadata().len() > app.get_max_preview_size() {
+        return file_download(app, req, resolved_object, true).await;
+    }
+    let content = fs::read(resolved_object.path()).await?;
+    let Some(text) = preview::as_text(&content) else {
+        return file_download(app, req, resolved_object, true).await;
+    };
+
+    let file_name = object_path
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let text = text.to_owned();
+    let highlighted = spawn_blocking(move || preview::highlight(&file_name, &text))
+        .await
+        .expect("Highlighting should not panic")
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let body = templates::Preview::new_wrapped(
+        app,
+        object_path,
+        resolved_object.is_unlisted(),
+        query.key.as_deref(),
+        &highlighted,
+    )
+    .into_string()?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(body))
+}
+
+/// Files of the directory containing all words of the query.
+fn search_results(
+    app: &AppData,
+    query: &DownloadQuery,
+    object_path: &str,
+    resolved_object: ResolvedObject<'_>,
+) -> Result<HttpResponse> {
+    let index = app.get_search_index().ok_or(FiledlError::SearchDisabled)?;
+    let q = query.q.as_deref().expect("Checked in validation");
+    let (object_id, directory) = split_object_path(object_path);
+    let hits = index.search(object_id, directory.unwrap_or_default(), q, MAX_SEARCH_HITS);
+
+    let body = templates::SearchResults::new_wrapped(
+        app,
+        object_path,
+        resolved_object.is_unlisted(),
+        q,
+        &hits,
+    )
+    .into_string()?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(body))
 }
 
-include! {concat!(env!("OUT_DIR"), "/assets/assets.rs")}
+/// Show a video or audio file in the browser's player.
+/// The media itself is streamed by `file_download`, including range requests for seeking.
+fn media_player(
+    app: &AppData,
+    query: &DownloadQuery,
+    object_path: &str,
+    resolved_object: ResolvedObject<'_>,
+) -> Result<HttpResponse> {
+    let body = templates::MediaPlayer::new_wrapped(
+        app,
+        object_path,
+        resolved_object.is_unlisted(),
+        query.key.as_deref(),
+        resolved_object.item_type().is_video(),
+    )
+    .into_string()?;
+    Ok(HttpResponse::Ok()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(body))
+}
+
+async fn file_download<'a>(
+    app: &AppData,
+    req: &HttpRequest,
+    resolved_object: ResolvedObject<'a>,
+    force_download: bool,
+) -> Result<HttpResponse> {
+    if resolved_object.watermark().is_some() && is_thumbnailable(resolved_object.path()) {
+        return watermarked_download(app, req, resolved_object, force_download).await;
+    }
+
+    let mut nf = NamedFile::open_async(resolved_object.path()).await?;
+
+    let disposition = if force_download {
+        header::DispositionType::Attachment
+    } else {
+        nf.content_disposition().disposition.clone()
+    };
+    if let Some(file_name) = resolved_object
+        .path()
+        .file_name()
+        .and_then(|name| name.to_str())
+    {
+        nf = nf.set_content_disposition(content_disposition(disposition, file_name));
+    }
+
+    let mut response = nf.into_response(req);
+
+    if is_active_content(response.headers().get(header::CONTENT_TYPE)) {
+        // Shared HTML or SVG viewed inline runs with an opaque origin, so that it can't
+        // access cookies or pages of the app itself.
+        let policy = if resolved_object.is_static_site() {
+            SANDBOX_POLICY_STATIC_SITE
+        } else {
+            SANDBOX_POLICY
+        };
+        response.headers_mut().insert(
+            header::CONTENT_SECURITY_POLICY,
+            header::HeaderValue::from_static(policy),
+        );
+    }
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+
+    let source_hash = resolved_object
+        .source_hash()
+        .filter(|_| app.content_checksums_enabled());
+    if let Some(source_hash) = source_hash {
+        let checksums = app.get_checksums();
+        if let Some(checksum) = checksums.get(source_hash) {
+            response.headers_mut().insert(
+                header::HeaderName::from_static(CONTENT_SHA256_HEADER),
+                header::HeaderValue::from_str(&checksum.to_string())
+                    .expect("Hex digest is a valid header value"),
+            );
+        } else if response.status() == StatusCode::OK && req.method() != Method::HEAD {
+            // Only complete responses can be used to calculate the checksum
+            let checksums = Arc::clone(checksums);
+            response = response
+                .map_body(|_, body| BoxBody::new(HashingBody::new(body, checksums, source_hash)));
+        }
+    }
+
+    Ok(with_watchdog(app, response, resolved_object.path()))
+}
+
+/// Check if the request comes from a page on a host that is not allowed to link to files.
+fn is_hotlinked(app: &AppData, req: &HttpRequest) -> bool {
+    let Some(referrer_host) = req
+        .headers()
+        .get(header::REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .and_then(|referer| referer.host().map(str::to_owned))
+    else {
+        return false;
+    };
+
+    let own_host = proxy::host(app.get_trusted_proxies(), req)
+        .parse::<Authority>()
+        .ok()
+        .map(|authority| authority.host().to_owned());
+    if own_host.is_some_and(|own_host| own_host.eq_ignore_ascii_case(&referrer_host)) {
+        return false;
+    }
+
+    !app.referrer_allowed(&referrer_host)
+}
+
+async fn hotlink_response(
+    app: &AppData,
+    object_path: &str,
+    key: Option<&str>,
+) -> Result<HttpResponse> {
+    match app.get_hotlink_response() {
+        HotlinkResponse::Redirect => {
+            let mut location = app.get_download_base_url().to_owned();
+            if let Some((parent, _)) = object_path.rsplit_once('/') {
+                for segment in parent.split('/') {
+                    location.push('/');
+                    location.extend(url_encode(segment));
+                }
+                if let Some(key) = key {
+                    location.push_str("?key=");
+                    location.extend(url_encode(key));
+                }
+            }
+            Ok(HttpResponse::SeeOther()
+                .insert_header((header::LOCATION, location))
+                .finish())
+        }
+        HotlinkResponse::Placeholder => {
+            let (content, ct) = get_asset(HOTLINK_PLACEHOLDER_ASSET, app.get_asset_source())
+                .await?
+                .ok_or(FiledlError::ObjectNotFound)?;
+            Ok(HttpResponse::Ok()
+                .insert_header(header::ContentType(ct))
+                .insert_header(cache_control(None))
+                .body(content))
+        }
+    }
+}
+
+/// Read the client hints relevant to thumbnails from request headers.
+fn client_hints(req: &HttpRequest) -> ClientHints {
+    let get = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            req.headers()
+                .get(*name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+        })
+    };
+    ClientHints {
+        dpr: get(&["sec-ch-dpr", "dpr"]).and_then(|dpr| dpr.parse().ok()),
+        width: get(&["sec-ch-width", "width"]).and_then(|width| width.parse().ok()),
+        save_data: get(&["save-data"])
+            .is_some_and(|save_data| save_data.eq_ignore_ascii_case("on")),
+    }
+}
+
+async fn archive_download<'a>(
+    app: &AppData,
+    resolved_object: ResolvedObject<'a>,
+    format: ArchiveFormat,
+) -> Result<HttpResponse> {
+    let path = resolved_object.path().to_owned();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("download");
+    let content_disposition = content_disposition(
+        header::DispositionType::Attachment,
+        &format!("{}.{}", name, format.extension()),
+    );
+
+    let stream = Box::pin(tar::tar_stream(path.clone()).await?);
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(content_disposition)
+        .insert_header(cache_control(None));
+    let response = match format {
+        ArchiveFormat::Tar => response.content_type("application/x-tar").streaming(stream),
+        ArchiveFormat::TarGz => response
+            .content_type("application/gzip")
+            .streaming(tar::gzip_stream(stream)),
+        ArchiveFormat::Zip => unreachable!("Zip downloads are handled separately"),
+    };
+    Ok(with_watchdog(app, response, &path))
+}
+
+/// Abort the response if its body stalls for longer than the configured timeout.
+fn with_watchdog(app: &AppData, response: HttpResponse, path: &Path) -> HttpResponse {
+    let Some(timeout) = app.get_stream_stall_timeout() else {
+        return response;
+    };
+    let description = path.display().to_string();
+    response.map_body(|_, body| BoxBody::new(WatchdogBody::new(body, timeout, description)))
+}
+
+async fn chunk_map<'a>(app: &AppData, resolved_object: ResolvedObject<'a>) -> Result<HttpResponse> {
+    // Directories named like images are resolved as images, but have no source hash.
+    let source_hash = resolved_object
+        .source_hash()
+        .ok_or(FiledlError::BadDownloadMode)?;
+    let chunk_map = app
+        .get_checksums()
+        .chunk_map(source_hash, resolved_object.path().to_owned())
+        .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control(None))
+        .json(chunk_map.as_ref()))
+}
+
+/// Full size image of an object with a watermark, rendered with the watermark as JPEG.
+/// The original file is never sent.
+async fn watermarked_download<'a>(
+    app: &AppData,
+    req: &HttpRequest,
+    resolved_object: ResolvedObject<'a>,
+    force_download: bool,
+) -> Result<HttpResponse> {
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(header::ContentType(mime::IMAGE_JPEG))
+        .insert_header((
+            header::X_CONTENT_TYPE_OPTIONS,
+            header::HeaderValue::from_static("nosniff"),
+        ));
+    if let Some(file_name) = resolved_object
+        .path()
+        .file_name()
+        .map(|name| Path::new(name).with_extension("jpg"))
+    {
+        let disposition = if force_download {
+            header::DispositionType::Attachment
+        } else {
+            header::DispositionType::Inline
+        };
+        response.insert_header(content_disposition(
+            disposition,
+            &file_name.to_string_lossy(),
+        ));
+    }
+    if req.method() == Method::HEAD {
+        return Ok(response.finish());
+    }
+
+    let path = resolved_object.path().to_owned();
+    let watermark = resolved_object
+        .watermark()
+        .cloned()
+        .expect("Checked by the caller");
+    drop(resolved_object);
+    let image = spawn_blocking(move || create_watermarked_image(&path, &watermark))
+        .await
+        .expect("Rendering the watermark should not panic")?;
+    app.relieve_memory_pressure().await;
+    Ok(response.body(image))
+}
+
+/// Check if content of this type can run scripts when opened in the browser.
+fn is_active_content(content_type: Option<&header::HeaderValue>) -> bool {
+    let Some(mime) = content_type
+        .and_then(|ct| ct.to_str().ok())
+        .and_then(|ct| ct.parse::<mime::Mime>().ok())
+    else {
+        return false;
+    };
+    mime.essence_str() == mime::TEXT_HTML.essence_str()
+        || mime.essence_str() == mime::IMAGE_SVG.essence_str()
+        || mime.suffix() == Some(mime::XML)
+        || mime.subtype() == mime::XML
+}
+
+async fn thumb_download<'a>(
+    app: &AppData,
+    req: &HttpRequest,
+    resolved_object: ResolvedObject<'a>,
+    size: u32,
+    fit: ThumbnailFit,
+    cache_hash: Option<&str>,
+) -> Result<HttpResponse> {
+    let size = app.clamp_thumbnail_size(size);
+    let params = ThumbnailParams::negotiate(size, client_hints(req));
+    let size = (params.size, params.size);
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(header::ContentType(mime::IMAGE_JPEG))
+        .insert_header((header::VARY, CLIENT_HINTS))
+        .insert_header(cache_control(cache_hash));
+
+    // The hash is known without rendering the thumbnail, so revalidation and HEAD requests
+    // don't need to recompute thumbnails that are no longer cached.
+    let etag =
+        header::EntityTag::new_strong(resolved_object.thumbnail_hash(size, params.quality, fit));
+    if etag_matches(req, &etag) {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .insert_header(header::ETag(etag))
+            .finish());
+    }
+    if req.method() == Method::HEAD {
+        return Ok(response.insert_header(header::ETag(etag)).finish());
+    }
+
+    let (thumb, _) = resolved_object
+        .into_thumbnail(size, params.quality, fit)
+        .await?;
+    app.relieve_memory_pressure().await;
+    Ok(response.insert_header(header::ETag(etag)).body(thumb))
+}
+
+/// Check if the request's If-None-Match header matches the entity tag.
+fn etag_matches(req: &HttpRequest, etag: &header::EntityTag) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// Render a directory listing.
+/// If `probe_dir` is set, the items don't have image dimensions yet and they are probed
+/// in the directory, only for the displayed page.
+#[allow(clippy::too_many_arguments)]
+async fn dir_listing(
+    app: &AppData,
+    req: &HttpRequest,
+    query: &DownloadQuery,
+    object_path: &str,
+    is_unlisted: bool,
+    expires: Option<DateTime<Utc>>,
+    mut items: Vec<DirListingItem>,
+    probe_dir: Option<&Path>,
+) -> Result<HttpResponse> {
+    let (mut response, view_mode, display_timezone) = listing_response(app, req, query);
+    let options = listing_options(query);
+    let tags = items
+        .iter()
+        .flat_map(|item| item.tags.iter().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    options.apply(&mut items);
+    let (mut items, pagination) = paginate(app, query, items);
+    if let Some(dir) = probe_dir {
+        items = stream::iter(items)
+            .then(|item| {
+                let path = dir.join(item.name.as_ref());
+                item.with_probed_dimensions(path)
+            })
+            .collect()
+            .await;
+    }
+
+    let body = templates::DirListing::new_wrapped(
+        app,
+        object_path,
+        is_unlisted,
+        expires,
+        view_mode,
+        options,
+        pagination,
+        &display_timezone,
+        items,
+        tags,
+    )
+    .into_string()?;
+
+    // Listings contain relative times that change even when the directory doesn't,
+    // so Last-Modified can't be used and the tag is derived from the rendered page.
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = header::EntityTag::new_weak(format!("{:X}", hasher.finish()));
+    let not_modified = etag_matches(req, &etag);
+    response.insert_header(header::ETag(etag));
+
+    if not_modified {
+        Ok(response.status(StatusCode::NOT_MODIFIED).finish())
+    } else {
+        Ok(response.body(body))
+    }
+}
+
+/// Render a directory listing progressively, items are sent as they are read
+/// from the directory.
+async fn streamed_dir_listing<S>(
+    app: &Arc<AppData>,
+    req: &HttpRequest,
+    query: &DownloadQuery,
+    object_path: &str,
+    is_unlisted: bool,
+    expires: Option<DateTime<Utc>>,
+    items: S,
+) -> Result<HttpResponse>
+where
+    S: Stream<Item = std::io::Result<DirListingItem>> + 'static,
+{
+    let (mut response, view_mode, display_timezone) = listing_response(app, req, query);
+    let options = listing_options(query);
+    let page = templates::DirListing::new_streaming_wrapped(
+        app,
+        object_path,
+        is_unlisted,
+        expires,
+        view_mode,
+        options.clone(),
+        &display_timezone,
+    )
+    .into_string()?;
+    let (head, tail) = page
+        .split_once(templates::ITEMS_MARKER)
+        .expect("Streaming listing contains the items marker");
+    let head = Bytes::from(head.to_owned());
+    let tail = Bytes::from(tail.to_owned());
+
+    let app = Arc::clone(app);
+    let object_path = object_path.to_owned();
+    let items = items
+        .filter(move |item| ready(item.as_ref().map_or(true, |item| options.matches(item))))
+        .map(move |item| {
+            let html = templates::DirListing::render_streamed_item(
+                &app,
+                &object_path,
+                view_mode,
+                &display_timezone,
+                &item?,
+            )
+            .map_err(std::io::Error::other)?;
+            Ok::<_, std::io::Error>(Bytes::from(html))
+        });
+
+    Ok(response.streaming(
+        stream::once(ready(Ok(head)))
+            .chain(items)
+            .chain(stream::once(ready(Ok(tail)))),
+    ))
+}
+
+/// Select the requested page of the items.
+/// Listings that fit on a single page are not paginated, unless a page was requested.
+fn paginate(
+    app: &AppData,
+    query: &DownloadQuery,
+    mut items: Vec<DirListingItem>,
+) -> (Vec<DirListingItem>, Option<Pagination>) {
+    let Some(per_page) = query.per_page.or(app.get_listing_page_size()) else {
+        return (items, None);
+    };
+    if query.page.is_none() && items.len() <= per_page {
+        return (items, None);
+    }
+
+    let mut pagination = Pagination {
+        page: 1,
+        per_page,
+        total_items: items.len(),
+    };
+    // Links to pages past the end, for example after files were removed, show the last page
+    pagination.page = query.page.unwrap_or(1).min(pagination.page_count());
+    let start = (pagination.page - 1)
+        .saturating_mul(per_page)
+        .min(items.len());
+    let end = start.saturating_add(per_page).min(items.len());
+    (items.drain(start..end).collect(), Some(pagination))
+}
+
+fn listing_options(query: &DownloadQuery) -> ListingOptions {
+    ListingOptions {
+        sort: query.sort,
+        order: query.order,
+        filter: query.filter.clone().filter(|filter| !filter.is_empty()),
+        tag: query.tag.clone().filter(|tag| !tag.is_empty()),
+    }
+}
+
+/// Response builder for listings, together with the view mode and timezone to use.
+/// View mode and timezone selected in the query are remembered in cookies,
+/// otherwise the cookies are used to pick them.
+fn listing_response(
+    app: &AppData,
+    req: &HttpRequest,
+    query: &DownloadQuery,
+) -> (HttpResponseBuilder, ViewMode, Tz) {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .insert_header(("Accept-CH", CLIENT_HINTS));
+
+    let view_mode = match query.view {
+        Some(view_mode) => {
+            response.cookie(preference_cookie(app, VIEW_MODE_COOKIE, view_mode.as_str()));
+            view_mode
+        }
+        None => req
+            .cookie(VIEW_MODE_COOKIE)
+            .and_then(|cookie| ViewMode::parse(cookie.value()))
+            .unwrap_or_default(),
+    };
+
+    let display_timezone = match query.tz.as_deref().and_then(|name| Tz::from_str(name).ok()) {
+        Some(tz) => {
+            response.cookie(preference_cookie(app, TIMEZONE_COOKIE, tz.name()));
+            tz
+        }
+        None => req
+            .cookie(TIMEZONE_COOKIE)
+            .and_then(|cookie| Tz::from_str(cookie.value()).ok())
+            .unwrap_or(*app.get_display_timezone()),
+    };
+
+    (response, view_mode, display_timezone)
+}
+
+/// Long lived cookie storing a display preference of the visitor.
+fn preference_cookie<'c>(app: &AppData, name: &'c str, value: &'c str) -> Cookie<'c> {
+    Cookie::build(name, value)
+        .path(root_cookie_path(app))
+        .same_site(SameSite::Lax)
+        .permanent()
+        .finish()
+}
+
+/// Path for cookies that apply to all downloads.
+fn root_cookie_path(app: &AppData) -> String {
+    let download_base_url = app.get_download_base_url();
+    if download_base_url.is_empty() {
+        "/".into()
+    } else {
+        download_base_url.into()
+    }
+}
+
+fn tombstone(app: &AppData, message: Option<&str>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Gone()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .body(
+            templates::Message::new_wrapped(
+                app,
+                "Share ended",
+                message.unwrap_or("This share has ended."),
+            )
+            .into_string()?,
+        ))
+}
+
+/// Response for download routes while the server is in maintenance mode.
+fn maintenance_page(app: &AppData, retry_after: u32) -> Result<HttpResponse> {
+    Ok(HttpResponse::ServiceUnavailable()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .insert_header((header::RETRY_AFTER, retry_after))
+        .body(
+            templates::Message::new_wrapped(
+                app,
+                "Maintenance",
+                "The server is undergoing maintenance, please try again later.",
+            )
+            .into_string()?,
+        ))
+}
+
+/// Response for downloads after the transfer cap was reached.
+fn over_quota_page(app: &AppData, retry_after: u32) -> Result<HttpResponse> {
+    Ok(HttpResponse::ServiceUnavailable()
+        .content_type(mime::TEXT_HTML_UTF_8)
+        .insert_header(cache_control(None))
+        .insert_header((header::RETRY_AFTER, retry_after))
+        .body(
+            templates::Message::new_wrapped(
+                app,
+                "Transfer limit reached",
+                "This server has reached its transfer limit, downloads will be available again later.",
+            )
+            .into_string()?,
+        ))
+}
+
+#[get("/admin/maintenance")]
+async fn get_maintenance(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok().json(app.get_maintenance())
+}
+
+#[put("/admin/maintenance")]
+async fn set_maintenance(
+    app: web::Data<Arc<AppData>>,
+    maintenance: web::Json<Maintenance>,
+) -> Result<HttpResponse> {
+    app.set_maintenance(maintenance.into_inner())?;
+    Ok(HttpResponse::Ok().json(app.get_maintenance()))
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordChange {
+    /// New password, or null to remove the protection
+    password: Option<String>,
+}
+
+#[put("/admin/object/{object_id}/password")]
+async fn set_object_password(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+    change: web::Json<PasswordChange>,
+) -> Result<HttpResponse> {
+    if app
+        .set_object_password(&object_id, change.password.as_deref())
+        .await
+    {
+        app.flush().await?;
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(FiledlError::ObjectNotFound)
+    }
+}
+
+#[delete("/admin/object/{object_id}")]
+async fn remove_object(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    app.remove_object(&object_id).await?;
+    app.flush().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkRequest {
+    #[serde(default)]
+    filter: ObjectFilter,
+    operation: BulkOperation,
+    /// Only report which objects would be changed
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Change many objects at once, all or nothing.
+#[post("/admin/bulk")]
+async fn bulk_update(
+    app: web::Data<Arc<AppData>>,
+    request: web::Json<BulkRequest>,
+) -> Result<HttpResponse> {
+    let result = app
+        .bulk_update(&request.filter, &request.operation, request.dry_run)
+        .await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// Replace unlisted keys with a placeholder
+    #[serde(default)]
+    redact_keys: bool,
+}
+
+/// Object metadata in the format of `metadata.json`.
+#[get("/admin/metadata")]
+async fn export_metadata(
+    app: web::Data<Arc<AppData>>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(app.export_metadata(query.redact_keys).await)
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    on_conflict: ImportConflict,
+    /// Only report what would be imported
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Merge an exported `metadata.json` into the current objects, all or nothing.
+#[post("/admin/metadata")]
+async fn import_metadata(
+    app: web::Data<Arc<AppData>>,
+    query: web::Query<ImportQuery>,
+    objects: web::Json<BTreeMap<Arc<str>, Object>>,
+) -> Result<HttpResponse> {
+    let result = app
+        .import_metadata(objects.into_inner(), query.on_conflict, query.dry_run)
+        .await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareLinkRequest {
+    expires: DateTime<Utc>,
+    /// Number of file downloads allowed through the link, unlimited if not set
+    #[serde(default)]
+    max_downloads: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareLinkResponse {
+    url: String,
+    expires: DateTime<Utc>,
+    max_downloads: Option<u32>,
+}
+
+/// Create a signed link that gives access to the object until it expires,
+/// without its unlisted key or access token.
+#[post("/admin/object/{object_id}/share_link")]
+async fn create_share_link(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+    request: web::Json<ShareLinkRequest>,
+) -> Result<HttpResponse> {
+    let url = app
+        .create_share_link(&object_id, request.expires, request.max_downloads)
+        .await?;
+    Ok(HttpResponse::Ok().json(ShareLinkResponse {
+        url: proxy::absolute_url(app.get_trusted_proxies(), &req, &url),
+        expires: request.expires,
+        max_downloads: request.max_downloads,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct NestedObjectRequest {
+    /// ID of the object containing the shared directory
+    parent: String,
+    /// Directory inside the parent object
+    path: String,
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+    #[serde(default = "default_true")]
+    unlisted: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+struct NestedObjectResponse {
+    url: String,
+}
+
+/// Share a directory inside an existing object as a separate object,
+/// without copying its data.
+#[post("/admin/object/{object_id}/nested")]
+async fn create_nested_object(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    object_id: web::Path<String>,
+    request: web::Json<NestedObjectRequest>,
+) -> Result<HttpResponse> {
+    let url = app
+        .create_nested_object(
+            &object_id,
+            &request.parent,
+            &request.path,
+            request.expires,
+            request.unlisted,
+        )
+        .await?;
+    app.flush().await?;
+    Ok(HttpResponse::Ok().json(NestedObjectResponse {
+        url: proxy::absolute_url(app.get_trusted_proxies(), &req, &url),
+    }))
+}
+
+/// iCalendar feed of upcoming object expirations, to subscribe to in a calendar app.
+/// Like the rest of the admin interface it requires the admin token, calendar apps
+/// send it as the basic auth password (`https://:<token>@host/admin/expirations.ics`).
+#[get("/admin/expirations.ics")]
+async fn expirations_calendar(app: web::Data<Arc<AppData>>, req: HttpRequest) -> HttpResponse {
+    let mut events = app.expiration_events().await;
+    for event in &mut events {
+        event.url = event
+            .url
+            .as_deref()
+            .map(|path| proxy::absolute_url(app.get_trusted_proxies(), &req, path));
+    }
+    let name = format!("{} expirations", app.get_app_name());
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header(cache_control(None))
+        .body(ical::calendar(&name, &events, Utc::now()))
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    /// Path to return to after the login
+    #[serde(default)]
+    return_to: Option<String>,
+}
+
+fn oidc(app: &AppData) -> Result<&site_auth::Oidc> {
+    match app.get_site_auth() {
+        Some(SiteAuth::Oidc(oidc)) => Ok(oidc),
+        _ => Err(FiledlError::ObjectNotFound),
+    }
+}
+
+/// Start a login at the OpenID Connect provider.
+#[get("/auth/login")]
+async fn login(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    query: web::Query<LoginQuery>,
+) -> Result<HttpResponse> {
+    let oidc = oidc(&app)?;
+    // Only local paths, to avoid being an open redirect
+    let return_to = match query.into_inner().return_to {
+        Some(return_to)
+            if return_to.starts_with('/')
+                && !return_to.starts_with("//")
+                && !return_to.contains('\\') =>
+        {
+            return_to
+        }
+        _ => format!("{}/", proxy::path_prefix(app.get_trusted_proxies(), &req)),
+    };
+    let login = LoginState::new(return_to);
+    let location = oidc.authorization_url(&login).await?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header(cache_control(None))
+        .insert_header((header::LOCATION, location))
+        .cookie(login.to_cookie(app.get_cookie_key()))
+        .finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginCallbackQuery {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    state: Option<String>,
+    /// Set by the provider when the login failed or was cancelled
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Finish a login when the provider redirects back.
+#[get("/auth/callback")]
+async fn login_callback(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    query: web::Query<LoginCallbackQuery>,
+) -> Result<HttpResponse> {
+    let oidc = oidc(&app)?;
+    let result = async {
+        let login_state =
+            LoginState::from_request(&req, app.get_cookie_key()).ok_or_else(|| {
+                FiledlError::LoginFailed {
+                    reason: "login state cookie is missing".to_owned(),
+                }
+            })?;
+        if let Some(error) = &query.error {
+            return Err(FiledlError::LoginFailed {
+                reason: format!("provider returned {}", error),
+            });
+        }
+        let (Some(code), Some(state)) = (&query.code, &query.state) else {
+            return Err(FiledlError::LoginFailed {
+                reason: "code or state is missing".to_owned(),
+            });
+        };
+        if !constant_time_eq(state.as_bytes(), login_state.state.as_bytes()) {
+            return Err(FiledlError::LoginFailed {
+                reason: "state mismatch".to_owned(),
+            });
+        }
+        let (user, expires) = oidc.finish_login(&login_state, code).await?;
+        Ok((login_state, user, expires))
+    }
+    .await;
+    let (login_state, user, expires) = result.map_err(|e| {
+        log::warn!("{}", e);
+        e
+    })?;
+    log::info!("User {} logged in", user);
+
+    let mut login_cookie = Cookie::build(site_auth::LOGIN_COOKIE, "")
+        .path("/")
+        .finish();
+    login_cookie.make_removal();
+    Ok(HttpResponse::SeeOther()
+        .insert_header(cache_control(None))
+        .insert_header((header::LOCATION, login_state.return_to))
+        .cookie(site_auth::session_cookie(
+            app.get_cookie_key(),
+            &user,
+            expires,
+        ))
+        .cookie(login_cookie)
+        .finish())
+}
+
+/// Forget the login session.
+#[get("/auth/logout")]
+async fn logout(app: web::Data<Arc<AppData>>, req: HttpRequest) -> HttpResponse {
+    let mut session_cookie = Cookie::build(site_auth::SESSION_COOKIE, "")
+        .path("/")
+        .finish();
+    session_cookie.make_removal();
+    HttpResponse::SeeOther()
+        .insert_header(cache_control(None))
+        .insert_header((
+            header::LOCATION,
+            format!("{}/", proxy::path_prefix(app.get_trusted_proxies(), &req)),
+        ))
+        .cookie(session_cookie)
+        .finish()
+}
+
+#[derive(Debug, Deserialize)]
+struct PublicChange {
+    public: bool,
+}
+
+#[put("/admin/object/{object_id}/public")]
+async fn set_object_public(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+    change: web::Json<PublicChange>,
+) -> Result<HttpResponse> {
+    if app.set_object_public(&object_id, change.public).await {
+        app.flush().await?;
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(FiledlError::ObjectNotFound)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PinnedChange {
+    pinned: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagsChange {
+    tags: Vec<String>,
+}
+
+#[get("/admin/tags")]
+async fn list_tags(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok().json(app.list_tags().await)
+}
+
+#[put("/admin/object/{object_id}/tags")]
+async fn set_object_tags(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+    change: web::Json<TagsChange>,
+) -> Result<HttpResponse> {
+    app.set_object_tags(&object_id, &change.tags).await?;
+    app.flush().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/admin/object/{object_id}/pinned")]
+async fn set_object_pinned(
+    app: web::Data<Arc<AppData>>,
+    object_id: web::Path<String>,
+    change: web::Json<PinnedChange>,
+) -> Result<HttpResponse> {
+    if app.set_object_pinned(&object_id, change.pinned).await {
+        app.flush().await?;
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(FiledlError::ObjectNotFound)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    /// Client chosen ID under which progress of the upload can be polled
+    #[serde(default)]
+    session: Option<String>,
+}
+
+impl Validate for UploadQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(session) = &self.session {
+            if session.is_empty()
+                || session.len() > MAX_SESSION_ID_LENGTH
+                || !session
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                errors.push(FieldError::new(
+                    "session",
+                    "Session ID must be 1 to 64 letters, digits, dashes or underscores",
+                ));
+            }
+        }
+        errors
+    }
+}
+
+/// Upload a file into an owned object, the request body is the file content.
+/// Multipart form data bodies are also accepted, each file field is then stored
+/// under its file name inside the object.
+#[routes]
+#[put("/admin/upload/{object:.*}")]
+#[post("/admin/upload/{object:.*}")]
+async fn upload(
+    app: web::Data<Arc<AppData>>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: ValidQuery<UploadQuery>,
+    body: web::Payload,
+) -> Result<HttpResponse> {
+    match &query.session {
+        Some(session_id) => {
+            let total_bytes = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            let uploads = app.get_uploads();
+            let mut body =
+                UploadBody::new(&req, uploads.track(session_id, &path, total_bytes, body)?);
+            let result = body.store(&app, &path).await;
+            // Record the result before the tracked body is dropped, dropping it while
+            // the session is still receiving marks the upload as interrupted.
+            uploads.finish(session_id, &result);
+            if result.is_err() && uploads.is_cancelled(session_id) {
+                return Err(FiledlError::UploadCancelled);
+            }
+            result?;
+        }
+        None => {
+            UploadBody::new(&req, body).store(&app, &path).await?;
+        }
+    }
+    app.flush().await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+/// Body of an upload request, either the file content itself or a multipart form.
+enum UploadBody<S> {
+    Raw(S),
+    Multipart(Multipart),
+}
+
+impl<S, E> UploadBody<S>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin + 'static,
+    E: std::fmt::Display,
+{
+    fn new(req: &HttpRequest, body: S) -> Self {
+        let is_multipart = req.mime_type().ok().flatten().is_some_and(|mime| {
+            mime.type_() == mime::MULTIPART && mime.subtype() == mime::FORM_DATA
+        });
+
+        if is_multipart {
+            let body = body.map_err(|e| PayloadError::Io(std::io::Error::other(e.to_string())));
+            UploadBody::Multipart(Multipart::new(req.headers(), body))
+        } else {
+            UploadBody::Raw(body)
+        }
+    }
+
+    /// Store the upload, either as a single file or as the file fields of a multipart form.
+    async fn store(&mut self, app: &AppData, path: &str) -> Result<()> {
+        match self {
+            UploadBody::Raw(body) => {
+                app.upload(path, body).await?;
+                Ok(())
+            }
+            UploadBody::Multipart(multipart) => upload_multipart(app, path, multipart).await,
+        }
+    }
+}
+
+/// Store all file fields of a multipart body in the object.
+async fn upload_multipart(app: &AppData, path: &str, multipart: &mut Multipart) -> Result<()> {
+    let malformed = |e: MultipartError| FiledlError::InvalidRequest {
+        errors: vec![FieldError::new("body", e.to_string())],
+    };
+
+    let mut file_count = 0;
+    while let Some(field) = multipart.try_next().await.map_err(malformed)? {
+        let Some(file_name) = field
+            .content_disposition()
+            .get_filename()
+            .and_then(multipart_file_name)
+        else {
+            continue;
+        };
+        let file_path = format!("{}/{}", path.trim_end_matches('/'), file_name);
+        app.upload(&file_path, field).await?;
+        file_count += 1;
+    }
+
+    if file_count == 0 {
+        return Err(FiledlError::InvalidRequest {
+            errors: vec![FieldError::new("body", "No file in the multipart body")],
+        });
+    }
+    Ok(())
+}
+
+/// Extract the last path component of a file name sent by a client,
+/// browsers on some platforms send full paths.
+fn multipart_file_name(file_name: &str) -> Option<&str> {
+    let file_name = file_name.rsplit(['/', '\\']).next()?;
+    (!file_name.is_empty()).then_some(file_name)
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkList {
+    /// Hex encoded SHA-256 hashes of the chunks
+    chunks: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct MissingChunks {
+    missing: Vec<String>,
+}
+
+/// Find out which chunks of a chunked upload need to be sent.
+#[post("/admin/chunks/missing")]
+async fn missing_chunks(
+    app: web::Data<Arc<AppData>>,
+    chunks: web::Json<ChunkList>,
+) -> Result<HttpResponse> {
+    let missing = app.get_chunk_store()?.missing(&chunks.chunks).await?;
+    Ok(HttpResponse::Ok().json(MissingChunks { missing }))
+}
+
+/// Store a single chunk, the request body is the chunk content.
+#[put("/admin/chunks/{hash}")]
+async fn upload_chunk(
+    app: web::Data<Arc<AppData>>,
+    hash: web::Path<String>,
+    body: web::Payload,
+) -> Result<HttpResponse> {
+    app.get_chunk_store()?.store(&hash, body).await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+/// Assemble a file in an owned object from previously uploaded chunks.
+#[routes]
+#[put("/admin/upload-chunked/{object:.*}")]
+#[post("/admin/upload-chunked/{object:.*}")]
+async fn upload_chunked(
+    app: web::Data<Arc<AppData>>,
+    path: web::Path<String>,
+    chunks: web::Json<ChunkList>,
+) -> Result<HttpResponse> {
+    let body = app.get_chunk_store()?.assemble(&chunks.chunks).await?;
+    app.upload(&path, body).await?;
+    app.flush().await?;
+    Ok(HttpResponse::Created().finish())
+}
+
+/// Progress of all running and recently finished uploads.
+#[get("/admin/uploads")]
+async fn upload_sessions(app: web::Data<Arc<AppData>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(cache_control(None))
+        .json(app.get_uploads().all())
+}
+
+#[get("/admin/uploads/{session_id}")]
+async fn upload_progress(
+    app: web::Data<Arc<AppData>>,
+    session_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    let progress = app
+        .get_uploads()
+        .get(&session_id)
+        .ok_or(FiledlError::UploadSessionNotFound)?;
+    Ok(HttpResponse::Ok()
+        .insert_header(cache_control(None))
+        .json(progress))
+}
+
+/// Cancel a running upload.
+#[delete("/admin/uploads/{session_id}")]
+async fn cancel_upload(
+    app: web::Data<Arc<AppData>>,
+    session_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    if app.get_uploads().cancel(&session_id) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(FiledlError::UploadSessionNotFound)
+    }
+}
+
+/// Not found handler used for default route
+async fn default_service() -> Result<HttpResponse> {
+    Err(FiledlError::ObjectNotFound)
+}
+
+pub fn configure_pages(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(extractor_error_handler))
+        .app_data(web::QueryConfig::default().error_handler(extractor_error_handler))
+        .app_data(web::FormConfig::default().error_handler(extractor_error_handler))
+        .app_data(web::PathConfig::default().error_handler(extractor_error_handler))
+        .default_service(web::to(default_service))
+        .service(index_redirect)
+        .service(admin)
+        .service(admin_objects)
+        .service(admin_stats)
+        .service(get_maintenance)
+        .service(set_maintenance)
+        .service(thumbnail_cache_stats)
+        .service(memory_stats)
+        .service(bandwidth_report)
+        .service(remove_object)
+        .service(set_object_password)
+        .service(set_object_pinned)
+        .service(set_object_tags)
+        .service(list_tags)
+        .service(create_share_link)
+        .service(create_nested_object)
+        .service(set_object_public)
+        .service(expirations_calendar)
+        .service(login)
+        .service(login_callback)
+        .service(logout)
+        .service(set_object_inbox)
+        .service(bulk_update)
+        .service(export_metadata)
+        .service(import_metadata)
+        .service(upload)
+        .service(upload_sessions)
+        .service(missing_chunks)
+        .service(upload_chunk)
+        .service(upload_chunked)
+        .service(upload_progress)
+        .service(cancel_upload)
+        .service(download_root)
+        .service(download_object)
+        // Must go before the object form, both are posted to the download path
+        .service(upload_to_inbox_form)
+        .service(submit_object_form)
+        .service(upload_to_inbox)
+        .service(upload_to_drop_box);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app_data::TestApp;
+    use assert2::assert;
+
+    #[actix_web::test]
+    async fn inbox_uploads() {
+        use actix_web::test;
+
+        let test_app = TestApp::new("");
+        let inbox = Inbox {
+            max_size: Some(10),
+            closes: None,
+        };
+        test_app
+            .set_object_inbox("inbox", Some(inbox))
+            .await
+            .unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(test_app.data())
+                .configure(configure_pages),
+        )
+        .await;
+        let put = |uri: &str, body: &'static str| {
+            let request = test::TestRequest::put().uri(uri).set_payload(body);
+            let app = &app;
+            async move { test::call_service(app, request.to_request()).await.status() }
+        };
+        let read = |name: &str| std::fs::read_to_string(test_app.owned_data("inbox").join(name));
+
+        assert!(put("/download/inbox/a.txt", "hello").await == StatusCode::CREATED);
+        assert!(read("a.txt").unwrap() == "hello");
+        assert!(
+            put("/download/inbox/a.txt", "again").await
+                == FiledlError::InboxFileExists.status_code()
+        );
+        assert!(read("a.txt").unwrap() == "hello");
+
+        for uri in [
+            "/download/inbox/",
+            "/download/inbox/.hidden",
+            "/download/inbox/dir/b.txt",
+            "/download/inbox/%2E%2E/b.txt",
+        ] {
+            assert!(put(uri, "data").await == StatusCode::BAD_REQUEST);
+        }
+
+        // 5 of the 10 bytes are used
+        assert!(put("/download/inbox/b.txt", "123456").await == StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(let Err(_) = read("b.txt"));
+
+        let request = test::TestRequest::post()
+            .uri("/download/inbox")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=BOUNDARY",
+            ))
+            .set_payload(
+                "--BOUNDARY\r\n\
+                 Content-Disposition: form-data; name=\"files\"; filename=\"c.txt\"\r\n\
+                 Content-Type: text/plain\r\n\r\n\
+                 abc\r\n\
+                 --BOUNDARY--\r\n",
+            )
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.status() == StatusCode::SEE_OTHER);
+        assert!(read("c.txt").unwrap() == "abc");
+
+        // No temporary files are left behind
+        let owned_data = test_app.owned_data("");
+        let leftovers = std::fs::read_dir(owned_data)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with('.')
+            })
+            .count();
+        assert!(leftovers == 0);
+
+        let closed = Inbox {
+            max_size: None,
+            closes: Some(Utc::now() - chrono::Duration::hours(1)),
+        };
+        test_app
+            .set_object_inbox("inbox", Some(closed))
+            .await
+            .unwrap();
+        assert!(put("/download/inbox/d.txt", "late").await == StatusCode::GONE);
+        assert!(let Err(_) = read("d.txt"));
+    }
+
+    #[actix_web::test]
+    async fn drop_box_uploads() {
+        use actix_web::test;
+
+        let test_app = TestApp::new(
+            "drop_box_rate_limit = 5\ndrop_box_max_size = 10\ndrop_box_max_total_size = 15",
+        );
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(test_app.data())
+                .configure(configure_pages),
+        )
+        .await;
+        let put = |client: &str, uri: &str, body: &'static str| {
+            let request = test::TestRequest::put()
+                .uri(uri)
+                .peer_addr(client.parse().unwrap())
+                .set_payload(body);
+            let app = &app;
+            async move { test::call_service(app, request.to_request()).await }
+        };
+        let client = "192.0.2.1:1234";
+
+        let response = put(client, "/drop/a.txt", "hello").await;
+        assert!(response.status() == StatusCode::CREATED);
+        let url = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        let path = &url[url.find("/download/").unwrap()..].trim_end();
+        let request = test::TestRequest::get().uri(path).to_request();
+        assert!(test::call_and_read_body(&app, request).await == "hello");
+
+        assert!(put(client, "/drop/.hidden", "data").await.status() == StatusCode::BAD_REQUEST);
+        assert!(
+            put(client, "/drop/b.txt", "01234567890").await.status()
+                == StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Fills the drop box
+        assert!(put(client, "/drop/b.txt", "0123456789").await.status() == StatusCode::CREATED);
+        assert!(
+            put("192.0.2.2:1234", "/drop/c.txt", "c").await.status()
+                == StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // Fifth request from the same client is the last one allowed
+        assert!(put(client, "/drop/c.txt", "c").await.status() == StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(put(client, "/drop/c.txt", "c").await.status() == StatusCode::TOO_MANY_REQUESTS);
+
+        // Addresses from one IPv6 /64 network share the limit
+        for _ in 0..5 {
+            put("[2001:db8::1]:1234", "/drop/c.txt", "c").await;
+        }
+        assert!(
+            put("[2001:db8::2]:1234", "/drop/c.txt", "c").await.status()
+                == StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // No temporary files are left behind
+        let leftovers = std::fs::read_dir(test_app.owned_data(""))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with('.')
+            })
+            .count();
+        assert!(leftovers == 0);
+    }
+
+    #[actix_web::test]
+    async fn encoded_paths_stay_inside_the_object() {
+        use crate::app_data::ObjectOwnership;
+        use actix_web::test;
+        use relative_path::RelativePathBuf;
+
+        let objects = vec![(
+            "obj",
+            Object::new(ObjectOwnership::Linked(RelativePathBuf::from("obj"))),
+        )];
+        let test_app = TestApp::with_objects("", objects).unwrap();
+        let linked_root = test_app.linked_root();
+        std::fs::create_dir_all(linked_root.join("obj")).unwrap();
+        std::fs::write(linked_root.join("obj/a.txt"), "a").unwrap();
+        std::fs::write(linked_root.join("secret.txt"), "secret").unwrap();
+        let app = test::init_service(
+            actix_web::App::new()
+                .app_data(test_app.data())
+                .configure(configure_pages),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/download/obj/a.txt")
+            .to_request();
+        assert!(test::call_and_read_body(&app, request).await == "a");
+
+        for uri in [
+            "/download/obj/%2e%2e/secret.txt",
+            "/download/obj/%2E%2E/secret.txt",
+            "/download/obj/..%2Fsecret.txt",
+            "/download/obj/%2e%2e%2Fsecret.txt",
+            "/download/obj%2F..%2Fsecret.txt",
+            "/download/obj/a.txt%2F..%2F..%2Fsecret.txt",
+            "/download/obj/%5C..%5Csecret.txt",
+        ] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+            let status = response.status();
+            assert!(status.is_client_error(), "{}: {}", uri, status);
+            assert!(test::read_body(response).await != "secret");
+        }
+    }
+
+    #[actix_web::test]
+    async fn pagination() {
+        let app = TestApp::new("listing_page_size = 3");
+        let items = || -> Vec<DirListingItem> {
+            (1..=7)
+                .map(|i| DirListingItem {
+                    name: i.to_string().into(),
+                    item_type: ItemType::File,
+                    file_size: 0,
+                    modified: None,
+                    source_hash: None,
+                    dimensions: None,
+                    tags: Vec::new(),
+                })
+                .collect()
+        };
+        let page = |query: &str| {
+            let query = web::Query::<DownloadQuery>::from_query(query).unwrap();
+            let (items, pagination) = paginate(&app, &query, items());
+            let names: Vec<String> = items.iter().map(|item| item.name.to_string()).collect();
+            (
+                names,
+                pagination.map(|p| (p.page, p.per_page, p.page_count())),
+            )
+        };
+
+        assert!(page("") == (vec!["1".into(), "2".into(), "3".into()], Some((1, 3, 3))));
+        assert!(page("page=2") == (vec!["4".into(), "5".into(), "6".into()], Some((2, 3, 3))));
+        // The last page is partial
+        assert!(page("page=3") == (vec!["7".into()], Some((3, 3, 3))));
+        // Pages past the end show the last one
+        assert!(page("page=100") == (vec!["7".into()], Some((3, 3, 3))));
+        assert!(page(&format!("page={}", usize::MAX)) == (vec!["7".into()], Some((3, 3, 3))));
+
+        // Everything fits on one page, no pagination unless a page is requested
+        let (names, pagination) = page("per_page=7");
+        assert!(names.len() == 7);
+        assert!(pagination.is_none());
+        let (names, pagination) = page("per_page=10&page=1");
+        assert!(names.len() == 7);
+        assert!(pagination == Some((1, 10, 1)));
+    }
+
+    #[test]
+    fn pagination_query_limits() {
+        let errors = |query: &str| {
+            web::Query::<DownloadQuery>::from_query(query)
+                .unwrap()
+                .validate()
+                .into_iter()
+                .map(|error| error.field)
+                .collect::<Vec<_>>()
+        };
+        assert!(errors(&format!("per_page={}&page=1", MAX_PER_PAGE)).is_empty());
+        assert!(errors(&format!("per_page={}", MAX_PER_PAGE + 1)) == [Some("per_page")]);
+        assert!(errors("per_page=0") == [Some("per_page")]);
+        assert!(errors("page=0") == [Some("page")]);
+    }
+
+    #[test]
+    fn access_token_sources() {
+        use actix_web::test::TestRequest;
+
+        let basic = TestRequest::get()
+            .insert_header((header::AUTHORIZATION, "Basic OnRva2Vu"))
+            .to_http_request();
+        assert!(access_token(&basic).as_deref() == Some("token"));
+        // With basic site authentication the header holds the login
+        let both = TestRequest::get()
+            .insert_header((header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0"))
+            .insert_header((ACCESS_TOKEN_HEADER, "token"))
+            .to_http_request();
+        assert!(access_token(&both).as_deref() == Some("token"));
+        assert!(access_token(&TestRequest::get().to_http_request()).is_none());
+    }
+
+    #[test]
+    fn full_download() {
+        use actix_web::test::TestRequest;
+
+        let get = TestRequest::get().to_http_request();
+        assert!(is_full_download(&get, &DownloadMode::Default));
+        assert!(is_full_download(&get, &DownloadMode::Download));
+        assert!(!is_full_download(&get, &DownloadMode::Preview));
+        let head = TestRequest::default()
+            .method(Method::HEAD)
+            .to_http_request();
+        assert!(!is_full_download(&head, &DownloadMode::Download));
+        let from_start = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=0-"))
+            .to_http_request();
+        assert!(is_full_download(&from_start, &DownloadMode::Download));
+        let resumed = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=1000-"))
+            .to_http_request();
+        assert!(!is_full_download(&resumed, &DownloadMode::Download));
+    }
+
+    #[test]
+    fn static_site_redirect_keeps_key() {
+        let app = TestApp::new("");
+        let location = |object_path, key| {
+            let response = static_site_redirect(&app, object_path, key);
+            response
+                .headers()
+                .get(header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        assert!(location("site", None) == "/download/site/index.html");
+        assert!(location("site", Some("k")) == "/download/site~k/index.html?key=k");
+        assert!(location("site/sub/", Some("k")) == "/download/site~k/sub/index.html?key=k");
+        assert!(location("site~k/sub", None) == "/download/site~k/sub/index.html");
+        assert!(location("site~k", Some("k")) == "/download/site~k/index.html?key=k");
+    }
+
+    #[actix_web::test]
+    async fn chunk_map_of_directory_named_like_image() {
+        use actix_web::web::Bytes;
+        use figment::{
+            providers::{Format, Toml},
+            Figment,
+        };
+        use futures_util::stream;
+
+        let root =
+            std::env::temp_dir().join(format!("filedl-chunk-map-{:X}", rand::random::<u64>()));
+        let config = Figment::new()
+            .merge(Toml::string(&format!(
+                "data_path = {:?}\nlinked_objects_root = {:?}",
+                root, root
+            )))
+            .extract()
+            .unwrap();
+        let app = AppData::with_config(config).unwrap();
+        let body = stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(b"content"))]);
+        app.upload("photos/dir.jpg/file.txt", body).await.unwrap();
+
+        let resolved_object = app
+            .resolve_object("photos/dir.jpg", None, None)
+            .await
+            .unwrap();
+        let result = chunk_map(&app, resolved_object).await;
+        assert!(let Err(FiledlError::BadDownloadMode) = result);
+
+        drop(app);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn multipart_file_names() {
+        assert!(multipart_file_name("photo.jpg") == Some("photo.jpg"));
+        assert!(multipart_file_name("C:\\Users\\me\\photo.jpg") == Some("photo.jpg"));
+        assert!(multipart_file_name("dir/photo.jpg") == Some("photo.jpg"));
+        assert!(multipart_file_name("dir/") == None);
+        assert!(multipart_file_name("") == None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Salted and stretched password hashes for password protected objects.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// Argon2id password hash as stored in metadata.json, in the PHC string format
+/// (`$argon2id$v=19$m=...,t=...,p=...$<salt>$<hash>`).
+/// Hashing and verifying takes tens of milliseconds, so it shouldn't run
+/// on the async workers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    /// Hash the password with a random salt.
+    pub fn new(password: &str) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("Default Argon2 parameters accept any password");
+        PasswordHash(hash.to_string())
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        argon2::PasswordHash::new(&self.0).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+
+    /// Salt of the hash, changes whenever the password is set.
+    pub fn salt(&self) -> &str {
+        self.0.rsplit('$').nth(1).unwrap_or_default()
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(output, "{:02x}", byte).expect("Writing to string can't fail");
+    }
+    output
+}
+
+/// Compare without exiting early, so that the timing doesn't reveal the matching prefix.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn verify_password() {
+        let hash = PasswordHash::new("correct horse");
+        assert!(hash.verify("correct horse"));
+        assert!(!hash.verify("correct horse "));
+        assert!(!hash.verify(""));
+    }
+
+    #[test]
+    fn salted() {
+        let a = PasswordHash::new("password");
+        let b = PasswordHash::new("password");
+        assert!(a != b);
+        assert!(a.salt() != b.salt());
+        assert!(a.0.contains(a.salt()));
+    }
+
+    #[test]
+    fn malformed_hash_never_matches() {
+        assert!(!PasswordHash("".into()).verify(""));
+        assert!(!PasswordHash("$argon2id$nonsense".into()).verify("nonsense"));
+    }
+
+    #[test]
+    fn constant_time_eq_examples() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Syntax highlighted previews of text files.
+
+use std::sync::OnceLock;
+use syntect::{
+    highlighting::ThemeSet,
+    html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+/// Prefix of the highlighting CSS classes, to avoid clashes with the app styles.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+const THEME: &str = "InspiredGitHub";
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// Returns the content as text, or None if it looks like a binary file.
+pub fn as_text(content: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(content).ok()?;
+    (!text.contains('\0')).then_some(text)
+}
+
+/// Find the syntax by file extension, falling back to the first line (shebang, XML
+/// declaration, ...) and then to plain text.
+fn find_syntax<'a>(syntax_set: &'a SyntaxSet, file_name: &str, text: &str) -> &'a SyntaxReference {
+    let extension = file_name.rsplit_once('.').map_or(file_name, |(_, ext)| ext);
+    syntax_set
+        .find_syntax_by_extension(extension)
+        .or_else(|| syntax_set.find_syntax_by_first_line(text))
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+}
+
+/// Highlight the text as HTML spans with CSS classes, the styles are in `stylesheet()`.
+/// CPU intensive for large files, should run in a blocking task.
+pub fn highlight(file_name: &str, text: &str) -> Result<String, syntect::Error> {
+    let syntax_set = syntax_set();
+    let syntax = find_syntax(syntax_set, file_name, text);
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set, CLASS_STYLE);
+    for line in LinesWithEndings::from(text) {
+        generator.parse_html_for_line_which_includes_newline(line)?;
+    }
+    Ok(generator.finalize())
+}
+
+/// CSS for the highlighted HTML.
+pub fn stylesheet() -> &'static str {
+    static STYLESHEET: OnceLock<String> = OnceLock::new();
+    STYLESHEET.get_or_init(|| {
+        let themes = ThemeSet::load_defaults();
+        css_for_theme_with_class_style(&themes.themes[THEME], CLASS_STYLE)
+            .expect("Generating CSS for a built-in theme should not fail")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn text_detection() {
+        assert!(as_text(b"fn main() {}\n") == Some("fn main() {}\n"));
+        assert!(as_text(b"caf\xc3\xa9") == Some("caf\u{e9}"));
+        assert!(as_text(b"\x89PNG\r\n\x1a\n\0\0").is_none());
+        assert!(as_text(b"text\0with nul").is_none());
+    }
+
+    #[test]
+    fn syntax_from_extension_or_first_line() {
+        let syntax_set = syntax_set();
+        assert!(find_syntax(syntax_set, "main.rs", "").name == "Rust");
+        assert!(
+            find_syntax(syntax_set, "run", "#!/bin/bash\necho hi\n").name
+                == "Bourne Again Shell (bash)"
+        );
+        assert!(find_syntax(syntax_set, "notes", "just text").name == "Plain Text");
+    }
+
+    #[test]
+    fn highlighted_html_is_escaped() {
+        let html = highlight("notes.txt", "<script>\n").unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+}
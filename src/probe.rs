@@ -0,0 +1,56 @@
+//! Reading metadata of media files for listings.
+
+use crate::error::Result;
+use std::path::Path;
+
+/// Size of the image as it is displayed, after applying its EXIF orientation.
+/// Returns None if the image header can't be read.
+pub fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let dimensions = image::image_dimensions(path).ok()?;
+    let orientation = orientation(path).unwrap_or(1);
+    Some(oriented_dimensions(dimensions, orientation))
+}
+
+/// Returns EXIF orientation of the image (1 to 8), 1 if it is missing.
+pub fn orientation(path: &Path) -> Result<u32> {
+    let file = std::fs::File::open(path)?;
+    let mut bufreader = std::io::BufReader::new(file);
+    let exifreader = exif::Reader::new();
+    let Ok(exif_tags) = exifreader.read_from_container(&mut bufreader) else {
+        return Ok(1);
+    };
+
+    Ok(
+        match exif_tags.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
+            Some(orientation) => match orientation.value.get_uint(0) {
+                Some(v @ 1..=8) => v,
+                _ => 1,
+            },
+            None => 1,
+        },
+    )
+}
+
+/// Orientations 5 to 8 rotate the image by 90 degrees, swapping width and height.
+fn oriented_dimensions(dimensions: (u32, u32), orientation: u32) -> (u32, u32) {
+    match orientation {
+        5..=8 => (dimensions.1, dimensions.0),
+        _ => dimensions,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn oriented_dimensions_swap() {
+        for orientation in 1..=4 {
+            assert!(oriented_dimensions((400, 300), orientation) == (400, 300));
+        }
+        for orientation in 5..=8 {
+            assert!(oriented_dimensions((400, 300), orientation) == (300, 400));
+        }
+    }
+}
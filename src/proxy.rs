@@ -0,0 +1,141 @@
+//! Information about the original request when running behind a reverse proxy.
+//!
+//! `X-Forwarded-*` headers are only used when the connection comes from one of
+//! the configured trusted proxies, anyone else could use them to spoof their address
+//! or the links generated by the server.
+
+use actix_web::{http::header::HOST, HttpRequest};
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PREFIX: &str = "x-forwarded-prefix";
+
+fn is_from_trusted_proxy(trusted: &[IpAddr], req: &HttpRequest) -> bool {
+    req.peer_addr()
+        .is_some_and(|addr| trusted.contains(&addr.ip()))
+}
+
+/// Value of a forwarded header set by the nearest trusted proxy.
+/// With multiple proxies the header can contain a comma separated list, the first
+/// value describes the original request.
+fn forwarded_header<'a>(trusted: &[IpAddr], req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    if !is_from_trusted_proxy(trusted, req) {
+        return None;
+    }
+    let value = req.headers().get(name)?.to_str().ok()?;
+    let value = value.split(',').next()?.trim();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Address of the client that made the request.
+/// Behind trusted proxies this is the last address in `X-Forwarded-For` that isn't
+/// one of the proxies.
+pub fn client_address(trusted: &[IpAddr], req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<IpAddr> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|address| address.trim().parse().ok())
+        .collect();
+    Some(
+        forwarded
+            .iter()
+            .rev()
+            .find(|address| !trusted.contains(address))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer),
+    )
+}
+
+/// Scheme (`http` or `https`) that the client used.
+pub fn scheme(trusted: &[IpAddr], req: &HttpRequest) -> &'static str {
+    match forwarded_header(trusted, req, X_FORWARDED_PROTO) {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        Some(_) => "http",
+        None if req.app_config().secure() => "https",
+        None => "http",
+    }
+}
+
+/// Host (with a possible port) that the client connected to.
+pub fn host<'a>(trusted: &[IpAddr], req: &'a HttpRequest) -> &'a str {
+    forwarded_header(trusted, req, X_FORWARDED_HOST)
+        .or_else(|| req.headers().get(HOST)?.to_str().ok())
+        .unwrap_or_else(|| req.app_config().host())
+}
+
+/// Path under which the proxy exposes the server, without a trailing slash.
+/// Empty when the server is not behind a proxy at a subpath.
+pub fn path_prefix<'a>(trusted: &[IpAddr], req: &'a HttpRequest) -> &'a str {
+    match forwarded_header(trusted, req, X_FORWARDED_PREFIX) {
+        Some(prefix) if prefix.starts_with('/') && !prefix.contains(['?', '#']) => {
+            prefix.trim_end_matches('/')
+        }
+        _ => "",
+    }
+}
+
+/// Path of the current request as seen by the client, for redirecting back to it.
+pub fn original_uri(trusted: &[IpAddr], req: &HttpRequest) -> String {
+    format!("{}{}", path_prefix(trusted, req), req.uri())
+}
+
+/// Turn an absolute path on this server into a full URL, as seen by the client.
+pub fn absolute_url(trusted: &[IpAddr], req: &HttpRequest, path: &str) -> String {
+    format!("{}://{}{}", scheme(trusted, req), host(trusted, req), path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use assert2::assert;
+    use std::net::SocketAddr;
+
+    const PROXY: &str = "10.0.0.1:1234";
+    const CLIENT: &str = "192.0.2.7:1234";
+
+    fn forwarded(peer: &str) -> HttpRequest {
+        TestRequest::get()
+            .uri("/download/a?mode=preview")
+            .peer_addr(peer.parse::<SocketAddr>().unwrap())
+            .insert_header((HOST, "internal:8080"))
+            .insert_header((X_FORWARDED_FOR, "198.51.100.1, 192.0.2.9"))
+            .insert_header((X_FORWARDED_PROTO, "https"))
+            .insert_header((X_FORWARDED_HOST, "files.example.com"))
+            .insert_header((X_FORWARDED_PREFIX, "/files/"))
+            .to_http_request()
+    }
+
+    fn trusted() -> Vec<IpAddr> {
+        vec!["10.0.0.1".parse().unwrap()]
+    }
+
+    #[test]
+    fn trusted_proxy_headers_are_used() {
+        let trusted = trusted();
+        let req = forwarded(PROXY);
+        assert!(client_address(&trusted, &req) == Some("192.0.2.9".parse().unwrap()));
+        assert!(
+            absolute_url(&trusted, &req, "/download/a") == "https://files.example.com/download/a"
+        );
+        assert!(original_uri(&trusted, &req) == "/files/download/a?mode=preview");
+    }
+
+    #[test]
+    fn untrusted_headers_are_ignored() {
+        let trusted = trusted();
+        let req = forwarded(CLIENT);
+        assert!(client_address(&trusted, &req) == Some("192.0.2.7".parse().unwrap()));
+        assert!(absolute_url(&trusted, &req, "/download/a") == "http://internal:8080/download/a");
+        assert!(original_uri(&trusted, &req) == "/download/a?mode=preview");
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Any bucket untouched for this long has refilled completely and is equivalent to
+/// a new one, so it can be dropped.
+const IDLE_BUCKET_AGE: Duration = Duration::from_secs(60);
+
+/// Rate limiting key for a client address.
+/// IPv6 clients usually get a whole /64 network, so they are limited by the prefix.
+pub fn client_key(address: Option<IpAddr>) -> String {
+    match address.map(|address| address.to_canonical()) {
+        None => String::new(),
+        Some(IpAddr::V4(address)) => address.to_string(),
+        Some(IpAddr::V6(address)) => {
+            let prefix = u128::from(address) & !(u128::MAX >> 64);
+            format!("{}/64", std::net::Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// Token bucket allowing `limit` requests per minute on average, with bursts of up to
+/// `limit` requests.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.into(),
+            last_refill: now,
+        }
+    }
+
+    /// Take a token from the bucket, returns false if the bucket is empty.
+    fn try_take(&mut self, limit: u32, now: Instant) -> bool {
+        let limit = f64::from(limit);
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit / 60.0).min(limit);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Request rate limiting per object (or other key, like client address)
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<Arc<str>, TokenBucket>,
+    last_pruned: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drop the idle buckets, at most once per `IDLE_BUCKET_AGE`.
+    fn prune(&mut self, now: Instant) {
+        if self
+            .last_pruned
+            .is_some_and(|last| now.saturating_duration_since(last) < IDLE_BUCKET_AGE)
+        {
+            return;
+        }
+        self.buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.last_refill) < IDLE_BUCKET_AGE
+        });
+        self.last_pruned = Some(now);
+    }
+}
+
+impl RateLimiter {
+    /// Count a request to the object, returns false if the request is over the limit
+    /// of requests per minute.
+    pub fn check(&self, object_id: &str, limit: u32) -> bool {
+        let allowed = self.try_take(object_id, limit);
+        if !allowed {
+            log::warn!(
+                "Object {} is over its rate limit of {}/min",
+                object_id,
+                limit
+            );
+        }
+        allowed
+    }
+
+    /// Count a request under any key, returns false if it is over the limit
+    /// of requests per minute.
+    pub fn try_take(&self, key: &str, limit: u32) -> bool {
+        self.try_take_at(key, limit, Instant::now())
+    }
+
+    fn try_take_at(&self, key: &str, limit: u32, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.prune(now);
+        let buckets = &mut buckets.buckets;
+        if !buckets.contains_key(key) {
+            buckets.insert(key.into(), TokenBucket::new(limit, now));
+        }
+
+        buckets.get_mut(key).unwrap().try_take(limit, now)
+    }
+
+    /// Approximate memory used by the token buckets, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.buckets
+            .lock()
+            .unwrap()
+            .buckets
+            .keys()
+            .map(|object_id| object_id.len() + std::mem::size_of::<(Arc<str>, TokenBucket)>())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn burst_is_limited() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, start);
+        assert!(bucket.try_take(3, start));
+        assert!(bucket.try_take(3, start));
+        assert!(bucket.try_take(3, start));
+        assert!(!bucket.try_take(3, start));
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+        for _ in 0..60 {
+            assert!(bucket.try_take(60, start));
+        }
+        assert!(!bucket.try_take(60, start));
+
+        // One token per second
+        assert!(bucket.try_take(60, start + Duration::from_secs(1)));
+        assert!(!bucket.try_take(60, start + Duration::from_secs(1)));
+
+        // Never refills over the limit
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..60 {
+            assert!(bucket.try_take(60, later));
+        }
+        assert!(!bucket.try_take(60, later));
+    }
+
+    #[test]
+    fn keys_are_limited_separately() {
+        let limiter = RateLimiter::default();
+        assert!(limiter.try_take("192.0.2.1", 1));
+        assert!(!limiter.try_take("192.0.2.1", 1));
+        assert!(limiter.try_take("192.0.2.2", 1));
+    }
+
+    #[test]
+    fn idle_buckets_are_pruned() {
+        let start = Instant::now();
+        let limiter = RateLimiter::default();
+        assert!(limiter.try_take_at("192.0.2.1", 1, start));
+        assert!(limiter.try_take_at("192.0.2.2", 1, start + Duration::from_secs(30)));
+        assert!(limiter.buckets.lock().unwrap().buckets.len() == 2);
+
+        // The first bucket is full again, the second one is still in use
+        assert!(!limiter.try_take_at("192.0.2.2", 1, start + Duration::from_secs(61)));
+        assert!(limiter.buckets.lock().unwrap().buckets.len() == 1);
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_by_prefix() {
+        let key = |address: &str| client_key(Some(address.parse().unwrap()));
+        assert!(key("192.0.2.1") == "192.0.2.1");
+        assert!(key("::ffff:192.0.2.1") == "192.0.2.1");
+        assert!(key("2001:db8:1:2:3:4:5:6") == "2001:db8:1:2::/64");
+        assert!(key("2001:db8:1:2::ffff") == key("2001:db8:1:2:3:4:5:6"));
+        assert!(key("2001:db8:1:3::1") != key("2001:db8:1:2::1"));
+        assert!(client_key(None) == "");
+    }
+}
@@ -0,0 +1,303 @@
+//! Full-text search over small text files of listed objects.
+//!
+//! The index is kept in memory and rebuilt periodically in the background. It contains
+//! only objects that anyone can browse (no unlisted key, password, access group or
+//! terms), so search results never reveal content that the visitor couldn't open.
+
+use crate::{app_data::AppData, preview::as_text};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::task::spawn_blocking;
+
+/// Characters of context shown on each side of the match.
+const SNIPPET_CONTEXT: usize = 60;
+
+/// Text file in the index.
+#[derive(Debug)]
+struct IndexedFile {
+    /// Path relative to the object root, separated by `/`
+    path: String,
+    text: String,
+    /// Lowercase version of `text` for case insensitive matching
+    lowercase: String,
+}
+
+/// File matching a search query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchHit {
+    /// Path relative to the searched directory
+    pub path: String,
+    /// Line with the first match, shortened around it
+    pub snippet: String,
+}
+
+#[derive(Debug)]
+pub struct SearchIndex {
+    /// Total size of indexed text in bytes, files beyond it are left out
+    max_size: u64,
+    /// Larger files are not indexed
+    max_file_size: u64,
+    interval: Duration,
+    /// Indexed files by object ID
+    objects: RwLock<HashMap<Arc<str>, Vec<IndexedFile>>>,
+}
+
+impl SearchIndex {
+    pub fn new(max_size: u64, max_file_size: u64, interval: Duration) -> Self {
+        SearchIndex {
+            max_size,
+            max_file_size,
+            interval,
+            objects: Default::default(),
+        }
+    }
+
+    /// Read the text files of the objects, until the size limit is reached.
+    /// Blocking, walks the whole directory trees.
+    fn build(&self, roots: Vec<(Arc<str>, PathBuf)>) -> HashMap<Arc<str>, Vec<IndexedFile>> {
+        let mut remaining = self.max_size;
+        let mut objects = HashMap::new();
+        for (object_id, root) in roots {
+            let files = self.index_directory(&root, &mut remaining);
+            if !files.is_empty() {
+                objects.insert(object_id, files);
+            }
+            if remaining == 0 {
+                log::warn!("Search index is full, some files are not searchable");
+                break;
+            }
+        }
+        objects
+    }
+
+    fn index_directory(&self, root: &Path, remaining: &mut u64) -> Vec<IndexedFile> {
+        let mut files = Vec::new();
+        let entries = walkdir::WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+            });
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::debug!("Skipping unreadable entry while indexing: {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.len() > self.max_file_size {
+                continue;
+            }
+            if metadata.len() > *remaining {
+                *remaining = 0;
+                break;
+            }
+            let Ok(content) = std::fs::read(entry.path()) else {
+                continue;
+            };
+            let Some(text) = as_text(&content) else {
+                continue;
+            };
+            let Some(path) = relative_path(root, entry.path()) else {
+                continue;
+            };
+            *remaining -= content.len() as u64;
+            files.push(IndexedFile {
+                path,
+                lowercase: text.to_lowercase(),
+                text: text.to_owned(),
+            });
+        }
+        files
+    }
+
+    /// Files of the object under `directory` containing all words of the query,
+    /// ignoring case. At most `limit` hits are returned.
+    pub fn search(
+        &self,
+        object_id: &str,
+        directory: &str,
+        query: &str,
+        limit: usize,
+    ) -> Vec<SearchHit> {
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        let Some(first_word) = words.first() else {
+            return Vec::new();
+        };
+        let prefix = match directory.trim_matches('/') {
+            "" => String::new(),
+            directory => format!("{}/", directory),
+        };
+
+        let objects = self.objects.read().unwrap();
+        let Some(files) = objects.get(object_id) else {
+            return Vec::new();
+        };
+        files
+            .iter()
+            .filter_map(|file| {
+                let path = file.path.strip_prefix(&prefix)?;
+                words
+                    .iter()
+                    .all(|word| file.lowercase.contains(word.as_str()))
+                    .then(|| SearchHit {
+                        path: path.to_owned(),
+                        snippet: snippet(file, first_word),
+                    })
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Path relative to the root, with `/` separators.
+fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let components: Option<Vec<&str>> = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+    Some(components?.join("/"))
+}
+
+/// The first line containing the word, shortened to the context around the match.
+fn snippet(file: &IndexedFile, word: &str) -> String {
+    // Lowercasing never adds or removes line breaks, so the lines correspond
+    let Some((line, position)) =
+        file.text
+            .lines()
+            .zip(file.lowercase.lines())
+            .find_map(|(line, lowercase)| {
+                let byte_position = lowercase.find(word)?;
+                Some((line, lowercase[..byte_position].chars().count()))
+            })
+    else {
+        return String::new();
+    };
+    let line = line.trim_end();
+    let start = position.saturating_sub(SNIPPET_CONTEXT);
+    let length = SNIPPET_CONTEXT * 2 + word.chars().count();
+    let shortened: String = line.chars().skip(start).take(length).collect();
+    let prefix = if start > 0 { "…" } else { "" };
+    let suffix = if start + length < line.chars().count() {
+        "…"
+    } else {
+        ""
+    };
+    format!("{}{}{}", prefix, shortened.trim(), suffix)
+}
+
+/// Rebuild the search index forever, with the configured interval.
+pub async fn run(app: Arc<AppData>) {
+    let Some(index) = app.get_search_index() else {
+        return;
+    };
+    loop {
+        let roots = app.searchable_objects().await;
+        let built = spawn_blocking({
+            let app = Arc::clone(&app);
+            move || app.get_search_index().expect("Checked above").build(roots)
+        })
+        .await
+        .expect("Indexing should not panic");
+        log::debug!("Search index rebuilt, {} objects indexed", built.len());
+        *index.objects.write().unwrap() = built;
+        tokio::time::sleep(index.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn file(path: &str, text: &str) -> IndexedFile {
+        IndexedFile {
+            path: path.to_owned(),
+            text: text.to_owned(),
+            lowercase: text.to_lowercase(),
+        }
+    }
+
+    fn index() -> SearchIndex {
+        let index = SearchIndex::new(1 << 20, 1 << 16, Duration::from_secs(60));
+        index.objects.write().unwrap().insert(
+            "docs".into(),
+            vec![
+                file("README.md", "# Setup\nInstall the Server first.\n"),
+                file(
+                    "guide/usage.txt",
+                    "Start the server.\nThen open the browser.\n",
+                ),
+                file("guide/faq.txt", "Nothing here.\n"),
+            ],
+        );
+        index
+    }
+
+    #[test]
+    fn all_words_must_match() {
+        let index = index();
+        let hits = index.search("docs", "", "server BROWSER", 10);
+        assert!(
+            hits == [SearchHit {
+                path: "guide/usage.txt".to_owned(),
+                snippet: "Start the server.".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn limited_to_directory() {
+        let index = index();
+        let hits = index.search("docs", "guide", "server", 10);
+        assert!(hits.len() == 1);
+        assert!(hits[0].path == "usage.txt");
+        assert!(index.search("docs", "", "server", 10).len() == 2);
+        assert!(index.search("other", "", "server", 10).is_empty());
+        assert!(index.search("docs", "", "  ", 10).is_empty());
+    }
+
+    #[test]
+    fn long_lines_are_shortened() {
+        let line = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+        let snippet = snippet(&file("long.txt", &line), "needle");
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("needle"));
+        assert!(snippet.chars().count() == SNIPPET_CONTEXT * 2 + "needle".len() + 2);
+    }
+
+    #[test]
+    fn size_limits() {
+        let root =
+            std::env::temp_dir().join(format!("filedl-search-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), "small").unwrap();
+        std::fs::write(root.join("b.bin"), b"\0\x01\x02").unwrap();
+        std::fs::write(root.join("sub/c.txt"), "x".repeat(100)).unwrap();
+        std::fs::write(root.join(".hidden"), "secret").unwrap();
+
+        let index = SearchIndex::new(1000, 50, Duration::from_secs(60));
+        let objects = index.build(vec![("obj".into(), root.clone())]);
+        let paths: Vec<_> = objects["obj"].iter().map(|f| f.path.as_str()).collect();
+        assert!(paths == ["a.txt"]);
+
+        let index = SearchIndex::new(3, 1000, Duration::from_secs(60));
+        assert!(index.build(vec![("obj".into(), root.clone())]).is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
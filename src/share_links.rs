@@ -0,0 +1,259 @@
+//! Signed, time limited share links.
+//!
+//! A share link token grants access to a single object without its unlisted key or access
+//! token, until it expires or its download limit is used up. Tokens have the form
+//! `<expires>.<max downloads>.<signature>` and are used in place of the unlisted key,
+//! either embedded in the path or in the `key` query parameter.
+//! The signature is HMAC-SHA256 of the object ID, expiry and download limit.
+
+use crate::{
+    error::{FiledlError, Result},
+    storage::Storage,
+};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{path::PathBuf, sync::Mutex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Verified share link from a request.
+#[derive(Clone, Debug)]
+pub struct ShareLink {
+    pub expires: DateTime<Utc>,
+    pub max_downloads: Option<u32>,
+    signature: String,
+}
+
+/// Downloads made through a share link with a download limit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LinkUsage {
+    /// Kept to forget the usage once the link expires
+    expires: DateTime<Utc>,
+    downloads: u32,
+}
+
+/// Issues and verifies share links, counts downloads of links with a limit.
+#[derive(Debug)]
+pub struct ShareLinks {
+    key: Vec<u8>,
+    /// Keyed by signature of the link
+    usage: Mutex<Storage<LinkUsage>>,
+}
+
+impl ShareLinks {
+    pub fn new<P: Into<PathBuf>>(path: P, key: &[u8]) -> std::io::Result<Self> {
+        Ok(ShareLinks {
+            key: key.to_vec(),
+            usage: Mutex::new(Storage::new(path)?),
+        })
+    }
+
+    fn signature(
+        &self,
+        object_id: &str,
+        expires: DateTime<Utc>,
+        max_downloads: Option<u32>,
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        mac.update(object_id.as_bytes());
+        mac.update(b"\0");
+        mac.update(&expires.timestamp().to_be_bytes());
+        mac.update(&max_downloads.map_or(-1, i64::from).to_be_bytes());
+        mac
+    }
+
+    /// Create a token for the object.
+    pub fn create(
+        &self,
+        object_id: &str,
+        expires: DateTime<Utc>,
+        max_downloads: Option<u32>,
+    ) -> String {
+        let signature = self
+            .signature(object_id, expires, max_downloads)
+            .finalize()
+            .into_bytes();
+        format!(
+            "{}.{}.{}",
+            expires.timestamp(),
+            max_downloads.map(|max| max.to_string()).unwrap_or_default(),
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    /// Check the token against the object.
+    /// Tokens that are malformed or signed for another object are reported as a wrong
+    /// unlisted key, so that they don't reveal anything about the object.
+    pub fn verify(&self, object_id: &str, token: &str, now: DateTime<Utc>) -> Result<ShareLink> {
+        let link = self
+            .parse_and_verify(object_id, token)
+            .ok_or(FiledlError::Unlisted)?;
+        if link.expires <= now {
+            return Err(FiledlError::ShareLinkExpired);
+        }
+        if let Some(max_downloads) = link.max_downloads {
+            let usage = self.usage.lock().unwrap();
+            let downloads = usage
+                .get(&link.signature)
+                .map_or(0, |usage| usage.downloads);
+            if downloads >= max_downloads {
+                return Err(FiledlError::ShareLinkExhausted);
+            }
+        }
+        Ok(link)
+    }
+
+    fn parse_and_verify(&self, object_id: &str, token: &str) -> Option<ShareLink> {
+        let mut parts = token.splitn(3, '.');
+        let expires = parts.next()?.parse().ok()?;
+        let max_downloads = match parts.next()? {
+            "" => None,
+            max_downloads => Some(max_downloads.parse().ok()?),
+        };
+        let signature = parts.next()?;
+        let expires = DateTime::from_timestamp(expires, 0)?;
+
+        let signature_bytes = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.signature(object_id, expires, max_downloads)
+            .verify_slice(&signature_bytes)
+            .ok()?;
+        Some(ShareLink {
+            expires,
+            max_downloads,
+            signature: signature.to_owned(),
+        })
+    }
+
+    /// Write changed usage to the file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.usage.lock().unwrap().flush()
+    }
+
+    /// Count a file download made through the link, failing if its download limit is
+    /// already used up. The check and the increment happen under one lock, so concurrent
+    /// downloads can't exceed the limit.
+    /// The usage is only saved by `flush`, like the statistics.
+    pub fn record_download(&self, link: &ShareLink, now: DateTime<Utc>) -> Result<()> {
+        let Some(max_downloads) = link.max_downloads else {
+            return Ok(());
+        };
+        let mut usage = self.usage.lock().unwrap();
+        let downloads = usage
+            .get(&link.signature)
+            .map_or(0, |usage| usage.downloads);
+        if downloads >= max_downloads {
+            return Err(FiledlError::ShareLinkExhausted);
+        }
+        let expired: Vec<_> = usage
+            .iter()
+            .filter(|(_, usage)| usage.expires <= now)
+            .map(|(signature, _)| signature.clone())
+            .collect();
+        for signature in expired {
+            usage.remove(&signature);
+        }
+        usage.set(
+            link.signature.as_str().into(),
+            LinkUsage {
+                expires: link.expires,
+                downloads: downloads + 1,
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    /// Share links with a temporary usage file, removed when dropped.
+    struct TestShareLinks {
+        links: Option<ShareLinks>,
+        path: PathBuf,
+    }
+
+    impl std::ops::Deref for TestShareLinks {
+        type Target = ShareLinks;
+
+        fn deref(&self) -> &ShareLinks {
+            self.links.as_ref().unwrap()
+        }
+    }
+
+    impl Drop for TestShareLinks {
+        fn drop(&mut self) {
+            // The usage is saved when dropped, it must go before the file is removed
+            self.links.take();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn share_links() -> TestShareLinks {
+        let path = std::env::temp_dir().join(format!(
+            "filedl-share-links-{:016x}.json",
+            rand::random::<u64>()
+        ));
+        TestShareLinks {
+            links: Some(ShareLinks::new(&path, b"0123456789abcdef0123456789abcdef").unwrap()),
+            path,
+        }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn valid_until_expiry() {
+        let links = share_links();
+        let token = links.create("photos", at("2024-01-08T00:00:00Z"), None);
+        assert!(let Ok(_) = links.verify("photos", &token, at("2024-01-07T23:59:59Z")));
+        assert!(let Err(FiledlError::ShareLinkExpired) = links.verify("photos", &token, at("2024-01-08T00:00:00Z")));
+    }
+
+    #[test]
+    fn bound_to_object_and_parameters() {
+        let links = share_links();
+        let now = at("2024-01-01T00:00:00Z");
+        let token = links.create("photos", at("2024-01-08T00:00:00Z"), Some(3));
+        assert!(let Err(FiledlError::Unlisted) = links.verify("videos", &token, now));
+
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let tampered = format!(
+            "{}.30.{}",
+            at("2024-01-08T00:00:00Z").timestamp(),
+            signature
+        );
+        assert!(let Err(FiledlError::Unlisted) = links.verify("photos", &tampered, now));
+        assert!(let Err(FiledlError::Unlisted) = links.verify("photos", "0123abcd", now));
+    }
+
+    #[test]
+    fn download_limit() {
+        let links = share_links();
+        let now = at("2024-01-01T00:00:00Z");
+        let token = links.create("photos", at("2024-01-08T00:00:00Z"), Some(2));
+        for _ in 0..2 {
+            let link = links.verify("photos", &token, now).unwrap();
+            assert!(let Ok(()) = links.record_download(&link, now));
+        }
+        assert!(let Err(FiledlError::ShareLinkExhausted) = links.verify("photos", &token, now));
+    }
+
+    #[test]
+    fn download_limit_with_concurrent_verification() {
+        let links = share_links();
+        let now = at("2024-01-01T00:00:00Z");
+        let token = links.create("photos", at("2024-01-08T00:00:00Z"), Some(1));
+        // Both requests pass verification before either download is counted
+        let first = links.verify("photos", &token, now).unwrap();
+        let second = links.verify("photos", &token, now).unwrap();
+        assert!(let Ok(()) = links.record_download(&first, now));
+        assert!(let Err(FiledlError::ShareLinkExhausted) = links.record_download(&second, now));
+    }
+}
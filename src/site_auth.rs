@@ -0,0 +1,445 @@
+//! Optional authentication for the whole site, either with static HTTP basic credentials
+//! or through an OpenID Connect provider.
+//!
+//! OpenID Connect uses the authorization code flow. The ID token is received directly
+//! from the token endpoint over TLS, so its signature doesn't need to be verified
+//! (OpenID Connect Core 1.0, section 3.1.3.7). Logged in users get a signed session cookie.
+
+use crate::{
+    config::{OidcConfig, SiteAuthConfig},
+    error::{FiledlError, Result},
+    password::constant_time_eq,
+};
+use actix_web::{
+    cookie::{time, Cookie, CookieJar, Key, SameSite},
+    http::header,
+    HttpRequest,
+};
+use base64::prelude::{Engine as _, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+pub const SESSION_COOKIE: &str = "filedl_session";
+/// State of a login in progress at the OpenID Connect provider
+pub const LOGIN_COOKIE: &str = "filedl_login";
+
+/// Time allowed for logging in at the provider, in seconds.
+const LOGIN_TIMEOUT: i64 = 600;
+
+#[derive(Debug)]
+pub enum SiteAuth {
+    Basic { users: HashMap<String, String> },
+    Oidc(Oidc),
+}
+
+impl SiteAuth {
+    pub fn new(config: &SiteAuthConfig, client: reqwest::Client) -> Self {
+        match config {
+            SiteAuthConfig::Basic { users } => SiteAuth::Basic {
+                users: users.clone(),
+            },
+            SiteAuthConfig::Oidc(config) => SiteAuth::Oidc(Oidc {
+                config: config.clone(),
+                client,
+                provider: OnceCell::new(),
+            }),
+        }
+    }
+
+    /// User authenticated by the request, either by basic credentials or a session cookie.
+    pub fn authenticated_user(&self, req: &HttpRequest, key: &Key) -> Option<String> {
+        match self {
+            SiteAuth::Basic { users } => {
+                let (user, password) = basic_credentials(req)?;
+                let expected = users.get(&user)?;
+                constant_time_eq(password.as_bytes(), expected.as_bytes()).then_some(user)
+            }
+            SiteAuth::Oidc(_) => session_user(req, key, Utc::now()),
+        }
+    }
+}
+
+/// User name and password from HTTP basic authentication.
+pub fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let credentials = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = BASE64_STANDARD.decode(credentials.trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+fn signed_cookie(key: &Key, cookie: Cookie<'static>) -> Cookie<'static> {
+    let name = cookie.name().to_owned();
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(cookie);
+    jar.get(&name).expect("The cookie was just added").clone()
+}
+
+fn verified_cookie(req: &HttpRequest, key: &Key, name: &str) -> Option<Cookie<'static>> {
+    let jar = CookieJar::new();
+    let signed = jar.signed(key);
+    req.cookies()
+        .ok()?
+        .iter()
+        .filter(|cookie| cookie.name() == name)
+        .find_map(|cookie| signed.verify(cookie.clone()))
+}
+
+/// Signed cookie keeping the user logged in until `expires`.
+pub fn session_cookie(key: &Key, user: &str, expires: DateTime<Utc>) -> Cookie<'static> {
+    let cookie = Cookie::build(SESSION_COOKIE, format!("{}:{}", expires.timestamp(), user))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(
+            (expires - Utc::now()).num_seconds(),
+        ))
+        .finish();
+    signed_cookie(key, cookie)
+}
+
+fn session_user(req: &HttpRequest, key: &Key, now: DateTime<Utc>) -> Option<String> {
+    let cookie = verified_cookie(req, key, SESSION_COOKIE)?;
+    let (expires, user) = cookie.value().split_once(':')?;
+    (expires.parse::<i64>().ok()? > now.timestamp()).then(|| user.to_owned())
+}
+
+/// Login started by redirecting to the provider, remembered in a signed cookie
+/// until the user comes back.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginState {
+    pub state: String,
+    pub nonce: String,
+    /// Where to go after the login
+    pub return_to: String,
+}
+
+impl LoginState {
+    pub fn new(return_to: String) -> Self {
+        LoginState {
+            state: random_token(),
+            nonce: random_token(),
+            return_to,
+        }
+    }
+
+    pub fn to_cookie(&self, key: &Key) -> Cookie<'static> {
+        let value = serde_json::to_string(self).expect("Serializing strings can't fail");
+        let cookie = Cookie::build(LOGIN_COOKIE, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(LOGIN_TIMEOUT))
+            .finish();
+        signed_cookie(key, cookie)
+    }
+
+    pub fn from_request(req: &HttpRequest, key: &Key) -> Option<Self> {
+        serde_json::from_str(verified_cookie(req, key, LOGIN_COOKIE)?.value()).ok()
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Part of the provider configuration that is used
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AuthorizationRequest<'a> {
+    response_type: &'static str,
+    client_id: &'a str,
+    redirect_uri: &'a str,
+    scope: &'a str,
+    state: &'a str,
+    nonce: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'static str,
+    code: &'a str,
+    redirect_uri: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::Single(audience) => audience == client_id,
+            Audience::Multiple(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: Option<bool>,
+}
+
+impl IdTokenClaims {
+    /// Verified e-mail address if the provider sent one, subject ID otherwise.
+    fn user(&self) -> &str {
+        match &self.email {
+            Some(email) if self.email_verified != Some(false) => email,
+            _ => &self.sub,
+        }
+    }
+}
+
+/// Check the claims of an ID token and return the user it identifies.
+fn verify_id_token(
+    id_token: &str,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+    now: DateTime<Utc>,
+) -> std::result::Result<String, &'static str> {
+    let payload = id_token.split('.').nth(1).ok_or("malformed ID token")?;
+    let payload = BASE64_URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|_| "malformed ID token")?;
+    let claims: IdTokenClaims =
+        serde_json::from_slice(&payload).map_err(|_| "malformed ID token claims")?;
+    if claims.iss != issuer {
+        return Err("wrong issuer");
+    }
+    if !claims.aud.contains(client_id) {
+        return Err("wrong audience");
+    }
+    if claims.exp <= now.timestamp() {
+        return Err("expired ID token");
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err("nonce mismatch");
+    }
+    Ok(claims.user().to_owned())
+}
+
+#[derive(Debug)]
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    /// Discovered on the first login
+    provider: OnceCell<ProviderMetadata>,
+}
+
+impl Oidc {
+    async fn provider(&self) -> Result<&ProviderMetadata> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if metadata.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/')
+                {
+                    return Err(FiledlError::LoginFailed {
+                        reason: format!("provider reports a different issuer {}", metadata.issuer),
+                    });
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// URL of the provider's login page for the login.
+    pub async fn authorization_url(&self, login: &LoginState) -> Result<String> {
+        let endpoint = &self.provider().await?.authorization_endpoint;
+        let query = serde_urlencoded::to_string(AuthorizationRequest {
+            response_type: "code",
+            client_id: &self.config.client_id,
+            redirect_uri: &self.config.redirect_url,
+            scope: &self.config.scopes,
+            state: &login.state,
+            nonce: &login.nonce,
+        })
+        .expect("Serializing strings can't fail");
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}{}", endpoint, separator, query))
+    }
+
+    /// Exchange the authorization code for an ID token and return the logged in user
+    /// together with the expiry of the session.
+    pub async fn finish_login(
+        &self,
+        login: &LoginState,
+        code: &str,
+    ) -> Result<(String, DateTime<Utc>)> {
+        let provider = self.provider().await?;
+        let response: TokenResponse = self
+            .client
+            .post(&provider.token_endpoint)
+            .form(&TokenRequest {
+                grant_type: "authorization_code",
+                code,
+                redirect_uri: &self.config.redirect_url,
+                client_id: &self.config.client_id,
+                client_secret: &self.config.client_secret,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let now = Utc::now();
+        let user = verify_id_token(
+            &response.id_token,
+            &provider.issuer,
+            &self.config.client_id,
+            &login.nonce,
+            now,
+        )
+        .map_err(|reason| FiledlError::LoginFailed {
+            reason: reason.to_owned(),
+        })?;
+        if !self.config.allowed_users.is_empty() && !self.config.allowed_users.contains(&user) {
+            return Err(FiledlError::LoginFailed {
+                reason: format!("user {} is not allowed", user),
+            });
+        }
+        // Clamped, chrono panics on durations that don't fit in milliseconds
+        let lifetime = Duration::seconds(self.config.session_lifetime.min(u32::MAX.into()) as i64);
+        Ok((user, now + lifetime))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use assert2::assert;
+    use serde_json::json;
+
+    fn id_token(claims: serde_json::Value) -> String {
+        format!(
+            "{}.{}.signature",
+            BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[test]
+    fn id_token_claims_are_checked() {
+        let now = Utc::now();
+        let claims = json!({
+            "iss": "https://id.example.com",
+            "sub": "1234",
+            "aud": ["filedl", "other"],
+            "exp": now.timestamp() + 60,
+            "nonce": "n0nce",
+            "email": "user@example.com",
+        });
+        let verify = |claims: &serde_json::Value| {
+            verify_id_token(
+                &id_token(claims.clone()),
+                "https://id.example.com",
+                "filedl",
+                "n0nce",
+                now,
+            )
+        };
+        assert!(verify(&claims) == Ok("user@example.com".to_owned()));
+
+        let mut unverified_email = claims.clone();
+        unverified_email["email_verified"] = json!(false);
+        assert!(verify(&unverified_email) == Ok("1234".to_owned()));
+
+        for (field, value) in [
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!("other")),
+            ("exp", json!(now.timestamp() - 1)),
+            ("nonce", json!("replayed")),
+        ] {
+            let mut wrong = claims.clone();
+            wrong[field] = value;
+            assert!(verify(&wrong).is_err());
+        }
+    }
+
+    #[test]
+    fn basic_credentials_are_checked() {
+        let auth = SiteAuth::Basic {
+            users: [("alice".to_owned(), "secret".to_owned())].into(),
+        };
+        let key = Key::generate();
+        let request = |credentials: &str| {
+            TestRequest::get()
+                .insert_header((
+                    header::AUTHORIZATION,
+                    format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+                ))
+                .to_http_request()
+        };
+        assert!(
+            auth.authenticated_user(&request("alice:secret"), &key) == Some("alice".to_owned())
+        );
+        assert!(auth
+            .authenticated_user(&request("alice:wrong"), &key)
+            .is_none());
+        assert!(auth
+            .authenticated_user(&request("bob:secret"), &key)
+            .is_none());
+        assert!(auth
+            .authenticated_user(&TestRequest::get().to_http_request(), &key)
+            .is_none());
+    }
+
+    #[test]
+    fn session_cookie_expires() {
+        let key = Key::generate();
+        let expires = Utc::now() + Duration::seconds(60);
+        let cookie = session_cookie(&key, "user@example.com", expires);
+        let req = TestRequest::get().cookie(cookie).to_http_request();
+        assert!(session_user(&req, &key, Utc::now()) == Some("user@example.com".to_owned()));
+        assert!(session_user(&req, &key, expires).is_none());
+        assert!(session_user(&req, &Key::generate(), Utc::now()).is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! SQLite backend of Storage, for instances with many objects.
+//!
+//! Each entry is a row holding its value serialized as JSON, so saving writes only
+//! the entries that changed instead of rewriting the whole content.
+
+use crate::storage::{Map, StorageBackend};
+use rusqlite::{params, Connection};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug)]
+pub struct SqliteBackend {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    /// Open the database, creating it if it doesn't exist.
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let connection = Connection::open(&path).map_err(to_io_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    value TEXT NOT NULL
+                ) WITHOUT ROWID;",
+            )
+            .map_err(to_io_error)?;
+        Ok(SqliteBackend {
+            path,
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> StorageBackend<T> for SqliteBackend {
+    fn location(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(&mut self) -> std::io::Result<Option<Map<T>>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT key, value FROM entries")
+            .map_err(to_io_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(to_io_error)?;
+        let mut map = Map::new();
+        for row in rows {
+            let (key, value) = row.map_err(to_io_error)?;
+            map.insert(key.into(), serde_json::from_str(&value)?);
+        }
+        Ok(Some(map))
+    }
+
+    /// Changed entries are written in a single transaction.
+    fn save(&mut self, map: &Map<T>, changed: &HashSet<Arc<str>>) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(to_io_error)?;
+        for key in changed {
+            match map.get(key) {
+                Some(value) => transaction.execute(
+                    "INSERT INTO entries (key, value) VALUES (?1, ?2)
+                     ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                    params![&**key, serde_json::to_string(value)?],
+                ),
+                None => transaction.execute("DELETE FROM entries WHERE key = ?1", params![&**key]),
+            }
+            .map_err(to_io_error)?;
+        }
+        transaction.commit().map_err(to_io_error)
+    }
+}
+
+fn to_io_error(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage;
+    use assert2::assert;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "filedl-storage-{:016x}.sqlite",
+            rand::random::<u64>()
+        ))
+    }
+
+    fn open(path: &Path) -> Storage<u32> {
+        Storage::with_backend(Box::new(SqliteBackend::open(path.to_owned()).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let path = temp_path();
+        let mut storage = open(&path);
+        storage.set("a".into(), 1);
+        storage.set("b".into(), 2);
+        storage.dump().unwrap();
+        storage.remove("a");
+        storage.set("c".into(), 3);
+        storage.dump().unwrap();
+
+        let storage = open(&path);
+        assert!(storage.len() == 2);
+        assert!(storage.get("a").is_none());
+        assert!(storage.get("b") == Some(&2));
+        assert!(storage.get("c") == Some(&3));
+
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_all_is_written() {
+        let path = temp_path();
+        let mut storage = open(&path);
+        storage
+            .update_all([("a".into(), Some(1)), ("b".into(), Some(2))])
+            .unwrap();
+        storage.update_all([("a".into(), None)]).unwrap();
+
+        let storage = open(&path);
+        assert!(storage.get("a").is_none());
+        assert!(storage.get("b") == Some(&2));
+
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
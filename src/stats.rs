@@ -0,0 +1,87 @@
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, sync::Mutex};
+
+/// Access statistics of objects, persisted in a JSON file.
+#[derive(Debug)]
+pub struct Stats {
+    objects: Mutex<Storage<ObjectStats>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ObjectStats {
+    /// Number of file downloads from the object
+    #[serde(default)]
+    pub downloads: u64,
+    /// Number of bytes of all responses for the object, including thumbnails and listings
+    #[serde(default)]
+    pub bytes_served: u64,
+    /// Time of the last request to the object
+    #[serde(default)]
+    pub last_access: Option<DateTime<Utc>>,
+}
+
+impl Stats {
+    pub fn new<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+        Ok(Stats {
+            objects: Mutex::new(Storage::new(path)?),
+        })
+    }
+
+    fn update(&self, object_id: &str, f: impl FnOnce(&mut ObjectStats)) {
+        let mut objects = self.objects.lock().unwrap();
+        let mut object_stats = objects.get(object_id).cloned().unwrap_or_default();
+        f(&mut object_stats);
+        objects.set(object_id.into(), object_stats);
+    }
+
+    /// Record a request to the object, `download` marks requests for file content.
+    pub fn record_access(&self, object_id: &str, download: bool) {
+        self.update(object_id, |object_stats| {
+            object_stats.last_access = Some(Utc::now());
+            if download {
+                object_stats.downloads += 1;
+            }
+        });
+    }
+
+    /// Record size of a response sent for the object.
+    pub fn record_bytes(&self, object_id: &str, bytes: u64) {
+        self.update(object_id, |object_stats| object_stats.bytes_served += bytes);
+    }
+
+    /// Write changed statistics to the file.
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.objects.lock().unwrap().flush()
+    }
+
+    /// Approximate memory used by the statistics, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(object_id, _)| object_id.len() + std::mem::size_of::<(Arc<str>, ObjectStats)>())
+            .sum()
+    }
+
+    pub fn get(&self, object_id: &str) -> ObjectStats {
+        self.objects
+            .lock()
+            .unwrap()
+            .get(object_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Statistics of all objects that were accessed.
+    pub fn all(&self) -> HashMap<Arc<str>, ObjectStats> {
+        self.objects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(object_id, object_stats)| (Arc::clone(object_id), object_stats.clone()))
+            .collect()
+    }
+}
@@ -1,19 +1,62 @@
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+pub type Map<T> = HashMap<Arc<str>, T>;
+
+/// Where the content of a Storage is persisted.
+pub trait StorageBackend<T>: Send + Sync + std::fmt::Debug {
+    /// Path of the file or database, for log messages.
+    fn location(&self) -> &Path;
+
+    /// Read the whole content, None if nothing was stored yet.
+    /// Backends may recover from damaged data, for example from a backup.
+    fn load(&mut self) -> std::io::Result<Option<Map<T>>>;
+
+    /// Read the whole content after an external change, without any recovery.
+    fn reload(&mut self) -> std::io::Result<Option<Map<T>>> {
+        self.load()
+    }
+
+    /// Persist the content. `changed` are the keys that were set or removed since
+    /// the last save, backends that can't write partially store the whole map.
+    fn save(&mut self, map: &Map<T>, changed: &HashSet<Arc<str>>) -> std::io::Result<()>;
+
+    /// Returns true once for each change made by someone else since the content was last
+    /// loaded or saved.
+    fn changed_externally(&mut self) -> bool {
+        false
+    }
+}
 
 #[derive(Debug)]
 pub struct Storage<T: Serialize + DeserializeOwned> {
-    map: HashMap<Arc<str>, T>,
-    file: PathBuf,
+    map: Map<T>,
+    backend: Box<dyn StorageBackend<T>>,
     dirty: bool,
+    /// Keys set or removed since the last write
+    changed: HashSet<Arc<str>>,
 }
 
 impl<T: Serialize + DeserializeOwned> Storage<T> {
+    /// Storage in a JSON file.
     pub fn new<P: Into<PathBuf>>(path: P) -> std::io::Result<Self> {
+        Self::with_backend(Box::new(JsonFile::new(path.into())))
+    }
+
+    pub fn with_backend(backend: Box<dyn StorageBackend<T>>) -> std::io::Result<Self> {
         let mut s = Storage {
             map: Default::default(),
-            file: path.into(),
+            backend,
             dirty: false,
+            changed: HashSet::new(),
         };
 
         s.reload()?;
@@ -26,38 +69,98 @@ impl<T: Serialize + DeserializeOwned> Storage<T> {
 
     pub fn set(&mut self, key: Arc<str>, value: T) -> Option<T> {
         self.dirty = true;
+        self.changed.insert(Arc::clone(&key));
         self.map.insert(key, value)
     }
 
     pub fn remove(&mut self, key: &str) -> Option<T> {
         self.dirty = true;
-        self.map.remove(key)
+        let (key, value) = self.map.remove_entry(key)?;
+        self.changed.insert(key);
+        Some(value)
     }
 
     /// Immediately (and unconditionally) dump the content to the file
     pub fn dump(&mut self) -> std::io::Result<()> {
-        let f = File::create(&self.file)?;
-        serde_json::to_writer(f, &self.map)?;
+        self.backend.save(&self.map, &self.changed)?;
+        self.dirty = false;
+        self.changed.clear();
+        Ok(())
+    }
+
+    /// Dump the content to the file if it changed since the last dump
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.dirty {
+            self.dump()?;
+        }
+        Ok(())
+    }
+
+    /// Set (Some) or remove (None) several values at once and immediately write the result
+    /// to the file. If writing fails, the content is left unchanged.
+    pub fn update_all<I>(&mut self, changes: I) -> std::io::Result<()>
+    where
+        T: Clone,
+        I: IntoIterator<Item = (Arc<str>, Option<T>)>,
+    {
+        let mut map = self.map.clone();
+        let mut changed = self.changed.clone();
+        for (key, value) in changes {
+            changed.insert(Arc::clone(&key));
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+
+        self.backend.save(&map, &changed)?;
+
+        self.map = map;
         self.dirty = false;
+        self.changed.clear();
         Ok(())
     }
 
+    /// Load the content from the backend, which may recover it from a backup if it is
+    /// damaged.
     pub fn reload(&mut self) -> std::io::Result<()> {
-        let file = match File::open(&self.file) {
-            Ok(file) => file,
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => return Ok(()),
-                _ => return Err(e),
-            },
+        let Some(map) = self.backend.load()? else {
+            return Ok(());
         };
-        let reader = BufReader::new(file);
-
-        // Read the JSON contents of the file as an instance of `User`.
-        self.map = serde_json::from_reader(reader)?;
+        self.map = map;
         self.dirty = false;
+        self.changed.clear();
         Ok(())
     }
 
+    /// Reload the content if the file was changed by someone else since it was last read
+    /// or written. Returns true if the content was reloaded.
+    ///
+    /// Changes that were not written yet take precedence over the external edit, the file
+    /// is overwritten with them and the edited version is kept as the `.bak` file.
+    /// A file that can't be parsed is left alone and the current content is kept.
+    pub fn reload_if_changed(&mut self) -> std::io::Result<bool> {
+        if !self.backend.changed_externally() {
+            return Ok(false);
+        }
+        if self.dirty {
+            log::warn!(
+                "{} was changed externally while there are unsaved changes, \
+                 overwriting it, the external version is kept in the .bak file",
+                self.backend.location().display()
+            );
+            // The whole content is written, not just the keys changed here
+            self.changed.extend(self.map.keys().cloned());
+            self.dump()?;
+            return Ok(false);
+        }
+        let Some(map) = self.backend.reload()? else {
+            return Ok(false);
+        };
+        self.map = map;
+        Ok(true)
+    }
+
     pub fn len(&self) -> usize {
         self.map.len()
     }
@@ -79,4 +182,248 @@ impl<T: Serialize + DeserializeOwned> Drop for Storage<T> {
     }
 }
 
+/// The whole content in a single JSON file, rewritten on every save.
+#[derive(Debug)]
+pub struct JsonFile {
+    path: PathBuf,
+    /// Modification time of the file when it was last read or written, to detect
+    /// external edits
+    modified: Option<SystemTime>,
+}
+
+impl JsonFile {
+    pub fn new(path: PathBuf) -> Self {
+        JsonFile {
+            path,
+            modified: None,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> StorageBackend<T> for JsonFile {
+    fn location(&self) -> &Path {
+        &self.path
+    }
+
+    /// Falls back to the backup of the previous version if the file is corrupted.
+    fn load(&mut self) -> std::io::Result<Option<Map<T>>> {
+        let modified = modified_time(&self.path);
+        let map = match read_map(&self.path) {
+            Ok(map) => map,
+            // Invalid JSON, or truncated
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                match read_map(&with_suffix(&self.path, ".bak")) {
+                    Ok(Some(map)) => {
+                        log::warn!(
+                            "{} is corrupted ({}), loaded the previous version from backup",
+                            self.path.display(),
+                            e
+                        );
+                        Some(map)
+                    }
+                    _ => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        self.modified = modified;
+        Ok(map)
+    }
+
+    fn reload(&mut self) -> std::io::Result<Option<Map<T>>> {
+        read_map(&self.path)
+    }
+
+    fn save(&mut self, map: &Map<T>, _changed: &HashSet<Arc<str>>) -> std::io::Result<()> {
+        write_atomic(&self.path, map)?;
+        self.modified = modified_time(&self.path);
+        Ok(())
+    }
+
+    fn changed_externally(&mut self) -> bool {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        // Only reported once per edit
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Read the map from a JSON file, None if the file doesn't exist.
+pub fn read_map<T: DeserializeOwned>(path: &Path) -> std::io::Result<Option<Map<T>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(serde_json::from_reader(BufReader::new(file))?))
+}
+
+/// Replace the file so that a crash leaves either the old or the new content, never
+/// a truncated file. The previous content is kept next to it with a `.bak` suffix.
+fn write_atomic<T: Serialize>(path: &Path, map: &Map<T>) -> std::io::Result<()> {
+    // In the same directory, so that the rename stays within one file system
+    let temp_path = with_suffix(path, ".tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    serde_json::to_writer(&mut writer, map)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    match std::fs::copy(path, with_suffix(path, ".bak")) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    std::fs::rename(&temp_path, path)?;
+
+    // Persist the rename itself
+    if let Some(directory) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(directory)?.sync_all()?;
+    }
+    Ok(())
+}
+
 pub type Iterator<'a, T> = std::collections::hash_map::Iter<'a, Arc<str>, T>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "filedl-storage-{:016x}.json",
+            rand::random::<u64>()
+        ))
+    }
+
+    /// Remove the storage file created by the test, with its temporary file and backup.
+    fn remove_files(path: &Path) {
+        for path in [
+            path.to_owned(),
+            with_suffix(path, ".tmp"),
+            with_suffix(path, ".bak"),
+        ] {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn dump_keeps_backup() {
+        let path = temp_path();
+        let mut storage = Storage::<u32>::new(&path).unwrap();
+        storage.set("a".into(), 1);
+        storage.dump().unwrap();
+        storage.set("a".into(), 2);
+        storage.dump().unwrap();
+
+        let backup = read_map::<u32>(&with_suffix(&path, ".bak"))
+            .unwrap()
+            .unwrap();
+        assert!(backup.get("a") == Some(&1));
+        assert!(!with_suffix(&path, ".tmp").exists());
+
+        remove_files(&path);
+    }
+
+    /// Replace the file as an external editor would, with a distinct modification time.
+    fn edit_externally(path: &Path, content: &str) {
+        std::fs::write(path, content).unwrap();
+        let modified = SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn reload_external_change() {
+        let path = temp_path();
+        let mut storage = Storage::<u32>::new(&path).unwrap();
+        storage.set("a".into(), 1);
+        storage.dump().unwrap();
+        assert!(!storage.reload_if_changed().unwrap());
+
+        edit_externally(&path, "{\"a\": 2}");
+        assert!(storage.reload_if_changed().unwrap());
+        assert!(storage.get("a") == Some(&2));
+        assert!(!storage.reload_if_changed().unwrap());
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn unsaved_changes_win_over_external_change() {
+        let path = temp_path();
+        let mut storage = Storage::<u32>::new(&path).unwrap();
+        storage.set("a".into(), 1);
+        storage.dump().unwrap();
+        storage.set("a".into(), 3);
+
+        edit_externally(&path, "{\"a\": 2}");
+        assert!(!storage.reload_if_changed().unwrap());
+        assert!(storage.get("a") == Some(&3));
+        assert!(!storage.is_dirty());
+        let backup = read_map::<u32>(&with_suffix(&path, ".bak"))
+            .unwrap()
+            .unwrap();
+        assert!(backup.get("a") == Some(&2));
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn invalid_external_change_is_ignored() {
+        let path = temp_path();
+        let mut storage = Storage::<u32>::new(&path).unwrap();
+        storage.set("a".into(), 1);
+        storage.dump().unwrap();
+
+        edit_externally(&path, "{\"a\": ");
+        assert!(let Err(_) = storage.reload_if_changed());
+        assert!(storage.get("a") == Some(&1));
+        assert!(!storage.reload_if_changed().unwrap());
+
+        remove_files(&path);
+    }
+
+    #[test]
+    fn corrupted_file_falls_back_to_backup() {
+        let path = temp_path();
+        let mut storage = Storage::<u32>::new(&path).unwrap();
+        storage.set("a".into(), 1);
+        storage.dump().unwrap();
+        storage.dump().unwrap();
+        std::fs::write(&path, "{\"a\": 1").unwrap();
+
+        let storage = Storage::<u32>::new(&path).unwrap();
+        assert!(storage.get("a") == Some(&1));
+
+        remove_files(&path);
+    }
+}
@@ -0,0 +1,385 @@
+//! Streaming TAR (and TAR.GZ) archives of directories.
+//!
+//! Archives use the GNU tar format, long names and link targets are stored
+//! in GNU long name entries and large sizes in base-256 encoding.
+
+use actix_web::web::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{stream, Stream, StreamExt};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::{fs::File, io::AsyncReadExt, task::spawn_blocking};
+
+const BLOCK_SIZE: usize = 512;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum EntryKind {
+    Directory,
+    File { size: u64 },
+    Symlink { target: String },
+}
+
+/// File system entry that will be stored in the archive.
+#[derive(Clone, Debug)]
+struct TarEntry {
+    /// Path on the file system
+    path: PathBuf,
+    /// Name inside the archive, using `/` as a separator
+    name: String,
+    kind: EntryKind,
+    mode: u32,
+    mtime: u64,
+}
+
+/// Walk the directory and collect entries of the archive.
+/// Entries are named relative to the parent of `root`, so that the archive extracts
+/// into a single directory. Entries with non-unicode names are skipped.
+fn collect_entries(root: &Path) -> io::Result<Vec<TarEntry>> {
+    let base = root.parent().unwrap_or(root);
+    let mut entries = Vec::new();
+
+    for dir_entry in walkdir::WalkDir::new(root)
+        .follow_links(false)
+        .sort_by_file_name()
+    {
+        let dir_entry = dir_entry?;
+        let Some(name) = dir_entry
+            .path()
+            .strip_prefix(base)
+            .ok()
+            .and_then(Path::to_str)
+        else {
+            continue;
+        };
+        let metadata = dir_entry.metadata()?;
+        let file_type = dir_entry.file_type();
+
+        let (name, kind) = if file_type.is_dir() {
+            (format!("{}/", name), EntryKind::Directory)
+        } else if file_type.is_symlink() {
+            let Some(target) = std::fs::read_link(dir_entry.path())?
+                .to_str()
+                .map(str::to_owned)
+            else {
+                continue;
+            };
+            (name.to_owned(), EntryKind::Symlink { target })
+        } else if file_type.is_file() {
+            let size = metadata.len();
+            (name.to_owned(), EntryKind::File { size })
+        } else {
+            // Sockets, devices, ...
+            continue;
+        };
+
+        entries.push(TarEntry {
+            path: dir_entry.into_path(),
+            name,
+            mode: file_mode(&metadata, &kind),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_secs()),
+            kind,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata, _kind: &EntryKind) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata, kind: &EntryKind) -> u32 {
+    match kind {
+        EntryKind::File { .. } => 0o644,
+        _ => 0o755,
+    }
+}
+
+/// Write a numeric header field as zero padded octal, or in GNU base-256 encoding
+/// if the value doesn't fit.
+fn numeric_field(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits >= 22 || value < (1 << (3 * digits)) {
+        let formatted = format!("{:0width$o}\0", value, width = digits);
+        field.copy_from_slice(formatted.as_bytes());
+    } else {
+        field.fill(0);
+        let bytes = value.to_be_bytes();
+        let len = field.len();
+        field[len - bytes.len()..].copy_from_slice(&bytes);
+        field[0] = 0x80;
+    }
+}
+
+/// Create a single 512 byte header block.
+fn header_block(
+    name: &[u8],
+    typeflag: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    link: &[u8],
+) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    numeric_field(&mut block[100..108], mode.into());
+    numeric_field(&mut block[108..116], 0);
+    numeric_field(&mut block[116..124], 0);
+    numeric_field(&mut block[124..136], size);
+    numeric_field(&mut block[136..148], mtime);
+    block[156] = typeflag;
+    block[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+    // GNU magic and version
+    block[257..265].copy_from_slice(b"ustar  \0");
+
+    // Checksum is calculated with the checksum field filled with spaces
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    block
+}
+
+/// GNU long name (`L`) or long link (`K`) entry carrying a value that doesn't fit
+/// into the 100 byte header field.
+fn long_name_entry(typeflag: u8, value: &[u8], output: &mut Vec<u8>) {
+    let mut data = value.to_vec();
+    data.push(0);
+    output.extend_from_slice(&header_block(
+        b"././@LongLink",
+        typeflag,
+        0o644,
+        data.len() as u64,
+        0,
+        b"",
+    ));
+    output.extend_from_slice(&data);
+    output.resize(output.len() + padding(data.len() as u64), 0);
+}
+
+/// Serialize all headers of the entry.
+fn entry_headers(entry: &TarEntry) -> Vec<u8> {
+    let mut output = Vec::with_capacity(BLOCK_SIZE);
+    let name = entry.name.as_bytes();
+    if name.len() > 100 {
+        long_name_entry(b'L', name, &mut output);
+    }
+
+    let (typeflag, size, link) = match &entry.kind {
+        EntryKind::Directory => (b'5', 0, &b""[..]),
+        EntryKind::File { size } => (b'0', *size, &b""[..]),
+        EntryKind::Symlink { target } => (b'2', 0, target.as_bytes()),
+    };
+    if link.len() > 100 {
+        long_name_entry(b'K', link, &mut output);
+    }
+
+    output.extend_from_slice(&header_block(
+        name,
+        typeflag,
+        entry.mode,
+        size,
+        entry.mtime,
+        link,
+    ));
+    output
+}
+
+/// Number of zero bytes needed to pad data of this size to the block size.
+fn padding(size: u64) -> usize {
+    (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE
+}
+
+struct StreamState {
+    entries: VecDeque<TarEntry>,
+    /// File whose data is being streamed, number of bytes remaining and size of the padding
+    current: Option<(File, u64, usize)>,
+    finished: bool,
+}
+
+impl StreamState {
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if let Some((file, remaining, pad)) = &mut self.current {
+            if *remaining == 0 {
+                let chunk = Bytes::from(vec![0; *pad]);
+                self.current = None;
+                return Ok(Some(chunk));
+            }
+
+            let mut buffer = vec![0; READ_BUFFER_SIZE.min(*remaining as usize)];
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "File was truncated while it was being archived",
+                ));
+            }
+            buffer.truncate(read);
+            *remaining -= read as u64;
+            return Ok(Some(buffer.into()));
+        }
+
+        let Some(entry) = self.entries.pop_front() else {
+            if self.finished {
+                return Ok(None);
+            }
+            self.finished = true;
+            // End of archive marker
+            return Ok(Some(Bytes::from(vec![0; 2 * BLOCK_SIZE])));
+        };
+
+        if let EntryKind::File { size } = entry.kind {
+            self.current = Some((File::open(&entry.path).await?, size, padding(size)));
+        }
+        Ok(Some(entry_headers(&entry).into()))
+    }
+}
+
+/// Stream TAR archive of the directory.
+pub async fn tar_stream(root: PathBuf) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    tar_stream_paths(vec![root]).await
+}
+
+/// Stream TAR archive of several files or directories.
+/// Each is named relative to its own parent.
+pub async fn tar_stream_paths(
+    roots: Vec<PathBuf>,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let entries = spawn_blocking(move || {
+        let mut entries = Vec::new();
+        for root in roots {
+            entries.extend(collect_entries(&root)?);
+        }
+        io::Result::Ok(entries)
+    })
+    .await
+    .expect("Walking the directory should not panic")?;
+
+    let state = StreamState {
+        entries: entries.into(),
+        current: None,
+        finished: false,
+    };
+
+    Ok(stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        match state.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(state))),
+            Ok(None) => None,
+            // Stop after the first error
+            Err(e) => Some((Err(e), None)),
+        }
+    }))
+}
+
+/// Compress the stream with gzip.
+pub fn gzip_stream<S>(inner: S) -> impl Stream<Item = io::Result<Bytes>>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    stream::unfold(Some((inner, encoder)), |state| async move {
+        let (mut inner, mut encoder) = state?;
+        loop {
+            match inner.next().await {
+                Some(Ok(chunk)) => {
+                    if let Err(e) = encoder.write_all(&chunk) {
+                        return Some((Err(e), None));
+                    }
+                    let compressed = std::mem::take(encoder.get_mut());
+                    if !compressed.is_empty() {
+                        return Some((Ok(compressed.into()), Some((inner, encoder))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    return match encoder.finish() {
+                        Ok(compressed) => Some((Ok(compressed.into()), None)),
+                        Err(e) => Some((Err(e), None)),
+                    };
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use test_strategy::proptest;
+
+    fn file_entry(name: &str, size: u64) -> TarEntry {
+        TarEntry {
+            path: PathBuf::new(),
+            name: name.to_owned(),
+            kind: EntryKind::File { size },
+            mode: 0o644,
+            mtime: 1700000000,
+        }
+    }
+
+    #[test]
+    fn numeric_field_octal() {
+        let mut field = [0u8; 12];
+        numeric_field(&mut field, 0o1234);
+        assert!(&field == b"00000001234\0");
+    }
+
+    #[test]
+    fn numeric_field_base256() {
+        let mut field = [0u8; 12];
+        let size = 10 * 1024 * 1024 * 1024;
+        numeric_field(&mut field, size);
+        assert!(field[0] == 0x80);
+        assert!(field[4..] == size.to_be_bytes());
+    }
+
+    #[test]
+    fn header_checksum() {
+        let header = entry_headers(&file_entry("dir/file.txt", 5));
+        assert!(header.len() == BLOCK_SIZE);
+
+        let stored = std::str::from_utf8(&header[148..154]).unwrap();
+        let stored = u32::from_str_radix(stored, 8).unwrap();
+        let computed: u32 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+            .map(u32::from)
+            .sum();
+        assert!(stored == computed);
+        assert!(&header[0..12] == b"dir/file.txt");
+        assert!(header[156] == b'0');
+    }
+
+    #[test]
+    fn long_names() {
+        let name = "a/".repeat(80) + "file";
+        let header = entry_headers(&file_entry(&name, 0));
+        // Long name header, one block of the name, the entry itself
+        assert!(header.len() == 3 * BLOCK_SIZE);
+        assert!(header[156] == b'L');
+        assert!(&header[BLOCK_SIZE..BLOCK_SIZE + name.len()] == name.as_bytes());
+        assert!(header[2 * BLOCK_SIZE + 156] == b'0');
+    }
+
+    #[proptest]
+    fn padding_aligns(size: u64) {
+        let padded = size as u128 + padding(size) as u128;
+        assert!(padded % BLOCK_SIZE as u128 == 0);
+        assert!(padding(size) < BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,102 @@
+use super::{nav::Nav, page::Page, util::FormatedIsoTimestamp};
+use chrono_tz::Tz;
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+use humansize::{format_size, BINARY};
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{app_data::AppData, stats::ObjectStats};
+
+/// Administration overview with download statistics of objects.
+pub struct Admin<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    display_timezone: &'a Tz,
+    /// Statistics sorted by object ID
+    stats: Vec<(Arc<str>, ObjectStats)>,
+}
+
+impl<'a> Admin<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        stats: HashMap<Arc<str>, ObjectStats>,
+    ) -> Page<'a, Title<'a>, Admin<'a>> {
+        let mut stats: Vec<_> = stats.into_iter().collect();
+        stats.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let content = Admin {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+            stats,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: content.app_name,
+            },
+            content,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for Admin<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: "",
+            is_unlisted: false,
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                @ if self.stats.is_empty() {
+                    div(class = "message"): "No objects were accessed yet";
+                } else {
+                    table(class = "stats") {
+                        thead {
+                            tr {
+                                th: "Object";
+                                th: "Downloads";
+                                th: "Transferred";
+                                th: "Last access";
+                            }
+                        }
+                        tbody {
+                            @ for (object_id, object_stats) in self.stats.iter() {
+                                tr {
+                                    td: object_id.as_ref();
+                                    td: format_args!("{}", object_stats.downloads);
+                                    td: format_size(object_stats.bytes_served, BINARY);
+                                    td {
+                                        @ if let Some(last_access) = object_stats.last_access {
+                                            : FormatedIsoTimestamp(
+                                                last_access.with_timezone(self.display_timezone)
+                                            );
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("Administration - {}", self.app_name);
+    }
+}
@@ -1,151 +1,734 @@
-use std::fmt::{Display, Write};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    sync::Arc,
+};
 
 use super::{
-    breadcrumbs::BreadcrumbsIterator,
+    nav::Nav,
     page::Page,
-    util::{url_encode, FormatedIsoTimestamp},
+    util::{format_relative, url_encode, FormatedIsoTimestamp, FormatedTimestamp},
     AssetUrl,
 };
+use chrono::{DateTime, Duration, Utc};
 use chrono_tz::Tz;
-use horrorshow::{html, labels_sep_by, RenderOnce, TemplateBuffer};
+use horrorshow::{html, labels_sep_by, Raw, RenderOnce, Template as _, TemplateBuffer};
 use humansize::{format_size, BINARY};
+use serde::Deserialize;
 
-use crate::app_data::{AppData, DirListingItem, ItemType};
+use crate::{
+    app_data::{AppData, DirListingItem, ItemType},
+    glob::glob_match,
+};
 
 pub struct DirListing<'a> {
     app_name: &'a str,
     download_base_url: &'a str,
     display_timezone: &'a Tz,
+    now: DateTime<Utc>,
+    relative_time_cutoff: Duration,
     directory_path: &'a str,
-    static_content_hash: &'a str,
+    asset_fingerprints: bool,
     is_unlisted: bool,
+    expires: Option<DateTime<Utc>>,
+    view_mode: ViewMode,
+    options: ListingOptions,
+    pagination: Option<Pagination>,
+    video_thumbnails: bool,
+    document_thumbnails: bool,
+    inline_icons: bool,
+    /// Full-text search is enabled
+    search: bool,
+    /// Items are not known yet, ITEMS_MARKER is rendered in their place
+    streaming: bool,
     items: Vec<DirListingItem>,
+    /// Tags of all items before filtering, offered as filters
+    tags: Vec<Arc<str>>,
+    /// Grouping of the root listing into sections
+    grouping: RootGrouping,
+    root_group_separator: char,
+}
+
+/// Placeholder for the items of a streamed listing, the rendered page is split on it.
+pub const ITEMS_MARKER: &str = "<!-- filedl-items -->";
+
+/// Sections of the root listing.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootGrouping {
+    /// All objects in a single list
+    #[default]
+    None,
+    /// A section for each tag, objects with several tags appear in each of them
+    Tag,
+    /// A section for each part of the object ID before the separator
+    Prefix,
+}
+
+/// Layout of the directory listing.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ViewMode {
+    /// List with all details
+    #[default]
+    List,
+    /// Single line per item, without timestamps
+    Compact,
+    /// Grid of large thumbnails
+    Grid,
+}
+
+impl ViewMode {
+    const ALL: [ViewMode; 3] = [ViewMode::List, ViewMode::Compact, ViewMode::Grid];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ViewMode::List => "list",
+            ViewMode::Compact => "compact",
+            ViewMode::Grid => "grid",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ViewMode::List => "List",
+            ViewMode::Compact => "Compact",
+            ViewMode::Grid => "Grid",
+        }
+    }
+}
+
+/// Item property the listing is sorted by.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    /// Modification time
+    Mtime,
+}
+
+impl SortKey {
+    const ALL: [SortKey; 3] = [SortKey::Name, SortKey::Size, SortKey::Mtime];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            SortKey::Name => "Name",
+            SortKey::Size => "Size",
+            SortKey::Mtime => "Modified",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+
+    fn reversed(&self) -> Self {
+        match self {
+            SortOrder::Asc => SortOrder::Desc,
+            SortOrder::Desc => SortOrder::Asc,
+        }
+    }
+}
+
+/// Sorting and filtering of the listing selected by the visitor.
+#[derive(Clone, Debug, Default)]
+pub struct ListingOptions {
+    pub sort: SortKey,
+    pub order: SortOrder,
+    /// Glob pattern the item names must match
+    pub filter: Option<String>,
+    /// Tag the items must have, only objects in the root listing have tags
+    pub tag: Option<String>,
+}
+
+impl ListingOptions {
+    pub fn matches(&self, item: &DirListingItem) -> bool {
+        self.filter
+            .as_deref()
+            .map_or(true, |pattern| glob_match(pattern, &item.name))
+            && self.tag.as_deref().map_or(true, |tag| {
+                item.tags.iter().any(|item_tag| &**item_tag == tag)
+            })
+    }
+
+    /// Filter and sort the items.
+    pub fn apply(&self, items: &mut Vec<DirListingItem>) {
+        items.retain(|item| self.matches(item));
+        self.sort(items);
+    }
+
+    fn sort(&self, items: &mut [DirListingItem]) {
+        let mut collator = feruca::Collator::default();
+        items.sort_unstable_by(|a, b| {
+            let ordering = match self.sort {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.file_size.cmp(&b.file_size),
+                SortKey::Mtime => a.modified.cmp(&b.modified),
+            }
+            .then_with(|| collator.collate(a.name.as_bytes(), b.name.as_bytes()));
+            match self.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+    }
+
+    /// Query string parameters that differ from the defaults, each prefixed with `&`.
+    fn query_params(&self) -> String {
+        let mut params = String::new();
+        if self.sort != SortKey::default() {
+            write!(params, "&sort={}", self.sort.as_str()).unwrap();
+        }
+        if self.order != SortOrder::default() {
+            write!(params, "&order={}", self.order.as_str()).unwrap();
+        }
+        if let Some(filter) = &self.filter {
+            write!(params, "&filter={}", url_encode(filter)).unwrap();
+        }
+        if let Some(tag) = &self.tag {
+            write!(params, "&tag={}", url_encode(tag)).unwrap();
+        }
+        params
+    }
+}
+
+/// Position of the displayed items in a paginated listing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pagination {
+    /// One based page number
+    pub page: usize,
+    pub per_page: usize,
+    /// Number of items on all pages, after filtering
+    pub total_items: usize,
+}
+
+impl Pagination {
+    pub fn page_count(&self) -> usize {
+        self.total_items.div_ceil(self.per_page).max(1)
+    }
 }
 
 impl<'a> DirListing<'a> {
+    /// Items must already be filtered and sorted by `options`, and limited to the page.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_wrapped(
         app: &'a AppData,
         directory_path: &'a str,
         unlisted: bool,
-        mut items: Vec<DirListingItem>,
+        expires: Option<DateTime<Utc>>,
+        view_mode: ViewMode,
+        options: ListingOptions,
+        pagination: Option<Pagination>,
+        display_timezone: &'a Tz,
+        items: Vec<DirListingItem>,
+        tags: Vec<Arc<str>>,
     ) -> Page<'a, Title<'a>, DirListing<'a>> {
-        let mut collator = feruca::Collator::default();
-        items.sort_unstable_by(|a, b| collator.collate(a.name.as_bytes(), b.name.as_bytes()));
+        let dir_listing = Self::new(app, directory_path, view_mode, display_timezone);
+        Self::wrap(
+            app,
+            DirListing {
+                is_unlisted: unlisted,
+                expires,
+                options,
+                pagination,
+                items,
+                tags,
+                ..dir_listing
+            },
+        )
+    }
 
-        let dir_listing = DirListing {
+    /// Page of a listing whose items are streamed separately.
+    /// The rendered page contains ITEMS_MARKER where the items belong.
+    /// Streamed items can't be sorted, only the filter of `options` is used,
+    /// by the caller.
+    pub fn new_streaming_wrapped(
+        app: &'a AppData,
+        directory_path: &'a str,
+        unlisted: bool,
+        expires: Option<DateTime<Utc>>,
+        view_mode: ViewMode,
+        options: ListingOptions,
+        display_timezone: &'a Tz,
+    ) -> Page<'a, Title<'a>, DirListing<'a>> {
+        let dir_listing = Self::new(app, directory_path, view_mode, display_timezone);
+        Self::wrap(
+            app,
+            DirListing {
+                is_unlisted: unlisted,
+                expires,
+                options,
+                streaming: true,
+                ..dir_listing
+            },
+        )
+    }
+
+    /// Render a single item of a streamed listing.
+    pub fn render_streamed_item(
+        app: &'a AppData,
+        directory_path: &'a str,
+        view_mode: ViewMode,
+        display_timezone: &'a Tz,
+        item: &DirListingItem,
+    ) -> Result<String, horrorshow::Error> {
+        let dir_listing = Self::new(app, directory_path, view_mode, display_timezone);
+        html!(|tmpl| dir_listing.render_item(tmpl, item)).into_string()
+    }
+
+    fn new(
+        app: &'a AppData,
+        directory_path: &'a str,
+        view_mode: ViewMode,
+        display_timezone: &'a Tz,
+    ) -> Self {
+        DirListing {
             app_name: app.get_app_name(),
             download_base_url: app.get_download_base_url(),
-            display_timezone: app.get_display_timezone(),
+            display_timezone,
+            now: Utc::now(),
+            relative_time_cutoff: app.get_relative_time_cutoff(),
             directory_path,
-            static_content_hash: app.get_static_content_hash(),
-            is_unlisted: unlisted,
-            items,
-        };
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            is_unlisted: false,
+            expires: None,
+            view_mode,
+            options: ListingOptions::default(),
+            pagination: None,
+            video_thumbnails: app.video_thumbnails_enabled(),
+            document_thumbnails: app.document_thumbnails_enabled(),
+            inline_icons: app.inline_icons_enabled(),
+            search: app.search_enabled(),
+            streaming: false,
+            items: Vec::new(),
+            tags: Vec::new(),
+            grouping: if directory_path.is_empty() {
+                app.get_root_grouping()
+            } else {
+                RootGrouping::None
+            },
+            root_group_separator: app.get_root_group_separator(),
+        }
+    }
+
+    fn wrap(app: &'a AppData, dir_listing: Self) -> Page<'a, Title<'a>, DirListing<'a>> {
+        let display_timezone = dir_listing.display_timezone;
         Page {
             download_base_url: app.get_download_base_url(),
             title: Title::new(&dir_listing),
             content: dir_listing,
-            static_content_hash: app.get_static_content_hash(),
-            display_timezone: app.get_display_timezone(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone,
         }
     }
-    fn render_breadcrumbs(&self, tmpl: &mut TemplateBuffer<'_>) {
-        tmpl << html!(
-            @ for crumb in BreadcrumbsIterator::new(self.directory_path) {
-                : "/";
-                a(href = format_args!("{}/{}", self.download_base_url, url_encode(crumb.link_url))): crumb.name;
-            }
-        );
-    }
 
     fn render_item(&self, tmpl: &mut TemplateBuffer<'_>, item: &DirListingItem) {
+        // The compact visual details are hidden from screen readers, they get this instead
+        let description = item_description(item, self.display_timezone);
+        match self.view_mode {
+            ViewMode::List => self.render_list_item(tmpl, item, &description),
+            ViewMode::Compact => self.render_compact_item(tmpl, item, &description),
+            ViewMode::Grid => self.render_grid_item(tmpl, item, &description),
+        }
+    }
+
+    fn render_list_item(
+        &self,
+        tmpl: &mut TemplateBuffer<'_>,
+        item: &DirListingItem,
+        description: &str,
+    ) {
         let url = ItemUrl::new(self, item);
         tmpl << html!(
             li(class = format!("{}", item.item_type)) {
-                a(class = "main-link", href = url.clone()) {
-                    @ if item.item_type.is_thumbnailable() {
-                        img(
-                            class = "thumbnail",
-                            src = url.thumbnail(64, None),
-                            srcset = labels_sep_by!(
-                                ",";
-                                format_args!("{} {}w", url.thumbnail(64, None), 64),
-                                format_args!("{} {}w", url.thumbnail(128, None), 128),
-                                format_args!("{} {}w", url.thumbnail(256, None), 256)
-                            ),
-                            sizes = "4em",
-                            loading = "lazy"
-                        );
-                    }
-                    @ if !item.item_type.is_thumbnailable() {
-                        img(
-                            class = "thumbnail",
-                            src = match item.item_type {
-                                ItemType::Image => self.asset_url("image.svg"),
-                                ItemType::Directory => self.asset_url("directory.svg"),
-                                _ => self.asset_url("file.svg"),
-                            }
-                        );
-                    }
-                    span(class = "underlined") {
-                        : item.name.as_ref();
-                        @ if item.item_type.is_directory() {
-                            : "/";
-                        }
-                    }
+                a(class = "main-link", href = self.main_link(&url, item)) {
+                    |tmpl| self.render_thumbnail(tmpl, item, &url, "4em");
+                    |tmpl| self.render_name(tmpl, item);
+                    span(class = "visually-hidden"): description;
                 }
                 div(class = "details1") {
-                    div(class = "details2") {
+                    div(class = "details2", aria-hidden = "true") {
                         @ if !item.item_type.is_directory() {
                             span(class="size") {
                                 : format_size(item.file_size, BINARY)
                             }
                         }
                         @ if let Some(modified) = item.modified {
-                            : FormatedIsoTimestamp(modified.with_timezone(self.display_timezone))
+                            : self.formated_timestamp(modified)
+                        }
+                        @ if !item.tags.is_empty() {
+                            span(class = "tags"): item.tags.join(", ");
+                        }
+                    }
+                    |tmpl| self.render_download_link(tmpl, &url);
+                }
+            }
+        )
+    }
+
+    fn render_compact_item(
+        &self,
+        tmpl: &mut TemplateBuffer<'_>,
+        item: &DirListingItem,
+        description: &str,
+    ) {
+        let url = ItemUrl::new(self, item);
+        tmpl << html!(
+            li(class = format!("{}", item.item_type)) {
+                a(class = "main-link", href = self.main_link(&url, item)) {
+                    |tmpl| self.render_thumbnail(tmpl, item, &url, "1.5em");
+                    |tmpl| self.render_name(tmpl, item);
+                    span(class = "visually-hidden"): description;
+                }
+                @ if !item.item_type.is_directory() {
+                    span(class="size", aria-hidden = "true") {
+                        : format_size(item.file_size, BINARY)
+                    }
+                }
+                |tmpl| self.render_download_link(tmpl, &url);
+            }
+        )
+    }
+
+    fn render_grid_item(
+        &self,
+        tmpl: &mut TemplateBuffer<'_>,
+        item: &DirListingItem,
+        description: &str,
+    ) {
+        let url = ItemUrl::new(self, item);
+        tmpl << html!(
+            li(class = format!("{}", item.item_type)) {
+                a(class = "main-link", href = self.main_link(&url, item)) {
+                    |tmpl| self.render_thumbnail(tmpl, item, &url, "8em");
+                    |tmpl| self.render_name(tmpl, item);
+                    span(class = "visually-hidden"): description;
+                }
+                |tmpl| self.render_download_link(tmpl, &url);
+            }
+        )
+    }
+
+    fn render_thumbnail(
+        &self,
+        tmpl: &mut TemplateBuffer<'_>,
+        item: &DirListingItem,
+        url: &ItemUrl<'_>,
+        sizes: &str,
+    ) {
+        let has_thumbnail = item.item_type.is_thumbnailable()
+            || (item.item_type.is_video() && self.video_thumbnails)
+            || (item.item_type.is_document() && self.document_thumbnails);
+        // Used by the gallery to reserve space for the image before it loads
+        let dimensions = item
+            .dimensions
+            .map(|(width, height)| format!("{}x{}", width, height))
+            .unwrap_or_default();
+        // Videos and documents can't be shown in the gallery, it displays the largest
+        // thumbnail instead
+        let preview = if !item.item_type.is_thumbnailable() && has_thumbnail {
+            url.thumbnail(256, None).to_string()
+        } else {
+            String::new()
+        };
+        tmpl << html!(
+            @ if has_thumbnail {
+                img(
+                    class = "thumbnail",
+                    data-dimensions = dimensions,
+                    data-preview = preview,
+                    src = url.thumbnail(64, None),
+                    srcset = labels_sep_by!(
+                        ",";
+                        format_args!("{} {}w", url.thumbnail(64, None), 64),
+                        format_args!("{} {}w", url.thumbnail(128, None), 128),
+                        format_args!("{} {}w", url.thumbnail(256, None), 256)
+                    ),
+                    sizes = sizes,
+                    loading = "lazy"
+                );
+            }
+            @ if !has_thumbnail {
+                img(
+                    class = "thumbnail",
+                    src = match item.item_type {
+                        ItemType::Image => self.asset_url("image.svg"),
+                        ItemType::Directory => self.asset_url("directory.svg"),
+                        ItemType::Video => self.asset_url("video.svg"),
+                        ItemType::Audio => self.asset_url("audio.svg"),
+                        ItemType::Document => self.asset_url("document.svg"),
+                        _ => self.asset_url("file.svg"),
+                    }
+                );
+            }
+        )
+    }
+
+    fn render_name(&self, tmpl: &mut TemplateBuffer<'_>, item: &DirListingItem) {
+        tmpl << html!(
+            span(class = "underlined") {
+                : item.name.as_ref();
+                @ if item.item_type.is_directory() {
+                    : "/";
+                }
+            }
+        )
+    }
+
+    /// Media files link to their player page, everything else to the item itself.
+    fn main_link(&self, url: &ItemUrl<'_>, item: &DirListingItem) -> String {
+        if item.item_type.is_media() {
+            format!("{}{}mode=preview", url, url.next_qs_separator())
+        } else {
+            url.to_string()
+        }
+    }
+
+    fn render_download_link(&self, tmpl: &mut TemplateBuffer<'_>, url: &ItemUrl<'_>) {
+        tmpl << html!(
+            a(class = "download", href = format_args!("{}{}mode=download", url, url.next_qs_separator())) {
+                img(src = self.asset_url("download.svg"), alt = "Download", title = "Download");
+            }
+        )
+    }
+
+    fn render_items(&self, tmpl: &mut TemplateBuffer<'_>) {
+        let class = format!("dir-listing {}", self.view_mode.as_str());
+        if self.grouping == RootGrouping::None || self.streaming {
+            tmpl << html!(
+                ul(class = &class) {
+                    @ for item in self.items.iter() {
+                        |tmpl| self.render_item(tmpl, item)
+                    }
+                    @ if self.streaming {
+                        : Raw(ITEMS_MARKER);
+                    }
+                }
+            );
+            return;
+        }
+        let groups = group_items(&self.items, self.grouping, self.root_group_separator);
+        tmpl << html!(
+            @ for (name, items) in groups {
+                details(class = "group", open) {
+                    summary {
+                        : name.unwrap_or("Other");
+                        span(class = "count"): format_args!(" ({})", items.len());
+                    }
+                    ul(class = &class) {
+                        @ for item in items {
+                            |tmpl| self.render_item(tmpl, item)
                         }
                     }
-                    a(class = "download", href = format_args!("{}{}mode=download", url, url.next_qs_separator())) {
-                        img(src = self.asset_url("download.svg"), alt = "Download", title = "Download");
+                }
+            }
+        )
+    }
+
+    fn render_view_modes(&self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << html!(
+            div(class = "view-modes") {
+                @ for view_mode in ViewMode::ALL {
+                    @ if view_mode == self.view_mode {
+                        span(class = "selected"): view_mode.label();
+                    } else {
+                        a(href = format_args!("?view={}{}", view_mode.as_str(), self.options.query_params())): view_mode.label();
                     }
                 }
             }
         )
     }
 
+    fn render_listing_options(&self, tmpl: &mut TemplateBuffer<'_>) {
+        let filter = self.options.filter.as_deref().unwrap_or_default();
+        tmpl << html!(
+            div(class = "listing-options") {
+                @ if !self.streaming {
+                    div(class = "sort") {
+                        : "Sort by";
+                        @ for sort in SortKey::ALL {
+                            |tmpl| self.render_sort_link(tmpl, sort);
+                        }
+                    }
+                }
+                form(method = "get", action = "", class = "filter") {
+                    @ if self.options.sort != SortKey::default() {
+                        input(type = "hidden", name = "sort", value = self.options.sort.as_str());
+                    }
+                    @ if self.options.order != SortOrder::default() {
+                        input(type = "hidden", name = "order", value = self.options.order.as_str());
+                    }
+                    input(type = "search", name = "filter", value = filter, placeholder = "Filter, e.g. *.jpg");
+                }
+                @ if !self.tags.is_empty() {
+                    div(class = "tag-filter") {
+                        : "Tags";
+                        |tmpl| self.render_tag_link(tmpl, None);
+                        @ for tag in self.tags.iter() {
+                            |tmpl| self.render_tag_link(tmpl, Some(tag));
+                        }
+                    }
+                }
+                @ if self.search && !self.is_unlisted {
+                    form(method = "get", action = "", class = "search") {
+                        input(type = "hidden", name = "mode", value = "search");
+                        input(type = "search", name = "q", placeholder = "Search in files");
+                    }
+                }
+            }
+        )
+    }
+
+    fn render_pagination(&self, tmpl: &mut TemplateBuffer<'_>, pagination: Pagination) {
+        let page_url = |page: usize| {
+            format!(
+                "?page={}&per_page={}{}",
+                page,
+                pagination.per_page,
+                self.options.query_params()
+            )
+        };
+        let page_count = pagination.page_count();
+        tmpl << html!(
+            div(class = "pagination") {
+                @ if pagination.page > 1 {
+                    a(href = page_url(pagination.page.min(page_count + 1) - 1)): "\u{ab} Previous";
+                }
+                span: format_args!("Page {} of {}", pagination.page, page_count);
+                @ if pagination.page < page_count {
+                    a(href = page_url(pagination.page + 1)): "Next \u{bb}";
+                }
+            }
+        )
+    }
+
+    /// Link sorting by the key, the currently used key toggles the order.
+    fn render_sort_link(&self, tmpl: &mut TemplateBuffer<'_>, sort: SortKey) {
+        let selected = sort == self.options.sort;
+        let options = ListingOptions {
+            sort,
+            order: if selected {
+                self.options.order.reversed()
+            } else {
+                SortOrder::default()
+            },
+            filter: self.options.filter.clone(),
+            tag: self.options.tag.clone(),
+        };
+        let arrow = match (selected, self.options.order) {
+            (false, _) => "",
+            (true, SortOrder::Asc) => " \u{25b2}",
+            (true, SortOrder::Desc) => " \u{25bc}",
+        };
+        let href = format!("?{}", options.query_params().trim_start_matches('&'));
+        tmpl << html!(
+            a(class = if selected { "selected" } else { "" }, href = href) {
+                : sort.label();
+                : arrow;
+            }
+        )
+    }
+
+    /// Link selecting the tag filter, None shows all items.
+    fn render_tag_link(&self, tmpl: &mut TemplateBuffer<'_>, tag: Option<&str>) {
+        let selected = self.options.tag.as_deref() == tag;
+        let options = ListingOptions {
+            tag: tag.map(str::to_owned),
+            ..self.options.clone()
+        };
+        let href = format!("?{}", options.query_params().trim_start_matches('&'));
+        tmpl << html!(
+            a(class = if selected { "selected" } else { "" }, href = href): tag.unwrap_or("All");
+        )
+    }
+
+    fn formated_timestamp(&self, timestamp: DateTime<Utc>) -> FormatedTimestamp<Tz> {
+        FormatedTimestamp {
+            timestamp: timestamp.with_timezone(self.display_timezone),
+            now: self.now,
+            relative_cutoff: self.relative_time_cutoff,
+        }
+    }
+
     fn asset_url(&self, file_name: &'a str) -> AssetUrl<'a> {
         AssetUrl {
             download_base_url: self.download_base_url,
             file_name,
-            cache_hash: self.static_content_hash,
+            fingerprint: self.asset_fingerprints,
+            inline: self.inline_icons,
         }
     }
 }
 
 impl<'a> RenderOnce for DirListing<'a> {
     fn render_once(self, tmpl: &mut horrorshow::prelude::TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.directory_path,
+            is_unlisted: self.is_unlisted,
+        };
+        // A filter that matches nothing or a page past the end still shows the controls,
+        // so that they can be changed
+        let has_listing = !self.items.is_empty()
+            || self.streaming
+            || self.options.filter.is_some()
+            || self.options.tag.is_some()
+            || self.pagination.is_some();
+        let empty_message = if self
+            .pagination
+            .is_some_and(|pagination| pagination.total_items > 0)
+        {
+            "No items on this page"
+        } else {
+            "No items match the filter"
+        };
         tmpl << html!(
-            nav {
-                @ if !self.app_name.is_empty() {
-                    div(class = "app-name"): self.app_name;
-                }
-                h1(class = "breadcrumbs") {
-                    a(href = self.download_base_url) {
-                        img(src = self.asset_url("home.svg"), alt = "Home", title = "Home");
-                    }
-                    |tmpl| self.render_breadcrumbs(tmpl);
-                    @ if self.is_unlisted {
-                        img(src = self.asset_url("hidden.svg"), class = "unlisted", alt = "unlisted directory", title = "unlisted directory");
-                    }
+            : nav;
+
+            @ if let Some(expires) = self.expires {
+                div(class = "expiry") {
+                    : "Available until ";
+                    : FormatedIsoTimestamp(expires.with_timezone(self.display_timezone));
+                    : format_args!(" ({})", format_relative(self.now - expires));
                 }
             }
 
             section(id = "content") {
-                @ if self.items.is_empty() {
+                @ if !has_listing {
                     div(class = "empty-dir-listing"): "No data";
                 }
 
-                @ if !self.items.is_empty() {
+                @ if has_listing {
                     @ if !self.directory_path.is_empty() {
                         div(class = "download-all") {
                             a (
@@ -160,10 +743,14 @@ impl<'a> RenderOnce for DirListing<'a> {
                             }
                         }
                     }
-                    ul(class = "dir-listing") {
-                        @ for item in self.items.iter() {
-                            |tmpl| self.render_item(tmpl, item)
-                        }
+                    |tmpl| self.render_view_modes(tmpl);
+                    |tmpl| self.render_listing_options(tmpl);
+                    @ if self.items.is_empty() && !self.streaming {
+                        div(class = "empty-dir-listing"): empty_message;
+                    }
+                    |tmpl| self.render_items(tmpl);
+                    @ if let Some(pagination) = self.pagination {
+                        |tmpl| self.render_pagination(tmpl, pagination);
                     }
                 }
             }
@@ -192,6 +779,79 @@ impl<'a> RenderOnce for DirListing<'a> {
     }
 }
 
+/// Full text description of the item for screen readers: type, exact size in bytes,
+/// absolute modification time and tags. Starts with a separator, it follows the item name
+/// inside the link.
+fn item_description(item: &DirListingItem, timezone: &Tz) -> String {
+    let item_type = match item.item_type {
+        ItemType::Directory => "folder",
+        ItemType::Image => "image",
+        ItemType::Video => "video",
+        ItemType::Audio => "audio",
+        ItemType::Document => "document",
+        ItemType::File => "file",
+    };
+    let mut description = format!(", {}", item_type);
+    if !item.item_type.is_directory() {
+        let plural = if item.file_size == 1 { "" } else { "s" };
+        write!(description, ", {} byte{}", item.file_size, plural)
+            .expect("Writing to string can't fail");
+    }
+    if let Some(modified) = item.modified {
+        write!(
+            description,
+            ", modified {}",
+            modified
+                .with_timezone(timezone)
+                .format("%A %-d %B %Y, %H:%M:%S %Z")
+        )
+        .expect("Writing to string can't fail");
+    }
+    if !item.tags.is_empty() {
+        write!(description, ", tags: {}", item.tags.join(", "))
+            .expect("Writing to string can't fail");
+    }
+    description
+}
+
+/// Split the items into named sections, keeping their order within each section.
+/// Sections are ordered by name, items that don't belong to any are in the last, unnamed one.
+fn group_items(
+    items: &[DirListingItem],
+    grouping: RootGrouping,
+    separator: char,
+) -> Vec<(Option<&str>, Vec<&DirListingItem>)> {
+    let mut groups: BTreeMap<&str, Vec<&DirListingItem>> = BTreeMap::new();
+    let mut other = Vec::new();
+    for item in items {
+        let names: Vec<&str> = match grouping {
+            RootGrouping::None => Vec::new(),
+            RootGrouping::Tag => item.tags.iter().map(|tag| &**tag).collect(),
+            RootGrouping::Prefix => item
+                .name
+                .split_once(separator)
+                .map(|(prefix, _)| prefix)
+                .filter(|prefix| !prefix.is_empty())
+                .into_iter()
+                .collect(),
+        };
+        if names.is_empty() {
+            other.push(item);
+        }
+        for name in names {
+            groups.entry(name).or_default().push(item);
+        }
+    }
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .map(|(name, items)| (Some(name), items))
+        .collect();
+    if !other.is_empty() {
+        groups.push((None, other));
+    }
+    groups
+}
+
 #[derive(Clone)]
 struct ItemUrl<'a> {
     download_base_url: &'a str,
@@ -258,7 +918,7 @@ impl<'a> Display for ThumbnailUrl<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.item)?;
         f.write_char(self.item.next_qs_separator())?;
-        write!(f, "mode=thumb{}", self.resolution)?;
+        write!(f, "mode=thumb&size={}", self.resolution)?;
         if let Some(hash) = self.cache_hash {
             write!(f, "&cache_hash={:08x}", hash)?;
         }
@@ -296,3 +956,96 @@ impl<'a> RenderOnce for Title<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn page_count() {
+        let pagination = |total_items| Pagination {
+            page: 1,
+            per_page: 10,
+            total_items,
+        };
+        assert!(pagination(0).page_count() == 1);
+        assert!(pagination(1).page_count() == 1);
+        assert!(pagination(10).page_count() == 1);
+        assert!(pagination(11).page_count() == 2);
+        assert!(pagination(100).page_count() == 10);
+    }
+
+    fn item(name: &str, tags: &[&str]) -> DirListingItem {
+        DirListingItem {
+            name: name.into(),
+            item_type: ItemType::Directory,
+            file_size: 0,
+            modified: None,
+            source_hash: None,
+            dimensions: None,
+            tags: tags.iter().map(|&tag| tag.into()).collect(),
+        }
+    }
+
+    fn names<'a>(
+        groups: &[(Option<&'a str>, Vec<&'a DirListingItem>)],
+    ) -> Vec<(Option<&'a str>, Vec<&'a str>)> {
+        groups
+            .iter()
+            .map(|(name, items)| (*name, items.iter().map(|item| &*item.name).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn item_descriptions() {
+        let timezone: Tz = "Europe/Prague".parse().unwrap();
+        let mut file = item("report.pdf", &["work"]);
+        file.item_type = ItemType::File;
+        file.file_size = 1234567;
+        file.modified = Some("2024-03-05T13:07:09Z".parse().unwrap());
+        assert!(
+            item_description(&file, &timezone)
+                == ", file, 1234567 bytes, modified Tuesday 5 March 2024, 14:07:09 CET, tags: work"
+        );
+        assert!(item_description(&item("photos", &[]), &timezone) == ", folder");
+    }
+
+    #[test]
+    fn group_by_tag() {
+        let items = [
+            item("a", &["work"]),
+            item("b", &[]),
+            item("c", &["work", "family"]),
+        ];
+        let groups = group_items(&items, RootGrouping::Tag, '-');
+        assert!(
+            names(&groups)
+                == [
+                    (Some("family"), vec!["c"]),
+                    (Some("work"), vec!["a", "c"]),
+                    (None, vec!["b"]),
+                ]
+        );
+    }
+
+    #[test]
+    fn group_by_prefix() {
+        let items = [
+            item("2024-trip", &[]),
+            item("2023-party", &[]),
+            item("2024-party", &[]),
+            item("misc", &[]),
+            item("-odd", &[]),
+        ];
+        let groups = group_items(&items, RootGrouping::Prefix, '-');
+        assert!(
+            names(&groups)
+                == [
+                    (Some("2023"), vec!["2023-party"]),
+                    (Some("2024"), vec!["2024-trip", "2024-party"]),
+                    (None, vec!["misc", "-odd"]),
+                ]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use super::{
+    nav::Nav,
+    page::Page,
+    util::{url_encode, FormatedIsoTimestamp},
+};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+use humansize::{format_size, BINARY};
+
+use crate::app_data::{split_object_segment, AppData, Inbox};
+
+/// Upload form of an inbox object.
+pub struct InboxForm<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    display_timezone: &'a Tz,
+    path: &'a str,
+    is_unlisted: bool,
+    inbox: &'a Inbox,
+    now: DateTime<Utc>,
+}
+
+impl<'a> InboxForm<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        path: &'a str,
+        is_unlisted: bool,
+        inbox: &'a Inbox,
+    ) -> Page<'a, Title<'a>, InboxForm<'a>> {
+        let form = InboxForm {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+            path,
+            is_unlisted,
+            inbox,
+            now: Utc::now(),
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: form.app_name,
+                path: form.path,
+            },
+            content: form,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for InboxForm<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.path,
+            is_unlisted: self.is_unlisted,
+        };
+        // Files are uploaded into the object root, keeping a key embedded in the path
+        let (object_segment, _) = split_object_segment(self.path);
+        let upload_url = format!("{}/{}/", self.download_base_url, url_encode(object_segment));
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                @ if self.inbox.is_open(self.now) {
+                    // Without JavaScript the files are posted to the page URL, which
+                    // keeps the key query parameter
+                    form(
+                        class = "inbox",
+                        method = "post",
+                        enctype = "multipart/form-data",
+                        data-upload-url = upload_url
+                    ) {
+                        p: "Files uploaded here are only visible to the owner of this inbox.";
+                        @ if let Some(max_size) = self.inbox.max_size {
+                            p {
+                                : "Uploads are limited to ";
+                                : format_size(max_size, BINARY);
+                                : " in total.";
+                            }
+                        }
+                        @ if let Some(closes) = self.inbox.closes {
+                            p {
+                                : "Open until ";
+                                : FormatedIsoTimestamp(closes.with_timezone(self.display_timezone));
+                            }
+                        }
+                        input(type = "file", name = "files", multiple, required);
+                        button(type = "submit"): "Upload";
+                        ul(class = "inbox-status");
+                    }
+                } else {
+                    div(class = "message"): "This inbox no longer accepts uploads.";
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("{} - {}", self.path, self.app_name);
+    }
+}
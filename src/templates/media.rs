@@ -0,0 +1,108 @@
+use super::{nav::Nav, page::Page, util::url_encode};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+use crate::app_data::AppData;
+
+/// Page with a player for a video or audio file.
+pub struct MediaPlayer<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    path: &'a str,
+    is_unlisted: bool,
+    /// Key of an unlisted object given in the query
+    key: Option<&'a str>,
+    is_video: bool,
+    /// Show the largest video thumbnail before the playback starts
+    poster: bool,
+}
+
+impl<'a> MediaPlayer<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        path: &'a str,
+        is_unlisted: bool,
+        key: Option<&'a str>,
+        is_video: bool,
+    ) -> Page<'a, Title<'a>, MediaPlayer<'a>> {
+        let player = MediaPlayer {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            path,
+            is_unlisted,
+            key,
+            is_video,
+            poster: is_video && app.video_thumbnails_enabled(),
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: player.app_name,
+                path: player.path,
+            },
+            content: player,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+
+    /// URL of the file with the given mode, relative to the player page, which is served
+    /// from the file's own path.
+    fn file_url(&self, mode: &str) -> String {
+        let file_name = self.path.rsplit('/').next().unwrap_or_default();
+        let mut url = format!("{}?mode={}", url_encode(file_name), mode);
+        if let Some(key) = self.key {
+            url.push_str(&format!("&key={}", url_encode(key)));
+        }
+        url
+    }
+}
+
+impl<'a> RenderOnce for MediaPlayer<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.path,
+            is_unlisted: self.is_unlisted,
+        };
+        // Served inline by NamedFile, which supports range requests needed for seeking
+        let src = self.file_url("default");
+        let poster = if self.poster {
+            self.file_url("thumb512")
+        } else {
+            String::new()
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                div(class = "download-all") {
+                    a(href = self.file_url("download")): "Download";
+                }
+                @ if self.is_video {
+                    video(class = "player", src = &src, poster = &poster, controls, preload = "metadata") {
+                        a(href = &src): "Your browser can't play this video, open it directly";
+                    }
+                } else {
+                    audio(class = "player", src = &src, controls, preload = "metadata") {
+                        a(href = &src): "Your browser can't play this audio, open it directly";
+                    }
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("{} - {}", self.path, self.app_name);
+    }
+}
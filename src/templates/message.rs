@@ -0,0 +1,67 @@
+use super::{nav::Nav, page::Page};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+use crate::app_data::AppData;
+
+/// Page displaying a simple message, used for ended shares or maintenance notices.
+pub struct Message<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    message: &'a str,
+}
+
+impl<'a> Message<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        title: &'a str,
+        message: &'a str,
+    ) -> Page<'a, Title<'a>, Message<'a>> {
+        let content = Message {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            message,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: content.app_name,
+                title,
+            },
+            content,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for Message<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: "",
+            is_unlisted: false,
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                div(class = "message"): self.message;
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub title: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("{} - {}", self.title, self.app_name);
+    }
+}
@@ -1,27 +1,63 @@
+mod admin;
 mod breadcrumbs;
 mod dir_listing;
+mod inbox;
+mod media;
+mod message;
+mod nav;
 mod page;
+mod password;
+mod preview;
+mod search;
+mod terms;
 pub mod util;
 
 use std::fmt::{Display, Formatter};
 
-pub use dir_listing::DirListing;
+use crate::assets::{asset_hash, inline_asset};
+
+pub use admin::Admin;
+pub use dir_listing::{
+    DirListing, ListingOptions, Pagination, RootGrouping, SortKey, SortOrder, ViewMode,
+    ITEMS_MARKER,
+};
 use horrorshow::{RenderOnce, TemplateBuffer};
+pub use inbox::InboxForm;
+pub use media::MediaPlayer;
+pub use message::Message;
+pub use password::PasswordPrompt;
+pub use preview::Preview;
+pub use search::SearchResults;
+pub use terms::Terms;
 
 #[derive(Clone)]
 struct AssetUrl<'a> {
     download_base_url: &'a str,
     file_name: &'a str,
-    cache_hash: &'a str,
+    /// Add the content hash of the embedded asset to the URL
+    fingerprint: bool,
+    /// Render the asset as a data URI if it is small enough
+    inline: bool,
 }
 
 impl<'a> Display for AssetUrl<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.inline {
+            if let Some(data_uri) = inline_asset(self.file_name) {
+                return f.write_str(data_uri);
+            }
+        }
         write!(
             f,
-            "{}/{}?mode=internal&cache_hash={}",
-            self.download_base_url, self.file_name, self.cache_hash
-        )
+            "{}/{}?mode=internal",
+            self.download_base_url, self.file_name
+        )?;
+        if self.fingerprint {
+            if let Some(hash) = asset_hash(self.file_name) {
+                write!(f, "&cache_hash={}", hash)?;
+            }
+        }
+        Ok(())
     }
 }
 
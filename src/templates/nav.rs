@@ -0,0 +1,52 @@
+use super::{breadcrumbs::BreadcrumbsIterator, util::url_encode, AssetUrl};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+/// Navigation header shared by the pages, shows app name and breadcrumbs of the current path.
+pub struct Nav<'a> {
+    pub app_name: &'a str,
+    pub download_base_url: &'a str,
+    pub asset_fingerprints: bool,
+    pub directory_path: &'a str,
+    pub is_unlisted: bool,
+}
+
+impl<'a> Nav<'a> {
+    fn render_breadcrumbs(&self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << html!(
+            @ for crumb in BreadcrumbsIterator::new(self.directory_path) {
+                : "/";
+                a(href = format_args!("{}/{}", self.download_base_url, url_encode(crumb.link_url))): crumb.name;
+            }
+        );
+    }
+
+    fn asset_url(&self, file_name: &'a str) -> AssetUrl<'a> {
+        AssetUrl {
+            download_base_url: self.download_base_url,
+            file_name,
+            fingerprint: self.asset_fingerprints,
+            inline: false,
+        }
+    }
+}
+
+impl<'a> RenderOnce for Nav<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << html!(
+            nav {
+                @ if !self.app_name.is_empty() {
+                    div(class = "app-name"): self.app_name;
+                }
+                h1(class = "breadcrumbs") {
+                    a(href = self.download_base_url) {
+                        img(src = self.asset_url("home.svg"), alt = "Home", title = "Home");
+                    }
+                    |tmpl| self.render_breadcrumbs(tmpl);
+                    @ if self.is_unlisted {
+                        img(src = self.asset_url("hidden.svg"), class = "unlisted", alt = "unlisted directory", title = "unlisted directory");
+                    }
+                }
+            }
+        );
+    }
+}
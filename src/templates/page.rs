@@ -1,11 +1,12 @@
 use super::AssetUrl;
+use crate::assets::asset_integrity;
 use chrono_tz::Tz;
 use horrorshow::{helper::doctype, html, prelude::TemplateBuffer, RenderOnce};
 
 /// Wrapper around a template that provides the header and footer.
 pub struct Page<'a, T, C> {
     pub download_base_url: &'a str,
-    pub static_content_hash: &'a str,
+    pub asset_fingerprints: bool,
     pub display_timezone: &'a Tz,
 
     pub title: T,
@@ -21,14 +22,9 @@ impl<'a, T: RenderOnce, C: RenderOnce> RenderOnce for Page<'a, T, C> {
                     meta(
                         name = "viewport", content="width=device-width, initial-scale=1"
                     );
-                    link(
-                        rel = "stylesheet",
-                        href = self.asset_url("style.css")
-                    );
-                    script(
-                        src = self.asset_url("gallery.js"),
-                        defer
-                    );
+                    |tmpl| self.render_stylesheet(tmpl, "style.css");
+                    |tmpl| self.render_script(tmpl, "gallery.js");
+                    |tmpl| self.render_script(tmpl, "inbox.js");
                     title: self.title;
                 }
                 body {
@@ -55,11 +51,40 @@ impl<'a, T: RenderOnce, C: RenderOnce> RenderOnce for Page<'a, T, C> {
 }
 
 impl<'a, T: RenderOnce, C: RenderOnce> Page<'a, T, C> {
+    fn render_stylesheet(&self, tmpl: &mut TemplateBuffer<'_>, file_name: &'a str) {
+        let href = self.asset_url(file_name);
+        tmpl << html!(
+            @ if let Some(integrity) = self.integrity(file_name) {
+                link(rel = "stylesheet", href = href, integrity = integrity, crossorigin = "anonymous");
+            } else {
+                link(rel = "stylesheet", href = href);
+            }
+        );
+    }
+
+    fn render_script(&self, tmpl: &mut TemplateBuffer<'_>, file_name: &'a str) {
+        let src = self.asset_url(file_name);
+        tmpl << html!(
+            @ if let Some(integrity) = self.integrity(file_name) {
+                script(src = src, integrity = integrity, crossorigin = "anonymous", defer);
+            } else {
+                script(src = src, defer);
+            }
+        );
+    }
+
+    /// Integrity hashes only match the minified embedded assets, which are served
+    /// exactly when fingerprints are enabled.
+    fn integrity(&self, file_name: &str) -> Option<&'static str> {
+        asset_integrity(file_name).filter(|_| self.asset_fingerprints)
+    }
+
     fn asset_url(&self, file_name: &'a str) -> AssetUrl<'a> {
         AssetUrl {
             download_base_url: self.download_base_url,
             file_name,
-            cache_hash: self.static_content_hash,
+            fingerprint: self.asset_fingerprints,
+            inline: false,
         }
     }
 }
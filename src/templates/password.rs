@@ -0,0 +1,80 @@
+use super::{nav::Nav, page::Page};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+use crate::app_data::AppData;
+
+/// Prompt for the password of a password protected object.
+pub struct PasswordPrompt<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    path: &'a str,
+    is_unlisted: bool,
+    wrong_password: bool,
+}
+
+impl<'a> PasswordPrompt<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        path: &'a str,
+        is_unlisted: bool,
+        wrong_password: bool,
+    ) -> Page<'a, Title<'a>, PasswordPrompt<'a>> {
+        let prompt = PasswordPrompt {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            path,
+            is_unlisted,
+            wrong_password,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: prompt.app_name,
+                path: prompt.path,
+            },
+            content: prompt,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for PasswordPrompt<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.path,
+            is_unlisted: self.is_unlisted,
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                // Empty action posts back to the current URL, including the query string
+                form(method = "post", action = "", class = "password") {
+                    label(for = "password"): "This share is protected by a password";
+                    @ if self.wrong_password {
+                        div(class = "error"): "Wrong password";
+                    }
+                    input(type = "password", id = "password", name = "password", autofocus);
+                    button(type = "submit"): "Continue";
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("Password - {} - {}", self.path, self.app_name);
+    }
+}
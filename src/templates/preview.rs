@@ -0,0 +1,89 @@
+use super::{nav::Nav, page::Page, util::url_encode};
+use horrorshow::{html, Raw, RenderOnce, TemplateBuffer};
+
+use crate::{app_data::AppData, preview::stylesheet};
+
+/// Syntax highlighted content of a text file.
+pub struct Preview<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    path: &'a str,
+    is_unlisted: bool,
+    /// Key of an unlisted object given in the query
+    key: Option<&'a str>,
+    /// Already escaped and highlighted HTML of the file content
+    highlighted: &'a str,
+}
+
+impl<'a> Preview<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        path: &'a str,
+        is_unlisted: bool,
+        key: Option<&'a str>,
+        highlighted: &'a str,
+    ) -> Page<'a, Title<'a>, Preview<'a>> {
+        let preview = Preview {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            path,
+            is_unlisted,
+            key,
+            highlighted,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: preview.app_name,
+                path: preview.path,
+            },
+            content: preview,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for Preview<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.path,
+            is_unlisted: self.is_unlisted,
+        };
+        // Relative to the current URL, which keeps a key embedded in the path
+        let download_url = match self.key {
+            Some(key) => format!("?mode=download&key={}", url_encode(key)),
+            None => "?mode=download".to_owned(),
+        };
+        tmpl << html!(
+            : nav;
+
+            style: Raw(stylesheet());
+
+            section(id = "content") {
+                div(class = "download-all") {
+                    a(href = download_url): "Download";
+                }
+                pre(class = "preview") {
+                    code: Raw(self.highlighted);
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("{} - {}", self.path, self.app_name);
+    }
+}
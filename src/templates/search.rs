@@ -0,0 +1,102 @@
+use super::{nav::Nav, page::Page, util::url_encode};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+use crate::{app_data::AppData, search::SearchHit};
+
+/// Files of a directory matching a full-text search.
+pub struct SearchResults<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    directory_path: &'a str,
+    is_unlisted: bool,
+    query: &'a str,
+    hits: &'a [SearchHit],
+}
+
+impl<'a> SearchResults<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        directory_path: &'a str,
+        is_unlisted: bool,
+        query: &'a str,
+        hits: &'a [SearchHit],
+    ) -> Page<'a, Title<'a>, SearchResults<'a>> {
+        let results = SearchResults {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            directory_path,
+            is_unlisted,
+            query,
+            hits,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: results.app_name,
+                path: results.directory_path,
+            },
+            content: results,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+
+    /// Text preview of the file.
+    fn hit_url(&self, hit: &SearchHit) -> String {
+        format!(
+            "{}/{}/{}?mode=preview",
+            self.download_base_url,
+            url_encode(self.directory_path),
+            url_encode(&hit.path)
+        )
+    }
+}
+
+impl<'a> RenderOnce for SearchResults<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.directory_path,
+            is_unlisted: self.is_unlisted,
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                form(method = "get", action = "", class = "search") {
+                    input(type = "hidden", name = "mode", value = "search");
+                    input(type = "search", name = "q", value = self.query, placeholder = "Search in files");
+                }
+                @ if self.hits.is_empty() {
+                    div(class = "message"): "No files match the search";
+                } else {
+                    ul(class = "search-results") {
+                        @ for hit in self.hits {
+                            li {
+                                a(href = self.hit_url(hit)): &hit.path;
+                                @ if !hit.snippet.is_empty() {
+                                    pre(class = "snippet"): &hit.snippet;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("{} - {}", self.path, self.app_name);
+    }
+}
@@ -0,0 +1,76 @@
+use super::{nav::Nav, page::Page};
+use horrorshow::{html, RenderOnce, TemplateBuffer};
+
+use crate::app_data::AppData;
+
+/// Interstitial page with terms that have to be accepted before downloading.
+pub struct Terms<'a> {
+    app_name: &'a str,
+    download_base_url: &'a str,
+    asset_fingerprints: bool,
+    path: &'a str,
+    is_unlisted: bool,
+    terms: &'a str,
+}
+
+impl<'a> Terms<'a> {
+    pub fn new_wrapped(
+        app: &'a AppData,
+        path: &'a str,
+        is_unlisted: bool,
+        terms: &'a str,
+    ) -> Page<'a, Title<'a>, Terms<'a>> {
+        let terms = Terms {
+            app_name: app.get_app_name(),
+            download_base_url: app.get_download_base_url(),
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            path,
+            is_unlisted,
+            terms,
+        };
+        Page {
+            download_base_url: app.get_download_base_url(),
+            title: Title {
+                app_name: terms.app_name,
+                path: terms.path,
+            },
+            content: terms,
+            asset_fingerprints: app.asset_fingerprints_enabled(),
+            display_timezone: app.get_display_timezone(),
+        }
+    }
+}
+
+impl<'a> RenderOnce for Terms<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        let nav = Nav {
+            app_name: self.app_name,
+            download_base_url: self.download_base_url,
+            asset_fingerprints: self.asset_fingerprints,
+            directory_path: self.path,
+            is_unlisted: self.is_unlisted,
+        };
+        tmpl << html!(
+            : nav;
+
+            section(id = "content") {
+                div(class = "terms"): self.terms;
+                // Empty action posts back to the current URL, including the query string
+                form(method = "post", action = "", class = "accept-terms") {
+                    button(type = "submit"): "Accept and continue";
+                }
+            }
+        );
+    }
+}
+
+pub struct Title<'a> {
+    pub app_name: &'a str,
+    pub path: &'a str,
+}
+
+impl<'a> RenderOnce for Title<'a> {
+    fn render_once(self, tmpl: &mut TemplateBuffer<'_>) {
+        tmpl << format_args!("Terms - {} - {}", self.path, self.app_name);
+    }
+}
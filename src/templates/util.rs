@@ -1,4 +1,4 @@
-use chrono::{DateTime, Datelike, TimeZone, Timelike};
+use chrono::{DateTime, Datelike, Duration, SecondsFormat, TimeZone, Timelike, Utc};
 use horrorshow::{html, RenderOnce};
 
 use percent_encoding::{utf8_percent_encode, AsciiSet, PercentEncode, NON_ALPHANUMERIC};
@@ -7,7 +7,8 @@ const PERCENT_ENCODING_CHARSET: &AsciiSet = &NON_ALPHANUMERIC
     .remove(b'/')
     .remove(b'_')
     .remove(b'-')
-    .remove(b'.');
+    .remove(b'.')
+    .remove(b'~');
 
 pub fn url_encode<'a>(s: &'a str) -> PercentEncode<'a> {
     utf8_percent_encode(s, PERCENT_ENCODING_CHARSET)
@@ -24,16 +25,13 @@ where
 
         let y = ts.year();
         let m = ts.month();
-        let d = ts.month();
+        let d = ts.day();
         let h = ts.hour();
         let minute = ts.minute();
         let s = ts.second();
-        let offset = ts.offset();
 
         tmpl << html!(
-            time(
-                datetime = format_args!("{y}-{m:02}-{d:02}T{h:02}:{minute:02}:{s:02}+{offset}")
-            ) {
+            time(datetime = ts.to_rfc3339_opts(SecondsFormat::Secs, false)) {
                 : format_args!("{y}-{m:02}-{d:02}");
                 span(class = "separator"): "T";
                 : format_args!("{h:02}:{minute:02}:{s:02}");
@@ -42,3 +40,116 @@ where
         );
     }
 }
+
+/// Timestamp displayed relative to the current time ("5 minutes ago") if it is more recent
+/// than the cutoff, as an absolute time otherwise.
+/// Timestamps in the future are always displayed as absolute.
+pub struct FormatedTimestamp<Tz: TimeZone> {
+    pub timestamp: DateTime<Tz>,
+    pub now: DateTime<Utc>,
+    pub relative_cutoff: Duration,
+}
+
+impl<Tz> RenderOnce for FormatedTimestamp<Tz>
+where
+    Tz: TimeZone,
+{
+    fn render_once(self, tmpl: &mut horrorshow::prelude::TemplateBuffer<'_>) {
+        let age = self.now.signed_duration_since(self.timestamp.clone());
+        if age >= Duration::zero() && age < self.relative_cutoff {
+            let iso = self
+                .timestamp
+                .fixed_offset()
+                .to_rfc3339_opts(SecondsFormat::Secs, false);
+            tmpl << html!(
+                time(datetime = iso.as_str(), title = iso.as_str()): format_relative(age);
+            );
+        } else {
+            tmpl << FormatedIsoTimestamp(self.timestamp);
+        }
+    }
+}
+
+/// Format a time difference as a human readable "X ago" string,
+/// or "in X" if the time difference is negative.
+pub fn format_relative(age: Duration) -> String {
+    let abs_age = if age < Duration::zero() { -age } else { age };
+    let (count, unit) = if abs_age < Duration::minutes(1) {
+        return "just now".into();
+    } else if abs_age < Duration::hours(1) {
+        (abs_age.num_minutes(), "minute")
+    } else if abs_age < Duration::days(1) {
+        (abs_age.num_hours(), "hour")
+    } else if abs_age < Duration::weeks(1) {
+        (abs_age.num_days(), "day")
+    } else if abs_age < Duration::days(365) {
+        (abs_age.num_weeks(), "week")
+    } else {
+        (abs_age.num_days() / 365, "year")
+    };
+
+    let plural = if count == 1 { "" } else { "s" };
+    if age < Duration::zero() {
+        format!("in {count} {unit}{plural}")
+    } else {
+        format!("{count} {unit}{plural} ago")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+
+    #[test]
+    fn format_relative_examples() {
+        assert!(format_relative(Duration::seconds(59)) == "just now");
+        assert!(format_relative(Duration::seconds(60)) == "1 minute ago");
+        assert!(format_relative(Duration::minutes(59)) == "59 minutes ago");
+        assert!(format_relative(Duration::hours(5)) == "5 hours ago");
+        assert!(format_relative(Duration::days(1)) == "1 day ago");
+        assert!(format_relative(Duration::days(20)) == "2 weeks ago");
+        assert!(format_relative(Duration::days(800)) == "2 years ago");
+        assert!(format_relative(Duration::minutes(-1)) == "in 1 minute");
+        assert!(format_relative(Duration::days(-3)) == "in 3 days");
+    }
+
+    fn render(timestamp: DateTime<Utc>, now: DateTime<Utc>, relative_cutoff: Duration) -> String {
+        use horrorshow::Template as _;
+        FormatedTimestamp {
+            timestamp,
+            now,
+            relative_cutoff,
+        }
+        .into_string()
+        .unwrap()
+    }
+
+    #[test]
+    fn recent_timestamp_is_relative() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 5, 12, 0, 0).unwrap();
+        let rendered = render(now - Duration::minutes(5), now, Duration::hours(1));
+        assert!(rendered.contains("5 minutes ago"));
+    }
+
+    #[test]
+    fn old_timestamp_is_absolute() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 5, 12, 0, 0).unwrap();
+        let rendered = render(now - Duration::hours(2), now, Duration::hours(1));
+        assert!(!rendered.contains("ago"));
+        assert!(rendered.contains("2023-10-05"));
+        assert!(rendered.contains("10:00:00"));
+        assert!(rendered.contains("datetime=\"2023-10-05T10:00:00+00:00\""));
+    }
+
+    #[test]
+    fn future_timestamp_is_absolute() {
+        let now = Utc.with_ymd_and_hms(2023, 10, 5, 12, 0, 0).unwrap();
+        for cutoff in [Duration::zero(), Duration::hours(1)] {
+            let rendered = render(now + Duration::minutes(5), now, cutoff);
+            assert!(!rendered.contains("just now"));
+            assert!(!rendered.contains("ago"));
+            assert!(rendered.contains("12:05:00"));
+        }
+    }
+}
@@ -1,20 +1,348 @@
-use crate::error::Result;
-use actix_web::web::Bytes;
-use image::{
-    imageops, DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Pixel, Rgb, RgbImage,
+use crate::{
+    error::{FiledlError, Result},
+    probe::orientation,
 };
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use actix_web::web::Bytes;
+use image::{imageops, DynamicImage, ImageBuffer, ImageFormat, Pixel, Rgb, RgbImage, RgbaImage};
 use lru::LruCache;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
     fs::Metadata,
     hash::{Hash, Hasher},
-    io::Cursor,
+    io::{Cursor, Read},
     num::NonZeroU32,
     path::{Path, PathBuf},
-    time::SystemTime,
+    process::{Command, Output, Stdio},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{sync::Mutex, task::spawn_blocking};
 
+/// Color behind transparent images and around contained thumbnails
+const BACKGROUND_COLOR: [u8; 3] = [0xDA, 0xE1, 0xE4];
+/// JPEG quality of thumbnails
+const DEFAULT_QUALITY: u8 = 85;
+/// JPEG quality of thumbnails for clients that asked to reduce data usage
+const SAVE_DATA_QUALITY: u8 = 60;
+/// Largest thumbnail size in pixels that can be requested through client hints
+const MAX_NEGOTIATED_SIZE: u32 = 1024;
+/// Largest thumbnail size in pixels when no thumbnail sizes are configured
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+/// Negotiated thumbnail sizes are rounded up to a multiple of this, to limit the number
+/// of different thumbnails in the cache.
+const NEGOTIATED_SIZE_STEP: u32 = 16;
+
+/// Client hints sent by the browser that affect thumbnail rendering.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ClientHints {
+    /// Device pixel ratio
+    pub dpr: Option<f32>,
+    /// Intended display width of the image in physical pixels
+    pub width: Option<u32>,
+    pub save_data: bool,
+}
+
+/// Pixel size and quality of a thumbnail, negotiated from the requested size
+/// and client hints.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ThumbnailParams {
+    pub size: u32,
+    pub quality: u8,
+}
+
+impl ThumbnailParams {
+    pub fn negotiate(size: u32, hints: ClientHints) -> Self {
+        if hints.save_data {
+            return ThumbnailParams {
+                size,
+                quality: SAVE_DATA_QUALITY,
+            };
+        }
+
+        let dpr = hints
+            .dpr
+            .filter(|dpr| dpr.is_finite())
+            .unwrap_or(1.0)
+            .clamp(1.0, 4.0);
+        let mut pixel_size = (size as f32 * dpr).ceil() as u32;
+        if let Some(width) = hints.width {
+            pixel_size = pixel_size.min(width);
+        }
+        let pixel_size = if pixel_size > size {
+            pixel_size
+                .next_multiple_of(NEGOTIATED_SIZE_STEP)
+                .min(MAX_NEGOTIATED_SIZE.max(size))
+        } else {
+            pixel_size.max(1)
+        };
+
+        ThumbnailParams {
+            size: pixel_size,
+            quality: DEFAULT_QUALITY,
+        }
+    }
+}
+
+/// Round the requested size up to the nearest allowed size, sizes larger than all
+/// allowed ones are clamped to the largest. If the list is empty, sizes up to
+/// `MAX_THUMBNAIL_SIZE` are accepted.
+pub fn clamp_thumbnail_size(size: u32, allowed: &[u32]) -> u32 {
+    allowed
+        .iter()
+        .copied()
+        .filter(|&allowed| allowed >= size)
+        .min()
+        .or_else(|| allowed.iter().copied().max())
+        .unwrap_or(size.min(MAX_THUMBNAIL_SIZE))
+}
+
+/// Longest time an external command rendering a thumbnail source may run
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// File extensions of videos that can be thumbnailed by ffmpeg
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mkv", "webm", "mov", "avi"];
+
+/// File extensions of office documents that can be previewed by the converter command
+const DOCUMENT_EXTENSIONS: &[&str] = &[
+    "docx", "doc", "odt", "rtf", "xlsx", "xls", "ods", "pptx", "ppt", "odp",
+];
+
+/// Source of the images that thumbnails are rendered from.
+pub trait Thumbnailer: Send + Sync + std::fmt::Debug {
+    /// Returns true if this thumbnailer can handle the file.
+    fn handles(&self, path: &Path) -> bool;
+
+    /// Load the image to render the thumbnail from, together with its EXIF orientation.
+    fn load(&self, path: &Path) -> Result<(DynamicImage, u32)>;
+}
+
+/// Thumbnails of image files.
+#[derive(Debug)]
+pub struct ImageThumbnailer;
+
+impl Thumbnailer for ImageThumbnailer {
+    fn handles(&self, path: &Path) -> bool {
+        is_thumbnailable(path)
+    }
+
+    fn load(&self, path: &Path) -> Result<(DynamicImage, u32)> {
+        Ok((open_image(path)?, orientation(path)?))
+    }
+}
+
+/// Thumbnails of video files, made from a frame grabbed by ffmpeg.
+#[derive(Debug)]
+pub struct VideoThumbnailer {
+    ffmpeg_path: PathBuf,
+}
+
+impl VideoThumbnailer {
+    pub fn new(ffmpeg_path: PathBuf) -> Self {
+        VideoThumbnailer { ffmpeg_path }
+    }
+
+    /// Grab a single frame at the given time as PNG.
+    fn grab_frame(&self, path: &Path, seek: &str) -> Result<Vec<u8>> {
+        let mut command = Command::new(&self.ffmpeg_path);
+        command
+            .args(["-v", "error", "-ss", seek, "-i"])
+            .arg(path)
+            .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "-"]);
+        let output = output_with_timeout(&mut command, COMMAND_TIMEOUT)?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "ffmpeg failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .into());
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Run the command to completion and collect its output like `Command::output`,
+/// but kill it if it doesn't finish in time, so that a hung command doesn't occupy
+/// a blocking thread forever.
+fn output_with_timeout(command: &mut Command, timeout: Duration) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The pipes are drained concurrently, otherwise the command could block on a full pipe
+    fn drain<R: Read + Send + 'static>(
+        mut pipe: R,
+    ) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+        std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            pipe.read_to_end(&mut buffer)?;
+            Ok(buffer)
+        })
+    }
+    let stdout = drain(child.stdout.take().expect("stdout is piped"));
+    let stderr = drain(child.stderr.take().expect("stderr is piped"));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // The command may have exited in the meantime, then killing it fails
+            let _ = child.kill();
+            child.wait()?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("command didn't finish in {} s", timeout.as_secs()),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().expect("Reading stdout doesn't panic")?,
+        stderr: stderr.join().expect("Reading stderr doesn't panic")?,
+    })
+}
+
+impl Thumbnailer for VideoThumbnailer {
+    fn handles(&self, path: &Path) -> bool {
+        is_video(path)
+    }
+
+    fn load(&self, path: &Path) -> Result<(DynamicImage, u32)> {
+        // Skip the first second, it is often black, but fall back to the first frame
+        // for very short videos.
+        let mut frame = self.grab_frame(path, "1")?;
+        if frame.is_empty() {
+            frame = self.grab_frame(path, "0")?;
+        }
+        let image = image::load_from_memory_with_format(&frame, ImageFormat::Png)?;
+        // ffmpeg applies the rotation metadata of the video itself
+        Ok((image, 1))
+    }
+}
+
+/// Thumbnails of office documents, made from a first page preview rendered by an external
+/// converter command.
+///
+/// The command is given as a program with arguments, `{input}` in the arguments is replaced
+/// by the path of the document and `{output}` by the path of an image file that the command
+/// must create.
+#[derive(Debug)]
+pub struct DocumentThumbnailer {
+    command: Vec<String>,
+}
+
+impl DocumentThumbnailer {
+    pub fn new(command: Vec<String>) -> Self {
+        DocumentThumbnailer { command }
+    }
+
+    fn render_preview(&self, path: &Path, output_path: &Path) -> Result<DynamicImage> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "document preview command is empty",
+            )
+            .into());
+        };
+        let mut command = Command::new(program);
+        command.args(args.iter().map(|arg| {
+            arg.replace("{input}", &path.to_string_lossy())
+                .replace("{output}", &output_path.to_string_lossy())
+        }));
+        let output = output_with_timeout(&mut command, COMMAND_TIMEOUT)?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "document preview command failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .into());
+        }
+        let mut reader = image::io::Reader::open(output_path)?.with_guessed_format()?;
+        reader.no_limits();
+        Ok(reader.decode()?)
+    }
+}
+
+impl Thumbnailer for DocumentThumbnailer {
+    fn handles(&self, path: &Path) -> bool {
+        is_document(path)
+    }
+
+    fn load(&self, path: &Path) -> Result<(DynamicImage, u32)> {
+        let output_path =
+            std::env::temp_dir().join(format!("filedl-preview-{:016x}.png", rand::random::<u64>()));
+        let result = self.render_preview(path, &output_path);
+        // The command might have failed after creating the file
+        let _ = std::fs::remove_file(&output_path);
+        Ok((result?, 1))
+    }
+}
+
+fn default_watermark_opacity() -> u8 {
+    50
+}
+
+fn default_watermark_text_size() -> u8 {
+    5
+}
+
+/// Image or text overlaid over thumbnails and full size downloads of images of an object.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermark {
+    #[serde(flatten)]
+    pub content: WatermarkContent,
+    #[serde(default)]
+    pub position: WatermarkPosition,
+    /// Opacity of the overlay in percent.
+    #[serde(default = "default_watermark_opacity")]
+    pub opacity: u8,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WatermarkContent {
+    Image {
+        /// Absolute path to the overlay image. Transparency of the image is respected.
+        image: PathBuf,
+    },
+    Text {
+        text: String,
+        /// Absolute path to a TrueType or OpenType font to draw the text with
+        font: PathBuf,
+        /// Height of the text in percent of the image height.
+        #[serde(default = "default_watermark_text_size")]
+        text_size: u8,
+    },
+}
+
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+/// How images with a different aspect ratio are fitted into the thumbnail.
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFit {
+    /// Crop the image to fill the whole thumbnail
+    #[default]
+    Cover,
+    /// Show the whole image, with the rest of the thumbnail filled with the background color
+    Contain,
+}
+
 /// Describes a cached rendered thumbnail
 #[derive(Hash, Debug, PartialEq, Eq)]
 struct CacheKey {
@@ -26,10 +354,20 @@ struct CacheKey {
     // Properties of the final thumbnail
     width: u32,
     height: u32,
+    quality: u8,
+    fit: ThumbnailFit,
+    watermark: Option<Watermark>,
 }
 
 impl CacheKey {
-    fn new(path: PathBuf, metadata: &Metadata, size: (u32, u32)) -> Self {
+    fn new(
+        path: PathBuf,
+        metadata: &Metadata,
+        size: (u32, u32),
+        quality: u8,
+        fit: ThumbnailFit,
+        watermark: Option<Watermark>,
+    ) -> Self {
         CacheKey {
             path,
             size: metadata.len(),
@@ -37,6 +375,9 @@ impl CacheKey {
 
             width: size.0,
             height: size.1,
+            quality,
+            fit,
+            watermark,
         }
     }
 
@@ -76,6 +417,7 @@ struct Locked {
 pub struct CachedThumbnails {
     locked: Mutex<Locked>,
     max_size: usize,
+    thumbnailers: Vec<Arc<dyn Thumbnailer>>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -88,7 +430,7 @@ pub struct CacheStats {
 }
 
 impl CachedThumbnails {
-    pub fn new(max_size: usize) -> Self {
+    pub fn new(max_size: usize, thumbnailers: Vec<Arc<dyn Thumbnailer>>) -> Self {
         CachedThumbnails {
             locked: Mutex::new(Locked {
                 cache: LruCache::unbounded(),
@@ -97,17 +439,33 @@ impl CachedThumbnails {
                 wasted_creation_rate: HitRate { rate: 0.5 },
             }),
             max_size,
+            thumbnailers,
         }
     }
 
+    /// Hash identifying the thumbnail, usable as an ETag without rendering it.
+    pub fn hash(
+        file: PathBuf,
+        metadata: &Metadata,
+        size: (u32, u32),
+        quality: u8,
+        fit: ThumbnailFit,
+        watermark: Option<Watermark>,
+    ) -> String {
+        CacheKey::new(file, metadata, size, quality, fit, watermark).hash_string()
+    }
+
     pub async fn get(
         &self,
         file: PathBuf,
         metadata: &Metadata,
         size: (u32, u32),
+        quality: u8,
+        fit: ThumbnailFit,
+        watermark: Option<Watermark>,
     ) -> Result<(Bytes, String)> {
-        let mut key = CacheKey::new(file, metadata, size); // Must be mutable because of the
-                                                           // spawn_blocking trick below
+        // Must be mutable because of the spawn_blocking trick below
+        let mut key = CacheKey::new(file, metadata, size, quality, fit, watermark);
         let hash = key.hash_string();
         {
             let mut locked = self.locked.lock().await;
@@ -121,11 +479,25 @@ impl CachedThumbnails {
             }
         }
 
+        let thumbnailer = self
+            .thumbnailers
+            .iter()
+            .find(|thumbnailer| thumbnailer.handles(&key.path))
+            .ok_or(FiledlError::BadDownloadMode)?;
+        let thumbnailer = Arc::clone(thumbnailer);
+        let watermark = key.watermark.clone();
         // Here we pass the path through the closure, so that the compiler understands
         // that it will live long enough.
         let join_result = spawn_blocking(move || {
             let path = key.path;
-            let thumbnail = create_thumbnail(&path, size);
+            let thumbnail = create_thumbnail(
+                thumbnailer.as_ref(),
+                &path,
+                size,
+                quality,
+                fit,
+                watermark.as_ref(),
+            );
             (thumbnail, path)
         })
         .await;
@@ -172,6 +544,17 @@ impl CachedThumbnails {
         Ok((thumbnail, hash))
     }
 
+    /// Evict least recently used thumbnails until the cache uses at most `target_size` bytes.
+    pub async fn shrink(&self, target_size: usize) {
+        let mut locked = self.locked.lock().await;
+        while locked.used_size > target_size {
+            let Some((_, evicted_thumbnail)) = locked.cache.pop_lru() else {
+                break;
+            };
+            locked.used_size -= evicted_thumbnail.len();
+        }
+    }
+
     pub async fn cache_stats(&self) -> CacheStats {
         let locked = self.locked.lock().await;
         CacheStats {
@@ -184,22 +567,61 @@ impl CachedThumbnails {
     }
 }
 
-pub fn create_thumbnail(file: &Path, size: (u32, u32)) -> Result<Bytes> {
-    let img = open_image(file)?;
-    let orientation = get_orientation(file)?;
+pub fn create_thumbnail(
+    thumbnailer: &dyn Thumbnailer,
+    file: &Path,
+    size: (u32, u32),
+    quality: u8,
+    fit: ThumbnailFit,
+    watermark: Option<&Watermark>,
+) -> Result<Bytes> {
+    let (img, orientation) = thumbnailer.load(file)?;
+    let rgb_img = normalize_layers(img, BACKGROUND_COLOR.into());
 
     // TODO: Fix orientation for non-square non-centered crops
-    let crop_coords = crop_coordinates(img.dimensions(), size);
+    let resized = match fit {
+        ThumbnailFit::Cover => {
+            let crop_coords = crop_coordinates(rgb_img.dimensions(), size);
+            crop_and_resize(rgb_img, crop_coords, size)
+        }
+        ThumbnailFit::Contain => {
+            let (width, height) = rgb_img.dimensions();
+            let fitted_size = contained_size((width, height), size);
+            let fitted = crop_and_resize(rgb_img, (0, 0, width, height), fitted_size);
+            let mut canvas = RgbImage::from_pixel(size.0, size.1, BACKGROUND_COLOR.into());
+            imageops::replace(
+                &mut canvas,
+                &fitted,
+                ((size.0 - fitted_size.0) / 2).into(),
+                ((size.1 - fitted_size.1) / 2).into(),
+            );
+            canvas
+        }
+    };
+    let mut resized_and_reoriented = fix_orientation(resized, orientation);
 
-    // TODO: Don't hardcode background color
-    let rgb_img = normalize_layers(img, [0xDA, 0xE1, 0xE4].into());
-    let resized = crop_and_resize(rgb_img, crop_coords, size);
-    let resized_and_reoriented = fix_orientation(resized, orientation);
+    if let Some(watermark) = watermark {
+        apply_watermark(&mut resized_and_reoriented, watermark)?;
+    }
 
     let mut bytes: Vec<u8> = Vec::new();
     resized_and_reoriented.write_to(
         &mut Cursor::new(&mut bytes),
-        image::ImageOutputFormat::Jpeg(85),
+        image::ImageOutputFormat::Jpeg(quality),
+    )?;
+    Ok(bytes.into())
+}
+
+/// Render a full size image with the watermark applied, as JPEG.
+pub fn create_watermarked_image(file: &Path, watermark: &Watermark) -> Result<Bytes> {
+    let img = normalize_layers(open_image(file)?, BACKGROUND_COLOR.into());
+    let mut img = fix_orientation(img, orientation(file)?);
+    apply_watermark(&mut img, watermark)?;
+
+    let mut bytes: Vec<u8> = Vec::new();
+    img.write_to(
+        &mut Cursor::new(&mut bytes),
+        image::ImageOutputFormat::Jpeg(DEFAULT_QUALITY),
     )?;
     Ok(bytes.into())
 }
@@ -223,6 +645,28 @@ pub fn is_thumbnailable(path: &Path) -> bool {
     format.can_read()
 }
 
+/// Returns true if the file is a video, based on its extension.
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            VIDEO_EXTENSIONS
+                .iter()
+                .any(|video_extension| video_extension.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Returns true if the file is an office document, based on its extension.
+pub fn is_document(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            DOCUMENT_EXTENSIONS
+                .iter()
+                .any(|document_extension| document_extension.eq_ignore_ascii_case(extension))
+        })
+}
+
 fn open_image(path: &Path) -> Result<DynamicImage> {
     let mut reader = image::io::Reader::open(path)?;
     reader.no_limits();
@@ -275,25 +719,6 @@ fn crop_and_resize(
     RgbImage::from_vec(new_size.0, new_size.1, dst_image.into_vec()).unwrap()
 }
 
-fn get_orientation(path: &Path) -> Result<u32> {
-    let file = std::fs::File::open(path)?;
-    let mut bufreader = std::io::BufReader::new(file);
-    let exifreader = exif::Reader::new();
-    let Ok(exif_tags) = exifreader.read_from_container(&mut bufreader) else {
-        return Ok(1);
-    };
-
-    Ok(
-        match exif_tags.get_field(exif::Tag::Orientation, exif::In::PRIMARY) {
-            Some(orientation) => match orientation.value.get_uint(0) {
-                Some(v @ 1..=8) => v,
-                _ => 1,
-            },
-            None => 1,
-        },
-    )
-}
-
 fn fix_orientation<Px: 'static + Pixel>(
     mut img: ImageBuffer<Px, Vec<Px::Subpixel>>,
     orientation: u32,
@@ -371,6 +796,135 @@ where
     ret
 }
 
+/// Blend the watermark over the image.
+/// Watermark images are scaled down to at most half of the image size.
+fn apply_watermark(img: &mut RgbImage, watermark: &Watermark) -> Result<()> {
+    let overlay = match &watermark.content {
+        WatermarkContent::Image { image } => {
+            let overlay = fix_orientation(open_image(image)?.into_rgba8(), orientation(image)?);
+            DynamicImage::from(overlay)
+                .resize(
+                    (img.width() / 2).max(1),
+                    (img.height() / 2).max(1),
+                    imageops::FilterType::Triangle,
+                )
+                .into_rgba8()
+        }
+        WatermarkContent::Text {
+            text,
+            font,
+            text_size,
+        } => {
+            let height = img.height() as f32 * f32::from((*text_size).clamp(1, 100)) / 100.0;
+            render_text(text, font, height, img.width())?
+        }
+    };
+
+    let (x, y) = watermark_offset(img.dimensions(), overlay.dimensions(), watermark.position);
+    blend_overlay(img, &overlay, (x, y), watermark.opacity.min(100));
+
+    Ok(())
+}
+
+/// Draw white text with the given line height, made smaller if needed to fit into
+/// `max_width`. Returns the text as a transparent image.
+fn render_text(text: &str, font_path: &Path, height: f32, max_width: u32) -> Result<RgbaImage> {
+    let font = FontVec::try_from_vec(std::fs::read(font_path)?).map_err(|_| {
+        FiledlError::WatermarkFont {
+            path: font_path.to_owned(),
+        }
+    })?;
+
+    let width_at = |height: f32| {
+        let scaled = font.as_scaled(PxScale::from(height));
+        text.chars()
+            .map(|c| scaled.h_advance(scaled.glyph_id(c)))
+            .sum::<f32>()
+    };
+    let max_width = max_width as f32 * 0.9;
+    let mut height = height.max(1.0);
+    let width = width_at(height);
+    if width > max_width {
+        height *= max_width / width;
+    }
+
+    let scaled = font.as_scaled(PxScale::from(height));
+    let mut overlay = RgbaImage::new(
+        (width_at(height).ceil() as u32).max(1),
+        (scaled.height().ceil() as u32).max(1),
+    );
+    let mut x = 0.0;
+    for c in text.chars() {
+        let glyph = scaled
+            .glyph_id(c)
+            .with_scale_and_position(height, ab_glyph::point(x, scaled.ascent()));
+        x += scaled.h_advance(glyph.id);
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i64 + i64::from(gx);
+            let py = bounds.min.y as i64 + i64::from(gy);
+            let (Ok(px), Ok(py)) = (u32::try_from(px), u32::try_from(py)) else {
+                return;
+            };
+            if let Some(pixel) = overlay.get_pixel_mut_checked(px, py) {
+                let alpha = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+                *pixel = image::Rgba([255, 255, 255, pixel.0[3].max(alpha)]);
+            }
+        });
+    }
+    Ok(overlay)
+}
+
+fn blend_overlay(img: &mut RgbImage, overlay: &RgbaImage, offset: (u32, u32), opacity: u8) {
+    for (ox, oy, from) in overlay.enumerate_pixels() {
+        let Some(to) = img.get_pixel_mut_checked(offset.0 + ox, offset.1 + oy) else {
+            continue;
+        };
+
+        let a = u32::from(from.0[3]) * u32::from(opacity);
+        let na = 255 * 100 - a;
+        for (to_channel, from_channel) in to.0.iter_mut().zip(from.0) {
+            *to_channel = ((u32::from(from_channel) * a + u32::from(*to_channel) * na)
+                / (255 * 100))
+                .try_into()
+                .unwrap();
+        }
+    }
+}
+
+/// Position of the top left corner of the overlay, so that it is placed at the given position
+/// inside the image, with a small margin.
+fn watermark_offset(
+    img_size: (u32, u32),
+    overlay_size: (u32, u32),
+    position: WatermarkPosition,
+) -> (u32, u32) {
+    let margin_x = img_size.0 / 32;
+    let margin_y = img_size.1 / 32;
+    let free_x = img_size.0.saturating_sub(overlay_size.0);
+    let free_y = img_size.1.saturating_sub(overlay_size.1);
+
+    let x = match position {
+        WatermarkPosition::TopLeft | WatermarkPosition::BottomLeft => margin_x.min(free_x),
+        WatermarkPosition::TopRight | WatermarkPosition::BottomRight => {
+            free_x.saturating_sub(margin_x)
+        }
+        WatermarkPosition::Center => free_x / 2,
+    };
+    let y = match position {
+        WatermarkPosition::TopLeft | WatermarkPosition::TopRight => margin_y.min(free_y),
+        WatermarkPosition::BottomLeft | WatermarkPosition::BottomRight => {
+            free_y.saturating_sub(margin_y)
+        }
+        WatermarkPosition::Center => free_y / 2,
+    };
+
+    (x, y)
+}
+
 /// Given original image size and target thumbnail size, finds subimage x, y, width, height in the
 /// original image, so that the cropped image is centered, maximally sized and has identical aspect
 /// ratio to target_size. The output crop is also always non-empty.
@@ -395,6 +949,25 @@ fn crop_coordinates(orig_size: (u32, u32), target_size: (u32, u32)) -> (u32, u32
     }
 }
 
+/// Largest size with the aspect ratio of the original that fits into the target size.
+/// Both dimensions are always at least 1.
+fn contained_size(orig_size: (u32, u32), target_size: (u32, u32)) -> (u32, u32) {
+    let ow = orig_size.0 as u64;
+    let oh = orig_size.1 as u64;
+    let tw = target_size.0 as u64;
+    let th = target_size.1 as u64;
+
+    if ow * th > tw * oh {
+        // Original is wider than target
+        let height = ((oh * tw + ow / 2) / ow) as u32;
+        (target_size.0, height.clamp(1, target_size.1))
+    } else {
+        // Original is narrower than target
+        let width = ((ow * th + oh / 2) / oh) as u32;
+        (width.clamp(1, target_size.0), target_size.1)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -407,6 +980,253 @@ mod test {
         assert!(crop_coordinates((200, 100), (50, 50)) == (50, 0, 100, 100));
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn command_output_is_collected() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo out; echo err >&2"]);
+        let output = output_with_timeout(&mut command, Duration::from_secs(10)).unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout == b"out\n");
+        assert!(output.stderr == b"err\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_command_is_killed() {
+        let start = Instant::now();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let result = output_with_timeout(&mut command, Duration::from_millis(100));
+        assert!(let Err(std::io::ErrorKind::TimedOut) = result.map_err(|e| e.kind()));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn video_extensions() {
+        assert!(is_video(Path::new("a/movie.mp4")));
+        assert!(is_video(Path::new("movie.MKV")));
+        assert!(!is_video(Path::new("photo.jpg")));
+        assert!(!is_video(Path::new("mp4")));
+    }
+
+    #[test]
+    fn document_extensions() {
+        assert!(is_document(Path::new("a/report.docx")));
+        assert!(is_document(Path::new("sheet.ODS")));
+        assert!(!is_document(Path::new("report.pdf")));
+        assert!(!is_document(Path::new("docx")));
+    }
+
+    #[test]
+    fn empty_document_preview_command() {
+        let thumbnailer = DocumentThumbnailer::new(Vec::new());
+        assert!(let Err(_) = thumbnailer.load(Path::new("report.docx")));
+    }
+
+    #[test]
+    fn negotiate_without_hints() {
+        let params = ThumbnailParams::negotiate(128, ClientHints::default());
+        assert!(
+            params
+                == ThumbnailParams {
+                    size: 128,
+                    quality: DEFAULT_QUALITY
+                }
+        );
+    }
+
+    #[test]
+    fn negotiate_dpr() {
+        let hints = ClientHints {
+            dpr: Some(2.625),
+            ..Default::default()
+        };
+        assert!(ThumbnailParams::negotiate(64, hints).size == 176);
+        assert!(ThumbnailParams::negotiate(256, hints).size == 672);
+
+        let hints = ClientHints {
+            dpr: Some(2.0),
+            width: Some(100),
+            ..Default::default()
+        };
+        assert!(ThumbnailParams::negotiate(64, hints).size == 112);
+        assert!(ThumbnailParams::negotiate(128, hints).size == 100);
+    }
+
+    #[test]
+    fn negotiate_save_data() {
+        let hints = ClientHints {
+            dpr: Some(3.0),
+            width: None,
+            save_data: true,
+        };
+        let params = ThumbnailParams::negotiate(128, hints);
+        assert!(
+            params
+                == ThumbnailParams {
+                    size: 128,
+                    quality: SAVE_DATA_QUALITY
+                }
+        );
+    }
+
+    #[proptest]
+    fn negotiate_bounds(
+        #[strategy(1u32..=256)] size: u32,
+        dpr: Option<f32>,
+        width: Option<u32>,
+        save_data: bool,
+    ) {
+        let params = ThumbnailParams::negotiate(
+            size,
+            ClientHints {
+                dpr,
+                width,
+                save_data,
+            },
+        );
+        assert!(params.size > 0);
+        assert!(params.size <= MAX_NEGOTIATED_SIZE);
+    }
+
+    #[test]
+    fn clamp_thumbnail_size_examples() {
+        let allowed = [64, 256, 128];
+        assert!(clamp_thumbnail_size(64, &allowed) == 64);
+        assert!(clamp_thumbnail_size(100, &allowed) == 128);
+        assert!(clamp_thumbnail_size(1, &allowed) == 64);
+        assert!(clamp_thumbnail_size(4000, &allowed) == 256);
+        assert!(clamp_thumbnail_size(100, &[]) == 100);
+        assert!(clamp_thumbnail_size(u32::MAX, &[]) == MAX_THUMBNAIL_SIZE);
+    }
+
+    #[test]
+    fn contained_size_example() {
+        assert!(contained_size((200, 100), (50, 50)) == (50, 25));
+        assert!(contained_size((100, 400), (64, 64)) == (16, 64));
+        assert!(contained_size((10000, 1), (64, 64)) == (64, 1));
+    }
+
+    #[proptest]
+    fn contained_size_fits(
+        #[strategy((1u32..10000, 1u32..10000))] orig_size: (u32, u32),
+        #[strategy((1u32..1000, 1u32..1000))] target_size: (u32, u32),
+    ) {
+        let (w, h) = contained_size(orig_size, target_size);
+        assert!(w >= 1 && w <= target_size.0);
+        assert!(h >= 1 && h <= target_size.1);
+        assert!(w == target_size.0 || h == target_size.1);
+    }
+
+    #[test]
+    fn watermark_offset_example() {
+        assert!(watermark_offset((256, 256), (128, 64), WatermarkPosition::TopLeft) == (8, 8));
+        assert!(
+            watermark_offset((256, 256), (128, 64), WatermarkPosition::BottomRight) == (120, 184)
+        );
+        assert!(watermark_offset((256, 256), (128, 64), WatermarkPosition::Center) == (64, 96));
+    }
+
+    #[test]
+    fn watermark_config() {
+        let watermark: Watermark =
+            serde_json::from_str(r#"{"image": "/srv/logo.png", "opacity": 30}"#).unwrap();
+        assert!(let WatermarkContent::Image { .. } = watermark.content);
+        assert!(watermark.opacity == 30);
+
+        let watermark: Watermark = serde_json::from_str(
+            r#"{"text": "Proof", "font": "/srv/font.ttf", "position": "center"}"#,
+        )
+        .unwrap();
+        assert!(let WatermarkContent::Text { text_size: 5, .. } = watermark.content);
+        assert!(watermark.position == WatermarkPosition::Center);
+    }
+
+    #[test]
+    fn full_size_image_is_watermarked() {
+        let dir =
+            std::env::temp_dir().join(format!("filedl-watermark-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("photo.png");
+        let overlay = dir.join("logo.png");
+        RgbImage::from_pixel(320, 240, Rgb([0, 0, 0]))
+            .save(&source)
+            .unwrap();
+        RgbImage::from_pixel(32, 32, Rgb([255, 255, 255]))
+            .save(&overlay)
+            .unwrap();
+        let watermark = Watermark {
+            content: WatermarkContent::Image { image: overlay },
+            position: WatermarkPosition::TopLeft,
+            opacity: 100,
+        };
+
+        let result = create_watermarked_image(&source, &watermark);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let img = image::load_from_memory(&result.unwrap())
+            .unwrap()
+            .into_rgb8();
+        assert!(img.dimensions() == (320, 240));
+        // The overlay starts after a margin of 1/32 of the image size
+        assert!(img.get_pixel(20, 20).0.iter().all(|&c| c > 200));
+        assert!(img.get_pixel(300, 220).0.iter().all(|&c| c < 50));
+    }
+
+    #[test]
+    fn text_watermark_fits_the_image() {
+        let not_a_font =
+            std::env::temp_dir().join(format!("filedl-font-{:016x}.ttf", rand::random::<u64>()));
+        std::fs::write(&not_a_font, b"not a font").unwrap();
+        let result = render_text("Proof", &not_a_font, 20.0, 1000);
+        std::fs::remove_file(&not_a_font).unwrap();
+        assert!(let Err(FiledlError::WatermarkFont { .. }) = result);
+
+        // Rendering itself needs a real font, which is not part of the repository
+        let font = Path::new("/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf");
+        if !font.exists() {
+            return;
+        }
+        let overlay = render_text("Proof", font, 20.0, 1000).unwrap();
+        assert!((18..=26).contains(&overlay.height()));
+        assert!(overlay.pixels().any(|pixel| pixel.0[3] == 255));
+        let narrow = render_text("Proof", font, 20.0, 30).unwrap();
+        assert!(narrow.width() <= 28);
+    }
+
+    #[proptest]
+    fn watermark_offset_in_bounds(
+        #[strategy(1u32..10000)] img_w: u32,
+        #[strategy(1u32..10000)] img_h: u32,
+        #[strategy(1u32..=#img_w)] overlay_w: u32,
+        #[strategy(1u32..=#img_h)] overlay_h: u32,
+    ) {
+        for position in [
+            WatermarkPosition::TopLeft,
+            WatermarkPosition::TopRight,
+            WatermarkPosition::BottomLeft,
+            WatermarkPosition::BottomRight,
+            WatermarkPosition::Center,
+        ] {
+            let (x, y) = watermark_offset((img_w, img_h), (overlay_w, overlay_h), position);
+            assert!(x + overlay_w <= img_w);
+            assert!(y + overlay_h <= img_h);
+        }
+    }
+
+    #[test]
+    fn blend_overlay_opacity() {
+        let mut img = RgbImage::from_pixel(2, 2, Rgb([0, 0, 0]));
+        let overlay = RgbaImage::from_pixel(1, 1, image::Rgba([255, 255, 255, 255]));
+        blend_overlay(&mut img, &overlay, (1, 1), 100);
+        assert!(img.get_pixel(1, 1) == &Rgb([255, 255, 255]));
+        assert!(img.get_pixel(0, 0) == &Rgb([0, 0, 0]));
+
+        let mut img = RgbImage::from_pixel(1, 1, Rgb([0, 0, 0]));
+        blend_overlay(&mut img, &overlay, (0, 0), 50);
+        assert!(img.get_pixel(0, 0) == &Rgb([127, 127, 127]));
+    }
+
     #[proptest]
     fn crop_coordinates_all(orig_size: (u32, u32), target_size: (u32, u32)) {
         prop_assume!(orig_size.0 > 0);
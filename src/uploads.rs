@@ -0,0 +1,305 @@
+//! Registry of uploads in progress, so that clients can poll progress of their uploads
+//! and cancel them.
+
+use crate::error::{FiledlError, Result};
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// How long finished sessions are kept around for polling.
+const FINISHED_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Longest accepted session ID.
+pub const MAX_SESSION_ID_LENGTH: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    /// The request body is being received
+    Receiving,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Progress of a single upload, as reported to the client.
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadProgress {
+    pub path: String,
+    pub state: UploadState,
+    pub bytes_received: u64,
+    /// Size of the upload from Content-Length, if known
+    pub total_bytes: Option<u64>,
+    pub started: DateTime<Utc>,
+    /// User facing description of the failure
+    pub error: Option<&'static str>,
+    #[serde(skip)]
+    finished: Option<Instant>,
+}
+
+impl UploadProgress {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.finished
+            .is_some_and(|finished| now.saturating_duration_since(finished) > FINISHED_RETENTION)
+    }
+}
+
+/// Upload sessions identified by client chosen IDs.
+#[derive(Debug, Default)]
+pub struct UploadSessions {
+    sessions: Mutex<HashMap<Arc<str>, Arc<Mutex<UploadProgress>>>>,
+}
+
+impl UploadSessions {
+    /// Register a new upload and wrap its body so that the progress is tracked.
+    /// Fails if an upload with the same ID is still receiving.
+    pub fn track<S>(
+        &self,
+        session_id: &str,
+        path: &str,
+        total_bytes: Option<u64>,
+        body: S,
+    ) -> Result<TrackedBody<S>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, progress| !progress.lock().unwrap().is_expired(now));
+
+        if let Some(existing) = sessions.get(session_id) {
+            if existing.lock().unwrap().state == UploadState::Receiving {
+                return Err(FiledlError::UploadSessionActive);
+            }
+        }
+
+        let progress = Arc::new(Mutex::new(UploadProgress {
+            path: path.to_owned(),
+            state: UploadState::Receiving,
+            bytes_received: 0,
+            total_bytes,
+            started: Utc::now(),
+            error: None,
+            finished: None,
+        }));
+        sessions.insert(session_id.into(), Arc::clone(&progress));
+
+        Ok(TrackedBody {
+            inner: body,
+            progress,
+        })
+    }
+
+    /// Record the result of the upload. A cancelled upload stays cancelled.
+    pub fn finish<T>(&self, session_id: &str, result: &Result<T>) {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(progress) = sessions.get(session_id) else {
+            return;
+        };
+        let mut progress = progress.lock().unwrap();
+        if progress.state == UploadState::Receiving {
+            match result {
+                Ok(_) => progress.state = UploadState::Completed,
+                Err(e) => {
+                    progress.state = UploadState::Failed;
+                    progress.error = Some(e.user_message());
+                }
+            }
+        }
+        progress.finished = Some(Instant::now());
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<UploadProgress> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .map(|progress| progress.lock().unwrap().clone())
+    }
+
+    /// Progress of all uploads, both running and recently finished.
+    pub fn all(&self) -> HashMap<Arc<str>, UploadProgress> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, progress)| (Arc::clone(id), progress.lock().unwrap().clone()))
+            .collect()
+    }
+
+    /// Cancel a running upload, its body fails at the next chunk.
+    /// Returns false if the session doesn't exist or has already finished.
+    pub fn cancel(&self, session_id: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(progress) = sessions.get(session_id) else {
+            return false;
+        };
+        let mut progress = progress.lock().unwrap();
+        if progress.state != UploadState::Receiving {
+            return false;
+        }
+        progress.state = UploadState::Cancelled;
+        progress.error = Some(FiledlError::UploadCancelled.user_message());
+        true
+    }
+
+    /// Whether the session was cancelled, to report the cancellation instead of
+    /// the error it caused.
+    pub fn is_cancelled(&self, session_id: &str) -> bool {
+        self.get(session_id)
+            .is_some_and(|progress| progress.state == UploadState::Cancelled)
+    }
+}
+
+/// Upload body counting the received bytes into the session progress.
+pub struct TrackedBody<S> {
+    inner: S,
+    progress: Arc<Mutex<UploadProgress>>,
+}
+
+/// Marks the session as failed if the body goes away while the upload is still receiving,
+/// for example when the client disconnects and the handler future is dropped.
+/// The handler must record the result using `UploadSessions::finish` before dropping the body.
+impl<S> Drop for TrackedBody<S> {
+    fn drop(&mut self) {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.state == UploadState::Receiving {
+            progress.state = UploadState::Failed;
+            progress.error = Some(FiledlError::UploadInterrupted.user_message());
+        }
+        if progress.finished.is_none() {
+            progress.finished = Some(Instant::now());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TrackedBodyError<E> {
+    Inner(E),
+    Cancelled,
+}
+
+impl<E: Display> Display for TrackedBodyError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackedBodyError::Inner(e) => e.fmt(f),
+            TrackedBodyError::Cancelled => f.write_str("Upload was cancelled"),
+        }
+    }
+}
+
+impl<S, E> Stream for TrackedBody<S>
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Unpin,
+{
+    type Item = std::result::Result<Bytes, TrackedBodyError<E>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.progress.lock().unwrap().state == UploadState::Cancelled {
+            return Poll::Ready(Some(Err(TrackedBodyError::Cancelled)));
+        }
+
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.progress.lock().unwrap().bytes_received += chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(TrackedBodyError::Inner(e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert2::assert;
+    use futures_util::{stream, StreamExt};
+    use std::{convert::Infallible, future::Future};
+
+    fn run<F: Future>(f: F) -> F::Output {
+        actix_web::rt::System::new().block_on(f)
+    }
+
+    fn body(
+        chunks: &[&'static [u8]],
+    ) -> impl Stream<Item = std::result::Result<Bytes, Infallible>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn counts_received_bytes() {
+        let sessions = UploadSessions::default();
+        let mut tracked = sessions
+            .track("a", "obj/file", Some(6), body(&[b"abc", b"def"]))
+            .unwrap();
+        run(async {
+            while let Some(chunk) = tracked.next().await {
+                chunk.unwrap();
+            }
+        });
+        sessions.finish("a", &Ok(()));
+
+        let progress = sessions.get("a").unwrap();
+        assert!(progress.bytes_received == 6);
+        assert!(progress.state == UploadState::Completed);
+    }
+
+    #[test]
+    fn cancel_fails_body() {
+        let sessions = UploadSessions::default();
+        let mut tracked = sessions.track("a", "obj", None, body(&[b"abc"])).unwrap();
+        assert!(sessions.cancel("a"));
+        let chunk = run(tracked.next());
+        assert!(let Some(Err(TrackedBodyError::Cancelled)) = chunk);
+
+        sessions.finish("a", &Err::<(), _>(FiledlError::UploadCancelled));
+        assert!(sessions.get("a").unwrap().state == UploadState::Cancelled);
+        assert!(!sessions.cancel("a"));
+    }
+
+    #[test]
+    fn active_session_id_is_rejected() {
+        let sessions = UploadSessions::default();
+        let _tracked = sessions.track("a", "obj", None, body(&[])).unwrap();
+        assert!(let Err(FiledlError::UploadSessionActive) = sessions.track("a", "obj", None, body(&[])));
+
+        sessions.finish("a", &Ok(()));
+        assert!(let Ok(_) = sessions.track("a", "obj", None, body(&[])));
+    }
+
+    #[test]
+    fn dropped_body_fails_session() {
+        let sessions = UploadSessions::default();
+        let tracked = sessions.track("a", "obj", None, body(&[b"abc"])).unwrap();
+        drop(tracked);
+
+        let progress = sessions.get("a").unwrap();
+        assert!(progress.state == UploadState::Failed);
+        assert!(progress.error == Some(FiledlError::UploadInterrupted.user_message()));
+        assert!(progress.finished.is_some());
+        assert!(let Ok(_) = sessions.track("a", "obj", None, body(&[])));
+    }
+
+    #[test]
+    fn finish_before_drop_keeps_result() {
+        let sessions = UploadSessions::default();
+        let tracked = sessions.track("a", "obj", None, body(&[])).unwrap();
+        sessions.finish("a", &Ok(()));
+        drop(tracked);
+        assert!(sessions.get("a").unwrap().state == UploadState::Completed);
+    }
+}
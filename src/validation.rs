@@ -0,0 +1,86 @@
+use crate::error::FiledlError;
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::{ready, Ready},
+    ops::Deref,
+};
+
+/// Description of a problem with a request parameter
+#[derive(Clone, Debug, Serialize)]
+pub struct FieldError {
+    /// Name of the offending parameter, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'static str>,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: Some(field),
+            message: message.into(),
+        }
+    }
+}
+
+/// Request parameters that check constraints beyond what deserialization can express.
+pub trait Validate {
+    /// Returns list of problems found, empty if the parameters are valid.
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+/// Query string extractor that deserializes and validates the parameters,
+/// failing with 400 and a list of problems instead of the actix default error.
+#[derive(Debug)]
+pub struct ValidQuery<T>(pub T);
+
+impl<T> Deref for ValidQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate> ValidQuery<T> {
+    pub fn from_query(query_string: &str) -> Result<Self, FiledlError> {
+        let value: T =
+            serde_urlencoded::from_str(query_string).map_err(|e| FiledlError::InvalidRequest {
+                errors: vec![FieldError {
+                    field: None,
+                    message: e.to_string(),
+                }],
+            })?;
+
+        let errors = value.validate();
+        if errors.is_empty() {
+            Ok(ValidQuery(value))
+        } else {
+            Err(FiledlError::InvalidRequest { errors })
+        }
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromRequest for ValidQuery<T> {
+    type Error = FiledlError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_query(req.query_string()))
+    }
+}
+
+/// Error handler for actix's built-in extractors, producing the same output as `ValidQuery`.
+pub fn extractor_error_handler(
+    error: impl std::fmt::Display,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    FiledlError::InvalidRequest {
+        errors: vec![FieldError {
+            field: None,
+            message: error.to_string(),
+        }],
+    }
+    .into()
+}
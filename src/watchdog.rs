@@ -0,0 +1,128 @@
+//! Detection of response bodies that stopped producing data, for example because
+//! of a hung network file system.
+
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    web::Bytes,
+};
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Response body wrapper that fails the response if the inner body doesn't produce any data
+/// for longer than the timeout.
+/// Only time spent waiting for the inner body counts, a slow client doesn't trigger the timeout.
+/// Aborting the response frees the connection, a blocking read that is stuck in the file system
+/// still occupies its thread until the file system recovers.
+pub struct WatchdogBody {
+    inner: BoxBody,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    /// The inner body returned pending and the deadline is running
+    waiting: bool,
+    failed: bool,
+    /// What is being streamed, for the log message
+    description: String,
+}
+
+impl WatchdogBody {
+    pub fn new(inner: BoxBody, timeout: Duration, description: impl Into<String>) -> Self {
+        WatchdogBody {
+            inner,
+            timeout,
+            deadline: Box::pin(sleep(timeout)),
+            waiting: false,
+            failed: false,
+            description: description.into(),
+        }
+    }
+}
+
+impl MessageBody for WatchdogBody {
+    type Error = Box<dyn Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        if this.failed {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Pending => {
+                if !this.waiting {
+                    this.waiting = true;
+                    this.deadline.as_mut().reset(Instant::now() + this.timeout);
+                }
+                if this.deadline.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                log::warn!(
+                    "Streaming {} stalled for {:?}, aborting the response",
+                    this.description,
+                    this.timeout
+                );
+                this.failed = true;
+                Poll::Ready(Some(Err(Box::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Reading the data stalled",
+                )))))
+            }
+            ready => {
+                this.waiting = false;
+                ready
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::body::{to_bytes, BodyStream};
+    use assert2::assert;
+    use futures_util::{stream, StreamExt};
+
+    fn run<F: Future>(f: F) -> F::Output {
+        actix_web::rt::System::new().block_on(f)
+    }
+
+    #[test]
+    fn passes_data_through() {
+        let inner = BoxBody::new(BodyStream::new(stream::iter(vec![
+            Ok::<_, io::Error>(Bytes::from_static(b"abc")),
+            Ok(Bytes::from_static(b"def")),
+        ])));
+        let result = run(async {
+            let body = WatchdogBody::new(inner, Duration::from_secs(10), "test");
+            to_bytes(body).await
+        });
+        assert!(result.unwrap() == Bytes::from_static(b"abcdef"));
+    }
+
+    #[test]
+    fn aborts_stalled_body() {
+        let inner = BoxBody::new(BodyStream::new(
+            stream::iter(vec![Ok::<_, io::Error>(Bytes::from_static(b"abc"))])
+                .chain(stream::pending()),
+        ));
+        let result = run(async {
+            let body = WatchdogBody::new(inner, Duration::from_millis(20), "test");
+            to_bytes(body).await
+        });
+        assert!(let Err(_) = result);
+    }
+}